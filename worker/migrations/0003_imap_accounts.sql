@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS imap_accounts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL UNIQUE,
+    host TEXT NOT NULL,
+    port INTEGER NOT NULL DEFAULT 993,
+    tls INTEGER NOT NULL DEFAULT 1,
+    username TEXT NOT NULL,
+    password_enc TEXT NOT NULL,
+    folder TEXT NOT NULL DEFAULT 'INBOX',
+    sender_filter TEXT,
+    uid_validity INTEGER,
+    last_uid INTEGER NOT NULL DEFAULT 0,
+    last_polled DATETIME,
+    last_error TEXT,
+    created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS sheet_outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    log_id INTEGER NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_error TEXT,
+    synced_at DATETIME,
+    created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(log_id) REFERENCES logs(id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS sheet_outbox_due ON sheet_outbox(status, next_attempt);
+
+-- Last permanent Sheets error (lost access, deleted sheet), shown to the user
+-- until the sheet is reconnected or a sync succeeds.
+ALTER TABLE users ADD COLUMN sheet_error TEXT;
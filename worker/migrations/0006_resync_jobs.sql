@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS resync_jobs (
+    id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    mode TEXT NOT NULL,
+    date_from DATE,
+    date_to DATE,
+    status TEXT NOT NULL DEFAULT 'running',
+    total INTEGER NOT NULL DEFAULT 0,
+    written INTEGER NOT NULL DEFAULT 0,
+    error TEXT,
+    created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    finished DATETIME,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,9 @@
+-- Edits and deletions reuse the outbox row of their log: `op` says whether
+-- the sheet row should be written (`upsert`) or removed (`delete`).
+-- `seq` is bumped on every re-queue so a sync that was in flight for an older
+-- operation cannot overwrite the newer one's status.
+ALTER TABLE sheet_outbox ADD COLUMN op TEXT NOT NULL DEFAULT 'upsert';
+ALTER TABLE sheet_outbox ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+-- Deleted logs are kept until their sheet row has been removed.
+ALTER TABLE logs ADD COLUMN deleted_at DATETIME;
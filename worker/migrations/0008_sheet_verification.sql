@@ -0,0 +1,4 @@
+-- Result of the last access check: verified, not_found, not_shared,
+-- read_only or unverified (the check itself failed).
+ALTER TABLE users ADD COLUMN sheet_status TEXT;
+ALTER TABLE users ADD COLUMN sheet_verified_at DATETIME;
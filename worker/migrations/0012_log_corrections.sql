@@ -0,0 +1,21 @@
+-- Values of the log as last written to the sheet (JSON), the base that
+-- sheet edits and DB edits are compared against when pulling edits back.
+ALTER TABLE sheet_outbox ADD COLUMN synced_values TEXT;
+
+-- Audit trail of values pulled from the sheet: `applied` edits, `conflict`s
+-- where both the sheet and the DB changed (the DB value is kept), and
+-- `rejected` cells that could not be parsed.
+CREATE TABLE IF NOT EXISTS log_corrections (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    log_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    field TEXT NOT NULL,
+    db_value TEXT,
+    sheet_value TEXT,
+    status TEXT NOT NULL,
+    created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(log_id) REFERENCES logs(id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS log_corrections_user ON log_corrections(user_id, id);
@@ -0,0 +1,16 @@
+-- Where a user's logs are exported: `sheets` (Google Sheets) or `excel`
+-- (an Excel Online workbook).
+ALTER TABLE users ADD COLUMN sink TEXT NOT NULL DEFAULT 'sheets';
+
+-- The workbook an Excel user exports to, with their Microsoft OAuth grant
+-- sealed with CREDENTIALS_KEY.
+CREATE TABLE IF NOT EXISTS excel_workbooks (
+    user_id INTEGER PRIMARY KEY,
+    item_id TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    refresh_token_enc TEXT NOT NULL,
+    revoked_at DATETIME,
+    created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,21 @@
+-- Amounts move from REAL dollars to INTEGER cents so totals are exact, and
+-- each log records its currency. SQLite cannot change a column's type, so
+-- the cents go into new columns that then take the old names.
+ALTER TABLE logs ADD COLUMN gross_cents INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE logs ADD COLUMN tips_cents INTEGER NOT NULL DEFAULT 0;
+UPDATE logs SET gross_cents = CAST(ROUND(gross * 100) AS INTEGER),
+                tips_cents = CAST(ROUND(tips * 100) AS INTEGER);
+ALTER TABLE logs DROP COLUMN gross;
+ALTER TABLE logs DROP COLUMN tips;
+ALTER TABLE logs RENAME COLUMN gross_cents TO gross;
+ALTER TABLE logs RENAME COLUMN tips_cents TO tips;
+ALTER TABLE logs ADD COLUMN currency TEXT NOT NULL DEFAULT 'USD';
+
+-- The values last written to the sheet are compared with the log's, so
+-- they move to cents too.
+UPDATE sheet_outbox
+SET synced_values = json_set(
+        synced_values,
+        '$.gross', CAST(ROUND(json_extract(synced_values, '$.gross') * 100) AS INTEGER),
+        '$.tips', CAST(ROUND(json_extract(synced_values, '$.tips') * 100) AS INTEGER))
+WHERE synced_values IS NOT NULL;
@@ -0,0 +1,26 @@
+-- Personal data exports. The archive is written to EXPORT_DIR/<id>.zip and
+-- served at /api/exports/<token> until `expires`.
+CREATE TABLE IF NOT EXISTS export_jobs (
+    id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    token TEXT NOT NULL UNIQUE,
+    status TEXT NOT NULL DEFAULT 'running',
+    error TEXT,
+    created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    finished DATETIME,
+    expires DATETIME,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS export_jobs_user ON export_jobs(user_id);
+
+-- Lemon Squeezy events received for a known user.
+CREATE TABLE IF NOT EXISTS billing_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    received DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS billing_events_user ON billing_events(user_id);
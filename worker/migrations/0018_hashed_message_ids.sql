@@ -0,0 +1,8 @@
+-- dedupe_key kept the Message-ID verbatim (`mid:<id>`), so it survived the
+-- retention scrub. New keys are `mid-sha256:<hex sha256 of the id>`. SQLite
+-- has no sha256(), so this queues the rewrite and db::migrate does it once,
+-- deleting the row in the same transaction.
+CREATE TABLE IF NOT EXISTS pending_rewrites (
+    name TEXT PRIMARY KEY
+);
+INSERT INTO pending_rewrites (name) VALUES ('hash_message_id_keys');
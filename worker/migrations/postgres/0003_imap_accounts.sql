@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS imap_accounts (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    user_id BIGINT NOT NULL UNIQUE,
+    host TEXT NOT NULL,
+    port BIGINT NOT NULL DEFAULT 993,
+    tls BOOLEAN NOT NULL DEFAULT TRUE,
+    username TEXT NOT NULL,
+    password_enc TEXT NOT NULL,
+    folder TEXT NOT NULL DEFAULT 'INBOX',
+    sender_filter TEXT,
+    uid_validity BIGINT,
+    last_uid BIGINT NOT NULL DEFAULT 0,
+    last_polled TIMESTAMP,
+    last_error TEXT,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
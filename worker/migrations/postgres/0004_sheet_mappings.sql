@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS sheet_mappings (
+    user_id BIGINT PRIMARY KEY,
+    tab TEXT NOT NULL DEFAULT 'Sheet1',
+    start_column TEXT NOT NULL DEFAULT 'A',
+    fields JSONB NOT NULL DEFAULT '["date","gross","tips","mileage"]',
+    extras JSONB NOT NULL DEFAULT '[]',
+    date_format TEXT NOT NULL DEFAULT '%Y-%m-%d',
+    updated TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
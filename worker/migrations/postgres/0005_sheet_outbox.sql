@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS sheet_outbox (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    log_id BIGINT NOT NULL UNIQUE,
+    user_id BIGINT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts BIGINT NOT NULL DEFAULT 0,
+    next_attempt TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_error TEXT,
+    synced_at TIMESTAMP,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(log_id) REFERENCES logs(id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS sheet_outbox_due ON sheet_outbox(status, next_attempt);
+
+-- Last permanent Sheets error (lost access, deleted sheet), shown to the user
+-- until the sheet is reconnected or a sync succeeds.
+ALTER TABLE users ADD COLUMN sheet_error TEXT;
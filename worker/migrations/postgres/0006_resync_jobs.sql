@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS resync_jobs (
+    id TEXT PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    mode TEXT NOT NULL,
+    date_from DATE,
+    date_to DATE,
+    status TEXT NOT NULL DEFAULT 'running',
+    total BIGINT NOT NULL DEFAULT 0,
+    written BIGINT NOT NULL DEFAULT 0,
+    error TEXT,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    finished TIMESTAMP,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
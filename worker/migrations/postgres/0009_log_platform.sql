@@ -0,0 +1,2 @@
+-- Gig platform a payout came from (Uber, Lyft, ...); NULL when unknown.
+ALTER TABLE logs ADD COLUMN platform TEXT;
@@ -0,0 +1,2 @@
+-- none, monthly (`2026-10` tabs) or yearly (`2026` tabs).
+ALTER TABLE sheet_mappings ADD COLUMN rotation TEXT NOT NULL DEFAULT 'none';
@@ -0,0 +1,11 @@
+-- A user's own Google OAuth grant, used for Sheets writes instead of the
+-- service account. The refresh token is sealed with CREDENTIALS_KEY.
+CREATE TABLE IF NOT EXISTS google_credentials (
+    user_id BIGINT PRIMARY KEY,
+    scope TEXT NOT NULL,
+    refresh_token_enc TEXT NOT NULL,
+    revoked_at TIMESTAMP,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,21 @@
+-- Values of the log as last written to the sheet, the base that sheet edits
+-- and DB edits are compared against when pulling edits back.
+ALTER TABLE sheet_outbox ADD COLUMN synced_values JSONB;
+
+-- Audit trail of values pulled from the sheet: `applied` edits, `conflict`s
+-- where both the sheet and the DB changed (the DB value is kept), and
+-- `rejected` cells that could not be parsed.
+CREATE TABLE IF NOT EXISTS log_corrections (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    log_id BIGINT NOT NULL,
+    user_id BIGINT NOT NULL,
+    field TEXT NOT NULL,
+    db_value TEXT,
+    sheet_value TEXT,
+    status TEXT NOT NULL,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(log_id) REFERENCES logs(id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS log_corrections_user ON log_corrections(user_id, id);
@@ -0,0 +1,2 @@
+-- Amounts move from dollars to integer cents and each log records its
+-- currency. Postgres support came later, so 0001 already creates them.
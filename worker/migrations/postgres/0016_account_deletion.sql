@@ -0,0 +1,12 @@
+-- Set when the user asks to delete their account. Once it passes the user
+-- and everything cascading from them is purged, unless they cancel first.
+ALTER TABLE users ADD COLUMN delete_after TIMESTAMP;
+
+-- Forward keys of purged accounts. New keys are never drawn from here, so
+-- mail sent to an old address cannot reach someone else's account.
+CREATE TABLE IF NOT EXISTS retired_forward_keys (
+    forward_key TEXT PRIMARY KEY,
+    retired TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS messages_received ON messages(received_at);
@@ -0,0 +1,10 @@
+-- dedupe_key kept the Message-ID verbatim (`mid:<id>`), so it survived the
+-- retention scrub. New keys are `mid-sha256:<hex sha256 of the id>`.
+-- pending_rewrites queues data rewrites SQLite has to do in db::migrate;
+-- Postgres does them here.
+CREATE TABLE IF NOT EXISTS pending_rewrites (
+    name TEXT PRIMARY KEY
+);
+UPDATE messages
+SET dedupe_key = 'mid-sha256:' || encode(sha256(convert_to(substr(dedupe_key, 5), 'UTF8')), 'hex')
+WHERE dedupe_key LIKE 'mid:%';
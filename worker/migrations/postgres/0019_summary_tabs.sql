@@ -0,0 +1,12 @@
+-- Summary tabs the worker created in each spreadsheet, by tab id. Only these
+-- are ever cleared and rewritten, so a user's own tab of the same name is
+-- left alone.
+CREATE TABLE IF NOT EXISTS summary_tabs (
+    user_id BIGINT NOT NULL,
+    spreadsheet_id TEXT NOT NULL,
+    tab TEXT NOT NULL,
+    tab_id BIGINT NOT NULL,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, spreadsheet_id, tab),
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+-- Set when a user's summary tabs need rewriting; the summary task clears it
+-- and refreshes them at most once every SUMMARY_REFRESH_SECS.
+ALTER TABLE users ADD COLUMN summary_stale BOOLEAN NOT NULL DEFAULT FALSE;
@@ -1,18 +1,38 @@
 use crate::db;
-use crate::models::{LemonWebhook, LogEntry, User, UserUpsert};
+use crate::excel::{GraphApiError, FILES_SCOPE};
+use crate::export;
+use crate::imap;
+use crate::import;
+use crate::models::{
+    ExcelConnection, ExcelWorkbook, ExportJob, GoogleAuthorization, GoogleCredential, ImapAccount,
+    ImapAccountUpsert, ImportJob, LemonWebhook, LogCorrection, LogEntry, LogUpdate, PullReport,
+    ResyncJob, ResyncRequest, SheetMapping, SinkKind, SinkSelection, User, UserUpsert, Webhook,
+    WebhookCreate, WebhookDelivery,
+};
+use crate::oauth::GrantRevoked;
+use crate::pull;
+use crate::resync;
+use crate::sheets::{SheetAccess, SHEETS_SCOPE};
+use crate::sink::{self, ExportSink, NoDestination};
 use crate::state::AppState;
-use axum::body::Bytes;
-use axum::extract::{Path, State};
-use axum::http::{HeaderMap, StatusCode};
+use crate::webhooks;
+use anyhow::Context;
+use axum::body::{Body, Bytes};
+use axum::extract::{DefaultBodyLimit, Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::IntoResponse;
-use axum::routing::{get, post};
+use axum::routing::{delete, get, patch, post, put};
 use axum::{Json, Router};
 use chrono::{Duration, Utc};
 use constant_time_eq::constant_time_eq;
+use futures::StreamExt;
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
 use tower_http::cors::{Any, CorsLayer};
 use tracing::{info, warn};
+use uuid::Uuid;
 
 pub fn app_router(state: AppState) -> Router {
     let cors = CorsLayer::new()
@@ -22,7 +42,60 @@ pub fn app_router(state: AppState) -> Router {
 
     Router::new()
         .route("/api/users", post(upsert_user))
+        .route("/api/users/:id", delete(delete_user))
+        .route("/api/users/:id/deletion", delete(cancel_deletion))
+        .route("/api/users/:id/exports", post(start_export))
+        .route("/api/users/:id/exports/:job_id", get(export_status))
+        .route("/api/exports/:token", get(download_export))
         .route("/api/users/:id/logs", get(list_logs))
+        .route(
+            "/api/users/:id/logs/:log_id",
+            patch(update_log).delete(delete_log),
+        )
+        .route(
+            "/api/users/:id/imports",
+            post(upload_import).layer(DefaultBodyLimit::max(IMPORT_UPLOAD_LIMIT)),
+        )
+        .route("/api/users/:id/imports/:job_id", get(import_status))
+        .route(
+            "/api/users/:id/imap",
+            get(get_imap).put(put_imap).delete(delete_imap),
+        )
+        .route(
+            "/api/users/:id/google",
+            get(get_google).put(put_google).delete(delete_google),
+        )
+        .route(
+            "/api/users/:id/excel",
+            get(get_excel).put(put_excel).delete(delete_excel),
+        )
+        .route("/api/users/:id/sink", put(put_sink))
+        .route(
+            "/api/users/:id/sheet-mapping",
+            get(get_sheet_mapping).put(put_sheet_mapping),
+        )
+        .route("/api/users/:id/sheet", post(create_sheet))
+        .route("/api/users/:id/sheet/bootstrap", post(bootstrap_sheet))
+        .route("/api/users/:id/sheet/resync", post(start_resync))
+        .route("/api/users/:id/sheet/resync/:job_id", get(resync_status))
+        .route("/api/users/:id/sheet/pull", post(pull_sheet))
+        .route("/api/users/:id/corrections", get(list_corrections))
+        .route(
+            "/api/users/:id/webhooks",
+            get(list_webhooks).post(create_webhook),
+        )
+        .route(
+            "/api/users/:id/webhooks/:webhook_id",
+            delete(delete_webhook),
+        )
+        .route(
+            "/api/users/:id/webhooks/:webhook_id/deliveries",
+            get(webhook_deliveries),
+        )
+        .route(
+            "/api/users/:id/webhooks/:webhook_id/test",
+            post(test_webhook),
+        )
         .route("/api/lemon-webhook", post(lemon_webhook))
         .route("/health", get(health))
         .layer(cors)
@@ -31,15 +104,135 @@ pub fn app_router(state: AppState) -> Router {
 
 type HmacSha256 = Hmac<Sha256>;
 
+const MAX_WEBHOOKS: usize = 5;
+
+const IMPORT_UPLOAD_LIMIT: usize = 2 * 1024 * 1024 * 1024;
+
+/// Saves the user and, when the sheet is new or not yet verified, checks
+/// that the service account can edit it. Access problems are stored on the
+/// user and returned as a structured `422`; a check that could not run
+/// (Sheets outage) leaves the sheet `unverified` without failing the save.
 async fn upsert_user(
     State(state): State<AppState>,
     Json(payload): Json<UserUpsert>,
 ) -> Result<Json<UserResponse>, ApiError> {
     let payload = normalize_sheet(payload);
-    let user = db::upsert_user(&state.pool, payload).await?;
+    let previous = db::user_by_google_id(&state.pool, &payload.google_id).await?;
+    let mut user = db::upsert_user(&state.pool, payload).await?;
+    let changed = previous.and_then(|p| p.sheet_id) != user.sheet_id;
+
+    if let Some(sheet_id) = user.sheet_id.clone() {
+        if changed || user.sheet_status.as_deref() != Some("verified") {
+            let sheets = state.sheets_for(user.id).await?;
+            let status = match sheets.verify_access(&sheet_id).await {
+                Ok(SheetAccess::Writable) => SheetAccess::Writable.as_str(),
+                Ok(problem) => {
+                    db::set_sheet_status(&state.pool, user.id, problem.as_str()).await?;
+                    return Err(ApiError::SheetAccess {
+                        access: problem,
+                        service_account: (!sheets.is_user())
+                            .then(|| state.sheets.service_account_email().to_string()),
+                        user_id: user.id,
+                    });
+                }
+                Err(err) => {
+                    warn!("Could not verify sheet for user {}: {err:#}", user.id);
+                    "unverified"
+                }
+            };
+            user = db::set_sheet_status(&state.pool, user.id, status).await?;
+            spawn_sheet_bootstrap(state.clone(), user.clone());
+        }
+    }
     Ok(Json(UserResponse::from(user, &state)))
 }
 
+/// Schedules the account for deletion after `DELETION_GRACE_DAYS`. Until
+/// then everything keeps working and `DELETE .../deletion` undoes it.
+async fn delete_user(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<(StatusCode, Json<UserResponse>), ApiError> {
+    if db::user_by_id(&state.pool, id).await?.is_none() {
+        return Err(ApiError::NotFound);
+    }
+    let grace_secs = state.config.deletion_grace_days as i64 * 86_400;
+    let user = db::schedule_user_deletion(&state.pool, id, grace_secs).await?;
+    Ok((StatusCode::ACCEPTED, Json(UserResponse::from(user, &state))))
+}
+
+/// Cancels a scheduled account deletion.
+async fn cancel_deletion(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<UserResponse>, ApiError> {
+    if !db::cancel_user_deletion(&state.pool, id).await? {
+        return Err(ApiError::NotFound);
+    }
+    let user = db::user_by_id(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(UserResponse::from(user, &state)))
+}
+
+/// Starts building an archive of everything stored about the user. Poll
+/// the job; once `completed` it carries a `downloadUrl` that works for
+/// `EXPORT_LINK_HOURS`.
+async fn start_export(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<(StatusCode, Json<ExportJobResponse>), ApiError> {
+    let user = db::user_by_id(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let jobs = db::export_jobs(&state.pool, id).await?;
+    if jobs.iter().any(|job| job.status == "running") {
+        return Err(ApiError::Conflict("An export is already running"));
+    }
+    let job = db::create_export_job(&state.pool, id, &export::new_token()).await?;
+    export::spawn_export(state, user, job.clone());
+    Ok((StatusCode::ACCEPTED, Json(ExportJobResponse::from(job))))
+}
+
+async fn export_status(
+    State(state): State<AppState>,
+    Path((id, job_id)): Path<(i64, String)>,
+) -> Result<Json<ExportJobResponse>, ApiError> {
+    let job = db::export_job(&state.pool, id, &job_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(ExportJobResponse::from(job)))
+}
+
+/// Serves a finished export. The token is the only credential, so an unknown
+/// and an expired link look the same.
+async fn download_export(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let job = db::downloadable_export(&state.pool, &token)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    // Archives can be large, so they are streamed rather than read whole.
+    let archive = tokio::fs::File::open(export::archive_path(&state.config, &job.id))
+        .await
+        .map_err(|_| ApiError::NotFound)?;
+    let length = archive
+        .metadata()
+        .await
+        .context("Failed to read export archive metadata")?
+        .len();
+    let disposition = format!("attachment; filename=\"driversheet-export-{}.zip\"", job.id);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(ReaderStream::new(archive)),
+    ))
+}
+
 async fn list_logs(
     State(state): State<AppState>,
     Path(id): Path<i64>,
@@ -59,6 +252,589 @@ async fn list_logs(
     Ok(Json(logs))
 }
 
+/// Corrects a parsed value; the change is queued for the user's sheet.
+async fn update_log(
+    State(state): State<AppState>,
+    Path((id, log_id)): Path<(i64, i64)>,
+    Json(update): Json<LogUpdate>,
+) -> Result<Json<LogEntry>, ApiError> {
+    let log = db::update_log(&state.pool, id, log_id, &update)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    state.sheet_sync.notify_one();
+    state.webhook_delivery.notify_one();
+    Ok(Json(log))
+}
+
+async fn delete_log(
+    State(state): State<AppState>,
+    Path((id, log_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, ApiError> {
+    if !db::delete_log(&state.pool, id, log_id).await? {
+        return Err(ApiError::NotFound);
+    }
+    state.sheet_sync.notify_one();
+    state.webhook_delivery.notify_one();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Accepts a raw mbox upload, stores it under `TMP_DIR` and imports it in
+/// the background. Poll the returned job for progress.
+async fn upload_import(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    body: Body,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(user) = db::user_by_id(&state.pool, id).await? else {
+        return Err(ApiError::NotFound);
+    };
+
+    tokio::fs::create_dir_all(&state.config.tmp_dir)
+        .await
+        .context("Failed to create tmp dir")?;
+    let path = std::path::Path::new(&state.config.tmp_dir).join(format!("{}.mbox", Uuid::new_v4()));
+    let mut file = tokio::fs::File::create(&path)
+        .await
+        .context("Failed to create upload file")?;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                tokio::fs::remove_file(&path).await.ok();
+                warn!(?err, "mbox upload interrupted");
+                return Err(ApiError::UploadFailed);
+            }
+        };
+        file.write_all(&chunk)
+            .await
+            .context("Failed to write upload")?;
+    }
+    file.flush().await.context("Failed to write upload")?;
+
+    let job = db::create_import_job(&state.pool, user.id).await?;
+    import::spawn_mbox_import(state.clone(), user, job.id.clone(), path);
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+async fn import_status(
+    State(state): State<AppState>,
+    Path((id, job_id)): Path<(i64, String)>,
+) -> Result<Json<ImportJob>, ApiError> {
+    let job = db::import_job(&state.pool, id, &job_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(job))
+}
+
+async fn get_imap(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<ImapAccount>, ApiError> {
+    let account = db::imap_account(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(account))
+}
+
+/// Connects a mailbox for pull mode. The login is tried before anything is
+/// stored; the password is sealed with `CREDENTIALS_KEY`.
+async fn put_imap(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(settings): Json<ImapAccountUpsert>,
+) -> Result<Json<ImapAccount>, ApiError> {
+    let secrets = state.secrets.as_ref().ok_or(ApiError::Unavailable(
+        "credential storage is not configured",
+    ))?;
+    if db::user_by_id(&state.pool, id).await?.is_none() {
+        return Err(ApiError::NotFound);
+    }
+
+    // Connection and login errors are only logged: echoing them would tell
+    // the caller what answers on which host and port.
+    imap::verify(&settings, state.config.imap_allow_private)
+        .await
+        .map_err(|err| {
+            warn!(user_id = id, "IMAP verification failed: {err:#}");
+            let message = if err
+                .chain()
+                .any(|cause| cause.is::<webhooks::NonPublicHost>())
+            {
+                "host must be a public address"
+            } else {
+                "could not log in to the mailbox; check the host, port, TLS setting, \
+                 username, password and folder"
+            };
+            ApiError::Unprocessable(message.to_string())
+        })?;
+
+    let password_enc = secrets.seal(&settings.password)?;
+    let account = db::upsert_imap_account(&state.pool, id, &settings, &password_enc).await?;
+    info!("Connected IMAP mailbox {} for user {}", account.host, id);
+    Ok(Json(account))
+}
+
+async fn delete_imap(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    if db::delete_imap_account(&state.pool, id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
+
+async fn get_google(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<GoogleCredential>, ApiError> {
+    let credential = db::google_credential(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(credential))
+}
+
+/// Connects the user's own Google account: redeems the authorization code
+/// from the consent screen (requested with offline access and the Sheets
+/// scope) and stores the refresh token sealed with `CREDENTIALS_KEY`. Sheet
+/// writes use it instead of the service account from then on.
+async fn put_google(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(authorization): Json<GoogleAuthorization>,
+) -> Result<Json<GoogleCredential>, ApiError> {
+    let (Some(oauth), Some(secrets)) = (&state.google_oauth, &state.secrets) else {
+        return Err(ApiError::Unavailable(
+            "Google account connections are not configured",
+        ));
+    };
+    if db::user_by_id(&state.pool, id).await?.is_none() {
+        return Err(ApiError::NotFound);
+    }
+
+    let grant = oauth
+        .exchange(&authorization.code, &authorization.redirect_uri)
+        .await
+        .map_err(|err| match GrantRevoked::find(&err) {
+            Some(_) => ApiError::Unprocessable("authorization code is invalid or expired".into()),
+            None => ApiError::Upstream(err),
+        })?;
+    if !grant.scope.split(' ').any(|scope| scope == SHEETS_SCOPE) {
+        return Err(ApiError::Unprocessable(
+            "the grant does not include Google Sheets access".to_string(),
+        ));
+    }
+    let refresh_token = grant.refresh_token.ok_or_else(|| {
+        ApiError::Unprocessable(
+            "Google returned no refresh token; request offline access with prompt=consent"
+                .to_string(),
+        )
+    })?;
+
+    let sealed = secrets.seal(&refresh_token)?;
+    let credential = db::save_google_credential(&state.pool, id, &grant.scope, &sealed).await?;
+    requeue_sheet_syncs(&state, id).await?;
+    info!("Connected Google account for user {}", id);
+    Ok(Json(credential))
+}
+
+/// Disconnects the user's Google account and revokes the grant at Google;
+/// writes go back to the service account.
+async fn delete_google(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    let credential = db::google_credential(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if let (Some(oauth), Some(secrets)) = (&state.google_oauth, &state.secrets) {
+        let revoked = match secrets.open(&credential.refresh_token_enc) {
+            Ok(refresh_token) => oauth.revoke(id, &refresh_token).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = revoked {
+            warn!("Could not revoke Google grant for user {}: {err:#}", id);
+        }
+    }
+    db::delete_google_credential(&state.pool, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_excel(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<ExcelWorkbook>, ApiError> {
+    let workbook = db::excel_workbook(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(workbook))
+}
+
+/// Connects an Excel Online workbook: redeems the Microsoft authorization
+/// code (requested with `offline_access` and `Files.ReadWrite`), checks that
+/// the picked workbook can be opened with it, and makes Excel the user's
+/// sink. The refresh token is sealed with `CREDENTIALS_KEY`.
+async fn put_excel(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(connection): Json<ExcelConnection>,
+) -> Result<Json<ExcelWorkbook>, ApiError> {
+    let (Some(oauth), Some(secrets)) = (&state.microsoft_oauth, &state.secrets) else {
+        return Err(ApiError::Unavailable(
+            "Excel workbook connections are not configured",
+        ));
+    };
+    if db::user_by_id(&state.pool, id).await?.is_none() {
+        return Err(ApiError::NotFound);
+    }
+
+    let grant = oauth
+        .exchange(&connection.code, &connection.redirect_uri)
+        .await
+        .map_err(|err| match GrantRevoked::find(&err) {
+            Some(_) => ApiError::Unprocessable("authorization code is invalid or expired".into()),
+            None => ApiError::Upstream(err),
+        })?;
+    // Graph reports delegated scopes either bare or as resource URLs.
+    let files = grant
+        .scope
+        .split(' ')
+        .any(|scope| scope == FILES_SCOPE || scope.ends_with(&format!("/{FILES_SCOPE}")));
+    if !files {
+        return Err(ApiError::Unprocessable(
+            "the grant does not include OneDrive file access".to_string(),
+        ));
+    }
+    let refresh_token = grant.refresh_token.ok_or_else(|| {
+        ApiError::Unprocessable(
+            "Microsoft returned no refresh token; request the offline_access scope".to_string(),
+        )
+    })?;
+
+    state
+        .excel
+        .as_user(oauth.clone(), id, refresh_token.clone())
+        .workbook_name(&connection.item_id)
+        .await
+        .map_err(|err| match GraphApiError::permanent(&err) {
+            Some(api) => ApiError::Unprocessable(api.user_message()),
+            None => ApiError::Upstream(err),
+        })?;
+
+    let sealed = secrets.seal(&refresh_token)?;
+    let workbook =
+        db::save_excel_workbook(&state.pool, id, &connection.item_id, &grant.scope, &sealed)
+            .await?;
+    if let Some(user) = db::user_by_id(&state.pool, id).await? {
+        spawn_sheet_bootstrap(state.clone(), user);
+    }
+    info!("Connected Excel workbook for user {}", id);
+    Ok(Json(workbook))
+}
+
+/// Disconnects the workbook; the user's sink goes back to Google Sheets.
+async fn delete_excel(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    let workbook = db::excel_workbook(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if let (Some(oauth), Some(secrets)) = (&state.microsoft_oauth, &state.secrets) {
+        if let Ok(refresh_token) = secrets.open(&workbook.refresh_token_enc) {
+            oauth.revoke(id, &refresh_token).await.ok();
+        }
+    }
+    db::delete_excel_workbook(&state.pool, id).await?;
+    requeue_sheet_syncs(&state, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Switches where the user's logs are exported. The destination must
+/// already be connected.
+async fn put_sink(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(selection): Json<SinkSelection>,
+) -> Result<Json<UserResponse>, ApiError> {
+    let user = db::user_by_id(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let connected = match selection.sink {
+        SinkKind::Sheets => user.sheet_id.is_some(),
+        SinkKind::Excel => db::excel_workbook(&state.pool, id).await?.is_some(),
+    };
+    if !connected {
+        return Err(ApiError::Unprocessable(format!(
+            "no {} destination connected",
+            match selection.sink {
+                SinkKind::Sheets => "Google Sheets",
+                SinkKind::Excel => "Excel",
+            }
+        )));
+    }
+    let user = db::set_sink(&state.pool, id, selection.sink).await?;
+    requeue_sheet_syncs(&state, id).await?;
+    spawn_sheet_bootstrap(state.clone(), user.clone());
+    Ok(Json(UserResponse::from(user, &state)))
+}
+
+async fn get_sheet_mapping(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<SheetMapping>, ApiError> {
+    if db::user_by_id(&state.pool, id).await?.is_none() {
+        return Err(ApiError::NotFound);
+    }
+    Ok(Json(db::sheet_mapping(&state.pool, id).await?))
+}
+
+async fn put_sheet_mapping(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(mut mapping): Json<SheetMapping>,
+) -> Result<Json<SheetMapping>, ApiError> {
+    let user = db::user_by_id(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    mapping.validate().map_err(ApiError::Unprocessable)?;
+    mapping.start_column.make_ascii_uppercase();
+
+    db::save_sheet_mapping(&state.pool, id, &mapping).await?;
+    info!(
+        "Updated sheet mapping for user {} ({})",
+        id,
+        mapping.range()
+    );
+    if user.sheet_id.is_some() || user.sink == SinkKind::Excel {
+        spawn_sheet_bootstrap(state, user);
+    }
+    Ok(Json(mapping))
+}
+
+/// Creates a spreadsheet from our template for users who do not have one,
+/// shares it with their login email and connects it.
+async fn create_sheet(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = db::user_by_id(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if user.sheet_id.is_some() && user.sheet_status.as_deref() == Some("verified") {
+        return Err(ApiError::Conflict("user already has a working sheet"));
+    }
+
+    let mapping = db::sheet_mapping(&state.pool, id).await?;
+    let sheets = state.sheets_for(id).await?;
+    // A spreadsheet created with the user's own grant is already theirs.
+    let editor = (!sheets.is_user()).then_some(user.email.as_str());
+    let created = sheets
+        .create_spreadsheet("DriverSheet earnings", &mapping, editor)
+        .await
+        .map_err(ApiError::Upstream)?;
+    db::set_verified_sheet(&state.pool, id, &created.sheet_id).await?;
+    requeue_sheet_syncs(&state, id).await?;
+    info!("Created spreadsheet {} for user {}", created.sheet_id, id);
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+/// Re-runs the sheet (or workbook) setup on demand and reports API errors to
+/// the caller.
+async fn bootstrap_sheet(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    let user = db::user_by_id(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let sink = sink::for_user(&state, &user)
+        .await
+        .map_err(destination_error)?;
+    let mapping = db::sheet_mapping(&state.pool, id).await?;
+    sink.bootstrap(&mapping).await.map_err(ApiError::Upstream)?;
+    requeue_sheet_syncs(&state, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Rewrites the user's history into their sheet in the background; poll the
+/// returned job for progress.
+async fn start_resync(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(request): Json<ResyncRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = db::user_by_id(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    sink::for_user(&state, &user)
+        .await
+        .map_err(destination_error)?;
+    request.validate().map_err(ApiError::Unprocessable)?;
+
+    let job = db::create_resync_job(&state.pool, id, &request).await?;
+    resync::spawn_resync(state, user, job.clone(), request);
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+async fn resync_status(
+    State(state): State<AppState>,
+    Path((id, job_id)): Path<(i64, String)>,
+) -> Result<Json<ResyncJob>, ApiError> {
+    let job = db::resync_job(&state.pool, id, &job_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(job))
+}
+
+/// Pulls edits made in the sheet back into the user's logs now instead of
+/// waiting for the periodic pull.
+async fn pull_sheet(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<PullReport>, ApiError> {
+    let user = db::user_by_id(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if user.sheet_id.is_none() || user.sink != SinkKind::Sheets {
+        return Err(ApiError::Unprocessable(
+            "user does not export to a Google sheet".to_string(),
+        ));
+    }
+    let report = pull::pull_edits(&state, &user)
+        .await
+        .map_err(ApiError::Upstream)?;
+    Ok(Json(report))
+}
+
+/// The most recent values applied, rejected or in conflict from the sheet.
+async fn list_corrections(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<LogCorrection>>, ApiError> {
+    db::user_by_id(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let corrections = db::log_corrections(&state.pool, id, 100).await?;
+    Ok(Json(corrections))
+}
+
+async fn list_webhooks(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<Webhook>>, ApiError> {
+    Ok(Json(db::webhooks(&state.pool, id).await?))
+}
+
+/// Registers an endpoint for the user's log events. The response carries the
+/// generated signing secret.
+async fn create_webhook(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(request): Json<WebhookCreate>,
+) -> Result<impl IntoResponse, ApiError> {
+    if db::user_by_id(&state.pool, id).await?.is_none() {
+        return Err(ApiError::NotFound);
+    }
+    let url = reqwest::Url::parse(request.url.trim())
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https") && url.host().is_some())
+        .ok_or_else(|| ApiError::Unprocessable("url must be an http(s) URL".to_string()))?;
+    if !state.config.webhook_allow_private {
+        webhooks::check_public(&url).await.map_err(|err| {
+            ApiError::Unprocessable(match err.downcast_ref::<webhooks::NonPublicHost>() {
+                Some(_) => "url must point at a public address".to_string(),
+                None => format!("could not resolve {}", url.host_str().unwrap_or_default()),
+            })
+        })?;
+    }
+    if db::webhooks(&state.pool, id).await?.len() >= MAX_WEBHOOKS {
+        return Err(ApiError::Unprocessable(format!(
+            "at most {MAX_WEBHOOKS} webhooks per user"
+        )));
+    }
+    let webhook =
+        db::create_webhook(&state.pool, id, url.as_str(), &webhooks::new_secret()).await?;
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+async fn delete_webhook(
+    State(state): State<AppState>,
+    Path((id, webhook_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, ApiError> {
+    if !db::delete_webhook(&state.pool, id, webhook_id).await? {
+        return Err(ApiError::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The webhook's last 50 deliveries, newest first.
+async fn webhook_deliveries(
+    State(state): State<AppState>,
+    Path((id, webhook_id)): Path<(i64, i64)>,
+) -> Result<Json<Vec<WebhookDelivery>>, ApiError> {
+    let webhook = db::webhook(&state.pool, id, webhook_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(
+        db::webhook_deliveries(&state.pool, webhook.id, 50).await?,
+    ))
+}
+
+/// Sends a `test` event now and returns how the endpoint answered.
+async fn test_webhook(
+    State(state): State<AppState>,
+    Path((id, webhook_id)): Path<(i64, i64)>,
+) -> Result<Json<WebhookDelivery>, ApiError> {
+    let webhook = db::webhook(&state.pool, id, webhook_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(webhooks::send_test(&state, &webhook).await?))
+}
+
+/// `NoDestination` is the caller's to fix; anything else is upstream.
+fn destination_error(err: anyhow::Error) -> ApiError {
+    if err.is::<NoDestination>() {
+        ApiError::Unprocessable("user has no sheet or workbook connected".to_string())
+    } else {
+        ApiError::Upstream(err)
+    }
+}
+
+/// A sheet that was just set up gets another chance at appends that failed
+/// against it (or against the sheet it replaced).
+async fn requeue_sheet_syncs(state: &AppState, user_id: i64) -> anyhow::Result<()> {
+    if db::requeue_sheet_syncs(&state.pool, user_id).await? > 0 {
+        state.sheet_sync.notify_one();
+    }
+    Ok(())
+}
+
+/// Sets up the user's sheet or workbook in the background; failures are only
+/// logged because appends still work without headers.
+fn spawn_sheet_bootstrap(state: AppState, user: User) {
+    tokio::spawn(async move {
+        let result = match sink::for_user(&state, &user).await {
+            Ok(sink) => match db::sheet_mapping(&state.pool, user.id).await {
+                Ok(mapping) => sink.bootstrap(&mapping).await,
+                Err(err) => Err(err),
+            },
+            Err(err) if err.is::<NoDestination>() => return,
+            Err(err) => Err(err),
+        };
+        let result = match result {
+            Ok(()) => requeue_sheet_syncs(&state, user.id).await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => info!("Bootstrapped sheet for user {}", user.id),
+            Err(err) => warn!("Sheet bootstrap for user {} failed: {err:#}", user.id),
+        }
+    });
+}
+
 async fn lemon_webhook(
     State(state): State<AppState>,
     headers: HeaderMap,
@@ -74,6 +850,8 @@ async fn lemon_webhook(
     verify_signature(&state, signature, &payload)?;
 
     let webhook: LemonWebhook = serde_json::from_str(&payload)?;
+    let email = &webhook.data.attributes.customer_email;
+    db::record_billing_event(&state.pool, email, &webhook.meta.event_name).await?;
     if webhook.meta.event_name == "invoice.paid" {
         db::mark_paid(&state.pool, &webhook.data.attributes.customer_email).await?;
         info!("Marked {} as paid", webhook.data.attributes.customer_email);
@@ -119,6 +897,26 @@ fn normalize_sheet_id(input: &str) -> String {
     input.to_string()
 }
 
+/// An export job with its download link once the archive is ready.
+#[derive(serde::Serialize)]
+struct ExportJobResponse {
+    #[serde(flatten)]
+    job: ExportJob,
+    #[serde(rename = "downloadUrl")]
+    download_url: Option<String>,
+}
+
+impl From<ExportJob> for ExportJobResponse {
+    fn from(job: ExportJob) -> Self {
+        let live = job.status == "completed"
+            && job
+                .expires
+                .is_some_and(|expires| expires > Utc::now().naive_utc());
+        let download_url = live.then(|| format!("/api/exports/{}", job.token));
+        Self { job, download_url }
+    }
+}
+
 #[derive(serde::Serialize)]
 struct UserResponse {
     id: i64,
@@ -131,6 +929,13 @@ struct UserResponse {
     created: chrono::NaiveDateTime,
     #[serde(rename = "trialExpired")]
     trial_expired: bool,
+    #[serde(rename = "sheetStatus")]
+    sheet_status: Option<String>,
+    #[serde(rename = "sheetError")]
+    sheet_error: Option<String>,
+    sink: SinkKind,
+    #[serde(rename = "deleteAfter")]
+    delete_after: Option<chrono::NaiveDateTime>,
     #[serde(rename = "lemonPaymentUrl")]
     lemon_payment_url: String,
 }
@@ -151,6 +956,10 @@ impl UserResponse {
             paid: user.paid,
             created: user.created,
             trial_expired,
+            sheet_status: user.sheet_status,
+            sheet_error: user.sheet_error,
+            sink: user.sink,
+            delete_after: user.delete_after,
             lemon_payment_url: state.config.lemon_payment_url.clone(),
         }
     }
@@ -164,6 +973,17 @@ pub enum ApiError {
     Database(sqlx::Error),
     BadRequest(serde_json::Error),
     BadUtf8,
+    UploadFailed,
+    Unprocessable(String),
+    Unavailable(&'static str),
+    Upstream(anyhow::Error),
+    Conflict(&'static str),
+    SheetAccess {
+        access: SheetAccess,
+        /// `None` when the sheet is written with the user's own grant.
+        service_account: Option<String>,
+        user_id: i64,
+    },
     Other(anyhow::Error),
 }
 
@@ -205,6 +1025,36 @@ impl IntoResponse for ApiError {
                 tracing::warn!("invalid utf8 payload");
                 (StatusCode::BAD_REQUEST, "invalid utf8").into_response()
             }
+            ApiError::UploadFailed => {
+                (StatusCode::BAD_REQUEST, "upload interrupted").into_response()
+            }
+            ApiError::Unprocessable(message) => {
+                tracing::warn!(%message, "unprocessable request");
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+            ApiError::Unavailable(message) => {
+                (StatusCode::SERVICE_UNAVAILABLE, message).into_response()
+            }
+            ApiError::Conflict(message) => (StatusCode::CONFLICT, message).into_response(),
+            ApiError::SheetAccess {
+                access,
+                service_account,
+                user_id,
+            } => {
+                let mut body = serde_json::json!({
+                    "error": access.as_str(),
+                    "message": access.user_message(service_account.as_deref()),
+                    "userId": user_id,
+                });
+                if let Some(service_account) = service_account {
+                    body["serviceAccountEmail"] = service_account.into();
+                }
+                (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response()
+            }
+            ApiError::Upstream(err) => {
+                tracing::warn!(?err, "upstream error");
+                (StatusCode::BAD_GATEWAY, format!("{err:#}")).into_response()
+            }
             ApiError::Other(err) => {
                 tracing::error!(?err, "server error");
                 (StatusCode::INTERNAL_SERVER_ERROR, "server error").into_response()
@@ -215,7 +1065,288 @@ impl IntoResponse for ApiError {
 
 #[cfg(test)]
 mod tests {
-    use super::normalize_sheet_id;
+    use super::*;
+    use crate::money::Cents;
+    use crate::outbox;
+    use crate::sheets_mock::{test_log, test_user, MockSheets};
+
+    fn authorization(code: &str) -> Json<GoogleAuthorization> {
+        Json(GoogleAuthorization {
+            code: code.to_string(),
+            redirect_uri: "https://app.invalid/callback".to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn account_deletion_can_be_undone_during_the_grace_period() {
+        let (_mock, url) = MockSheets::start().await;
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, Some("sheet-1")).await;
+
+        let (status, Json(scheduled)) = delete_user(State(state.clone()), Path(user.id))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let delete_after = scheduled.delete_after.unwrap();
+        let grace = delete_after - Utc::now().naive_utc();
+        assert!(grace > Duration::days(13) && grace <= Duration::days(14));
+
+        // Asking again does not push the date back.
+        let (_, Json(again)) = delete_user(State(state.clone()), Path(user.id))
+            .await
+            .unwrap();
+        assert_eq!(again.delete_after, Some(delete_after));
+
+        let Json(kept) = cancel_deletion(State(state.clone()), Path(user.id))
+            .await
+            .unwrap();
+        assert_eq!(kept.delete_after, None);
+        let err = cancel_deletion(State(state.clone()), Path(user.id)).await;
+        assert!(matches!(err, Err(ApiError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn finished_export_is_downloadable_by_token() {
+        let (_mock, url) = MockSheets::start().await;
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, Some("sheet-1")).await;
+
+        let (status, Json(started)) = start_export(State(state.clone()), Path(user.id))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(started.download_url, None);
+        let mut job = started;
+        for _ in 0..200 {
+            if job.job.status != "running" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            let id = job.job.id.clone();
+            job = export_status(State(state.clone()), Path((user.id, id)))
+                .await
+                .unwrap()
+                .0;
+        }
+        assert_eq!(job.job.status, "completed", "{:?}", job.job.error);
+        let link = job.download_url.unwrap();
+        let token = link.strip_prefix("/api/exports/").unwrap().to_string();
+
+        let response = download_export(State(state.clone()), Path(token.clone()))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let length = response.headers()[header::CONTENT_LENGTH].clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.starts_with(b"PK"));
+        assert_eq!(length, body.len().to_string());
+
+        let unknown = download_export(State(state.clone()), Path("nope".to_string())).await;
+        assert!(matches!(unknown, Err(ApiError::NotFound)));
+        std::fs::remove_file(export::archive_path(&state.config, &job.job.id)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sheet_access_errors_name_the_service_account_only_when_it_writes() {
+        let body = |service_account: Option<String>| async move {
+            let response = ApiError::SheetAccess {
+                access: SheetAccess::NotShared,
+                service_account,
+                user_id: 7,
+            }
+            .into_response();
+            assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+        };
+
+        let shared = body(Some("sheets@mock.iam.gserviceaccount.com".to_string())).await;
+        assert_eq!(
+            shared["serviceAccountEmail"],
+            "sheets@mock.iam.gserviceaccount.com"
+        );
+        assert!(shared["message"]
+            .as_str()
+            .unwrap()
+            .contains("sheets@mock.iam.gserviceaccount.com"));
+
+        let own = body(None).await;
+        assert_eq!(own["error"], "not_shared");
+        assert!(own.get("serviceAccountEmail").is_none());
+        assert!(own["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Your Google account cannot edit"));
+    }
+
+    #[tokio::test]
+    async fn imap_hosts_must_be_public() {
+        let (_mock, url) = MockSheets::start().await;
+        let mut state = MockSheets::app_state(&url).await;
+        let mut config = (*state.config).clone();
+        config.imap_allow_private = false;
+        state.config = std::sync::Arc::new(config);
+        let user = test_user(&state.pool, Some("sheet-1")).await;
+        let settings = |host: &str| ImapAccountUpsert {
+            host: host.to_string(),
+            port: 993,
+            tls: true,
+            username: "driver".to_string(),
+            password: "hunter2".to_string(),
+            folder: "INBOX".to_string(),
+            sender_filter: None,
+        };
+
+        for host in ["127.0.0.1", "localhost", "169.254.169.254"] {
+            let err = put_imap(State(state.clone()), Path(user.id), Json(settings(host)))
+                .await
+                .err()
+                .unwrap();
+            assert!(
+                matches!(&err, ApiError::Unprocessable(message) if message == "host must be a public address"),
+                "{host}"
+            );
+        }
+        assert!(db::imap_account(&state.pool, user.id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn webhooks_must_point_at_public_addresses() {
+        let (_mock, url) = MockSheets::start().await;
+        let mut state = MockSheets::app_state(&url).await;
+        let mut config = (*state.config).clone();
+        config.webhook_allow_private = false;
+        state.config = std::sync::Arc::new(config);
+        let user = test_user(&state.pool, Some("sheet-1")).await;
+
+        for url in [
+            "http://169.254.169.254/latest",
+            "http://localhost:9000/hook",
+        ] {
+            let err = create_webhook(
+                State(state.clone()),
+                Path(user.id),
+                Json(WebhookCreate {
+                    url: url.to_string(),
+                }),
+            )
+            .await
+            .err()
+            .unwrap();
+            assert!(
+                matches!(&err, ApiError::Unprocessable(message) if message.contains("public")),
+                "{url}"
+            );
+        }
+        assert!(db::webhooks(&state.pool, user.id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn connected_google_account_is_used_for_writes() {
+        let (mock, url) = MockSheets::start().await;
+        mock.add_spreadsheet("sheet-1", &["Sheet1"]);
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, Some("sheet-1")).await;
+
+        let rejected = put_google(
+            State(state.clone()),
+            Path(user.id),
+            authorization("no-sheets"),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(rejected, ApiError::Unprocessable(_)));
+
+        let Json(credential) = put_google(State(state.clone()), Path(user.id), authorization("1"))
+            .await
+            .unwrap();
+        assert_eq!(credential.scope, SHEETS_SCOPE);
+        let secrets = state.secrets.as_ref().unwrap();
+        assert_eq!(
+            secrets.open(&credential.refresh_token_enc).unwrap(),
+            "refresh-1"
+        );
+
+        test_log(&state.pool, &user, "2024-08-15", Cents(1000)).await;
+        outbox::sync_due(&state).await.unwrap();
+        assert_eq!(mock.appends().len(), 1);
+        let bearers = mock.bearers();
+        assert!(bearers
+            .iter()
+            .all(|bearer| bearer.starts_with("user-token-")));
+
+        delete_google(State(state.clone()), Path(user.id))
+            .await
+            .unwrap();
+        assert!(db::google_credential(&state.pool, user.id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn connected_excel_workbook_becomes_the_sink() {
+        let (mock, url) = MockSheets::start().await;
+        mock.add_spreadsheet("book-1", &["Sheet1"]);
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, None).await;
+        let connection = |item_id: &str| {
+            Json(ExcelConnection {
+                code: "1".to_string(),
+                redirect_uri: "https://app.invalid/callback".to_string(),
+                item_id: item_id.to_string(),
+            })
+        };
+
+        let missing = put_excel(State(state.clone()), Path(user.id), connection("gone"))
+            .await
+            .unwrap_err();
+        assert!(matches!(missing, ApiError::Unprocessable(_)));
+        let rejected = put_sink(
+            State(state.clone()),
+            Path(user.id),
+            Json(SinkSelection {
+                sink: SinkKind::Excel,
+            }),
+        )
+        .await
+        .map(|_| ())
+        .unwrap_err();
+        assert!(matches!(rejected, ApiError::Unprocessable(_)));
+
+        let Json(workbook) = put_excel(State(state.clone()), Path(user.id), connection("book-1"))
+            .await
+            .unwrap();
+        assert_eq!(workbook.item_id, "book-1");
+        assert!(workbook.scope.ends_with(FILES_SCOPE));
+        let user = db::user_by_id(&state.pool, user.id).await.unwrap().unwrap();
+        assert_eq!(user.sink, SinkKind::Excel);
+
+        let sink = sink::for_user(&state, &user).await.unwrap();
+        assert!(sink.sheet_id().is_none());
+        bootstrap_sheet(State(state.clone()), Path(user.id))
+            .await
+            .unwrap();
+        assert_eq!(mock.rows("book-1", "Sheet1")[0][0], "Date");
+
+        delete_excel(State(state.clone()), Path(user.id))
+            .await
+            .unwrap();
+        let user = db::user_by_id(&state.pool, user.id).await.unwrap().unwrap();
+        assert_eq!(user.sink, SinkKind::Sheets);
+        let unconnected = bootstrap_sheet(State(state.clone()), Path(user.id))
+            .await
+            .unwrap_err();
+        assert!(matches!(unconnected, ApiError::Unprocessable(_)));
+    }
 
     #[test]
     fn normalize_sheet_id_strips_google_url_path() {
@@ -0,0 +1,334 @@
+use crate::db;
+use crate::state::AppState;
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Backups are `driversheet-<UTC timestamp>.db`, so they sort by age.
+const PREFIX: &str = "driversheet-";
+const SUFFIX: &str = ".db";
+
+/// The SQLite migrations this build knows, to check a backup's schema.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Snapshots the database into `BACKUP_DIR` every `BACKUP_SECS`, keeping the
+/// newest `BACKUP_KEEP`; `0` disables it. Postgres deployments back up with
+/// their own tooling (`pg_dump`), so this only runs on SQLite builds.
+pub fn spawn_backups(state: AppState) {
+    if state.config.backup_secs == 0 {
+        return;
+    }
+    if cfg!(feature = "postgres") {
+        warn!("BACKUP_SECS is ignored on Postgres; back up with pg_dump");
+        return;
+    }
+    let every = Duration::from_secs(state.config.backup_secs);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        // The first tick is immediate; a restart should not add a backup.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let dir = Path::new(&state.config.backup_dir);
+            match backup(&state.pool, dir, state.config.backup_keep).await {
+                Ok(path) => info!(path = %path.display(), "database backed up"),
+                Err(err) => error!(?err, "database backup failed"),
+            }
+        }
+    });
+}
+
+/// Writes a consistent copy of the live database into `dir` with
+/// `VACUUM INTO`, then deletes all but the newest `keep` backups. Writers
+/// wait while the copy is read; nothing else has to stop.
+pub async fn backup(pool: &db::Pool, dir: &Path, keep: usize) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    let name = format!("{PREFIX}{}{SUFFIX}", Utc::now().format("%Y%m%dT%H%M%SZ"));
+    let path = dir.join(&name);
+    // Written under a name pruning ignores, so a failed copy is never kept
+    // as a backup.
+    let partial = dir.join(format!(".{name}.partial"));
+    tokio::fs::remove_file(&partial).await.ok();
+
+    sqlx::query("VACUUM INTO $1")
+        .bind(partial.to_string_lossy().as_ref())
+        .execute(pool)
+        .await
+        .context("VACUUM INTO failed")?;
+    tokio::fs::rename(&partial, &path).await?;
+
+    prune(dir, keep.max(1)).await?;
+    Ok(path)
+}
+
+/// Deletes all but the newest `keep` backups in `dir`.
+async fn prune(dir: &Path, keep: usize) -> Result<()> {
+    let mut backups = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(PREFIX) && name.ends_with(SUFFIX) {
+            backups.push(entry.path());
+        }
+    }
+    backups.sort();
+    let stale = backups.len().saturating_sub(keep);
+    for path in &backups[..stale] {
+        tokio::fs::remove_file(path)
+            .await
+            .with_context(|| format!("Failed to delete {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// What `restore` swapped in.
+#[derive(Debug)]
+pub struct Restored {
+    /// Highest migration the backup had applied.
+    pub schema_version: i64,
+    /// Migrations applied to the backup to bring it up to this build.
+    pub migrated: usize,
+    /// Where the database that was replaced was moved.
+    pub previous: Option<PathBuf>,
+}
+
+/// Replaces the SQLite database at `database_url` with the backup at `from`.
+/// Run it with the worker stopped.
+///
+/// The backup is copied next to the database and checked there: it must
+/// pass `PRAGMA integrity_check` and carry only migrations this build knows,
+/// with matching checksums. Missing migrations are then applied to the copy.
+/// Only after that is the current database moved aside to
+/// `<file>.pre-restore-<timestamp>` and the copy renamed into its place, so
+/// any failure leaves the current database untouched.
+pub async fn restore(database_url: &str, from: &Path) -> Result<Restored> {
+    if cfg!(feature = "postgres") {
+        bail!("restore only handles SQLite; restore Postgres with pg_restore");
+    }
+    let target = SqliteConnectOptions::from_str(database_url)
+        .context("Invalid DATABASE_URL")?
+        .get_filename()
+        .to_path_buf();
+    for journal in ["-journal", "-wal"] {
+        let path = sibling(&target, journal);
+        if path.exists() {
+            bail!(
+                "{} exists; stop the worker before restoring",
+                path.display()
+            );
+        }
+    }
+    if !from.is_file() {
+        bail!("{} is not a file", from.display());
+    }
+
+    let staged = sibling(&target, ".restoring");
+    tokio::fs::copy(from, &staged)
+        .await
+        .with_context(|| format!("Failed to copy {}", from.display()))?;
+    let checked = check_and_migrate(&staged).await;
+    let (schema_version, migrated) = match checked {
+        Ok(checked) => checked,
+        Err(err) => {
+            tokio::fs::remove_file(&staged).await.ok();
+            return Err(err);
+        }
+    };
+
+    let previous = if target.exists() {
+        let aside = sibling(
+            &target,
+            &format!(".pre-restore-{}", Utc::now().format("%Y%m%dT%H%M%SZ")),
+        );
+        tokio::fs::rename(&target, &aside).await?;
+        Some(aside)
+    } else {
+        None
+    };
+    tokio::fs::rename(&staged, &target).await?;
+    Ok(Restored {
+        schema_version,
+        migrated,
+        previous,
+    })
+}
+
+/// Checks the copied backup and migrates it. Returns the backup's schema
+/// version and how many migrations were applied.
+async fn check_and_migrate(path: &Path) -> Result<(i64, usize)> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(SqliteConnectOptions::new().filename(path))
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let result = async {
+        let checked = check_backup(&pool).await?;
+        MIGRATOR
+            .run(&pool)
+            .await
+            .context("Failed to migrate the backup")?;
+        Ok(checked)
+    }
+    .await;
+    pool.close().await;
+    result
+}
+
+/// Returns the backup's schema version and the number of migrations it
+/// lacks, or why it cannot be restored.
+async fn check_backup(pool: &SqlitePool) -> Result<(i64, usize)> {
+    let problems: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_all(pool)
+        .await
+        .context("Not a readable SQLite database")?;
+    if problems != ["ok"] {
+        bail!("Backup failed the integrity check: {}", problems.join("; "));
+    }
+
+    let applied: Vec<(i64, Vec<u8>, bool)> =
+        sqlx::query_as("SELECT version, checksum, success FROM _sqlx_migrations ORDER BY version")
+            .fetch_all(pool)
+            .await
+            .context("Backup has no migration history; is it a DriverSheet database?")?;
+    let known: HashMap<i64, &[u8]> = MIGRATOR
+        .iter()
+        .map(|migration| (migration.version, migration.checksum.as_ref()))
+        .collect();
+    for (version, checksum, success) in &applied {
+        match known.get(version) {
+            None => bail!(
+                "Backup has migration {version}, which this build does not know; \
+                 restore it with a newer worker"
+            ),
+            Some(expected) if *expected != checksum.as_slice() => {
+                bail!("Backup's migration {version} differs from this build's")
+            }
+            Some(_) if !success => bail!("Backup's migration {version} did not complete"),
+            Some(_) => {}
+        }
+    }
+    let version = applied.last().map_or(0, |(version, ..)| *version);
+    Ok((version, known.len() - applied.len()))
+}
+
+/// `data/data.db` with `suffix` appended to the file name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(all(test, not(feature = "postgres")))]
+mod tests {
+    use super::*;
+
+    async fn file_pool(path: &Path) -> SqlitePool {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(2)
+            .connect_with(
+                SqliteConnectOptions::new()
+                    .filename(path)
+                    .create_if_missing(true),
+            )
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        pool
+    }
+
+    async fn user_count(path: &Path) -> i64 {
+        let pool = file_pool(path).await;
+        let count = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        count
+    }
+
+    #[tokio::test]
+    async fn backups_are_pruned_and_restore_swaps_the_database_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let live = dir.path().join("data.db");
+        let backups = dir.path().join("backups");
+        let pool = file_pool(&live).await;
+        sqlx::query("INSERT INTO users (google_id, email, forward_key) VALUES ('g', 'e', 'k')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        std::fs::create_dir_all(&backups).unwrap();
+        for stale in [
+            "driversheet-20240101T000000Z.db",
+            "driversheet-20240102T000000Z.db",
+        ] {
+            std::fs::write(backups.join(stale), b"").unwrap();
+        }
+        let path = backup(&pool, &backups, 2).await.unwrap();
+        let mut names: Vec<_> = std::fs::read_dir(&backups)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[0], "driversheet-20240102T000000Z.db");
+        assert_eq!(backups.join(&names[1]), path);
+
+        sqlx::query("DELETE FROM users")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let url = format!("sqlite://{}", live.display());
+        let restored = restore(&url, &path).await.unwrap();
+        assert_eq!(
+            restored.schema_version,
+            MIGRATOR.iter().last().unwrap().version
+        );
+        assert_eq!(restored.migrated, 0);
+        assert!(restored.previous.unwrap().exists());
+        assert_eq!(user_count(&live).await, 1);
+    }
+
+    #[tokio::test]
+    async fn restore_rejects_corrupt_and_newer_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let live = dir.path().join("data.db");
+        file_pool(&live).await.close().await;
+        let url = format!("sqlite://{}", live.display());
+
+        let garbage = dir.path().join("garbage.db");
+        std::fs::write(&garbage, b"not a database, just some bytes").unwrap();
+        assert!(restore(&url, &garbage).await.is_err());
+
+        let newer = dir.path().join("newer.db");
+        let pool = file_pool(&newer).await;
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, \
+             execution_time) VALUES (9999, 'future', TRUE, X'00', 0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool.close().await;
+        let err = restore(&url, &newer).await.unwrap_err();
+        assert!(err.to_string().contains("migration 9999"), "{err}");
+
+        // Both attempts left the live database alone.
+        let live_files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("data.db"))
+            .collect();
+        assert_eq!(live_files, ["data.db"]);
+    }
+}
@@ -0,0 +1,286 @@
+use crate::backup;
+use crate::config::AppConfig;
+use crate::db;
+use crate::export;
+use crate::import::{self, MboxReader};
+use crate::mail::{self, Delivery};
+use crate::models::{MessageSource, ResyncMode, ResyncRequest, User};
+use crate::resync;
+use crate::state::AppState;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::NaiveDate;
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use std::path::PathBuf;
+use tokio::io::AsyncReadExt;
+use tracing::error;
+
+// sysexits.h codes understood by Postfix's pipe(8) transport.
+const EX_OK: i32 = 0;
+const EX_DATAERR: i32 = 65;
+const EX_NOUSER: i32 = 67;
+const EX_TEMPFAIL: i32 = 75;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "driversheet-worker",
+    version,
+    about = "DriverSheet API and mail worker"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP API and mail listeners (default).
+    Serve,
+    /// Read one RFC822 message from stdin and run it through the pipeline.
+    Deliver(DeliverArgs),
+    /// Backfill a user's history from an mbox export or a Maildir folder.
+    Import(ImportArgs),
+    /// Rewrite a user's sheet from the logs table.
+    Resync(ResyncArgs),
+    /// Snapshot the SQLite database while the worker runs.
+    Backup(BackupArgs),
+    /// Replace the SQLite database with a backup. Stop the worker first.
+    Restore(RestoreArgs),
+    /// Write everything stored about a user to a zip archive.
+    Export(ExportArgs),
+    /// Sandboxed PDF text extraction child (PDF on stdin, text on stdout).
+    #[command(hide = true)]
+    ExtractPdf,
+}
+
+#[derive(Debug, Args)]
+pub struct DeliverArgs {
+    /// Envelope recipient, e.g. `${recipient}` in a Postfix pipe transport.
+    /// Falls back to the X-Original-To, Delivered-To and To headers.
+    #[arg(long)]
+    pub recipient: Option<String>,
+}
+
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("source").required(true).args(["mbox", "maildir"])))]
+pub struct ImportArgs {
+    /// User id or login email.
+    #[arg(long)]
+    pub user: String,
+    /// Path to an mbox file, e.g. a Google Takeout export.
+    #[arg(long)]
+    pub mbox: Option<PathBuf>,
+    /// Path to a Maildir (with cur/ and new/) or a folder of .eml files.
+    #[arg(long)]
+    pub maildir: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct ResyncArgs {
+    /// User id or login email.
+    #[arg(long)]
+    pub user: String,
+    /// First order date to write (YYYY-MM-DD).
+    #[arg(long)]
+    pub from: Option<NaiveDate>,
+    /// Last order date to write (YYYY-MM-DD).
+    #[arg(long)]
+    pub to: Option<NaiveDate>,
+    /// Clear the mapped data rows before writing instead of appending.
+    #[arg(long)]
+    pub replace: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct BackupArgs {
+    /// Directory to write to instead of BACKUP_DIR.
+    #[arg(long)]
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct RestoreArgs {
+    /// Backup file written by `backup` or the scheduled backups.
+    #[arg(long)]
+    pub from: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// User id or login email.
+    #[arg(long)]
+    pub user: String,
+    /// Archive to write, e.g. `export.zip`.
+    #[arg(long)]
+    pub out: PathBuf,
+}
+
+/// Handles `deliver` and returns the process exit code.
+pub async fn deliver(state: &AppState, args: DeliverArgs) -> i32 {
+    let mut data = Vec::new();
+    if let Err(err) = tokio::io::stdin().read_to_end(&mut data).await {
+        error!("Failed to read message from stdin: {err:?}");
+        return EX_TEMPFAIL;
+    }
+
+    let forward_key = match recipient_key(args.recipient.as_deref(), &data) {
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            error!("No driversheet.com recipient found for piped message");
+            return EX_NOUSER;
+        }
+        Err(err) => {
+            error!("Failed to read piped message headers: {err:?}");
+            return EX_DATAERR;
+        }
+    };
+
+    match mail::process_message(state, MessageSource::Pipe, &forward_key, &data).await {
+        Ok(Delivery::Logged | Delivery::Duplicate | Delivery::NoStatement) => EX_OK,
+        Ok(Delivery::UnknownRecipient) => EX_NOUSER,
+        Err(err) if mail::is_transient(&err) => {
+            error!("Delivery deferred: {err:?}");
+            EX_TEMPFAIL
+        }
+        Err(err) => {
+            error!("Delivery failed: {err:?}");
+            EX_DATAERR
+        }
+    }
+}
+
+pub async fn import(state: &AppState, args: ImportArgs) -> Result<()> {
+    let user = resolve_user(state, &args.user).await?;
+    let summary = match (args.mbox, args.maildir) {
+        (Some(path), _) => {
+            let reader = MboxReader::open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            import::import_messages(state, &user, reader, None).await?
+        }
+        (None, Some(path)) => {
+            let messages = import::maildir_messages(&path)?;
+            import::import_messages(state, &user, messages, None).await?
+        }
+        (None, None) => bail!("either --mbox or --maildir is required"),
+    };
+
+    println!(
+        "imported {} of {} messages for {} ({} skipped, {} failed)",
+        summary.imported, summary.total, user.email, summary.skipped, summary.failed
+    );
+    Ok(())
+}
+
+pub async fn resync(state: &AppState, args: ResyncArgs) -> Result<()> {
+    let user = resolve_user(state, &args.user).await?;
+    let request = ResyncRequest {
+        mode: if args.replace {
+            ResyncMode::Replace
+        } else {
+            ResyncMode::Append
+        },
+        from: args.from,
+        to: args.to,
+    };
+    request.validate().map_err(|message| anyhow!(message))?;
+    let written = resync::resync_sheet(state, &user, &request, None).await?;
+
+    println!(
+        "wrote {} rows to sheet {} for {}",
+        written,
+        user.sheet_id.as_deref().unwrap_or_default(),
+        user.email
+    );
+    Ok(())
+}
+
+pub async fn backup(state: &AppState, args: BackupArgs) -> Result<()> {
+    if cfg!(feature = "postgres") {
+        bail!("backup only handles SQLite; back up Postgres with pg_dump");
+    }
+    let dir = args
+        .dir
+        .unwrap_or_else(|| PathBuf::from(&state.config.backup_dir));
+    let path = backup::backup(&state.pool, &dir, state.config.backup_keep).await?;
+    println!("backed up to {}", path.display());
+    Ok(())
+}
+
+pub async fn export(state: &AppState, args: ExportArgs) -> Result<()> {
+    let user = resolve_user(state, &args.user).await?;
+    export::write_archive(&state.pool, &user, &args.out).await?;
+    println!("exported {} to {}", user.email, args.out.display());
+    Ok(())
+}
+
+/// Runs before the worker opens the database, which is being replaced.
+pub async fn restore(config: &AppConfig, args: RestoreArgs) -> Result<()> {
+    let restored = backup::restore(&config.database_url, &args.from).await?;
+    println!(
+        "restored {} (schema version {}, {} migrations applied)",
+        args.from.display(),
+        restored.schema_version,
+        restored.migrated
+    );
+    if let Some(previous) = restored.previous {
+        println!("previous database kept at {}", previous.display());
+    }
+    Ok(())
+}
+
+async fn resolve_user(state: &AppState, user: &str) -> Result<User> {
+    let found = match user.parse::<i64>() {
+        Ok(id) => db::user_by_id(&state.pool, id).await?,
+        Err(_) => db::user_by_email(&state.pool, user).await?,
+    };
+    found.ok_or_else(|| anyhow!("no user matches {user:?}"))
+}
+
+fn recipient_key(explicit: Option<&str>, data: &[u8]) -> Result<Option<String>> {
+    if let Some(recipient) = explicit {
+        return Ok(mail::parse_forward_key(recipient));
+    }
+
+    let (headers, _) = mailparse::parse_headers(data).context("Invalid message headers")?;
+    for name in ["X-Original-To", "Delivered-To", "To"] {
+        for header in headers
+            .iter()
+            .filter(|h| h.get_key().eq_ignore_ascii_case(name))
+        {
+            let value = header.get_value();
+            let key = value
+                .split(',')
+                .map(|addr| match addr.rsplit_once('<') {
+                    Some((_, tail)) => tail.trim_end_matches('>'),
+                    None => addr,
+                })
+                .find_map(mail::parse_forward_key);
+            if key.is_some() {
+                return Ok(key);
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::recipient_key;
+
+    #[test]
+    fn recipient_key_prefers_explicit_recipient() {
+        let data = b"To: user-fromhdr@driversheet.com\r\n\r\nbody";
+        let key = recipient_key(Some("user-Explicit@driversheet.com"), data).unwrap();
+        assert_eq!(key.as_deref(), Some("explicit"));
+    }
+
+    #[test]
+    fn recipient_key_falls_back_to_headers() {
+        let data = b"Delivered-To: me@gmail.com\r\n\
+                     To: Me <me@gmail.com>, Sheet <user-abc123@driversheet.com>\r\n\r\nbody";
+        let key = recipient_key(None, data).unwrap();
+        assert_eq!(key.as_deref(), Some("abc123"));
+
+        let none = recipient_key(None, b"To: someone@example.com\r\n\r\n").unwrap();
+        assert!(none.is_none());
+    }
+}
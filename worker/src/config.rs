@@ -1,4 +1,5 @@
-use anyhow::{Context, Result};
+use crate::smtp::ListenAddr;
+use anyhow::{anyhow, Context, Result};
 use serde::Deserialize;
 use std::env;
 use std::net::SocketAddr;
@@ -12,8 +13,56 @@ pub struct AppConfig {
     pub bind_api: SocketAddr,
     #[serde(default = "default_bind_mail")]
     pub bind_mail: SocketAddr,
+    #[serde(default)]
+    pub mail_ingress: MailIngress,
+    #[serde(default = "default_bind_lmtp")]
+    pub bind_lmtp: ListenAddr,
     pub tmp_dir: String,
     pub lemon_payment_url: String,
+    pub credentials_key: Option<String>,
+    #[serde(default = "default_imap_poll_secs")]
+    pub imap_poll_secs: u64,
+    /// Lets mailboxes live on loopback and private addresses; for local
+    /// development only.
+    pub imap_allow_private: bool,
+    pub sheet_sync_secs: u64,
+    /// How often sheet edits are pulled back into logs; `0` disables it.
+    pub sheet_pull_secs: u64,
+    pub webhook_delivery_secs: u64,
+    /// How often stale summary tabs are rewritten, so a burst of syncs costs
+    /// one refresh.
+    pub summary_refresh_secs: u64,
+    /// Lets webhooks target loopback and private addresses; for local
+    /// development only.
+    pub webhook_allow_private: bool,
+    /// How often the SQLite database is backed up; `0` disables it.
+    pub backup_secs: u64,
+    pub backup_dir: String,
+    /// Backups kept; older ones are deleted after each backup.
+    pub backup_keep: usize,
+    /// Days between a user asking to delete their account and the purge.
+    pub deletion_grace_days: u64,
+    /// Days subjects and senders of received mail are kept; `0` keeps them.
+    pub message_retention_days: u64,
+    /// Where personal data export archives are written.
+    pub export_dir: String,
+    /// How long an export's download link works.
+    pub export_link_hours: u64,
+    pub sheets_requests_per_min: u32,
+    pub sheets_api_url: String,
+    pub drive_api_url: String,
+    pub sheets_access_token: Option<String>,
+    pub google_client_id: Option<String>,
+    pub google_client_secret: Option<String>,
+    pub google_oauth_url: String,
+    pub graph_api_url: String,
+    pub graph_requests_per_min: u32,
+    pub microsoft_client_id: Option<String>,
+    pub microsoft_client_secret: Option<String>,
+    pub microsoft_oauth_url: String,
+    pub pdf_timeout_secs: u64,
+    pub pdf_cpu_secs: u64,
+    pub pdf_memory_mb: u64,
 }
 
 fn default_bind_api() -> SocketAddr {
@@ -24,6 +73,48 @@ fn default_bind_mail() -> SocketAddr {
     "0.0.0.0:25".parse().unwrap()
 }
 
+fn default_bind_lmtp() -> ListenAddr {
+    "127.0.0.1:2424".parse().unwrap()
+}
+
+fn default_imap_poll_secs() -> u64 {
+    300
+}
+
+/// Which mail listeners the worker starts: its own port-25 SMTP server, an
+/// LMTP socket for an MTA such as Postfix to hand mail to, or both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailIngress {
+    #[default]
+    Smtp,
+    Lmtp,
+    Both,
+}
+
+impl MailIngress {
+    pub fn smtp(self) -> bool {
+        matches!(self, Self::Smtp | Self::Both)
+    }
+
+    pub fn lmtp(self) -> bool {
+        matches!(self, Self::Lmtp | Self::Both)
+    }
+}
+
+impl std::str::FromStr for MailIngress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "smtp" => Ok(Self::Smtp),
+            "lmtp" => Ok(Self::Lmtp),
+            "both" => Ok(Self::Both),
+            other => Err(anyhow!("unknown mail ingress {other:?}")),
+        }
+    }
+}
+
 impl AppConfig {
     pub fn from_env() -> Result<Self> {
         let database_url = env::var("DATABASE_URL").context("DATABASE_URL missing")?;
@@ -38,9 +129,103 @@ impl AppConfig {
             .unwrap_or_else(|_| "0.0.0.0:25".to_string())
             .parse()
             .context("Invalid BIND_MAIL")?;
+        let mail_ingress = env::var("MAIL_INGRESS")
+            .unwrap_or_else(|_| "smtp".to_string())
+            .parse()
+            .context("Invalid MAIL_INGRESS")?;
+        let bind_lmtp = env::var("BIND_LMTP")
+            .unwrap_or_else(|_| "127.0.0.1:2424".to_string())
+            .parse()
+            .context("Invalid BIND_LMTP")?;
         let tmp_dir = env::var("TMP_DIR").unwrap_or_else(|_| "data/tmp".to_string());
         let lemon_payment_url = env::var("LEMON_PAYMENT_URL")
             .unwrap_or_else(|_| "https://pay.lemon.com/driver-sheet".to_string());
+        let credentials_key = env::var("CREDENTIALS_KEY").ok();
+        let imap_poll_secs = env::var("IMAP_POLL_SECS")
+            .unwrap_or_else(|_| "300".to_string())
+            .parse()
+            .context("Invalid IMAP_POLL_SECS")?;
+        let imap_allow_private = env::var("IMAP_ALLOW_PRIVATE")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .context("Invalid IMAP_ALLOW_PRIVATE")?;
+        let sheet_sync_secs = env::var("SHEET_SYNC_SECS")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse()
+            .context("Invalid SHEET_SYNC_SECS")?;
+        let sheet_pull_secs = env::var("SHEET_PULL_SECS")
+            .unwrap_or_else(|_| "3600".to_string())
+            .parse()
+            .context("Invalid SHEET_PULL_SECS")?;
+        let webhook_delivery_secs = env::var("WEBHOOK_DELIVERY_SECS")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse()
+            .context("Invalid WEBHOOK_DELIVERY_SECS")?;
+        let summary_refresh_secs = env::var("SUMMARY_REFRESH_SECS")
+            .unwrap_or_else(|_| "300".to_string())
+            .parse()
+            .context("Invalid SUMMARY_REFRESH_SECS")?;
+        let webhook_allow_private = env::var("WEBHOOK_ALLOW_PRIVATE")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .context("Invalid WEBHOOK_ALLOW_PRIVATE")?;
+        let backup_secs = env::var("BACKUP_SECS")
+            .unwrap_or_else(|_| "86400".to_string())
+            .parse()
+            .context("Invalid BACKUP_SECS")?;
+        let backup_dir = env::var("BACKUP_DIR").unwrap_or_else(|_| "data/backups".to_string());
+        let backup_keep = env::var("BACKUP_KEEP")
+            .unwrap_or_else(|_| "7".to_string())
+            .parse()
+            .context("Invalid BACKUP_KEEP")?;
+        let deletion_grace_days = env::var("DELETION_GRACE_DAYS")
+            .unwrap_or_else(|_| "14".to_string())
+            .parse()
+            .context("Invalid DELETION_GRACE_DAYS")?;
+        let message_retention_days = env::var("MESSAGE_RETENTION_DAYS")
+            .unwrap_or_else(|_| "0".to_string())
+            .parse()
+            .context("Invalid MESSAGE_RETENTION_DAYS")?;
+        let export_dir = env::var("EXPORT_DIR").unwrap_or_else(|_| "data/exports".to_string());
+        let export_link_hours = env::var("EXPORT_LINK_HOURS")
+            .unwrap_or_else(|_| "24".to_string())
+            .parse()
+            .context("Invalid EXPORT_LINK_HOURS")?;
+        let sheets_requests_per_min = env::var("SHEETS_REQUESTS_PER_MIN")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse()
+            .context("Invalid SHEETS_REQUESTS_PER_MIN")?;
+        let sheets_api_url = env::var("SHEETS_API_URL")
+            .unwrap_or_else(|_| "https://sheets.googleapis.com".to_string());
+        let drive_api_url =
+            env::var("DRIVE_API_URL").unwrap_or_else(|_| "https://www.googleapis.com".to_string());
+        let sheets_access_token = env::var("SHEETS_ACCESS_TOKEN").ok();
+        let google_client_id = env::var("GOOGLE_CLIENT_ID").ok();
+        let google_client_secret = env::var("GOOGLE_CLIENT_SECRET").ok();
+        let google_oauth_url = env::var("GOOGLE_OAUTH_URL")
+            .unwrap_or_else(|_| "https://oauth2.googleapis.com".to_string());
+        let graph_api_url = env::var("GRAPH_API_URL")
+            .unwrap_or_else(|_| "https://graph.microsoft.com/v1.0".to_string());
+        let graph_requests_per_min = env::var("GRAPH_REQUESTS_PER_MIN")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse()
+            .context("Invalid GRAPH_REQUESTS_PER_MIN")?;
+        let microsoft_client_id = env::var("MICROSOFT_CLIENT_ID").ok();
+        let microsoft_client_secret = env::var("MICROSOFT_CLIENT_SECRET").ok();
+        let microsoft_oauth_url = env::var("MICROSOFT_OAUTH_URL")
+            .unwrap_or_else(|_| "https://login.microsoftonline.com/common/oauth2/v2.0".to_string());
+        let pdf_timeout_secs = env::var("PDF_TIMEOUT_SECS")
+            .unwrap_or_else(|_| "20".to_string())
+            .parse()
+            .context("Invalid PDF_TIMEOUT_SECS")?;
+        let pdf_cpu_secs = env::var("PDF_CPU_SECS")
+            .unwrap_or_else(|_| "10".to_string())
+            .parse()
+            .context("Invalid PDF_CPU_SECS")?;
+        let pdf_memory_mb = env::var("PDF_MEMORY_MB")
+            .unwrap_or_else(|_| "512".to_string())
+            .parse()
+            .context("Invalid PDF_MEMORY_MB")?;
 
         Ok(Self {
             database_url,
@@ -48,8 +233,40 @@ impl AppConfig {
             lemon_webhook_secret,
             bind_api,
             bind_mail,
+            mail_ingress,
+            bind_lmtp,
             tmp_dir,
             lemon_payment_url,
+            credentials_key,
+            imap_poll_secs,
+            imap_allow_private,
+            sheet_sync_secs,
+            sheet_pull_secs,
+            webhook_delivery_secs,
+            summary_refresh_secs,
+            webhook_allow_private,
+            backup_secs,
+            backup_dir,
+            backup_keep,
+            deletion_grace_days,
+            message_retention_days,
+            export_dir,
+            export_link_hours,
+            sheets_requests_per_min,
+            sheets_api_url,
+            drive_api_url,
+            sheets_access_token,
+            google_client_id,
+            google_client_secret,
+            google_oauth_url,
+            graph_api_url,
+            graph_requests_per_min,
+            microsoft_client_id,
+            microsoft_client_secret,
+            microsoft_oauth_url,
+            pdf_timeout_secs,
+            pdf_cpu_secs,
+            pdf_memory_mb,
         })
     }
 }
@@ -0,0 +1,83 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+const NONCE_LEN: usize = 12;
+
+/// Seals per-user credentials (IMAP passwords, OAuth refresh tokens) with the
+/// 32-byte `CREDENTIALS_KEY` before they are written to SQLite.
+#[derive(Clone)]
+pub struct SecretBox {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SecretBox {
+    pub fn from_base64(key: &str) -> Result<Self> {
+        let bytes = STANDARD
+            .decode(key.trim())
+            .context("CREDENTIALS_KEY is not valid base64")?;
+        if bytes.len() != 32 {
+            return Err(anyhow!("CREDENTIALS_KEY must decode to 32 bytes"));
+        }
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&bytes)),
+        })
+    }
+
+    /// Returns `base64(nonce || ciphertext)`.
+    pub fn seal(&self, plaintext: &str) -> Result<String> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt credential"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(STANDARD.encode(sealed))
+    }
+
+    pub fn open(&self, sealed: &str) -> Result<String> {
+        let bytes = STANDARD
+            .decode(sealed)
+            .context("Stored credential is not valid base64")?;
+        if bytes.len() <= NONCE_LEN {
+            return Err(anyhow!("Stored credential is truncated"));
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt credential; was CREDENTIALS_KEY rotated?"))?;
+        String::from_utf8(plaintext).context("Stored credential is not UTF-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    #[test]
+    fn seal_round_trips_and_uses_fresh_nonces() {
+        let secrets = SecretBox::from_base64(KEY).unwrap();
+        let first = secrets.seal("app-password").unwrap();
+        let second = secrets.seal("app-password").unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(secrets.open(&first).unwrap(), "app-password");
+        assert_eq!(secrets.open(&second).unwrap(), "app-password");
+    }
+
+    #[test]
+    fn open_rejects_other_keys_and_bad_input() {
+        let sealed = SecretBox::from_base64(KEY).unwrap().seal("x").unwrap();
+        let other = SecretBox::from_base64(&STANDARD.encode([7u8; 32])).unwrap();
+
+        assert!(other.open(&sealed).is_err());
+        assert!(other.open("AAAA").is_err());
+        assert!(SecretBox::from_base64("c2hvcnQ=").is_err());
+    }
+}
@@ -1,18 +1,166 @@
-use crate::models::{LogEntry, NewLogEntry, User, UserUpsert};
-use anyhow::Result;
+use crate::models::{
+    BillingEvent, DueDelivery, ExcelWorkbook, ExportJob, GoogleCredential, ImapAccount,
+    ImapAccountUpsert, ImportJob, ImportSummary, LogCorrection, LogEntry, LogEvent, LogUpdate,
+    LogValues, Message, NewCorrection, NewLogEntry, NewMessage, PeriodTotals, PullCandidate,
+    ResyncJob, ResyncRequest, SheetMapping, SheetSync, SinkKind, User, UserUpsert, Webhook,
+    WebhookDelivery,
+};
+use anyhow::{bail, Result};
+use chrono::NaiveDate;
 use rand::{distributions::Alphanumeric, Rng};
-use sqlx::{Sqlite, SqlitePool, Transaction};
+use sha2::{Digest, Sha256};
+use sqlx::pool::PoolOptions;
+use sqlx::Transaction;
 
-pub async fn migrate(pool: &SqlitePool) -> Result<()> {
+/// The database the worker is built for: SQLite, or Postgres with the
+/// `postgres` feature. Queries use `$N` placeholders, which both accept.
+#[cfg(not(feature = "postgres"))]
+pub type Db = sqlx::Sqlite;
+#[cfg(feature = "postgres")]
+pub type Db = sqlx::Postgres;
+
+pub type Pool = sqlx::Pool<Db>;
+
+/// `DATABASE_URL` schemes of the backend this build supports.
+#[cfg(not(feature = "postgres"))]
+const URL_SCHEMES: &[&str] = &["sqlite"];
+#[cfg(feature = "postgres")]
+const URL_SCHEMES: &[&str] = &["postgres", "postgresql"];
+
+/// SQL the two backends spell differently.
+#[cfg(not(feature = "postgres"))]
+mod dialect {
+    /// The Monday starting the week of `order_date`, as `YYYY-MM-DD`.
+    pub const ORDER_WEEK: &str = "date(order_date, 'weekday 0', '-6 days')";
+    /// `order_date` as `YYYY-MM`.
+    pub const ORDER_MONTH: &str = "strftime('%Y-%m', order_date)";
+    /// The log's current values as JSON, evaluated against an outbox row.
+    pub const SYNCED_VALUES: &str = "(SELECT json_object('order_date', l.order_date, \
+         'gross', l.gross, 'tips', l.tips, 'mileage', l.mileage, 'platform', l.platform) \
+         FROM logs l WHERE l.id = sheet_outbox.log_id)";
+
+    /// The current time moved by the number of seconds bound to `param`.
+    pub fn now_plus_secs(param: &str) -> String {
+        format!("datetime('now', {param} || ' seconds')")
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod dialect {
+    pub const ORDER_WEEK: &str = "to_char(date_trunc('week', order_date), 'YYYY-MM-DD')";
+    pub const ORDER_MONTH: &str = "to_char(order_date, 'YYYY-MM')";
+    pub const SYNCED_VALUES: &str = "(SELECT jsonb_build_object('order_date', l.order_date, \
+         'gross', l.gross, 'tips', l.tips, 'mileage', l.mileage, 'platform', l.platform) \
+         FROM logs l WHERE l.id = sheet_outbox.log_id)";
+
+    pub fn now_plus_secs(param: &str) -> String {
+        format!("CURRENT_TIMESTAMP + make_interval(secs => {param})")
+    }
+}
+
+use dialect::SYNCED_VALUES;
+
+/// Opens the pool for `DATABASE_URL`, which must name the backend this build
+/// supports.
+pub async fn connect(url: &str) -> Result<Pool> {
+    let scheme = url.split(':').next().unwrap_or_default();
+    if !URL_SCHEMES.contains(&scheme) {
+        bail!(
+            "DATABASE_URL uses `{scheme}:` but this build supports `{}:`; \
+             Postgres needs a build with `--features postgres`",
+            URL_SCHEMES[0]
+        );
+    }
+    let pool = PoolOptions::<Db>::new()
+        .max_connections(5)
+        .connect(url)
+        .await?;
+    Ok(pool)
+}
+
+/// A migrated, empty database for one test: a private in-memory SQLite
+/// database, or a fresh schema in the Postgres database at
+/// `TEST_DATABASE_URL`.
+#[cfg(test)]
+pub async fn test_pool() -> Pool {
+    #[cfg(not(feature = "postgres"))]
+    let pool = PoolOptions::<Db>::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    #[cfg(feature = "postgres")]
+    let pool = {
+        use sqlx::postgres::PgConnectOptions;
+
+        let url = std::env::var("TEST_DATABASE_URL")
+            .expect("TEST_DATABASE_URL must name a Postgres database for tests");
+        let options: PgConnectOptions = url.parse().unwrap();
+        let schema = format!("test_{}", uuid::Uuid::new_v4().simple());
+        let admin = PoolOptions::<Db>::new()
+            .max_connections(1)
+            .connect_with(options.clone())
+            .await
+            .unwrap();
+        sqlx::query(&format!("CREATE SCHEMA {schema}"))
+            .execute(&admin)
+            .await
+            .unwrap();
+        admin.close().await;
+        PoolOptions::<Db>::new()
+            .max_connections(5)
+            .connect_with(options.options([("search_path", schema.as_str())]))
+            .await
+            .unwrap()
+    };
+    migrate(&pool).await.unwrap();
+    pool
+}
+
+pub async fn migrate(pool: &Pool) -> Result<()> {
+    #[cfg(not(feature = "postgres"))]
     sqlx::migrate!("./migrations").run(pool).await?;
+    #[cfg(feature = "postgres")]
+    sqlx::migrate!("./migrations/postgres").run(pool).await?;
+    #[cfg(not(feature = "postgres"))]
+    hash_message_id_keys(pool).await?;
+    Ok(())
+}
+
+/// Finishes migration 0018 on SQLite, which has no `sha256()`: rewrites the
+/// `mid:<Message-ID>` keys of older rows as [`message_id_key`]s. The
+/// migration queues this in `pending_rewrites`, so it runs on one boot only.
+#[cfg(not(feature = "postgres"))]
+async fn hash_message_id_keys(pool: &Pool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    let queued = sqlx::query("DELETE FROM pending_rewrites WHERE name = 'hash_message_id_keys'")
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if queued == 0 {
+        return Ok(());
+    }
+    let rows: Vec<(i64, String)> =
+        sqlx::query_as("SELECT id, dedupe_key FROM messages WHERE dedupe_key LIKE 'mid:%'")
+            .fetch_all(&mut *tx)
+            .await?;
+    for (id, key) in rows {
+        sqlx::query("UPDATE messages SET dedupe_key = $1 WHERE id = $2")
+            .bind(message_id_key(&key["mid:".len()..]))
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
     Ok(())
 }
 
-pub async fn upsert_user(pool: &SqlitePool, payload: UserUpsert) -> Result<User> {
+pub async fn upsert_user(pool: &Pool, payload: UserUpsert) -> Result<User> {
     let mut tx = pool.begin().await?;
     let existing: Option<User> = sqlx::query_as(
-        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created
-        FROM users WHERE google_id = ?"#,
+        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after
+        FROM users WHERE google_id = $1"#,
     )
     .bind(&payload.google_id)
     .fetch_optional(&mut *tx)
@@ -30,8 +178,10 @@ pub async fn upsert_user(pool: &SqlitePool, payload: UserUpsert) -> Result<User>
 
     sqlx::query(
         r#"INSERT INTO users (google_id, email, sheet_id, forward_key, paid)
-           VALUES (?, ?, ?, ?, COALESCE((SELECT paid FROM users WHERE google_id = ?), 0))
-           ON CONFLICT(google_id) DO UPDATE SET email=excluded.email, sheet_id=excluded.sheet_id"#,
+           VALUES ($1, $2, $3, $4, COALESCE((SELECT paid FROM users WHERE google_id = $5), FALSE))
+           ON CONFLICT(google_id) DO UPDATE SET email=excluded.email, sheet_id=excluded.sheet_id,
+               sheet_status = CASE WHEN users.sheet_id IS NOT DISTINCT FROM excluded.sheet_id
+                   THEN users.sheet_status ELSE NULL END"#,
     )
     .bind(&payload.google_id)
     .bind(&payload.email)
@@ -42,8 +192,9 @@ pub async fn upsert_user(pool: &SqlitePool, payload: UserUpsert) -> Result<User>
     .await?;
 
     let user = sqlx::query_as::<_, User>(
-        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created
-            FROM users WHERE google_id = ?"#,
+        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after
+            FROM users WHERE google_id = $1"#,
     )
     .bind(&payload.google_id)
     .fetch_one(&mut *tx)
@@ -53,46 +204,239 @@ pub async fn upsert_user(pool: &SqlitePool, payload: UserUpsert) -> Result<User>
     Ok(user)
 }
 
-pub async fn mark_paid(pool: &SqlitePool, email: &str) -> Result<()> {
-    sqlx::query("UPDATE users SET paid = 1 WHERE email = ?")
+/// Connects a spreadsheet the worker created itself, so it is known to be
+/// writable.
+pub async fn set_verified_sheet(pool: &Pool, user_id: i64, sheet_id: &str) -> Result<User> {
+    let user = sqlx::query_as::<_, User>(
+        r#"UPDATE users
+           SET sheet_id = $1, sheet_status = 'verified', sheet_verified_at = CURRENT_TIMESTAMP,
+               sheet_error = NULL
+           WHERE id = $2
+           RETURNING id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after"#,
+    )
+    .bind(sheet_id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(user)
+}
+
+/// Records the result of a sheet access check. A successful check also
+/// clears the last sheet error.
+pub async fn set_sheet_status(pool: &Pool, user_id: i64, status: &str) -> Result<User> {
+    let user = sqlx::query_as::<_, User>(
+        r#"UPDATE users
+           SET sheet_status = $1, sheet_verified_at = CURRENT_TIMESTAMP,
+               sheet_error = CASE WHEN $2 = 'verified' THEN NULL ELSE sheet_error END
+           WHERE id = $3
+           RETURNING id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after"#,
+    )
+    .bind(status)
+    .bind(status)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(user)
+}
+
+pub async fn mark_paid(pool: &Pool, email: &str) -> Result<()> {
+    sqlx::query("UPDATE users SET paid = TRUE WHERE email = $1")
         .bind(email)
         .execute(pool)
         .await?;
     Ok(())
 }
 
-pub async fn recent_logs(pool: &SqlitePool, user_id: i64, limit: i64) -> Result<Vec<LogEntry>> {
-    let rows = sqlx::query_as::<_, LogEntry>(
-        r#"SELECT id, user_id, order_date, gross, tips, mileage, parsed_at
-           FROM logs WHERE user_id = ? ORDER BY parsed_at DESC LIMIT ?"#,
+/// Records a Lemon Squeezy event against the user with the billing email.
+/// Events for unknown emails are not kept.
+pub async fn record_billing_event(pool: &Pool, email: &str, event: &str) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO billing_events (user_id, event) SELECT id, $1 FROM users WHERE email = $2",
+    )
+    .bind(event)
+    .bind(email)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn billing_events(pool: &Pool, user_id: i64) -> Result<Vec<BillingEvent>> {
+    let rows = sqlx::query_as::<_, BillingEvent>(
+        "SELECT id, event, received FROM billing_events WHERE user_id = $1 ORDER BY id",
     )
     .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+const LOG_SELECT: &str = "SELECT l.id, l.user_id, l.order_date, l.gross, l.tips, l.currency, \
+     l.mileage, l.platform, l.parsed_at, o.status AS sync_status, o.last_error AS sync_error \
+     FROM logs l LEFT JOIN sheet_outbox o ON o.log_id = l.id";
+
+pub async fn recent_logs(pool: &Pool, user_id: i64, limit: i64) -> Result<Vec<LogEntry>> {
+    let rows = sqlx::query_as::<_, LogEntry>(&format!(
+        "{LOG_SELECT} WHERE l.user_id = $1 AND l.deleted_at IS NULL \
+         ORDER BY l.parsed_at DESC LIMIT $2"
+    ))
+    .bind(user_id)
     .bind(limit)
     .fetch_all(pool)
     .await?;
     Ok(rows)
 }
 
-pub async fn insert_log(pool: &SqlitePool, entry: NewLogEntry) -> Result<LogEntry> {
-    let record = sqlx::query_as::<_, LogEntry>(
-        r#"INSERT INTO logs (user_id, order_date, gross, tips, mileage)
-           VALUES (?, ?, ?, ?, ?)
-           RETURNING id, user_id, order_date, gross, tips, mileage, parsed_at"#,
+pub async fn log_by_id(pool: &Pool, id: i64) -> Result<Option<LogEntry>> {
+    let log = sqlx::query_as::<_, LogEntry>(&format!(
+        "{LOG_SELECT} WHERE l.id = $1 AND l.deleted_at IS NULL"
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(log)
+}
+
+/// A user's logs in sheet order, optionally limited to an order date range.
+pub async fn logs_between(
+    pool: &Pool,
+    user_id: i64,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> Result<Vec<LogEntry>> {
+    let rows = sqlx::query_as::<_, LogEntry>(&format!(
+        "{LOG_SELECT} WHERE l.user_id = $1 AND l.deleted_at IS NULL \
+         AND ($2 IS NULL OR l.order_date >= $3) AND ($4 IS NULL OR l.order_date <= $5) \
+         ORDER BY l.order_date, l.id"
+    ))
+    .bind(user_id)
+    .bind(from)
+    .bind(from)
+    .bind(to)
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Weekly (periods are the Monday starting each week) or monthly (`YYYY-MM`)
+/// totals per platform, newest period first.
+pub async fn period_totals(pool: &Pool, user_id: i64, weekly: bool) -> Result<Vec<PeriodTotals>> {
+    let period = if weekly {
+        dialect::ORDER_WEEK
+    } else {
+        dialect::ORDER_MONTH
+    };
+    let rows = sqlx::query_as::<_, PeriodTotals>(&format!(
+        r#"SELECT {period} AS period, COALESCE(platform, 'Other') AS platform,
+               CAST(SUM(gross) AS BIGINT) AS gross, CAST(SUM(tips) AS BIGINT) AS tips,
+               COALESCE(SUM(mileage), 0.0) AS mileage, COUNT(*) AS payouts
+           FROM logs WHERE user_id = $1 AND deleted_at IS NULL
+           GROUP BY 1, 2 ORDER BY 1 DESC, 2"#
+    ))
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Marks the user's summary tabs for the next refresh.
+pub async fn mark_summary_stale(pool: &Pool, user_id: i64) -> Result<()> {
+    sqlx::query("UPDATE users SET summary_stale = TRUE WHERE id = $1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Users whose summary tabs are stale, clearing the mark so changes made
+/// during the refresh mark them again.
+pub async fn take_stale_summaries(pool: &Pool) -> Result<Vec<i64>> {
+    let ids = sqlx::query_scalar(
+        "UPDATE users SET summary_stale = FALSE WHERE summary_stale = TRUE RETURNING id",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(ids)
+}
+
+/// Id of the summary tab the worker created as `tab` in the spreadsheet.
+pub async fn summary_tab_id(
+    pool: &Pool,
+    user_id: i64,
+    spreadsheet_id: &str,
+    tab: &str,
+) -> Result<Option<i64>> {
+    let id = sqlx::query_scalar(
+        r#"SELECT tab_id FROM summary_tabs
+           WHERE user_id = $1 AND spreadsheet_id = $2 AND tab = $3"#,
+    )
+    .bind(user_id)
+    .bind(spreadsheet_id)
+    .bind(tab)
+    .fetch_optional(pool)
+    .await?;
+    Ok(id)
+}
+
+pub async fn save_summary_tab(
+    pool: &Pool,
+    user_id: i64,
+    spreadsheet_id: &str,
+    tab: &str,
+    tab_id: i64,
+) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO summary_tabs (user_id, spreadsheet_id, tab, tab_id)
+           VALUES ($1, $2, $3, $4)
+           ON CONFLICT(user_id, spreadsheet_id, tab) DO UPDATE SET tab_id = excluded.tab_id"#,
+    )
+    .bind(user_id)
+    .bind(spreadsheet_id)
+    .bind(tab)
+    .bind(tab_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Inserts the log and, when the user has a sheet connected, queues it in
+/// `sheet_outbox` in the same transaction so no append can be lost. The
+/// user's webhooks are queued the same way.
+pub async fn insert_log(pool: &Pool, entry: NewLogEntry) -> Result<LogEntry> {
+    let mut tx = pool.begin().await?;
+    let id: i64 = sqlx::query_scalar(
+        r#"INSERT INTO logs (user_id, order_date, gross, tips, currency, mileage, platform)
+           VALUES ($1, $2, $3, $4, $5, $6, $7)
+           RETURNING id"#,
     )
     .bind(entry.user_id)
     .bind(entry.order_date)
     .bind(entry.gross)
     .bind(entry.tips)
+    .bind(&entry.currency)
     .bind(entry.mileage)
-    .fetch_one(pool)
+    .bind(&entry.platform)
+    .fetch_one(&mut *tx)
     .await?;
+
+    enqueue_sheet_sync(&mut tx, entry.user_id, id, "upsert").await?;
+
+    let record = sqlx::query_as::<_, LogEntry>(&format!("{LOG_SELECT} WHERE l.id = $1"))
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+    enqueue_webhooks(&mut tx, &LogEvent::new("log.created", &record)).await?;
+    tx.commit().await?;
     Ok(record)
 }
 
-pub async fn user_by_forward(pool: &SqlitePool, forward_key: &str) -> Result<Option<User>> {
+pub async fn user_by_forward(pool: &Pool, forward_key: &str) -> Result<Option<User>> {
     let user = sqlx::query_as::<_, User>(
-        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created
-           FROM users WHERE forward_key = ?"#,
+        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after
+           FROM users WHERE forward_key = $1"#,
     )
     .bind(forward_key)
     .fetch_optional(pool)
@@ -100,10 +444,11 @@ pub async fn user_by_forward(pool: &SqlitePool, forward_key: &str) -> Result<Opt
     Ok(user)
 }
 
-pub async fn user_by_id(pool: &SqlitePool, id: i64) -> Result<Option<User>> {
+pub async fn user_by_id(pool: &Pool, id: i64) -> Result<Option<User>> {
     let user = sqlx::query_as::<_, User>(
-        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created
-           FROM users WHERE id = ?"#,
+        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after
+           FROM users WHERE id = $1"#,
     )
     .bind(id)
     .fetch_optional(pool)
@@ -111,33 +456,1570 @@ pub async fn user_by_id(pool: &SqlitePool, id: i64) -> Result<Option<User>> {
     Ok(user)
 }
 
-pub async fn users_on_trial(pool: &SqlitePool, days: i64) -> Result<Vec<User>> {
-    let offset = format!("-{} days", days);
-    let rows = sqlx::query_as::<_, User>(
-        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created
-           FROM users WHERE paid = 0 AND created <= datetime('now', ?)"#,
+pub async fn user_by_google_id(pool: &Pool, google_id: &str) -> Result<Option<User>> {
+    let user = sqlx::query_as::<_, User>(
+        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after
+           FROM users WHERE google_id = $1"#,
+    )
+    .bind(google_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(user)
+}
+
+pub async fn user_by_email(pool: &Pool, email: &str) -> Result<Option<User>> {
+    let user = sqlx::query_as::<_, User>(
+        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after
+           FROM users WHERE email = $1"#,
     )
-    .bind(offset)
+    .bind(email)
+    .fetch_optional(pool)
+    .await?;
+    Ok(user)
+}
+
+pub async fn users_on_trial(pool: &Pool, days: i64) -> Result<Vec<User>> {
+    let rows = sqlx::query_as::<_, User>(&format!(
+        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after
+           FROM users WHERE NOT paid AND created <= {}"#,
+        dialect::now_plus_secs("$1")
+    ))
+    .bind(-days * 86_400)
     .fetch_all(pool)
     .await?;
     Ok(rows)
 }
 
-async fn generate_forward_key(tx: &mut Transaction<'_, Sqlite>) -> Result<String> {
-    loop {
-        let candidate: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
-            .map(char::from)
-            .take(8)
-            .collect();
-        let slug = candidate.to_lowercase();
-        let exists: Option<(i64,)> = sqlx::query_as("SELECT id FROM users WHERE forward_key = ?")
-            .bind(&slug)
-            .fetch_optional(&mut **tx)
-            .await?;
-        if exists.is_none() {
-            return Ok(slug);
-        }
+/// The `dedupe_key` of a message with this Message-ID (without `<>`). Only a
+/// hash is stored so the key outlives the retention scrub without keeping
+/// the Message-ID itself.
+pub fn message_id_key(message_id: &str) -> String {
+    format!("mid-sha256:{}", hex::encode(Sha256::digest(message_id)))
+}
+
+/// True when a message with this key was already handled for the user.
+/// Earlier failures do not count so a retry or re-import can succeed.
+pub async fn message_seen(pool: &Pool, user_id: i64, dedupe_key: &str) -> Result<bool> {
+    let row: Option<(i64,)> = sqlx::query_as(
+        "SELECT id FROM messages WHERE user_id = $1 AND dedupe_key = $2 AND status != 'failed'",
+    )
+    .bind(user_id)
+    .bind(dedupe_key)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.is_some())
+}
+
+pub async fn record_message(pool: &Pool, message: NewMessage) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO messages
+               (user_id, dedupe_key, message_id, subject, sender, source, status, error, log_id)
+           VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+           ON CONFLICT(user_id, dedupe_key) DO UPDATE SET
+               source=excluded.source, status=excluded.status, error=excluded.error,
+               log_id=excluded.log_id, received_at=CURRENT_TIMESTAMP"#,
+    )
+    .bind(message.user_id)
+    .bind(&message.dedupe_key)
+    .bind(&message.message_id)
+    .bind(&message.subject)
+    .bind(&message.sender)
+    .bind(message.source.as_str())
+    .bind(message.status)
+    .bind(&message.error)
+    .bind(message.log_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The user's inbound message ledger, oldest first.
+pub async fn messages(pool: &Pool, user_id: i64) -> Result<Vec<Message>> {
+    let rows = sqlx::query_as::<_, Message>(
+        r#"SELECT id, dedupe_key, message_id, subject, sender, source, status, error, log_id,
+                  received_at
+           FROM messages WHERE user_id = $1 ORDER BY id"#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn create_import_job(pool: &Pool, user_id: i64) -> Result<ImportJob> {
+    let job = sqlx::query_as::<_, ImportJob>(
+        r#"INSERT INTO import_jobs (id, user_id) VALUES ($1, $2)
+           RETURNING id, user_id, status, total, imported, skipped, failed, error, created, finished"#,
+    )
+    .bind(uuid::Uuid::new_v4().to_string())
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(job)
+}
+
+pub async fn import_job(pool: &Pool, user_id: i64, id: &str) -> Result<Option<ImportJob>> {
+    let job = sqlx::query_as::<_, ImportJob>(
+        r#"SELECT id, user_id, status, total, imported, skipped, failed, error, created, finished
+           FROM import_jobs WHERE user_id = $1 AND id = $2"#,
+    )
+    .bind(user_id)
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+pub async fn import_jobs(pool: &Pool, user_id: i64) -> Result<Vec<ImportJob>> {
+    let rows = sqlx::query_as::<_, ImportJob>(
+        r#"SELECT id, user_id, status, total, imported, skipped, failed, error, created, finished
+           FROM import_jobs WHERE user_id = $1 ORDER BY created"#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn update_import_job(
+    pool: &Pool,
+    id: &str,
+    status: &str,
+    summary: &ImportSummary,
+    error: Option<&str>,
+) -> Result<()> {
+    sqlx::query(
+        r#"UPDATE import_jobs
+           SET status = $1, total = $2, imported = $3, skipped = $4, failed = $5, error = $6,
+               finished = CASE WHEN $7 = 'running' THEN NULL ELSE CURRENT_TIMESTAMP END
+           WHERE id = $8"#,
+    )
+    .bind(status)
+    .bind(summary.total)
+    .bind(summary.imported)
+    .bind(summary.skipped)
+    .bind(summary.failed)
+    .bind(error)
+    .bind(status)
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+const IMAP_COLUMNS: &str = "id, user_id, host, port, tls, username, password_enc, folder, \
+     sender_filter, uid_validity, last_uid, last_polled, last_error, created";
+
+const GOOGLE_CREDENTIAL_COLUMNS: &str =
+    "user_id, scope, refresh_token_enc, revoked_at, created, updated";
+
+/// Stores a new grant, replacing (and un-revoking) any earlier one.
+pub async fn save_google_credential(
+    pool: &Pool,
+    user_id: i64,
+    scope: &str,
+    refresh_token_enc: &str,
+) -> Result<GoogleCredential> {
+    let credential = sqlx::query_as::<_, GoogleCredential>(&format!(
+        r#"INSERT INTO google_credentials (user_id, scope, refresh_token_enc)
+           VALUES ($1, $2, $3)
+           ON CONFLICT(user_id) DO UPDATE SET
+               scope = excluded.scope, refresh_token_enc = excluded.refresh_token_enc,
+               revoked_at = NULL, updated = CURRENT_TIMESTAMP
+           RETURNING {GOOGLE_CREDENTIAL_COLUMNS}"#
+    ))
+    .bind(user_id)
+    .bind(scope)
+    .bind(refresh_token_enc)
+    .fetch_one(pool)
+    .await?;
+    Ok(credential)
+}
+
+pub async fn google_credential(pool: &Pool, user_id: i64) -> Result<Option<GoogleCredential>> {
+    let credential = sqlx::query_as::<_, GoogleCredential>(&format!(
+        "SELECT {GOOGLE_CREDENTIAL_COLUMNS} FROM google_credentials WHERE user_id = $1"
+    ))
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(credential)
+}
+
+pub async fn revoke_google_credential(pool: &Pool, user_id: i64) -> Result<()> {
+    sqlx::query(
+        "UPDATE google_credentials SET revoked_at = CURRENT_TIMESTAMP \
+         WHERE user_id = $1 AND revoked_at IS NULL",
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_google_credential(pool: &Pool, user_id: i64) -> Result<bool> {
+    let result = sqlx::query("DELETE FROM google_credentials WHERE user_id = $1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+const EXCEL_WORKBOOK_COLUMNS: &str =
+    "user_id, item_id, scope, refresh_token_enc, revoked_at, created, updated";
+
+/// Stores the user's workbook and grant and makes Excel their sink.
+pub async fn save_excel_workbook(
+    pool: &Pool,
+    user_id: i64,
+    item_id: &str,
+    scope: &str,
+    refresh_token_enc: &str,
+) -> Result<ExcelWorkbook> {
+    let mut tx = pool.begin().await?;
+    let workbook = sqlx::query_as::<_, ExcelWorkbook>(&format!(
+        r#"INSERT INTO excel_workbooks (user_id, item_id, scope, refresh_token_enc)
+           VALUES ($1, $2, $3, $4)
+           ON CONFLICT(user_id) DO UPDATE SET
+               item_id = excluded.item_id, scope = excluded.scope,
+               refresh_token_enc = excluded.refresh_token_enc,
+               revoked_at = NULL, updated = CURRENT_TIMESTAMP
+           RETURNING {EXCEL_WORKBOOK_COLUMNS}"#
+    ))
+    .bind(user_id)
+    .bind(item_id)
+    .bind(scope)
+    .bind(refresh_token_enc)
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE users SET sink = 'excel' WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(workbook)
+}
+
+pub async fn excel_workbook(pool: &Pool, user_id: i64) -> Result<Option<ExcelWorkbook>> {
+    let workbook = sqlx::query_as::<_, ExcelWorkbook>(&format!(
+        "SELECT {EXCEL_WORKBOOK_COLUMNS} FROM excel_workbooks WHERE user_id = $1"
+    ))
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(workbook)
+}
+
+pub async fn revoke_excel_workbook(pool: &Pool, user_id: i64) -> Result<()> {
+    sqlx::query(
+        "UPDATE excel_workbooks SET revoked_at = CURRENT_TIMESTAMP \
+         WHERE user_id = $1 AND revoked_at IS NULL",
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Forgets the workbook and moves the user back to Google Sheets.
+pub async fn delete_excel_workbook(pool: &Pool, user_id: i64) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query("DELETE FROM excel_workbooks WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE users SET sink = 'sheets' WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn set_sink(pool: &Pool, user_id: i64, sink: SinkKind) -> Result<User> {
+    let user = sqlx::query_as::<_, User>(
+        r#"UPDATE users SET sink = $1 WHERE id = $2
+           RETURNING id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after"#,
+    )
+    .bind(sink)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(user)
+}
+
+/// Saves the user's IMAP settings. Changing the server, login or folder
+/// resets the UID cursor.
+pub async fn upsert_imap_account(
+    pool: &Pool,
+    user_id: i64,
+    settings: &ImapAccountUpsert,
+    password_enc: &str,
+) -> Result<ImapAccount> {
+    let account = sqlx::query_as::<_, ImapAccount>(&format!(
+        r#"INSERT INTO imap_accounts
+               (user_id, host, port, tls, username, password_enc, folder, sender_filter)
+           VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+           ON CONFLICT(user_id) DO UPDATE SET
+               uid_validity = CASE WHEN imap_accounts.host = excluded.host
+                   AND imap_accounts.username = excluded.username
+                   AND imap_accounts.folder = excluded.folder
+                   THEN imap_accounts.uid_validity ELSE NULL END,
+               last_uid = CASE WHEN imap_accounts.host = excluded.host
+                   AND imap_accounts.username = excluded.username
+                   AND imap_accounts.folder = excluded.folder
+                   THEN imap_accounts.last_uid ELSE 0 END,
+               host = excluded.host, port = excluded.port, tls = excluded.tls,
+               username = excluded.username, password_enc = excluded.password_enc,
+               folder = excluded.folder, sender_filter = excluded.sender_filter,
+               last_error = NULL
+           RETURNING {IMAP_COLUMNS}"#
+    ))
+    .bind(user_id)
+    .bind(&settings.host)
+    .bind(i64::from(settings.port))
+    .bind(settings.tls)
+    .bind(&settings.username)
+    .bind(password_enc)
+    .bind(&settings.folder)
+    .bind(&settings.sender_filter)
+    .fetch_one(pool)
+    .await?;
+    Ok(account)
+}
+
+pub async fn imap_account(pool: &Pool, user_id: i64) -> Result<Option<ImapAccount>> {
+    let account = sqlx::query_as::<_, ImapAccount>(&format!(
+        "SELECT {IMAP_COLUMNS} FROM imap_accounts WHERE user_id = $1"
+    ))
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(account)
+}
+
+pub async fn imap_accounts(pool: &Pool) -> Result<Vec<ImapAccount>> {
+    let accounts = sqlx::query_as::<_, ImapAccount>(&format!(
+        "SELECT {IMAP_COLUMNS} FROM imap_accounts ORDER BY id"
+    ))
+    .fetch_all(pool)
+    .await?;
+    Ok(accounts)
+}
+
+pub async fn delete_imap_account(pool: &Pool, user_id: i64) -> Result<bool> {
+    let result = sqlx::query("DELETE FROM imap_accounts WHERE user_id = $1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn advance_imap_cursor(
+    pool: &Pool,
+    id: i64,
+    uid_validity: i64,
+    last_uid: i64,
+) -> Result<()> {
+    sqlx::query("UPDATE imap_accounts SET uid_validity = $1, last_uid = $2 WHERE id = $3")
+        .bind(uid_validity)
+        .bind(last_uid)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn finish_imap_poll(pool: &Pool, id: i64, error: Option<&str>) -> Result<()> {
+    sqlx::query(
+        "UPDATE imap_accounts SET last_polled = CURRENT_TIMESTAMP, last_error = $1 WHERE id = $2",
+    )
+    .bind(error)
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The user's sheet layout, or the default `Sheet1!A:D` layout.
+pub async fn sheet_mapping(pool: &Pool, user_id: i64) -> Result<SheetMapping> {
+    let mapping = sqlx::query_as::<_, SheetMapping>(
+        r#"SELECT tab, start_column, fields, extras, date_format, rotation
+           FROM sheet_mappings WHERE user_id = $1"#,
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(mapping.unwrap_or_default())
+}
+
+pub async fn save_sheet_mapping(pool: &Pool, user_id: i64, mapping: &SheetMapping) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO sheet_mappings
+               (user_id, tab, start_column, fields, extras, date_format, rotation)
+           VALUES ($1, $2, $3, $4, $5, $6, $7)
+           ON CONFLICT(user_id) DO UPDATE SET
+               tab = excluded.tab, start_column = excluded.start_column,
+               fields = excluded.fields, extras = excluded.extras,
+               date_format = excluded.date_format, rotation = excluded.rotation,
+               updated = CURRENT_TIMESTAMP"#,
+    )
+    .bind(user_id)
+    .bind(&mapping.tab)
+    .bind(&mapping.start_column)
+    .bind(&mapping.fields)
+    .bind(&mapping.extras)
+    .bind(&mapping.date_format)
+    .bind(mapping.rotation)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Applies an API edit and queues the sheet row update. Returns `None` when
+/// the log does not exist for this user.
+pub async fn update_log(
+    pool: &Pool,
+    user_id: i64,
+    id: i64,
+    update: &LogUpdate,
+) -> Result<Option<LogEntry>> {
+    let mut tx = pool.begin().await?;
+    let updated = sqlx::query(
+        r#"UPDATE logs
+           SET order_date = COALESCE($1, order_date), gross = COALESCE($2, gross),
+               tips = COALESCE($3, tips), mileage = COALESCE($4, mileage),
+               platform = COALESCE($5, platform)
+           WHERE id = $6 AND user_id = $7 AND deleted_at IS NULL"#,
+    )
+    .bind(update.order_date)
+    .bind(update.gross)
+    .bind(update.tips)
+    .bind(update.mileage)
+    .bind(&update.platform)
+    .bind(id)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        return Ok(None);
+    }
+    enqueue_sheet_sync(&mut tx, user_id, id, "upsert").await?;
+
+    let record = sqlx::query_as::<_, LogEntry>(&format!("{LOG_SELECT} WHERE l.id = $1"))
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+    enqueue_webhooks(&mut tx, &LogEvent::new("log.updated", &record)).await?;
+    tx.commit().await?;
+    Ok(Some(record))
+}
+
+/// Hides the log and queues removal of its sheet row.
+pub async fn delete_log(pool: &Pool, user_id: i64, id: i64) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let deleted = sqlx::query(
+        r#"UPDATE logs SET deleted_at = CURRENT_TIMESTAMP
+           WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL"#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if deleted == 0 {
+        return Ok(false);
+    }
+    enqueue_sheet_sync(&mut tx, user_id, id, "delete").await?;
+    let record = sqlx::query_as::<_, LogEntry>(&format!("{LOG_SELECT} WHERE l.id = $1"))
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+    enqueue_webhooks(&mut tx, &LogEvent::new("log.deleted", &record)).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Queues (or re-queues) the log's row when the user has somewhere to export
+/// to: a sheet, or an Excel workbook.
+/// A log has at most one outbox row; the latest operation wins.
+async fn enqueue_sheet_sync(
+    tx: &mut Transaction<'_, Db>,
+    user_id: i64,
+    log_id: i64,
+    op: &str,
+) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO sheet_outbox (log_id, user_id, op)
+           SELECT $1, id, $2 FROM users
+           WHERE id = $3 AND (sheet_id IS NOT NULL OR sink = 'excel')
+           ON CONFLICT(log_id) DO UPDATE SET
+               op = excluded.op, seq = sheet_outbox.seq + 1, status = 'pending', attempts = 0,
+               last_error = NULL, next_attempt = CURRENT_TIMESTAMP"#,
+    )
+    .bind(log_id)
+    .bind(op)
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Queues the event for each of the log owner's webhooks.
+async fn enqueue_webhooks(tx: &mut Transaction<'_, Db>, event: &LogEvent) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO webhook_deliveries (webhook_id, user_id, event, log_id, payload)
+           SELECT w.id, w.user_id, $1, l.id, $2
+           FROM webhooks w JOIN logs l ON l.user_id = w.user_id
+           WHERE l.id = $3"#,
+    )
+    .bind(event.event)
+    .bind(serde_json::to_string(event)?)
+    .bind(event.log.id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+pub async fn due_sheet_syncs(pool: &Pool, limit: i64) -> Result<Vec<SheetSync>> {
+    let rows = sqlx::query_as::<_, SheetSync>(
+        r#"SELECT id, log_id, user_id, attempts, op, seq
+           FROM sheet_outbox o
+           WHERE o.status = 'pending' AND o.next_attempt <= CURRENT_TIMESTAMP
+           ORDER BY o.next_attempt, o.id LIMIT $1"#,
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Marks the append done and clears any sheet error shown to the user.
+pub async fn mark_sheet_synced(pool: &Pool, sync: &SheetSync) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+        r#"UPDATE sheet_outbox
+           SET status = 'synced', attempts = attempts + 1, last_error = NULL,
+               synced_at = CURRENT_TIMESTAMP, synced_values = {SYNCED_VALUES}
+           WHERE id = $1 AND seq = $2"#
+    ))
+    .bind(sync.id)
+    .bind(sync.seq)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE users SET sheet_error = NULL WHERE id = $1")
+        .bind(sync.user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn retry_sheet_sync(
+    pool: &Pool,
+    sync: &SheetSync,
+    error: &str,
+    delay_secs: i64,
+) -> Result<()> {
+    sqlx::query(&format!(
+        r#"UPDATE sheet_outbox
+           SET attempts = attempts + 1, last_error = $1, next_attempt = {}
+           WHERE id = $3 AND seq = $4"#,
+        dialect::now_plus_secs("$2")
+    ))
+    .bind(error)
+    .bind(delay_secs)
+    .bind(sync.id)
+    .bind(sync.seq)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Reschedules a row after a quota error without counting an attempt.
+pub async fn defer_sheet_sync(
+    pool: &Pool,
+    sync: &SheetSync,
+    error: &str,
+    delay_secs: i64,
+) -> Result<()> {
+    sqlx::query(&format!(
+        r#"UPDATE sheet_outbox
+           SET last_error = $1, next_attempt = {}
+           WHERE id = $3 AND seq = $4"#,
+        dialect::now_plus_secs("$2")
+    ))
+    .bind(error)
+    .bind(delay_secs)
+    .bind(sync.id)
+    .bind(sync.seq)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Gives up on an append. `user_error` is set when the failure needs the
+/// user's attention, e.g. the sheet is no longer shared.
+pub async fn fail_sheet_sync(
+    pool: &Pool,
+    sync: &SheetSync,
+    error: &str,
+    user_error: Option<&str>,
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"UPDATE sheet_outbox
+           SET status = 'failed', attempts = attempts + 1, last_error = $1
+           WHERE id = $2 AND seq = $3"#,
+    )
+    .bind(error)
+    .bind(sync.id)
+    .bind(sync.seq)
+    .execute(&mut *tx)
+    .await?;
+    if let Some(message) = user_error {
+        sqlx::query("UPDATE users SET sheet_error = $1 WHERE id = $2")
+            .bind(message)
+            .bind(sync.user_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Records logs written by a resync as synced so the outbox does not append
+/// them a second time.
+pub async fn mark_logs_synced(pool: &Pool, user_id: i64, log_ids: &[i64]) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    for log_id in log_ids {
+        sqlx::query(
+            r#"INSERT INTO sheet_outbox (log_id, user_id, status, synced_at)
+               VALUES ($1, $2, 'synced', CURRENT_TIMESTAMP)
+               ON CONFLICT(log_id) DO UPDATE SET
+                   op = 'upsert', seq = sheet_outbox.seq + 1, status = 'synced', last_error = NULL,
+                   synced_at = CURRENT_TIMESTAMP"#,
+        )
+        .bind(log_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "UPDATE sheet_outbox SET synced_values = {SYNCED_VALUES} WHERE log_id = $1"
+        ))
+        .bind(log_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Live logs of a user with their outbox status and last synced values.
+pub async fn pull_candidates(pool: &Pool, user_id: i64) -> Result<Vec<PullCandidate>> {
+    let rows = sqlx::query_as::<_, PullCandidate>(
+        r#"SELECT l.id, l.order_date, l.gross, l.tips, l.mileage, l.platform,
+                  o.status AS sync_status, o.synced_values
+           FROM logs l LEFT JOIN sheet_outbox o ON o.log_id = l.id
+           WHERE l.user_id = $1 AND l.deleted_at IS NULL"#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// One live log with the state of its sheet row, read fresh.
+pub async fn pull_candidate(
+    pool: &Pool,
+    user_id: i64,
+    log_id: i64,
+) -> Result<Option<PullCandidate>> {
+    let row = sqlx::query_as::<_, PullCandidate>(
+        r#"SELECT l.id, l.order_date, l.gross, l.tips, l.mileage, l.platform,
+                  o.status AS sync_status, o.synced_values
+           FROM logs l LEFT JOIN sheet_outbox o ON o.log_id = l.id
+           WHERE l.user_id = $1 AND l.id = $2 AND l.deleted_at IS NULL"#,
+    )
+    .bind(user_id)
+    .bind(log_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row)
+}
+
+/// Applies values edited in the sheet without queueing a write back, moves
+/// the synced base to `base`, and records the corrections. A conflict or
+/// rejection identical to the last one recorded for that field is not
+/// recorded again.
+///
+/// `expected` are the log's values the edits were worked out against. When
+/// the log no longer holds them (an API edit landed in between) nothing is
+/// written and `false` is returned, so the caller can compare again.
+pub async fn apply_sheet_edits(
+    pool: &Pool,
+    user_id: i64,
+    log_id: i64,
+    expected: &LogValues,
+    update: &LogUpdate,
+    base: &LogValues,
+    corrections: &[NewCorrection],
+) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let updated = sqlx::query(
+        r#"UPDATE logs
+           SET order_date = COALESCE($1, order_date), gross = COALESCE($2, gross),
+               tips = COALESCE($3, tips), mileage = COALESCE($4, mileage),
+               platform = COALESCE($5, platform)
+           WHERE id = $6 AND user_id = $7 AND deleted_at IS NULL
+             AND order_date = $8 AND gross = $9 AND tips = $10
+             AND mileage IS NOT DISTINCT FROM $11 AND platform IS NOT DISTINCT FROM $12"#,
+    )
+    .bind(update.order_date)
+    .bind(update.gross)
+    .bind(update.tips)
+    .bind(update.mileage)
+    .bind(&update.platform)
+    .bind(log_id)
+    .bind(user_id)
+    .bind(expected.order_date)
+    .bind(expected.gross)
+    .bind(expected.tips)
+    .bind(expected.mileage)
+    .bind(&expected.platform)
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+    sqlx::query("UPDATE sheet_outbox SET synced_values = $1 WHERE log_id = $2")
+        .bind(sqlx::types::Json(base))
+        .bind(log_id)
+        .execute(&mut *tx)
+        .await?;
+    if corrections.iter().any(|c| c.status == "applied") {
+        let record = sqlx::query_as::<_, LogEntry>(&format!("{LOG_SELECT} WHERE l.id = $1"))
+            .bind(log_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        enqueue_webhooks(&mut tx, &LogEvent::new("log.updated", &record)).await?;
+    }
+    for correction in corrections {
+        sqlx::query(
+            r#"INSERT INTO log_corrections (log_id, user_id, field, db_value, sheet_value, status)
+               SELECT $1, $2, $3, $4, $5, $6
+               WHERE $6 = 'applied' OR NOT EXISTS (
+                   SELECT 1 FROM log_corrections
+                   WHERE id = (SELECT MAX(id) FROM log_corrections
+                               WHERE log_id = $1 AND field = $3)
+                     AND status = $6 AND db_value IS NOT DISTINCT FROM $4
+                     AND sheet_value IS NOT DISTINCT FROM $5)"#,
+        )
+        .bind(log_id)
+        .bind(user_id)
+        .bind(correction.field.as_str())
+        .bind(&correction.db_value)
+        .bind(&correction.sheet_value)
+        .bind(correction.status)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+
+pub async fn log_corrections(pool: &Pool, user_id: i64, limit: i64) -> Result<Vec<LogCorrection>> {
+    let rows = sqlx::query_as::<_, LogCorrection>(
+        r#"SELECT id, log_id, field, db_value, sheet_value, status, created
+           FROM log_corrections WHERE user_id = $1 ORDER BY id DESC LIMIT $2"#,
+    )
+    .bind(user_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Users exporting to a verified sheet, for the periodic sheet pull.
+pub async fn users_with_sheets(pool: &Pool) -> Result<Vec<User>> {
+    let users = sqlx::query_as::<_, User>(
+        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after
+           FROM users
+           WHERE sink = 'sheets' AND sheet_id IS NOT NULL AND sheet_status = 'verified'
+           ORDER BY id"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(users)
+}
+
+pub async fn webhooks(pool: &Pool, user_id: i64) -> Result<Vec<Webhook>> {
+    let rows = sqlx::query_as::<_, Webhook>(
+        "SELECT id, user_id, url, secret, created FROM webhooks WHERE user_id = $1 ORDER BY id",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn webhook(pool: &Pool, user_id: i64, id: i64) -> Result<Option<Webhook>> {
+    let row = sqlx::query_as::<_, Webhook>(
+        "SELECT id, user_id, url, secret, created FROM webhooks WHERE id = $1 AND user_id = $2",
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row)
+}
+
+pub async fn create_webhook(pool: &Pool, user_id: i64, url: &str, secret: &str) -> Result<Webhook> {
+    let row = sqlx::query_as::<_, Webhook>(
+        r#"INSERT INTO webhooks (user_id, url, secret) VALUES ($1, $2, $3)
+           RETURNING id, user_id, url, secret, created"#,
+    )
+    .bind(user_id)
+    .bind(url)
+    .bind(secret)
+    .fetch_one(pool)
+    .await?;
+    Ok(row)
+}
+
+/// Removes the webhook with its queued and logged deliveries.
+pub async fn delete_webhook(pool: &Pool, user_id: i64, id: i64) -> Result<bool> {
+    let deleted = sqlx::query("DELETE FROM webhooks WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(deleted > 0)
+}
+
+const DUE_DELIVERY_SELECT: &str = "SELECT d.id, d.webhook_id, d.user_id, w.url, w.secret, \
+     d.event, d.payload, d.attempts \
+     FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id";
+
+/// Queues an event that is not about a stored log, such as a test event.
+pub async fn insert_webhook_delivery(
+    pool: &Pool,
+    webhook: &Webhook,
+    event: &LogEvent,
+) -> Result<DueDelivery> {
+    let id: i64 = sqlx::query_scalar(
+        r#"INSERT INTO webhook_deliveries (webhook_id, user_id, event, payload)
+           VALUES ($1, $2, $3, $4) RETURNING id"#,
+    )
+    .bind(webhook.id)
+    .bind(webhook.user_id)
+    .bind(event.event)
+    .bind(serde_json::to_string(event)?)
+    .fetch_one(pool)
+    .await?;
+    let row = sqlx::query_as::<_, DueDelivery>(&format!("{DUE_DELIVERY_SELECT} WHERE d.id = $1"))
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+    Ok(row)
+}
+
+pub async fn due_webhook_deliveries(pool: &Pool, limit: i64) -> Result<Vec<DueDelivery>> {
+    let rows = sqlx::query_as::<_, DueDelivery>(&format!(
+        "{DUE_DELIVERY_SELECT} WHERE d.status = 'pending' AND d.next_attempt <= CURRENT_TIMESTAMP \
+         ORDER BY d.next_attempt, d.id LIMIT $1"
+    ))
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn mark_webhook_delivered(pool: &Pool, id: i64, response_status: i64) -> Result<()> {
+    sqlx::query(
+        r#"UPDATE webhook_deliveries
+           SET status = 'delivered', attempts = attempts + 1, response_status = $1,
+               last_error = NULL, delivered_at = CURRENT_TIMESTAMP
+           WHERE id = $2"#,
+    )
+    .bind(response_status)
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Records a failed attempt; the delivery is retried after `retry_in_secs`,
+/// or marked `failed` when it is `None`.
+pub async fn record_webhook_failure(
+    pool: &Pool,
+    id: i64,
+    response_status: Option<i64>,
+    error: &str,
+    retry_in_secs: Option<i64>,
+) -> Result<()> {
+    sqlx::query(&format!(
+        r#"UPDATE webhook_deliveries
+           SET attempts = attempts + 1, response_status = $1, last_error = $2,
+               status = CASE WHEN $3 IS NULL THEN 'failed' ELSE 'pending' END,
+               next_attempt = {}
+           WHERE id = $5"#,
+        dialect::now_plus_secs("$4")
+    ))
+    .bind(response_status)
+    .bind(error)
+    .bind(retry_in_secs)
+    .bind(retry_in_secs.unwrap_or(0))
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn webhook_delivery(pool: &Pool, id: i64) -> Result<WebhookDelivery> {
+    let row = sqlx::query_as::<_, WebhookDelivery>(
+        r#"SELECT id, webhook_id, event, log_id, status, attempts, response_status, last_error,
+                  created, delivered_at
+           FROM webhook_deliveries WHERE id = $1"#,
+    )
+    .bind(id)
+    .fetch_one(pool)
+    .await?;
+    Ok(row)
+}
+
+/// The webhook's delivery log, newest first.
+pub async fn webhook_deliveries(
+    pool: &Pool,
+    webhook_id: i64,
+    limit: i64,
+) -> Result<Vec<WebhookDelivery>> {
+    let rows = sqlx::query_as::<_, WebhookDelivery>(
+        r#"SELECT id, webhook_id, event, log_id, status, attempts, response_status, last_error,
+                  created, delivered_at
+           FROM webhook_deliveries WHERE webhook_id = $1 ORDER BY id DESC LIMIT $2"#,
+    )
+    .bind(webhook_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Deliveries to all of the user's webhooks, oldest first.
+pub async fn user_webhook_deliveries(pool: &Pool, user_id: i64) -> Result<Vec<WebhookDelivery>> {
+    let rows = sqlx::query_as::<_, WebhookDelivery>(
+        r#"SELECT d.id, d.webhook_id, d.event, d.log_id, d.status, d.attempts, d.response_status,
+                  d.last_error, d.created, d.delivered_at
+           FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+           WHERE w.user_id = $1 ORDER BY d.id"#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Puts a user's failed appends back in the queue after their sheet was
+/// reconnected or set up again. Returns how many were requeued.
+pub async fn requeue_sheet_syncs(pool: &Pool, user_id: i64) -> Result<u64> {
+    let mut tx = pool.begin().await?;
+    let requeued = sqlx::query(
+        r#"UPDATE sheet_outbox
+           SET status = 'pending', attempts = 0, next_attempt = CURRENT_TIMESTAMP
+           WHERE user_id = $1 AND status = 'failed'"#,
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    sqlx::query("UPDATE users SET sheet_error = NULL WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(requeued)
+}
+
+const RESYNC_COLUMNS: &str =
+    "id, user_id, mode, date_from, date_to, status, total, written, error, created, finished";
+
+pub async fn create_resync_job(
+    pool: &Pool,
+    user_id: i64,
+    request: &ResyncRequest,
+) -> Result<ResyncJob> {
+    let job = sqlx::query_as::<_, ResyncJob>(&format!(
+        r#"INSERT INTO resync_jobs (id, user_id, mode, date_from, date_to)
+           VALUES ($1, $2, $3, $4, $5)
+           RETURNING {RESYNC_COLUMNS}"#
+    ))
+    .bind(uuid::Uuid::new_v4().to_string())
+    .bind(user_id)
+    .bind(request.mode.as_str())
+    .bind(request.from)
+    .bind(request.to)
+    .fetch_one(pool)
+    .await?;
+    Ok(job)
+}
+
+pub async fn resync_job(pool: &Pool, user_id: i64, id: &str) -> Result<Option<ResyncJob>> {
+    let job = sqlx::query_as::<_, ResyncJob>(&format!(
+        "SELECT {RESYNC_COLUMNS} FROM resync_jobs WHERE id = $1 AND user_id = $2"
+    ))
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+pub async fn resync_jobs(pool: &Pool, user_id: i64) -> Result<Vec<ResyncJob>> {
+    let rows = sqlx::query_as::<_, ResyncJob>(&format!(
+        "SELECT {RESYNC_COLUMNS} FROM resync_jobs WHERE user_id = $1 ORDER BY created"
+    ))
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn update_resync_job(
+    pool: &Pool,
+    id: &str,
+    status: &str,
+    total: i64,
+    written: i64,
+    error: Option<&str>,
+) -> Result<()> {
+    sqlx::query(
+        r#"UPDATE resync_jobs
+           SET status = $1, total = $2, written = $3, error = $4,
+               finished = CASE WHEN $5 = 'running' THEN NULL ELSE CURRENT_TIMESTAMP END
+           WHERE id = $6"#,
+    )
+    .bind(status)
+    .bind(total)
+    .bind(written)
+    .bind(error)
+    .bind(status)
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+const EXPORT_COLUMNS: &str = "id, user_id, token, status, error, created, finished, expires";
+
+pub async fn create_export_job(pool: &Pool, user_id: i64, token: &str) -> Result<ExportJob> {
+    let job = sqlx::query_as::<_, ExportJob>(&format!(
+        "INSERT INTO export_jobs (id, user_id, token) VALUES ($1, $2, $3) RETURNING {EXPORT_COLUMNS}"
+    ))
+    .bind(uuid::Uuid::new_v4().to_string())
+    .bind(user_id)
+    .bind(token)
+    .fetch_one(pool)
+    .await?;
+    Ok(job)
+}
+
+pub async fn export_job(pool: &Pool, user_id: i64, id: &str) -> Result<Option<ExportJob>> {
+    let job = sqlx::query_as::<_, ExportJob>(&format!(
+        "SELECT {EXPORT_COLUMNS} FROM export_jobs WHERE id = $1 AND user_id = $2"
+    ))
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+pub async fn export_jobs(pool: &Pool, user_id: i64) -> Result<Vec<ExportJob>> {
+    let rows = sqlx::query_as::<_, ExportJob>(&format!(
+        "SELECT {EXPORT_COLUMNS} FROM export_jobs WHERE user_id = $1 ORDER BY created"
+    ))
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// The completed export a download token unlocks, unless it has expired.
+pub async fn downloadable_export(pool: &Pool, token: &str) -> Result<Option<ExportJob>> {
+    let job = sqlx::query_as::<_, ExportJob>(&format!(
+        "SELECT {EXPORT_COLUMNS} FROM export_jobs \
+         WHERE token = $1 AND status = 'completed' AND expires > CURRENT_TIMESTAMP"
+    ))
+    .bind(token)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// Completes an export, whose link then works for `link_secs`, or records
+/// why it failed.
+pub async fn finish_export_job(
+    pool: &Pool,
+    id: &str,
+    error: Option<&str>,
+    link_secs: i64,
+) -> Result<ExportJob> {
+    let job = sqlx::query_as::<_, ExportJob>(&format!(
+        r#"UPDATE export_jobs
+           SET status = CASE WHEN $1 IS NULL THEN 'completed' ELSE 'failed' END, error = $2,
+               finished = CURRENT_TIMESTAMP,
+               expires = CASE WHEN $3 IS NULL THEN {} END
+           WHERE id = $5
+           RETURNING {EXPORT_COLUMNS}"#,
+        dialect::now_plus_secs("$4")
+    ))
+    .bind(error)
+    .bind(error)
+    .bind(error)
+    .bind(link_secs)
+    .bind(id)
+    .fetch_one(pool)
+    .await?;
+    Ok(job)
+}
+
+/// Completed exports whose link has expired.
+pub async fn expired_exports(pool: &Pool) -> Result<Vec<ExportJob>> {
+    let rows = sqlx::query_as::<_, ExportJob>(&format!(
+        "SELECT {EXPORT_COLUMNS} FROM export_jobs \
+         WHERE status = 'completed' AND expires <= CURRENT_TIMESTAMP"
+    ))
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn mark_export_expired(pool: &Pool, id: &str) -> Result<()> {
+    sqlx::query("UPDATE export_jobs SET status = 'expired' WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Marks the user's account for deletion `grace_secs` from now. Asking again
+/// keeps the original date.
+pub async fn schedule_user_deletion(pool: &Pool, user_id: i64, grace_secs: i64) -> Result<User> {
+    let user = sqlx::query_as::<_, User>(&format!(
+        r#"UPDATE users SET delete_after = COALESCE(delete_after, {})
+           WHERE id = $2
+           RETURNING id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after"#,
+        dialect::now_plus_secs("$1")
+    ))
+    .bind(grace_secs)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(user)
+}
+
+/// Undoes `schedule_user_deletion`. False when no deletion was pending.
+pub async fn cancel_user_deletion(pool: &Pool, user_id: i64) -> Result<bool> {
+    let result = sqlx::query(
+        "UPDATE users SET delete_after = NULL WHERE id = $1 AND delete_after IS NOT NULL",
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Users whose deletion grace period has run out.
+pub async fn users_due_for_deletion(pool: &Pool) -> Result<Vec<User>> {
+    let users = sqlx::query_as::<_, User>(
+        r#"SELECT id, google_id, email, sheet_id, forward_key, paid, created, sheet_error,
+               sheet_status, sheet_verified_at, sink, delete_after
+           FROM users WHERE delete_after <= CURRENT_TIMESTAMP
+           ORDER BY id"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(users)
+}
+
+/// Deletes the user and, through the foreign keys, their logs, messages,
+/// credentials, IMAP account, webhooks and queued work. The forward key is
+/// retired so it is never handed out again.
+pub async fn purge_user(pool: &Pool, user_id: i64) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"INSERT INTO retired_forward_keys (forward_key)
+           SELECT forward_key FROM users WHERE id = $1
+           ON CONFLICT(forward_key) DO NOTHING"#,
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Clears the subject, sender, Message-ID and error of messages received
+/// more than `days` days ago. The dedupe key and outcome stay, so an old
+/// message re-imported later is still recognised. Returns how many were
+/// scrubbed.
+pub async fn scrub_messages(pool: &Pool, days: i64) -> Result<u64> {
+    let result = sqlx::query(&format!(
+        r#"UPDATE messages SET subject = NULL, sender = NULL, message_id = NULL, error = NULL
+           WHERE received_at <= {}
+             AND (subject IS NOT NULL OR sender IS NOT NULL OR message_id IS NOT NULL
+                  OR error IS NOT NULL)"#,
+        dialect::now_plus_secs("$1")
+    ))
+    .bind(-days * 86_400)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+async fn generate_forward_key(tx: &mut Transaction<'_, Db>) -> Result<String> {
+    loop {
+        let candidate: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+            .map(char::from)
+            .take(8)
+            .collect();
+        let slug = candidate.to_lowercase();
+        // Keys of deleted accounts count as taken, so mail still sent to an
+        // old address never reaches a new user.
+        let exists: Option<(i32,)> = sqlx::query_as(
+            r#"SELECT 1 FROM users WHERE forward_key = $1
+               UNION ALL SELECT 1 FROM retired_forward_keys WHERE forward_key = $2"#,
+        )
+        .bind(&slug)
+        .bind(&slug)
+        .fetch_optional(&mut **tx)
+        .await?;
+        if exists.is_none() {
+            return Ok(slug);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::MessageSource;
+    use crate::money::Cents;
+    use crate::sheets_mock::{test_entry, test_log, test_user};
+
+    fn message(user_id: i64, status: &'static str) -> NewMessage {
+        NewMessage {
+            user_id,
+            dedupe_key: message_id_key("abc@uber.com"),
+            message_id: Some("<abc@uber.com>".to_string()),
+            subject: None,
+            sender: None,
+            source: MessageSource::Import,
+            status,
+            error: None,
+            log_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_rejects_urls_for_the_other_backend() {
+        let other = if cfg!(feature = "postgres") {
+            "sqlite://data/data.db"
+        } else {
+            "postgres://localhost/driversheet"
+        };
+        let err = connect(other).await.unwrap_err();
+        assert!(err.to_string().contains("--features postgres"));
+    }
+
+    #[tokio::test]
+    async fn message_seen_ignores_failed_attempts() {
+        let pool = test_pool().await;
+        let user = test_user(&pool, None).await;
+
+        assert!(
+            !message_seen(&pool, user.id, &message_id_key("abc@uber.com"))
+                .await
+                .unwrap()
+        );
+        record_message(&pool, message(user.id, "failed"))
+            .await
+            .unwrap();
+        assert!(
+            !message_seen(&pool, user.id, &message_id_key("abc@uber.com"))
+                .await
+                .unwrap()
+        );
+        record_message(&pool, message(user.id, "logged"))
+            .await
+            .unwrap();
+        assert!(
+            message_seen(&pool, user.id, &message_id_key("abc@uber.com"))
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn insert_log_queues_sheet_sync_for_connected_sheets() {
+        let pool = test_pool().await;
+        let user = |google_id: &str, sheet_id: Option<&str>| UserUpsert {
+            google_id: google_id.to_string(),
+            email: format!("{google_id}@example.com"),
+            sheet_id: sheet_id.map(str::to_string),
+        };
+        let with_sheet = upsert_user(&pool, user("g-1", Some("sheet-1")))
+            .await
+            .unwrap();
+        let without = upsert_user(&pool, user("g-2", None)).await.unwrap();
+
+        let log = test_log(&pool, &with_sheet, "2024-08-15", Cents(1000)).await;
+        assert_eq!(log.sync_status.as_deref(), Some("pending"));
+        let unsynced = test_log(&pool, &without, "2024-08-15", Cents(1000)).await;
+        assert_eq!(unsynced.sync_status, None);
+
+        let due = due_sheet_syncs(&pool, 10).await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].log_id, log.id);
+
+        retry_sheet_sync(&pool, &due[0], "503", 60).await.unwrap();
+        assert!(due_sheet_syncs(&pool, 10).await.unwrap().is_empty());
+
+        fail_sheet_sync(&pool, &due[0], "403", Some("unshared"))
+            .await
+            .unwrap();
+        let user = user_by_id(&pool, with_sheet.id).await.unwrap().unwrap();
+        assert_eq!(user.sheet_error.as_deref(), Some("unshared"));
+        let log = log_by_id(&pool, log.id).await.unwrap().unwrap();
+        assert_eq!(log.sync_status.as_deref(), Some("failed"));
+        assert_eq!(log.sync_error.as_deref(), Some("403"));
+
+        assert_eq!(requeue_sheet_syncs(&pool, with_sheet.id).await.unwrap(), 1);
+        let due = due_sheet_syncs(&pool, 10).await.unwrap();
+        assert_eq!(due[0].attempts, 0);
+        mark_sheet_synced(&pool, &due[0]).await.unwrap();
+        let logs = recent_logs(&pool, with_sheet.id, 10).await.unwrap();
+        assert_eq!(logs[0].sync_status.as_deref(), Some("synced"));
+        let user = user_by_id(&pool, with_sheet.id).await.unwrap().unwrap();
+        assert_eq!(user.sheet_error, None);
+    }
+
+    #[tokio::test]
+    async fn logs_between_filters_dates_and_resync_marks_synced() {
+        let pool = test_pool().await;
+        let user = test_user(&pool, None).await;
+        let date = |day| NaiveDate::from_ymd_opt(2024, 8, day).unwrap();
+        for day in [20, 5, 12] {
+            test_log(&pool, &user, &format!("2024-08-{day:02}"), Cents(1000)).await;
+        }
+
+        let all = logs_between(&pool, user.id, None, None).await.unwrap();
+        let days: Vec<_> = all.iter().map(|log| log.order_date).collect();
+        assert_eq!(days, [date(5), date(12), date(20)]);
+        let middle = logs_between(&pool, user.id, Some(date(6)), Some(date(20)))
+            .await
+            .unwrap();
+        assert_eq!(middle.len(), 2);
+
+        mark_logs_synced(&pool, user.id, &[middle[0].id])
+            .await
+            .unwrap();
+        let log = log_by_id(&pool, middle[0].id).await.unwrap().unwrap();
+        assert_eq!(log.sync_status.as_deref(), Some("synced"));
+    }
+
+    #[tokio::test]
+    async fn log_edits_requeue_and_supersede_in_flight_syncs() {
+        let pool = test_pool().await;
+        let user = test_user(&pool, Some("sheet-1")).await;
+        let log = test_log(&pool, &user, "2024-08-15", Cents(1000)).await;
+        let in_flight = due_sheet_syncs(&pool, 10).await.unwrap().remove(0);
+
+        let update = LogUpdate {
+            tips: Some(Cents(350)),
+            ..LogUpdate::default()
+        };
+        let edited = update_log(&pool, user.id, log.id, &update)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((edited.gross, edited.tips), (Cents(1000), Cents(350)));
+        assert!(update_log(&pool, user.id + 1, log.id, &update)
+            .await
+            .unwrap()
+            .is_none());
+
+        // The sync that started before the edit must not mark it done.
+        mark_sheet_synced(&pool, &in_flight).await.unwrap();
+        let due = due_sheet_syncs(&pool, 10).await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].op, "upsert");
+
+        assert!(delete_log(&pool, user.id, log.id).await.unwrap());
+        assert!(!delete_log(&pool, user.id, log.id).await.unwrap());
+        assert!(recent_logs(&pool, user.id, 10).await.unwrap().is_empty());
+        let due = due_sheet_syncs(&pool, 10).await.unwrap();
+        assert_eq!(due[0].op, "delete");
+        assert_eq!(due[0].log_id, log.id);
+    }
+
+    #[tokio::test]
+    async fn period_totals_group_by_monday_week_and_platform() {
+        let pool = test_pool().await;
+        let user = test_user(&pool, None).await;
+        // 2024-08-11 is a Sunday, 2024-08-12 the following Monday.
+        for (day, platform) in [(11, Some("Uber")), (12, Some("Uber")), (13, None)] {
+            let entry = NewLogEntry {
+                mileage: Some(2.5),
+                platform: platform.map(str::to_string),
+                ..test_entry(&user, &format!("2024-08-{day:02}"), Cents(1000))
+            };
+            insert_log(&pool, entry).await.unwrap();
+        }
+
+        let weekly = period_totals(&pool, user.id, true).await.unwrap();
+        let keys: Vec<_> = weekly
+            .iter()
+            .map(|t| (t.period.as_str(), t.platform.as_str(), t.payouts))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                ("2024-08-12", "Other", 1),
+                ("2024-08-12", "Uber", 1),
+                ("2024-08-05", "Uber", 1)
+            ]
+        );
+
+        let monthly = period_totals(&pool, user.id, false).await.unwrap();
+        assert_eq!(monthly.len(), 2);
+        assert_eq!(monthly[1].period, "2024-08");
+        assert_eq!(monthly[1].mileage, 5.0);
+    }
+
+    #[tokio::test]
+    async fn purged_users_lose_their_data_and_forward_key() {
+        let pool = test_pool().await;
+        let user = test_user(&pool, Some("sheet-1")).await;
+        test_log(&pool, &user, "2024-08-15", Cents(1000)).await;
+        record_message(&pool, message(user.id, "logged"))
+            .await
+            .unwrap();
+
+        let scheduled = schedule_user_deletion(&pool, user.id, 3600).await.unwrap();
+        assert!(scheduled.delete_after.is_some());
+        assert!(users_due_for_deletion(&pool).await.unwrap().is_empty());
+        assert!(cancel_user_deletion(&pool, user.id).await.unwrap());
+        assert!(!cancel_user_deletion(&pool, user.id).await.unwrap());
+
+        schedule_user_deletion(&pool, user.id, 0).await.unwrap();
+        let due = users_due_for_deletion(&pool).await.unwrap();
+        assert_eq!(due.len(), 1);
+        purge_user(&pool, user.id).await.unwrap();
+
+        assert!(user_by_forward(&pool, &user.forward_key)
+            .await
+            .unwrap()
+            .is_none());
+        for table in ["logs", "messages", "sheet_outbox"] {
+            let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(count, 0, "{table}");
+        }
+        let retired: Option<String> =
+            sqlx::query_scalar("SELECT forward_key FROM retired_forward_keys")
+                .fetch_optional(&pool)
+                .await
+                .unwrap();
+        assert_eq!(retired, Some(user.forward_key));
+    }
+
+    #[tokio::test]
+    async fn scrubbed_messages_keep_their_dedupe_key() {
+        let pool = test_pool().await;
+        let user = test_user(&pool, None).await;
+        let mut logged = message(user.id, "logged");
+        logged.subject = Some("Your Tuesday trip".to_string());
+        logged.sender = Some("uber.us@uber.com".to_string());
+        record_message(&pool, logged).await.unwrap();
+
+        assert_eq!(scrub_messages(&pool, 30).await.unwrap(), 0);
+        assert_eq!(scrub_messages(&pool, 0).await.unwrap(), 1);
+        let row: (Option<String>, Option<String>, Option<String>) =
+            sqlx::query_as("SELECT subject, sender, message_id FROM messages")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(row, (None, None, None));
+        assert!(
+            message_seen(&pool, user.id, &message_id_key("abc@uber.com"))
+                .await
+                .unwrap()
+        );
+        let keys: Vec<(String,)> = sqlx::query_as("SELECT dedupe_key FROM messages")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert!(!keys[0].0.contains("abc@uber.com"));
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    #[tokio::test]
+    async fn migrate_hashes_message_ids_kept_in_dedupe_keys() {
+        let pool = test_pool().await;
+        let user = test_user(&pool, None).await;
+        let mut old = message(user.id, "logged");
+        old.dedupe_key = "mid:abc@uber.com".to_string();
+        record_message(&pool, old).await.unwrap();
+        let mut hashed = message(user.id, "logged");
+        hashed.dedupe_key = "sha256:0123".to_string();
+        record_message(&pool, hashed).await.unwrap();
+        sqlx::query("INSERT INTO pending_rewrites (name) VALUES ('hash_message_id_keys')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        migrate(&pool).await.unwrap();
+        let mut keys: Vec<(String,)> = sqlx::query_as("SELECT dedupe_key FROM messages")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                (message_id_key("abc@uber.com"),),
+                ("sha256:0123".to_string(),)
+            ]
+        );
+
+        // Done once, later boots skip the scan.
+        let mut late = message(user.id, "logged");
+        late.dedupe_key = "mid:def@uber.com".to_string();
+        record_message(&pool, late).await.unwrap();
+        migrate(&pool).await.unwrap();
+        let late: Option<(i64,)> =
+            sqlx::query_as("SELECT id FROM messages WHERE dedupe_key = 'mid:def@uber.com'")
+                .fetch_optional(&pool)
+                .await
+                .unwrap();
+        assert!(late.is_some());
     }
 }
@@ -0,0 +1,633 @@
+use crate::config::AppConfig;
+use crate::models::{LogEntry, SheetMapping, TabRotation};
+use crate::oauth::OAuthClient;
+use crate::ratelimit::RateLimiter;
+use crate::sheets::cell_log_id;
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Method, RequestBuilder, StatusCode, Url};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::warn;
+
+/// Delegated permission needed to edit the user's workbook.
+pub const FILES_SCOPE: &str = "Files.ReadWrite";
+
+/// Pause after a throttled response without `Retry-After`.
+const THROTTLE_PAUSE_SECS: u64 = 30;
+
+/// A non-2xx response from Microsoft Graph.
+#[derive(Debug, Error)]
+#[error("Graph API error ({status}): {message}")]
+pub struct GraphApiError {
+    pub status: u16,
+    pub message: String,
+    /// `Retry-After` of a throttled response, in seconds.
+    pub retry_after: Option<u64>,
+}
+
+impl GraphApiError {
+    /// Errors that retrying will not fix until the user changes something:
+    /// the workbook was moved, deleted or unshared, or the mapped range is
+    /// invalid.
+    pub fn permanent(err: &anyhow::Error) -> Option<&Self> {
+        err.chain()
+            .filter_map(|cause| cause.downcast_ref::<Self>())
+            .find(|api| matches!(api.status, 400 | 403 | 404))
+    }
+
+    /// How long to hold off after Graph throttled us (429, or 503 with
+    /// `Retry-After`).
+    pub fn rate_limited(err: &anyhow::Error) -> Option<Duration> {
+        err.chain()
+            .filter_map(|cause| cause.downcast_ref::<Self>())
+            .find(|api| api.status == 429 || (api.status == 503 && api.retry_after.is_some()))
+            .map(|api| Duration::from_secs(api.retry_after.unwrap_or(THROTTLE_PAUSE_SECS)))
+    }
+
+    /// Short explanation suitable for showing to the user.
+    pub fn user_message(&self) -> String {
+        match self.status {
+            403 => "DriverSheet no longer has access to your workbook; reconnect Excel".to_string(),
+            404 => {
+                "Your Excel workbook was not found; it may have been moved or deleted".to_string()
+            }
+            _ => format!("Excel Online rejected the update: {}", self.message),
+        }
+    }
+}
+
+/// The user an `ExcelClient` acts as.
+struct GraphUser {
+    oauth: Arc<OAuthClient>,
+    user_id: i64,
+    refresh_token: String,
+}
+
+/// Writes log rows to Excel Online workbooks through the Microsoft Graph
+/// workbook API, laid out by the same `SheetMapping` as Google Sheets.
+#[derive(Clone)]
+pub struct ExcelClient {
+    http: Client,
+    graph_api: String,
+    user: Option<Arc<GraphUser>>,
+    limiter: Arc<RateLimiter>,
+}
+
+/// The used range of a worksheet: its top-left cell (zero-based) and values.
+struct UsedRange {
+    row_index: usize,
+    column_index: usize,
+    values: Vec<Vec<Value>>,
+}
+
+impl UsedRange {
+    fn is_empty(&self) -> bool {
+        self.values
+            .iter()
+            .flatten()
+            .all(|cell| cell.is_null() || cell.as_str() == Some(""))
+    }
+
+    /// First row (1-based) below everything in the worksheet.
+    fn next_row(&self) -> usize {
+        if self.is_empty() {
+            2
+        } else {
+            (self.row_index + self.values.len() + 1).max(2)
+        }
+    }
+}
+
+impl ExcelClient {
+    pub fn new(config: &AppConfig) -> Result<Self> {
+        Self::with_url(&config.graph_api_url, config.graph_requests_per_min)
+    }
+
+    /// A client for Graph at `graph_url`, e.g. `https://graph.microsoft.com/v1.0`.
+    pub fn with_url(graph_url: &str, requests_per_minute: u32) -> Result<Self> {
+        let http = Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .context("Failed to construct reqwest client")?;
+        Ok(Self {
+            http,
+            graph_api: graph_url.trim_end_matches('/').to_string(),
+            user: None,
+            limiter: Arc::new(RateLimiter::per_minute(requests_per_minute)),
+        })
+    }
+
+    /// The same client acting as the user through their Microsoft grant.
+    /// Requests of all users share one rate limiter.
+    pub fn as_user(&self, oauth: Arc<OAuthClient>, user_id: i64, refresh_token: String) -> Self {
+        Self {
+            user: Some(Arc::new(GraphUser {
+                oauth,
+                user_id,
+                refresh_token,
+            })),
+            ..self.clone()
+        }
+    }
+
+    /// Name of the workbook, proving the user can reach it.
+    pub async fn workbook_name(&self, item_id: &str) -> Result<String> {
+        let request = self.request(Method::GET, item_id, &[])?;
+        let item = self
+            .send(request)
+            .await
+            .context("Failed to open Excel workbook")?;
+        Ok(item["name"].as_str().unwrap_or_default().to_string())
+    }
+
+    /// Creates the mapping's worksheet if missing, writes the header row when
+    /// it is empty and freezes it. A rotating mapping sets up the current
+    /// period's worksheet. Safe to re-run.
+    pub async fn bootstrap(&self, item_id: &str, mapping: &SheetMapping) -> Result<()> {
+        let current;
+        let mapping = if mapping.rotation == TabRotation::None {
+            mapping
+        } else {
+            current = mapping.with_tab(&mapping.tab_for(Utc::now().date_naive()));
+            &current
+        };
+        if !self.worksheets(item_id).await?.contains(&mapping.tab) {
+            let request = self
+                .request(Method::POST, item_id, &["workbook", "worksheets", "add"])?
+                .json(&json!({ "name": mapping.tab }));
+            self.send(request)
+                .await
+                .with_context(|| format!("Failed to create worksheet {:?}", mapping.tab))?;
+        }
+
+        let header = self
+            .read_range(item_id, &mapping.tab, &mapping.address(1, 1))
+            .await
+            .context("Failed to read header row")?;
+        let blank = header
+            .iter()
+            .flatten()
+            .all(|cell| cell.is_null() || cell.as_str() == Some(""));
+        if blank {
+            let headers: Vec<Value> = mapping.headers().into_iter().map(Value::from).collect();
+            self.write_range(item_id, &mapping.tab, &mapping.address(1, 1), &[headers])
+                .await
+                .context("Failed to write header row")?;
+        }
+        let request = self
+            .worksheet_request(
+                Method::POST,
+                item_id,
+                &mapping.tab,
+                &["freezePanes", "freezeRows"],
+            )?
+            .json(&json!({ "count": 1 }));
+        self.send(request)
+            .await
+            .context("Failed to freeze header row")?;
+        Ok(())
+    }
+
+    /// Writes each log to the row carrying its ID, or below the last used row
+    /// when there is none, like `SheetsClient::upsert_logs`. With tab
+    /// rotation each log goes to its period's worksheet. Consecutive rows are
+    /// written with one request.
+    pub async fn upsert_logs(
+        &self,
+        item_id: &str,
+        mapping: &SheetMapping,
+        logs: &[LogEntry],
+    ) -> Result<()> {
+        let (index, mut next_rows) = self.index(item_id, mapping).await?;
+        let mut updates: HashMap<String, BTreeMap<usize, Vec<Value>>> = HashMap::new();
+        let mut appends: HashMap<String, Vec<Vec<Value>>> = HashMap::new();
+        let mut stale = Vec::new();
+        for log in logs {
+            let tab = mapping.tab_for(log.order_date);
+            let target = mapping.with_tab(&tab);
+            let mut found = None;
+            for (row_tab, row) in index.get(&log.id).into_iter().flatten() {
+                if *row_tab == tab && found.is_none() {
+                    found = Some(*row);
+                } else {
+                    stale.push((row_tab.clone(), *row));
+                }
+            }
+            match found {
+                Some(row) => {
+                    updates
+                        .entry(tab)
+                        .or_default()
+                        .insert(row, cells(&target, log));
+                }
+                None => appends.entry(tab).or_default().push(cells(&target, log)),
+            }
+        }
+
+        for (tab, rows) in updates {
+            let target = mapping.with_tab(&tab);
+            for (first, rows) in consecutive(rows) {
+                let last = first + rows.len() - 1;
+                self.write_range(item_id, &tab, &target.address(first, last), &rows)
+                    .await
+                    .context("Failed to update rows in Excel")?;
+            }
+        }
+
+        for (tab, rows) in appends {
+            let target = mapping.with_tab(&tab);
+            let first = match next_rows.get(&tab) {
+                Some(row) => *row,
+                None => {
+                    self.bootstrap(item_id, &target).await?;
+                    2
+                }
+            };
+            let last = first + rows.len() - 1;
+            self.write_range(item_id, &tab, &target.address(first, last), &rows)
+                .await
+                .context("Failed to append rows in Excel")?;
+            next_rows.insert(tab, last + 1);
+        }
+        self.delete_rows(item_id, mapping, stale).await
+    }
+
+    /// Removes every row carrying one of the log IDs.
+    pub async fn delete_logs(
+        &self,
+        item_id: &str,
+        mapping: &SheetMapping,
+        log_ids: &[i64],
+    ) -> Result<()> {
+        let (index, _) = self.index(item_id, mapping).await?;
+        let doomed = log_ids
+            .iter()
+            .filter_map(|id| index.get(id))
+            .flatten()
+            .cloned()
+            .collect();
+        self.delete_rows(item_id, mapping, doomed).await
+    }
+
+    /// Clears the data rows of every worksheet the mapping writes to.
+    pub async fn clear_logs(&self, item_id: &str, mapping: &SheetMapping) -> Result<()> {
+        for tab in self.worksheets(item_id).await? {
+            if !mapping.writes_to(&tab) {
+                continue;
+            }
+            let used = self.used_range(item_id, &tab).await?;
+            let last = used.next_row() - 1;
+            if last < 2 {
+                continue;
+            }
+            let address = mapping.with_tab(&tab).address(2, last);
+            let request = self
+                .worksheet_request(Method::POST, item_id, &tab, &[&range(&address), "clear"])?
+                .json(&json!({ "applyTo": "Contents" }));
+            self.send(request)
+                .await
+                .with_context(|| format!("Failed to clear worksheet {tab:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Log ID to every (worksheet, 1-based row) holding it, and the next free
+    /// row of each worksheet the mapping writes to.
+    #[allow(clippy::type_complexity)]
+    async fn index(
+        &self,
+        item_id: &str,
+        mapping: &SheetMapping,
+    ) -> Result<(HashMap<i64, Vec<(String, usize)>>, HashMap<String, usize>)> {
+        let id_column = mapping.start_index() + mapping.width() - 1;
+        let mut rows: HashMap<i64, Vec<(String, usize)>> = HashMap::new();
+        let mut next_rows = HashMap::new();
+        for tab in self.worksheets(item_id).await? {
+            if !mapping.writes_to(&tab) {
+                continue;
+            }
+            let used = self.used_range(item_id, &tab).await?;
+            if let Some(offset) = id_column.checked_sub(used.column_index) {
+                for (i, row) in used.values.iter().enumerate() {
+                    if let Some(id) = row.get(offset).and_then(cell_log_id) {
+                        rows.entry(id)
+                            .or_default()
+                            .push((tab.clone(), used.row_index + i + 1));
+                    }
+                }
+            }
+            next_rows.insert(tab, used.next_row());
+        }
+        Ok((rows, next_rows))
+    }
+
+    /// Deletes each run of consecutive rows with one request, bottom-up
+    /// within each worksheet so earlier deletions do not shift the rows still
+    /// to go.
+    async fn delete_rows(
+        &self,
+        item_id: &str,
+        mapping: &SheetMapping,
+        rows: Vec<(String, usize)>,
+    ) -> Result<()> {
+        let mut by_tab: HashMap<String, BTreeSet<usize>> = HashMap::new();
+        for (tab, row) in rows {
+            by_tab.entry(tab).or_default().insert(row);
+        }
+        for (tab, rows) in by_tab {
+            let target = mapping.with_tab(&tab);
+            let runs = consecutive(rows.into_iter().map(|row| (row, ())));
+            for (first, run) in runs.into_iter().rev() {
+                let address = target.address(first, first + run.len() - 1);
+                let request = self
+                    .worksheet_request(Method::POST, item_id, &tab, &[&range(&address), "delete"])?
+                    .json(&json!({ "shift": "Up" }));
+                self.send(request)
+                    .await
+                    .context("Failed to delete rows in Excel")?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn worksheets(&self, item_id: &str) -> Result<Vec<String>> {
+        let request = self.request(Method::GET, item_id, &["workbook", "worksheets"])?;
+        let reply = self
+            .send(request)
+            .await
+            .context("Failed to list worksheets")?;
+        Ok(reply["value"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|sheet| sheet["name"].as_str().map(str::to_string))
+            .collect())
+    }
+
+    async fn used_range(&self, item_id: &str, tab: &str) -> Result<UsedRange> {
+        let request =
+            self.worksheet_request(Method::GET, item_id, tab, &["usedRange(valuesOnly=true)"])?;
+        let reply = self
+            .send(request)
+            .await
+            .with_context(|| format!("Failed to read worksheet {tab:?}"))?;
+        Ok(UsedRange {
+            row_index: reply["rowIndex"].as_u64().unwrap_or(0) as usize,
+            column_index: reply["columnIndex"].as_u64().unwrap_or(0) as usize,
+            values: serde_json::from_value(reply["values"].clone()).unwrap_or_default(),
+        })
+    }
+
+    async fn read_range(&self, item_id: &str, tab: &str, address: &str) -> Result<Vec<Vec<Value>>> {
+        let request = self.worksheet_request(Method::GET, item_id, tab, &[&range(address)])?;
+        let reply = self.send(request).await?;
+        Ok(serde_json::from_value(reply["values"].clone()).unwrap_or_default())
+    }
+
+    async fn write_range(
+        &self,
+        item_id: &str,
+        tab: &str,
+        address: &str,
+        rows: &[Vec<Value>],
+    ) -> Result<()> {
+        let request = self
+            .worksheet_request(Method::PATCH, item_id, tab, &[&range(address)])?
+            .json(&json!({ "values": rows }));
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// `{graph}/me/drive/items/{item_id}/{segments...}`, each segment escaped.
+    fn request(&self, method: Method, item_id: &str, segments: &[&str]) -> Result<RequestBuilder> {
+        let mut url = Url::parse(&self.graph_api).context("Invalid GRAPH_API_URL")?;
+        url.path_segments_mut()
+            .map_err(|()| anyhow!("Invalid GRAPH_API_URL"))?
+            .pop_if_empty()
+            .extend(["me", "drive", "items", item_id])
+            .extend(segments);
+        Ok(self.http.request(method, url))
+    }
+
+    fn worksheet_request(
+        &self,
+        method: Method,
+        item_id: &str,
+        tab: &str,
+        segments: &[&str],
+    ) -> Result<RequestBuilder> {
+        let mut path = vec!["workbook", "worksheets", tab];
+        path.extend_from_slice(segments);
+        self.request(method, item_id, &path)
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Value> {
+        let user = self
+            .user
+            .as_ref()
+            .ok_or_else(|| anyhow!("Excel requests need a connected Microsoft account"))?;
+        self.limiter.acquire().await;
+        let token = user
+            .oauth
+            .access_token(user.user_id, &user.refresh_token)
+            .await?;
+        let response = request
+            .bearer_auth(token)
+            .send()
+            .await
+            .context("Failed to send request to Graph API")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            if status == StatusCode::TOO_MANY_REQUESTS || retry_after.is_some() {
+                let pause = retry_after.unwrap_or(THROTTLE_PAUSE_SECS);
+                warn!("Graph API throttled; pausing requests for {pause}s");
+                self.limiter.pause(Duration::from_secs(pause));
+            }
+            let text = response.text().await.unwrap_or_default();
+            let message = serde_json::from_str::<Value>(&text)
+                .ok()
+                .and_then(|body| body["error"]["message"].as_str().map(str::to_string))
+                .unwrap_or(text);
+            return Err(GraphApiError {
+                status: status.as_u16(),
+                message,
+                retry_after,
+            }
+            .into());
+        }
+        Ok(response.json().await.unwrap_or(Value::Null))
+    }
+}
+
+/// `range(address='C2:H2')`, the path segment addressing cells in a worksheet.
+fn range(address: &str) -> String {
+    format!("range(address='{address}')")
+}
+
+/// Splits rows given in ascending order into runs of consecutive row
+/// numbers, each with its first row.
+fn consecutive<T>(rows: impl IntoIterator<Item = (usize, T)>) -> Vec<(usize, Vec<T>)> {
+    let mut runs: Vec<(usize, Vec<T>)> = Vec::new();
+    for (row, item) in rows {
+        match runs.last_mut() {
+            Some((first, items)) if *first + items.len() == row => items.push(item),
+            _ => runs.push((row, vec![item])),
+        }
+    }
+    runs
+}
+
+/// The log's row for Graph, which leaves cells sent as `null` unchanged, so
+/// empty values are written as `""`.
+fn cells(mapping: &SheetMapping, log: &LogEntry) -> Vec<Value> {
+    mapping
+        .row(log)
+        .into_iter()
+        .map(|cell| if cell.is_null() { json!("") } else { cell })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::models::{LogUpdate, NewLogEntry};
+    use crate::money::Cents;
+    use crate::outbox;
+    use crate::sheets_mock::{test_entry, test_log, test_user, MockSheets};
+    use crate::sink::{self, ExportSink};
+
+    #[tokio::test]
+    async fn outbox_writes_updates_and_deletes_workbook_rows() {
+        let (mock, url) = MockSheets::start().await;
+        mock.add_spreadsheet("book-1", &["Sheet1"]);
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, None).await;
+        let oauth = state.microsoft_oauth.as_ref().unwrap();
+        let grant = oauth.exchange("1", "https://app.invalid").await.unwrap();
+        let sealed = state
+            .secrets
+            .as_ref()
+            .unwrap()
+            .seal(&grant.refresh_token.unwrap())
+            .unwrap();
+        db::save_excel_workbook(&state.pool, user.id, "book-1", &grant.scope, &sealed)
+            .await
+            .unwrap();
+        let user = db::user_by_id(&state.pool, user.id).await.unwrap().unwrap();
+        let mapping = db::sheet_mapping(&state.pool, user.id).await.unwrap();
+        sink::for_user(&state, &user)
+            .await
+            .unwrap()
+            .bootstrap(&mapping)
+            .await
+            .unwrap();
+
+        let entry = |gross: i64, platform: &str| NewLogEntry {
+            platform: Some(platform.to_string()),
+            ..test_entry(&user, "2024-08-15", Cents(gross))
+        };
+        let first = db::insert_log(&state.pool, entry(1000, "uber"))
+            .await
+            .unwrap();
+        let second = db::insert_log(&state.pool, entry(2000, "lyft"))
+            .await
+            .unwrap();
+        outbox::sync_due(&state).await.unwrap();
+
+        let rows = mock.rows("book-1", "Sheet1");
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0][0], "Date");
+        assert_eq!(rows[0][4], "Log ID");
+        assert_eq!(cell_log_id(&rows[1][4]), Some(first.id));
+        assert_eq!(cell_log_id(&rows[2][4]), Some(second.id));
+        assert_eq!(rows[2][1], 20.0);
+        assert!(mock.appends().is_empty());
+
+        let update = LogUpdate {
+            order_date: None,
+            gross: Some(Cents(1250)),
+            tips: None,
+            mileage: None,
+            platform: None,
+        };
+        db::update_log(&state.pool, user.id, first.id, &update)
+            .await
+            .unwrap();
+        db::delete_log(&state.pool, user.id, second.id)
+            .await
+            .unwrap();
+        outbox::sync_due(&state).await.unwrap();
+
+        let rows = mock.rows("book-1", "Sheet1");
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1][1], 12.5);
+        assert_eq!(cell_log_id(&rows[1][4]), Some(first.id));
+        assert!(mock
+            .bearers()
+            .iter()
+            .all(|bearer| bearer.starts_with("user-token-")));
+    }
+
+    #[tokio::test]
+    async fn consecutive_rows_are_written_and_deleted_together() {
+        let (mock, url) = MockSheets::start().await;
+        mock.add_spreadsheet("book-1", &["Sheet1"]);
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, None).await;
+        let oauth = state.microsoft_oauth.clone().unwrap();
+        let grant = oauth.exchange("1", "https://app.invalid").await.unwrap();
+        let client = state
+            .excel
+            .as_user(oauth, user.id, grant.refresh_token.unwrap());
+        let mapping = SheetMapping::default();
+        client.bootstrap("book-1", &mapping).await.unwrap();
+        let mut logs = Vec::new();
+        for day in 1..=5 {
+            let date = format!("2024-08-{day:02}");
+            logs.push(test_log(&state.pool, &user, &date, Cents(1000)).await);
+        }
+        client.upsert_logs("book-1", &mapping, &logs).await.unwrap();
+
+        // Listing the worksheets and reading the used range, then one write.
+        let before = mock.bearers().len();
+        client.upsert_logs("book-1", &mapping, &logs).await.unwrap();
+        assert_eq!(mock.bearers().len() - before, 3);
+
+        // Rows 2-3 and row 5 go in two requests.
+        let before = mock.bearers().len();
+        let ids = [logs[0].id, logs[1].id, logs[3].id];
+        client.delete_logs("book-1", &mapping, &ids).await.unwrap();
+        assert_eq!(mock.bearers().len() - before, 2 + 2);
+        let left: Vec<_> = mock.rows("book-1", "Sheet1")[1..]
+            .iter()
+            .map(|row| cell_log_id(&row[4]))
+            .collect();
+        assert_eq!(left, [Some(logs[2].id), Some(logs[4].id)]);
+    }
+
+    #[tokio::test]
+    async fn missing_workbook_fails_permanently() {
+        let (_mock, url) = MockSheets::start().await;
+        let state = MockSheets::app_state(&url).await;
+        let oauth = state.microsoft_oauth.clone().unwrap();
+        let grant = oauth.exchange("1", "https://app.invalid").await.unwrap();
+        let client = state.excel.as_user(oauth, 1, grant.refresh_token.unwrap());
+
+        let err = client.workbook_name("gone").await.unwrap_err();
+        let api = GraphApiError::permanent(&err).unwrap();
+        assert_eq!(api.status, 404);
+        assert!(api.user_message().contains("not found"));
+    }
+}
@@ -0,0 +1,242 @@
+use crate::config::AppConfig;
+use crate::db;
+use crate::models::{ExportJob, User};
+use crate::state::AppState;
+use anyhow::{Context, Result};
+use rand::RngCore;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Describes every file in the archive; written as `schema.json`.
+const SCHEMA: &str = include_str!("export_schema.json");
+
+/// Where a job's archive is written.
+pub fn archive_path(config: &AppConfig, job_id: &str) -> PathBuf {
+    Path::new(&config.export_dir).join(format!("{job_id}.zip"))
+}
+
+/// An unguessable download token; the link is the only thing guarding the
+/// archive.
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Builds the archive for `job` and records the outcome on it.
+pub fn spawn_export(state: AppState, user: User, job: ExportJob) {
+    tokio::spawn(async move {
+        let path = archive_path(&state.config, &job.id);
+        let link_secs = state.config.export_link_hours as i64 * 3600;
+        let update = match write_archive(&state.pool, &user, &path).await {
+            Ok(()) => {
+                info!(user_id = user.id, "data export finished");
+                db::finish_export_job(&state.pool, &job.id, None, link_secs).await
+            }
+            Err(err) => {
+                warn!(user_id = user.id, "data export failed: {err:#}");
+                let message = format!("{err:#}");
+                db::finish_export_job(&state.pool, &job.id, Some(&message), link_secs).await
+            }
+        };
+        if let Err(err) = update {
+            warn!("Failed to finish export job {}: {err:#}", job.id);
+        }
+    });
+}
+
+/// Writes everything stored about `user` to a zip archive at `path`: one
+/// JSON file per area plus `schema.json` describing them.
+pub async fn write_archive(pool: &db::Pool, user: &User, path: &Path) -> Result<()> {
+    let files = collect(pool, user).await?;
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    // Renamed into place once complete, so a half-written archive is never
+    // served.
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let staged = partial.clone();
+    tokio::task::spawn_blocking(move || write_zip(&staged, &files)).await??;
+    tokio::fs::rename(&partial, path).await?;
+    Ok(())
+}
+
+/// Deletes the archives of exports whose link has expired.
+pub async fn expire_archives(state: &AppState) -> Result<()> {
+    for job in db::expired_exports(&state.pool).await? {
+        remove_archive(&state.config, &job.id).await;
+        db::mark_export_expired(&state.pool, &job.id).await?;
+    }
+    Ok(())
+}
+
+/// Deletes every archive of the user, before their account is purged.
+pub async fn remove_archives(state: &AppState, user_id: i64) -> Result<()> {
+    for job in db::export_jobs(&state.pool, user_id).await? {
+        remove_archive(&state.config, &job.id).await;
+    }
+    Ok(())
+}
+
+async fn remove_archive(config: &AppConfig, job_id: &str) {
+    let path = archive_path(config, job_id);
+    if let Err(err) = tokio::fs::remove_file(&path).await {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            warn!("Could not delete {}: {err}", path.display());
+        }
+    }
+}
+
+async fn collect(pool: &db::Pool, user: &User) -> Result<Vec<(&'static str, Value)>> {
+    let webhooks: Vec<Value> = db::webhooks(pool, user.id)
+        .await?
+        .into_iter()
+        .map(|webhook| json!({ "id": webhook.id, "url": webhook.url, "created": webhook.created }))
+        .collect();
+
+    Ok(vec![
+        ("schema.json", serde_json::from_str(SCHEMA)?),
+        (
+            "profile.json",
+            json!({
+                "id": user.id,
+                "googleId": user.google_id,
+                "email": user.email,
+                "forwardAddress": user.forwarding_address(),
+                "paid": user.paid,
+                "created": user.created,
+                "deleteAfter": user.delete_after,
+            }),
+        ),
+        (
+            "settings.json",
+            json!({
+                "sink": user.sink,
+                "sheet": {
+                    "sheetId": user.sheet_id,
+                    "sheetStatus": user.sheet_status,
+                    "sheetVerifiedAt": user.sheet_verified_at,
+                    "sheetError": user.sheet_error,
+                },
+                "sheetMapping": db::sheet_mapping(pool, user.id).await?,
+                "google": db::google_credential(pool, user.id).await?,
+                "excel": db::excel_workbook(pool, user.id).await?,
+                "imap": db::imap_account(pool, user.id).await?,
+                "webhooks": webhooks,
+            }),
+        ),
+        (
+            "logs.json",
+            serde_json::to_value(db::logs_between(pool, user.id, None, None).await?)?,
+        ),
+        (
+            "messages.json",
+            serde_json::to_value(db::messages(pool, user.id).await?)?,
+        ),
+        (
+            "billing.json",
+            json!({
+                "paid": user.paid,
+                "events": db::billing_events(pool, user.id).await?,
+            }),
+        ),
+        (
+            "audit.json",
+            json!({
+                "corrections": db::log_corrections(pool, user.id, i64::MAX).await?,
+                "webhookDeliveries": db::user_webhook_deliveries(pool, user.id).await?,
+                "imports": db::import_jobs(pool, user.id).await?,
+                "resyncs": db::resync_jobs(pool, user.id).await?,
+                "exports": db::export_jobs(pool, user.id).await?,
+            }),
+        ),
+    ])
+}
+
+fn write_zip(path: &Path, files: &[(&'static str, Value)]) -> Result<()> {
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut zip = ZipWriter::new(file);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, value) in files {
+        zip.start_file(*name, options)?;
+        zip.write_all(&serde_json::to_vec_pretty(value)?)?;
+    }
+    zip.finish()?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Cents;
+    use crate::sheets_mock::{test_log, test_user};
+    use std::io::Read;
+
+    #[tokio::test]
+    async fn archive_holds_every_area_without_secrets() {
+        let pool = db::test_pool().await;
+        let user = test_user(&pool, Some("sheet-1")).await;
+        test_log(&pool, &user, "2024-08-15", Cents(1999)).await;
+        db::create_webhook(&pool, user.id, "https://hooks.invalid/", "whsec_hidden")
+            .await
+            .unwrap();
+        db::record_billing_event(&pool, "driver@example.com", "invoice.paid")
+            .await
+            .unwrap();
+        db::record_billing_event(&pool, "someone@else.com", "invoice.paid")
+            .await
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.zip");
+        write_archive(&pool, &user, &path).await.unwrap();
+
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let mut names: Vec<_> = zip.file_names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "audit.json",
+                "billing.json",
+                "logs.json",
+                "messages.json",
+                "profile.json",
+                "schema.json",
+                "settings.json"
+            ]
+        );
+        let mut read = |name: &str| {
+            let mut text = String::new();
+            zip.by_name(name)
+                .unwrap()
+                .read_to_string(&mut text)
+                .unwrap();
+            text
+        };
+        let schema: Value = serde_json::from_str(&read("schema.json")).unwrap();
+        for name in &names {
+            assert!(
+                name == "schema.json" || schema["files"].get(name).is_some(),
+                "{name} is not described"
+            );
+        }
+        let logs: Value = serde_json::from_str(&read("logs.json")).unwrap();
+        assert_eq!(logs[0]["gross"], 19.99);
+        let billing: Value = serde_json::from_str(&read("billing.json")).unwrap();
+        assert_eq!(billing["events"].as_array().unwrap().len(), 1);
+        let settings = read("settings.json");
+        assert!(settings.contains("https://hooks.invalid/"));
+        assert!(!settings.contains("whsec_hidden"));
+        assert!(!dir.path().join("export.zip.partial").exists());
+    }
+}
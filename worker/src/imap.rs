@@ -0,0 +1,433 @@
+use crate::db;
+use crate::mail::{self, Delivery};
+use crate::models::{ImapAccount, ImapAccountUpsert, MessageSource};
+use crate::state::AppState;
+use crate::webhooks;
+use anyhow::{anyhow, Context, Result};
+use async_imap::Client;
+use futures::TryStreamExt;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{self, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+use tokio_util::either::Either;
+use tracing::{error, info, warn};
+
+const POLL_TIMEOUT: Duration = Duration::from_secs(120);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+/// Upper bound on messages pulled per account per poll; the rest wait for
+/// the next tick.
+const BATCH_LIMIT: usize = 50;
+
+type Transport = Either<TcpStream, tokio_rustls::client::TlsStream<TcpStream>>;
+
+/// Where the poller left off in a mailbox. A different UIDVALIDITY means the
+/// server renumbered the folder and the cursor starts over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub uid_validity: u32,
+    pub last_uid: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Batch {
+    pub uid_validity: u32,
+    pub messages: Vec<(u32, Vec<u8>)>,
+}
+
+pub fn spawn_imap_poller(state: AppState) {
+    let every = Duration::from_secs(state.config.imap_poll_secs);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(err) = poll_all(&state).await {
+                error!(?err, "IMAP poller error");
+            }
+        }
+    });
+}
+
+async fn poll_all(state: &AppState) -> Result<()> {
+    let Some(secrets) = state.secrets.as_ref() else {
+        return Ok(());
+    };
+    for account in db::imap_accounts(&state.pool).await? {
+        let result = match secrets.open(&account.password_enc) {
+            Ok(password) => {
+                tokio::time::timeout(POLL_TIMEOUT, poll_account(state, &account, &password))
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("IMAP poll timed out")))
+            }
+            Err(err) => Err(err),
+        };
+        let error = result.as_ref().err().map(|err| format!("{err:#}"));
+        if let Some(message) = &error {
+            warn!(user_id = account.user_id, "IMAP poll failed: {message}");
+        }
+        db::finish_imap_poll(&state.pool, account.id, error.as_deref()).await?;
+    }
+    Ok(())
+}
+
+async fn poll_account(state: &AppState, account: &ImapAccount, password: &str) -> Result<()> {
+    let Some(user) = db::user_by_id(&state.pool, account.user_id).await? else {
+        return Ok(());
+    };
+    let cursor = account.uid_validity.map(|validity| Cursor {
+        uid_validity: validity as u32,
+        last_uid: account.last_uid as u32,
+    });
+
+    let client = connect(
+        &account.host,
+        account.port as u16,
+        account.tls,
+        state.config.imap_allow_private,
+    )
+    .await?;
+    let batch = fetch_new(
+        client,
+        &account.username,
+        password,
+        &account.folder,
+        account.sender_filter.as_deref(),
+        cursor,
+    )
+    .await?;
+
+    if cursor.map(|c| c.uid_validity) != Some(batch.uid_validity) {
+        db::advance_imap_cursor(&state.pool, account.id, batch.uid_validity.into(), 0).await?;
+    }
+
+    for (uid, data) in batch.messages {
+        match mail::process_message(state, MessageSource::Imap, &user.forward_key, &data).await {
+            Ok(Delivery::Logged) => info!(user_id = user.id, uid, "IMAP message logged"),
+            Ok(_) => {}
+            // Leave the cursor in place so the message is fetched again.
+            Err(err) if mail::is_transient(&err) => return Err(err),
+            Err(err) => warn!(user_id = user.id, uid, "IMAP message failed: {err:#}"),
+        }
+        db::advance_imap_cursor(
+            &state.pool,
+            account.id,
+            batch.uid_validity.into(),
+            uid.into(),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Logs in and selects the folder once so bad settings are reported when the
+/// user saves them rather than on the first poll.
+pub async fn verify(settings: &ImapAccountUpsert, allow_private: bool) -> Result<()> {
+    let client = connect(&settings.host, settings.port, settings.tls, allow_private).await?;
+    let mut session = client
+        .login(&settings.username, &settings.password)
+        .await
+        .map_err(|(err, _)| anyhow!("IMAP login failed: {err}"))?;
+    session
+        .select(&settings.folder)
+        .await
+        .with_context(|| format!("Folder {:?} not found", settings.folder))?;
+    session.logout().await.ok();
+    Ok(())
+}
+
+/// Connects to the server. Unless `allow_private`, the host must resolve
+/// only to public addresses, so a mailbox setting cannot reach internal
+/// services.
+pub async fn connect(
+    host: &str,
+    port: u16,
+    tls: bool,
+    allow_private: bool,
+) -> Result<Client<Transport>> {
+    let connecting = async {
+        if allow_private {
+            return Ok(TcpStream::connect((host, port)).await?);
+        }
+        let addrs = webhooks::resolve_public(host, port).await?;
+        Ok::<_, anyhow::Error>(TcpStream::connect(&addrs[..]).await?)
+    };
+    let tcp = tokio::time::timeout(CONNECT_TIMEOUT, connecting)
+        .await
+        .map_err(|_| anyhow!("Timed out connecting to {host}:{port}"))?
+        .with_context(|| format!("Failed to connect to {host}:{port}"))?;
+
+    let stream = if tls {
+        let server_name =
+            ServerName::try_from(host).map_err(|_| anyhow!("Invalid IMAP host {host:?}"))?;
+        let tls = tls_connector()
+            .connect(server_name, tcp)
+            .await
+            .with_context(|| format!("TLS handshake with {host} failed"))?;
+        Either::Right(tls)
+    } else {
+        Either::Left(tcp)
+    };
+
+    let mut client = Client::new(stream);
+    client
+        .read_response()
+        .await
+        .context("Failed to read IMAP greeting")?
+        .ok_or_else(|| anyhow!("IMAP server closed the connection"))?;
+    Ok(client)
+}
+
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Logs in, selects `folder` and downloads up to [`BATCH_LIMIT`] messages
+/// newer than `cursor`, oldest first. Messages are fetched with `BODY.PEEK[]`
+/// so they stay unread in the driver's mailbox.
+pub async fn fetch_new<T>(
+    client: Client<T>,
+    username: &str,
+    password: &str,
+    folder: &str,
+    sender_filter: Option<&str>,
+    cursor: Option<Cursor>,
+) -> Result<Batch>
+where
+    T: AsyncRead + AsyncWrite + Unpin + fmt::Debug + Send,
+{
+    let mut session = client
+        .login(username, password)
+        .await
+        .map_err(|(err, _)| anyhow!("IMAP login failed: {err}"))?;
+    let mailbox = session
+        .select(folder)
+        .await
+        .with_context(|| format!("Failed to select {folder:?}"))?;
+    let uid_validity = mailbox
+        .uid_validity
+        .ok_or_else(|| anyhow!("IMAP server did not report UIDVALIDITY"))?;
+
+    let last_uid = match cursor {
+        Some(cursor) if cursor.uid_validity == uid_validity => cursor.last_uid,
+        _ => 0,
+    };
+    let mut query = format!("UID {}:*", last_uid + 1);
+    if let Some(sender) = sender_filter.filter(|s| !s.trim().is_empty()) {
+        query.push_str(&format!(" FROM {}", quote(sender.trim())));
+    }
+
+    // `n:*` always matches the highest UID, even when it is below `n`.
+    let mut uids: Vec<u32> = session
+        .uid_search(&query)
+        .await
+        .context("IMAP search failed")?
+        .into_iter()
+        .filter(|uid| *uid > last_uid)
+        .collect();
+    uids.sort_unstable();
+    uids.truncate(BATCH_LIMIT);
+
+    let mut messages = Vec::with_capacity(uids.len());
+    if !uids.is_empty() {
+        let set = uids
+            .iter()
+            .map(u32::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        let fetches: Vec<_> = session
+            .uid_fetch(&set, "BODY.PEEK[]")
+            .await
+            .context("IMAP fetch failed")?
+            .try_collect()
+            .await
+            .context("IMAP fetch failed")?;
+        for fetch in &fetches {
+            if let (Some(uid), Some(body)) = (fetch.uid, fetch.body()) {
+                messages.push((uid, body.to_vec()));
+            }
+        }
+        messages.sort_by_key(|(uid, _)| *uid);
+    }
+
+    session.logout().await.ok();
+    Ok(Batch {
+        uid_validity,
+        messages,
+    })
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    struct StandIn {
+        uid_validity: u32,
+        messages: Vec<(u32, &'static str, &'static str)>,
+    }
+
+    /// Minimal IMAP4rev1 server covering the commands the poller sends.
+    async fn serve_stand_in(listener: TcpListener, server: Arc<StandIn>) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                write.write_all(b"* OK stand-in ready\r\n").await.unwrap();
+                while let Some(line) = lines.next_line().await.unwrap() {
+                    let (tag, command) = line.split_once(' ').unwrap();
+                    let upper = command.to_ascii_uppercase();
+                    let reply = if upper.starts_with("LOGIN") {
+                        if command == "LOGIN \"driver\" \"app-pass\"" {
+                            format!("{tag} OK LOGIN completed\r\n")
+                        } else {
+                            format!("{tag} NO [AUTHENTICATIONFAILED] Invalid credentials\r\n")
+                        }
+                    } else if upper.starts_with("SELECT") {
+                        format!(
+                            "* {} EXISTS\r\n* 0 RECENT\r\n* OK [UIDVALIDITY {}] UIDs valid\r\n\
+                             * OK [UIDNEXT 100] Predicted next UID\r\n\
+                             {tag} OK [READ-WRITE] SELECT completed\r\n",
+                            server.messages.len(),
+                            server.uid_validity
+                        )
+                    } else if upper.starts_with("UID SEARCH") {
+                        let from = command[15..]
+                            .split(':')
+                            .next()
+                            .unwrap()
+                            .parse::<u32>()
+                            .unwrap();
+                        let sender = command
+                            .split_once(" FROM ")
+                            .map(|(_, s)| s.trim_matches('"').to_string());
+                        let max = server.messages.iter().map(|m| m.0).max().unwrap_or(0);
+                        let hits: Vec<String> = server
+                            .messages
+                            .iter()
+                            .filter(|(uid, _, _)| *uid >= from || *uid == max)
+                            .filter(|(_, msg_from, _)| {
+                                sender.as_deref().is_none_or(|s| msg_from.contains(s))
+                            })
+                            .map(|(uid, _, _)| uid.to_string())
+                            .collect();
+                        format!(
+                            "* SEARCH {}\r\n{tag} OK SEARCH completed\r\n",
+                            hits.join(" ")
+                        )
+                    } else if upper.starts_with("UID FETCH") {
+                        let set = command.split(' ').nth(2).unwrap();
+                        let mut out = String::new();
+                        for (seq, (uid, _, body)) in server.messages.iter().enumerate() {
+                            if set.split(',').any(|u| u == uid.to_string()) {
+                                out.push_str(&format!(
+                                    "* {} FETCH (UID {uid} BODY[] {{{}}}\r\n{body})\r\n",
+                                    seq + 1,
+                                    body.len()
+                                ));
+                            }
+                        }
+                        format!("{out}{tag} OK FETCH completed\r\n")
+                    } else if upper.starts_with("LOGOUT") {
+                        format!("* BYE logging out\r\n{tag} OK LOGOUT completed\r\n")
+                    } else {
+                        format!("{tag} BAD unsupported\r\n")
+                    };
+                    write.write_all(reply.as_bytes()).await.unwrap();
+                    if upper.starts_with("LOGOUT") {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    async fn start(server: StandIn) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_stand_in(listener, Arc::new(server)));
+        port
+    }
+
+    fn stand_in() -> StandIn {
+        StandIn {
+            uid_validity: 7,
+            messages: vec![
+                (3, "Uber <payouts@uber.com>", "Subject: week 1\r\n\r\nA\r\n"),
+                (5, "Mom <mom@example.com>", "Subject: dinner\r\n\r\nB\r\n"),
+                (9, "Uber <payouts@uber.com>", "Subject: week 2\r\n\r\nC\r\n"),
+            ],
+        }
+    }
+
+    async fn fetch(port: u16, password: &str, cursor: Option<Cursor>) -> Result<Batch> {
+        let client = connect("127.0.0.1", port, false, true).await?;
+        fetch_new(
+            client,
+            "driver",
+            password,
+            "INBOX",
+            Some("payouts@uber.com"),
+            cursor,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn fetch_new_pulls_matching_messages_after_cursor() {
+        let port = start(stand_in()).await;
+
+        let first = fetch(port, "app-pass", None).await.unwrap();
+        assert_eq!(first.uid_validity, 7);
+        let uids: Vec<u32> = first.messages.iter().map(|(uid, _)| *uid).collect();
+        assert_eq!(uids, [3, 9]);
+        assert_eq!(first.messages[0].1, b"Subject: week 1\r\n\r\nA\r\n");
+
+        let cursor = Cursor {
+            uid_validity: 7,
+            last_uid: 9,
+        };
+        let caught_up = fetch(port, "app-pass", Some(cursor)).await.unwrap();
+        assert!(caught_up.messages.is_empty());
+
+        let stale = Cursor {
+            uid_validity: 6,
+            last_uid: 9,
+        };
+        let reset = fetch(port, "app-pass", Some(stale)).await.unwrap();
+        assert_eq!(reset.messages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fetch_new_reports_login_failure() {
+        let port = start(stand_in()).await;
+        let err = fetch(port, "wrong", None).await.unwrap_err();
+        assert!(format!("{err:#}").contains("IMAP login failed"));
+    }
+
+    #[test]
+    fn quote_escapes_search_strings() {
+        assert_eq!(quote(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+}
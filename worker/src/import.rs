@@ -0,0 +1,289 @@
+use crate::db;
+use crate::mail::{self, Delivery};
+use crate::models::{ImportSummary, MessageSource, User};
+use crate::state::AppState;
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const PROGRESS_EVERY: i64 = 25;
+
+/// Splits an mbox file (mboxo or mboxrd, as written by Google Takeout) into
+/// individual RFC822 messages.
+pub struct MboxReader<R> {
+    reader: R,
+    started: bool,
+    done: bool,
+}
+
+impl<R: BufRead> MboxReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            started: false,
+            done: false,
+        }
+    }
+}
+
+impl MboxReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> Iterator for MboxReader<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Loops rather than recursing past empty messages, so a run of bare
+        // separators cannot grow the stack.
+        while !self.done {
+            match self.read_message() {
+                Ok(message) => {
+                    if self.started && !message.iter().all(u8::is_ascii_whitespace) {
+                        return Some(Ok(message));
+                    }
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<R: BufRead> MboxReader<R> {
+    /// Reads up to the next separator or the end of the input.
+    fn read_message(&mut self) -> io::Result<Vec<u8>> {
+        let mut message = Vec::new();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                self.done = true;
+                break;
+            }
+
+            if line.starts_with(b"From ") {
+                if self.started {
+                    break;
+                }
+                // Anything before the first separator is not a message.
+                self.started = true;
+                message.clear();
+                continue;
+            }
+
+            // mboxrd quotes body lines that start with "From " as ">From ".
+            let unquoted = match line.iter().position(|b| *b != b'>') {
+                Some(pos) if pos > 0 && line[pos..].starts_with(b"From ") => &line[1..],
+                _ => &line[..],
+            };
+            message.extend_from_slice(unquoted);
+        }
+
+        // The blank line before the next separator belongs to the mbox format.
+        if message.ends_with(b"\r\n\r\n") {
+            message.truncate(message.len() - 2);
+        } else if message.ends_with(b"\n\n") {
+            message.truncate(message.len() - 1);
+        }
+        Ok(message)
+    }
+}
+
+/// Message files in a Maildir (`cur/` and `new/`), or every regular file when
+/// the folder is a plain directory of `.eml` files.
+pub fn maildir_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let subdirs: Vec<PathBuf> = ["cur", "new"]
+        .iter()
+        .map(|name| root.join(name))
+        .filter(|dir| dir.is_dir())
+        .collect();
+    let dirs = if subdirs.is_empty() {
+        vec![root.to_path_buf()]
+    } else {
+        subdirs
+    };
+
+    let mut files = Vec::new();
+    for dir in dirs {
+        for entry in
+            fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))?
+        {
+            let path = entry?.path();
+            let hidden = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with('.'));
+            if path.is_file() && !hidden {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+pub fn maildir_messages(root: &Path) -> Result<impl Iterator<Item = io::Result<Vec<u8>>>> {
+    Ok(maildir_files(root)?.into_iter().map(fs::read))
+}
+
+/// Runs every message through the mail pipeline for `user`. Progress is
+/// logged and, when `job_id` is set, written to `import_jobs`.
+pub async fn import_messages<I>(
+    state: &AppState,
+    user: &User,
+    messages: I,
+    job_id: Option<&str>,
+) -> Result<ImportSummary>
+where
+    I: IntoIterator<Item = io::Result<Vec<u8>>>,
+{
+    let mut summary = ImportSummary::default();
+    for message in messages {
+        summary.total += 1;
+        match message {
+            Ok(data) => {
+                match mail::process_message(state, MessageSource::Import, &user.forward_key, &data)
+                    .await
+                {
+                    Ok(Delivery::Logged) => summary.imported += 1,
+                    Ok(_) => summary.skipped += 1,
+                    Err(err) if mail::is_transient(&err) => return Err(err),
+                    Err(err) => {
+                        warn!("Import message {} failed: {err:#}", summary.total);
+                        summary.failed += 1;
+                    }
+                }
+            }
+            Err(err) => {
+                warn!("Import message {} unreadable: {err}", summary.total);
+                summary.failed += 1;
+            }
+        }
+
+        if summary.total % PROGRESS_EVERY == 0 {
+            info!(
+                user_id = user.id,
+                total = summary.total,
+                imported = summary.imported,
+                skipped = summary.skipped,
+                failed = summary.failed,
+                "import progress"
+            );
+            if let Some(id) = job_id {
+                db::update_import_job(&state.pool, id, "running", &summary, None).await?;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Imports an uploaded mbox file in the background and removes it afterwards.
+pub fn spawn_mbox_import(state: AppState, user: User, job_id: String, path: PathBuf) {
+    tokio::spawn(async move {
+        let result = match MboxReader::open(&path) {
+            Ok(reader) => import_messages(&state, &user, reader, Some(&job_id)).await,
+            Err(err) => Err(err.into()),
+        };
+        fs::remove_file(&path).ok();
+
+        let update = match result {
+            Ok(summary) => {
+                info!(user_id = user.id, ?summary, "mbox import finished");
+                db::update_import_job(&state.pool, &job_id, "completed", &summary, None).await
+            }
+            Err(err) => {
+                warn!(user_id = user.id, "mbox import failed: {err:#}");
+                let job = db::import_job(&state.pool, user.id, &job_id)
+                    .await
+                    .ok()
+                    .flatten();
+                let summary = job
+                    .map(|job| ImportSummary {
+                        total: job.total,
+                        imported: job.imported,
+                        skipped: job.skipped,
+                        failed: job.failed,
+                    })
+                    .unwrap_or_default();
+                let message = format!("{err:#}");
+                db::update_import_job(&state.pool, &job_id, "failed", &summary, Some(&message))
+                    .await
+            }
+        };
+        if let Err(err) = update {
+            warn!("Failed to finish import job {job_id}: {err:#}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(input: &str) -> Vec<String> {
+        MboxReader::new(input.as_bytes())
+            .map(|message| String::from_utf8(message.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn mbox_reader_splits_messages_and_unquotes_from_lines() {
+        let mbox = "From 1234@xxx Mon Jan 01 00:00:00 +0000 2024\n\
+                    Subject: one\n\
+                    \n\
+                    >From the desk of Uber\n\
+                    >>From stays quoted once\n\
+                    \n\
+                    From 5678@xxx Tue Jan 02 00:00:00 +0000 2024\n\
+                    Subject: two\n\
+                    \n\
+                    body\n";
+        let messages = split(mbox);
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0],
+            "Subject: one\n\nFrom the desk of Uber\n>From stays quoted once\n"
+        );
+        assert_eq!(messages[1], "Subject: two\n\nbody\n");
+    }
+
+    #[test]
+    fn mbox_reader_ignores_preamble_and_empty_input() {
+        assert!(split("").is_empty());
+        assert!(split("garbage before any separator\n").is_empty());
+        assert_eq!(split("junk\nFrom x\nSubject: a\n").len(), 1);
+    }
+
+    #[test]
+    fn mbox_reader_skips_long_runs_of_empty_messages() {
+        let mbox = format!("{}Subject: last\n", "From x\n".repeat(100_000));
+        assert_eq!(split(&mbox), ["Subject: last\n"]);
+    }
+
+    #[test]
+    fn maildir_files_reads_cur_and_new() {
+        let dir = tempfile::tempdir().unwrap();
+        for sub in ["cur", "new", "tmp"] {
+            fs::create_dir(dir.path().join(sub)).unwrap();
+        }
+        fs::write(dir.path().join("cur/1.eml"), "a").unwrap();
+        fs::write(dir.path().join("new/2.eml"), "b").unwrap();
+        fs::write(dir.path().join("tmp/3.eml"), "c").unwrap();
+
+        let files = maildir_files(dir.path()).unwrap();
+        let names: Vec<_> = files
+            .iter()
+            .map(|p| p.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["1.eml", "2.eml"]);
+    }
+}
@@ -1,207 +1,253 @@
 use crate::db;
-use crate::models::NewLogEntry;
+use crate::models::{LogEntry, MessageSource, NewLogEntry, NewMessage, User};
+use crate::money::{Cents, DEFAULT_CURRENCY};
+use crate::pdf::PdfSandbox;
 use crate::state::AppState;
 use anyhow::{anyhow, Context, Result};
 use chrono::NaiveDate;
-use mailin_embedded::{response, Handler, Response, Server, SslConfig};
-use mailparse::ParsedMail;
+use mailparse::{MailHeaderMap, ParsedMail};
 use regex::Regex;
-use serde_json::json;
-use std::fs;
-use std::io;
-use std::mem;
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::thread;
-use tokio::runtime::Handle;
-use tracing::{error, info, warn};
-use uuid::Uuid;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use tracing::{info, warn};
 
 const REGEX_GROSS: &str = r"Gross\s*\$?([\d,]+\.\d{2})";
 const REGEX_TIPS: &str = r"Tips\s*\$?([\d,]+\.\d{2})";
 const REGEX_DATE: &str = r"Date\s*(\d{1,2}/\d{1,2}/\d{4})";
 const REGEX_MILEAGE: &str = r"Mileage\s*([\d,]+\.?\d*)?\s*mi";
 
-pub fn run_mail_server(state: AppState, addr: SocketAddr) {
-    let handle = Handle::current();
-    let shared_state = Arc::new(state);
-    thread::spawn(move || {
-        let handler = MailApp::new(shared_state.clone(), handle.clone());
-        let mut server = Server::new(handler);
-        server.with_name("driversheet.com");
-        if let Err(err) = server
-            .with_ssl(SslConfig::None)
-            .and_then(|s| s.with_addr(addr))
-        {
-            error!("mailin server configuration failed: {err:?}");
-            return;
-        }
-
-        if let Err(err) = server.serve() {
-            error!("mailin server failed: {err:?}");
-        }
-    });
+/// Result of running one message through the ingest pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// The statement was parsed and stored for the user.
+    Logged,
+    /// No user owns the forward key the message was addressed to.
+    UnknownRecipient,
+    /// The message was already handled for this user.
+    Duplicate,
+    /// The message carries no PDF statement (confirmation mails, newsletters).
+    NoStatement,
 }
 
-struct MailApp {
-    state: Arc<AppState>,
-    handle: Handle,
-    recipients: Vec<String>,
-    buffer: Vec<u8>,
+/// Recipient lookup and delivery used by the mail ingress front-ends.
+pub trait Mailbox: Send + Sync {
+    fn accepts(&self, forward_key: &str) -> impl Future<Output = Result<bool>> + Send;
+    fn deliver(
+        &self,
+        source: MessageSource,
+        forward_key: &str,
+        data: &[u8],
+    ) -> impl Future<Output = Result<Delivery>> + Send;
 }
 
-impl MailApp {
-    fn new(state: Arc<AppState>, handle: Handle) -> Self {
-        Self {
-            state,
-            handle,
-            recipients: Vec::new(),
-            buffer: Vec::new(),
-        }
+impl Mailbox for AppState {
+    async fn accepts(&self, forward_key: &str) -> Result<bool> {
+        Ok(db::user_by_forward(&self.pool, forward_key)
+            .await?
+            .is_some())
     }
 
-    fn parse_forward_key(address: &str) -> Option<String> {
-        let lower = address.to_ascii_lowercase();
-        if let Some((local, domain)) = lower.split_once('@') {
-            if domain == "driversheet.com" && local.starts_with("user-") {
-                return Some(local.trim_start_matches("user-").to_string());
-            }
-        }
-        None
+    async fn deliver(
+        &self,
+        source: MessageSource,
+        forward_key: &str,
+        data: &[u8],
+    ) -> Result<Delivery> {
+        process_message(self, source, forward_key, data).await
     }
+}
 
-    async fn process_message(&self, recipient: &str, data: Vec<u8>) -> Result<()> {
-        let parsed = mailparse::parse_mail(&data).context("Failed to parse email")?;
-
-        let Some(user) = db::user_by_forward(&self.state.pool, recipient).await? else {
-            warn!("No user mapped to forward key {recipient}");
-            return Ok(());
-        };
-
-        let pdf_bytes = find_first_pdf(&parsed).context("No PDF attachment found")?;
-        let tmp_path = write_temp_file(&self.state.config.tmp_dir, &pdf_bytes)?;
-        let text = match pdf_extract::extract_text(&tmp_path) {
-            Ok(content) => content,
-            Err(err) => {
-                error!("Failed to extract PDF text: {err:?}");
-                return Ok(());
-            }
-        };
-        fs::remove_file(&tmp_path).ok();
-
-        let (order_date, gross, tips, mileage) = parse_text(&text)?;
-
-        if let Some(sheet_id) = user.sheet_id.clone() {
-            if let Err(err) = self
-                .state
-                .sheets
-                .append_row(
-                    &sheet_id,
-                    &[
-                        json!(order_date.to_string()),
-                        json!(gross),
-                        json!(tips),
-                        json!(mileage),
-                    ],
-                )
-                .await
-            {
-                error!("Sheets append failed: {err:?}");
-            }
-        } else {
-            warn!("User {} missing sheet_id; skipping Sheets append", user.id);
-        }
-
-        let new_log = NewLogEntry {
-            user_id: user.id,
-            order_date,
-            gross,
-            tips,
-            mileage,
-        };
-
-        if let Err(err) = db::insert_log(&self.state.pool, new_log).await {
-            error!("Failed to insert log: {err:?}");
+pub fn parse_forward_key(address: &str) -> Option<String> {
+    let lower = address
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_ascii_lowercase();
+    if let Some((local, domain)) = lower.split_once('@') {
+        if domain == "driversheet.com" && local.starts_with("user-") {
+            return Some(local.trim_start_matches("user-").to_string());
         }
-
-        Ok(())
     }
+    None
 }
 
-impl Clone for MailApp {
-    fn clone(&self) -> Self {
-        Self {
-            state: Arc::clone(&self.state),
-            handle: self.handle.clone(),
-            recipients: Vec::new(),
-            buffer: Vec::new(),
-        }
-    }
+/// Errors worth retrying later (storage hiccups) as opposed to messages we
+/// will never be able to parse.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain()
+        .any(|cause| cause.downcast_ref::<sqlx::Error>().is_some())
 }
 
-impl Handler for MailApp {
-    fn helo(&mut self, _ip: std::net::IpAddr, helo: &str) -> Response {
-        info!("SMTP HELO from {helo}");
-        response::OK
+/// Parses one RFC822 message addressed to `forward_key`, stores the log row
+/// and queues it for the user's sheet. Every message is recorded in
+/// `messages` so redeliveries and re-imports are skipped.
+pub async fn process_message(
+    state: &AppState,
+    source: MessageSource,
+    forward_key: &str,
+    data: &[u8],
+) -> Result<Delivery> {
+    let Some(user) = db::user_by_forward(&state.pool, forward_key).await? else {
+        warn!("No user mapped to forward key {forward_key}");
+        return Ok(Delivery::UnknownRecipient);
+    };
+
+    let meta = MessageMeta::read(data);
+    if db::message_seen(&state.pool, user.id, &meta.dedupe_key).await? {
+        info!(
+            "Skipping duplicate message {} for user {}",
+            meta.dedupe_key, user.id
+        );
+        return Ok(Delivery::Duplicate);
     }
 
-    fn mail(&mut self, _ip: std::net::IpAddr, _domain: &str, _from: &str) -> Response {
-        self.recipients.clear();
-        self.buffer.clear();
-        response::OK
+    let outcome = ingest(state, &user, &meta, data).await;
+    let (status, error, log_id) = match &outcome {
+        Ok(Some(log)) => ("logged", None, Some(log.id)),
+        Ok(None) => ("skipped", None, None),
+        Err(err) => ("failed", Some(format!("{err:#}")), None),
+    };
+    db::record_message(
+        &state.pool,
+        NewMessage {
+            user_id: user.id,
+            dedupe_key: meta.dedupe_key,
+            message_id: meta.message_id,
+            subject: meta.subject,
+            sender: meta.sender,
+            source,
+            status,
+            error,
+            log_id,
+        },
+    )
+    .await?;
+
+    match outcome? {
+        Some(_) => Ok(Delivery::Logged),
+        None => Ok(Delivery::NoStatement),
     }
+}
 
-    fn rcpt(&mut self, to: &str) -> Response {
-        if let Some(key) = Self::parse_forward_key(to) {
-            self.recipients.push(key);
-            response::OK
-        } else {
-            warn!("Rejecting RCPT {to}");
-            response::NO_MAILBOX
-        }
-    }
+async fn ingest(
+    state: &AppState,
+    user: &User,
+    meta: &MessageMeta,
+    data: &[u8],
+) -> Result<Option<LogEntry>> {
+    let parsed = mailparse::parse_mail(data).context("Failed to parse email")?;
+
+    let Ok(pdf_bytes) = find_first_pdf(&parsed) else {
+        info!("Message for user {} has no PDF attachment", user.id);
+        return Ok(None);
+    };
+    let text = PdfSandbox::from_config(&state.config)?
+        .extract_text(&pdf_bytes)
+        .await?;
+    log_statement(state, user, meta, &text).await.map(Some)
+}
 
-    fn data_start(&mut self, _domain: &str, _from: &str, _is8bit: bool, to: &[String]) -> Response {
-        if self.recipients.is_empty() {
-            for addr in to {
-                if let Some(key) = Self::parse_forward_key(addr) {
-                    self.recipients.push(key);
-                }
-            }
-        }
-        response::OK
+/// Parses statement text into a log and queues it for the user's sheet.
+async fn log_statement(
+    state: &AppState,
+    user: &User,
+    meta: &MessageMeta,
+    text: &str,
+) -> Result<LogEntry> {
+    let (order_date, gross, tips, mileage) = parse_text(text)?;
+
+    let new_log = NewLogEntry {
+        user_id: user.id,
+        order_date,
+        gross,
+        tips,
+        currency: DEFAULT_CURRENCY.to_string(),
+        mileage,
+        platform: detect_platform(&[
+            meta.sender.as_deref().unwrap_or_default(),
+            meta.subject.as_deref().unwrap_or_default(),
+            text,
+        ])
+        .map(str::to_string),
+    };
+
+    let log = db::insert_log(&state.pool, new_log)
+        .await
+        .context("Failed to insert log")?;
+    state.webhook_delivery.notify_one();
+
+    if user.sheet_id.is_some() {
+        state.sheet_sync.notify_one();
+    } else {
+        warn!("User {} missing sheet_id; skipping Sheets append", user.id);
     }
 
-    fn data(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.buffer.extend_from_slice(buf);
-        Ok(())
-    }
+    Ok(log)
+}
+
+/// Keywords identifying a payout's platform, checked in order.
+const PLATFORMS: &[(&str, &str)] = &[
+    ("Uber", "uber"),
+    ("Lyft", "lyft"),
+    ("DoorDash", "doordash"),
+    ("Instacart", "instacart"),
+    ("Grubhub", "grubhub"),
+    ("Amazon Flex", "amazon flex"),
+];
+
+/// Names the platform from the first source that mentions one. Sources go
+/// from most to least reliable: a forwarded message's `From` is often the
+/// driver, so the subject and statement text are consulted too.
+fn detect_platform(sources: &[&str]) -> Option<&'static str> {
+    sources.iter().find_map(|source| {
+        let source = source.to_lowercase();
+        PLATFORMS
+            .iter()
+            .find(|(_, keyword)| source.contains(keyword))
+            .map(|(name, _)| *name)
+    })
+}
+
+/// Header fields kept for every inbound message.
+struct MessageMeta {
+    dedupe_key: String,
+    message_id: Option<String>,
+    subject: Option<String>,
+    sender: Option<String>,
+}
 
-    fn data_end(&mut self) -> Response {
-        if let Some(recipient) = self.recipients.first().cloned() {
-            let payload = mem::take(&mut self.buffer);
-            if let Err(err) = self
-                .handle
-                .block_on(self.process_message(&recipient, payload))
-            {
-                error!("Failed to process inbound email: {err:?}");
-            }
-        } else {
-            warn!("No valid recipients captured for message");
+impl MessageMeta {
+    fn read(data: &[u8]) -> Self {
+        let headers = mailparse::parse_headers(data)
+            .map(|(headers, _)| headers)
+            .unwrap_or_default();
+        let header = |name: &str| {
+            headers
+                .get_first_value(name)
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let message_id = header("Message-ID");
+        // Message-ID survives forwarding hops and Takeout exports; hash the raw
+        // bytes when a sender leaves it out.
+        let dedupe_key = match &message_id {
+            Some(id) => db::message_id_key(id.trim_matches(['<', '>'])),
+            None => format!("sha256:{}", hex::encode(Sha256::digest(data))),
+        };
+        Self {
+            dedupe_key,
+            message_id,
+            subject: header("Subject"),
+            sender: header("From"),
         }
-        self.recipients.clear();
-        response::OK
     }
 }
 
 fn find_first_pdf(parsed: &ParsedMail<'_>) -> Result<Vec<u8>> {
-    if parsed.subparts.is_empty() {
-        if parsed.ctype.mimetype == "application/pdf" {
-            return parsed
-                .get_body_raw()
-                .map_err(|e| anyhow!("Failed to read PDF body: {e}"));
-        }
+    if parsed.subparts.is_empty() && parsed.ctype.mimetype == "application/pdf" {
+        return parsed
+            .get_body_raw()
+            .map_err(|e| anyhow!("Failed to read PDF body: {e}"));
     }
 
     for part in &parsed.subparts {
@@ -218,18 +264,7 @@ fn find_first_pdf(parsed: &ParsedMail<'_>) -> Result<Vec<u8>> {
     Err(anyhow!("PDF attachment not found"))
 }
 
-fn write_temp_file(tmp_root: &str, bytes: &[u8]) -> Result<PathBuf> {
-    let root = PathBuf::from(tmp_root);
-    if !root.exists() {
-        fs::create_dir_all(&root).context("Failed to create tmp dir")?;
-    }
-    let filename = format!("{}.pdf", Uuid::new_v4());
-    let path = root.join(filename);
-    fs::write(&path, bytes).context("Failed to write temp PDF")?;
-    Ok(path)
-}
-
-fn parse_text(text: &str) -> Result<(NaiveDate, f64, f64, Option<f64>)> {
+fn parse_text(text: &str) -> Result<(NaiveDate, Cents, Cents, Option<f64>)> {
     let gross = capture_amount(text, REGEX_GROSS).context("Gross not found")?;
     let tips = capture_amount(text, REGEX_TIPS).context("Tips not found")?;
     let mileage = capture_optional_amount(text, REGEX_MILEAGE);
@@ -237,11 +272,10 @@ fn parse_text(text: &str) -> Result<(NaiveDate, f64, f64, Option<f64>)> {
     Ok((date, gross, tips, mileage))
 }
 
-fn capture_amount(text: &str, pattern: &str) -> Option<f64> {
+fn capture_amount(text: &str, pattern: &str) -> Option<Cents> {
     let regex = Regex::new(pattern).ok()?;
     let caps = regex.captures(text)?;
-    let raw = caps.get(1)?.as_str().replace(',', "");
-    raw.parse().ok()
+    Cents::parse(caps.get(1)?.as_str())
 }
 
 fn capture_optional_amount(text: &str, pattern: &str) -> Option<f64> {
@@ -262,16 +296,19 @@ fn capture_date(text: &str, pattern: &str) -> Option<NaiveDate> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sheets_mock::{test_user, MockSheets};
     use chrono::NaiveDate;
+    use serde_json::json;
 
     #[test]
     fn parse_text_extracts_all_values() {
-        let text = "Weekly Earnings\nGross $1,234.56\nTips $78.90\nDate 08/15/2024\nMileage 123.4 mi";
+        let text =
+            "Weekly Earnings\nGross $1,234.56\nTips $78.90\nDate 08/15/2024\nMileage 123.4 mi";
         let (date, gross, tips, mileage) = parse_text(text).expect("parse succeeds");
 
         assert_eq!(date, NaiveDate::from_ymd_opt(2024, 8, 15).unwrap());
-        assert!((gross - 1234.56).abs() < f64::EPSILON);
-        assert!((tips - 78.90).abs() < f64::EPSILON);
+        assert_eq!(gross, Cents(123_456));
+        assert_eq!(tips, Cents(7890));
         assert_eq!(mileage, Some(123.4));
     }
 
@@ -281,8 +318,47 @@ mod tests {
         let (date, gross, tips, mileage) = parse_text(text).expect("parse succeeds");
 
         assert_eq!(date, NaiveDate::from_ymd_opt(2023, 1, 2).unwrap());
-        assert!((gross - 10.00).abs() < f64::EPSILON);
-        assert!((tips - 2.50).abs() < f64::EPSILON);
+        assert_eq!(gross, Cents(1000));
+        assert_eq!(tips, Cents(250));
         assert!(mileage.is_none());
     }
+
+    #[test]
+    fn detect_platform_prefers_earlier_sources() {
+        assert_eq!(
+            detect_platform(&["Uber Receipts <noreply@uber.com>", "", ""]),
+            Some("Uber")
+        );
+        assert_eq!(
+            detect_platform(&["me@gmail.com", "Fwd: Your weekly DoorDash pay", "Lyft"]),
+            Some("DoorDash")
+        );
+        assert_eq!(detect_platform(&["me@gmail.com", "Fwd: pay", ""]), None);
+    }
+
+    #[tokio::test]
+    async fn statement_is_written_to_the_sheet() {
+        let (mock, url) = MockSheets::start().await;
+        mock.add_spreadsheet("sheet-1", &["Sheet1"]);
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, Some("sheet-1")).await;
+        let meta = MessageMeta::read(
+            b"From: Uber Receipts <noreply@uber.com>\r\nSubject: Your weekly pay\r\n\r\n",
+        );
+        let text = "Gross $1,234.56\nTips $78.90\nDate 08/15/2024\nMileage 123.4 mi";
+
+        let log = log_statement(&state, &user, &meta, text).await.unwrap();
+        crate::outbox::sync_due(&state).await.unwrap();
+
+        let row = vec![
+            json!("2024-08-15"),
+            json!(1234.56),
+            json!(78.9),
+            json!(123.4),
+            json!(log.id),
+        ];
+        assert_eq!(mock.appends(), [("'Sheet1'!A:E".to_string(), vec![row])]);
+        let logs = db::recent_logs(&state.pool, user.id, 10).await.unwrap();
+        assert_eq!(logs[0].sync_status.as_deref(), Some("synced"));
+    }
 }
@@ -1,44 +1,147 @@
 mod api;
+mod backup;
+mod cli;
 mod config;
+mod crypto;
 mod db;
+mod excel;
+mod export;
+mod imap;
+mod import;
 mod mail;
 mod models;
+mod money;
+mod oauth;
+mod outbox;
+mod pdf;
+mod pull;
+mod ratelimit;
+mod resync;
+mod retention;
 mod sheets;
+#[cfg(test)]
+mod sheets_mock;
+mod sink;
+mod smtp;
 mod state;
+mod summary;
+mod webhooks;
 
+use crate::cli::{Cli, Command};
 use crate::config::AppConfig;
+use crate::crypto::SecretBox;
+use crate::excel::ExcelClient;
+use crate::oauth::OAuthClient;
 use crate::sheets::SheetsClient;
+use crate::smtp::{ListenAddr, Protocol};
 use crate::state::AppState;
 use anyhow::Result;
-use sqlx::sqlite::SqlitePoolOptions;
+use clap::Parser;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::signal;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use tracing_subscriber::EnvFilter;
 
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenvy::dotenv().ok();
     init_tracing();
 
+    let cli = Cli::parse();
+    if matches!(cli.command, Some(Command::ExtractPdf)) {
+        std::process::exit(pdf::run_child());
+    }
+
     let config = AppConfig::from_env()?;
+    if let Some(Command::Restore(args)) = cli.command {
+        return cli::restore(&config, args).await;
+    }
+    let state = bootstrap(config).await?;
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(state).await,
+        Command::Deliver(args) => {
+            let code = cli::deliver(&state, args).await;
+            state.pool.close().await;
+            std::process::exit(code);
+        }
+        Command::Import(args) => cli::import(&state, args).await,
+        Command::Resync(args) => cli::resync(&state, args).await,
+        Command::Backup(args) => cli::backup(&state, args).await,
+        Command::Export(args) => cli::export(&state, args).await,
+        Command::Restore(_) => unreachable!("handled before bootstrap"),
+        Command::ExtractPdf => unreachable!("handled before bootstrap"),
+    }
+}
+
+async fn bootstrap(config: AppConfig) -> Result<AppState> {
     std::fs::create_dir_all("data").ok();
     std::fs::create_dir_all(&config.tmp_dir).ok();
 
-    let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect(&config.database_url)
-        .await?;
-
+    let pool = db::connect(&config.database_url).await?;
     db::migrate(&pool).await?;
 
-    let sheets = SheetsClient::new(&config.google_sa_key).await?;
-
-    let bind_api = config.bind_api;
-    let bind_mail = config.bind_mail;
-
-    let state = AppState::new(pool.clone(), sheets, config);
+    let sheets = SheetsClient::new(&config).await?;
+    let secrets = config
+        .credentials_key
+        .as_deref()
+        .map(SecretBox::from_base64)
+        .transpose()?;
+    let excel = ExcelClient::new(&config)?;
+    let google_oauth = OAuthClient::google(&config)?;
+    let microsoft_oauth = OAuthClient::microsoft(&config)?;
+
+    AppState::new(
+        pool,
+        sheets,
+        excel,
+        secrets,
+        google_oauth,
+        microsoft_oauth,
+        config,
+    )
+}
 
-    mail::run_mail_server(state.clone(), bind_mail);
+async fn serve(state: AppState) -> Result<()> {
+    let bind_api = state.config.bind_api;
+    let ingress = state.config.mail_ingress;
+    let shutdown = CancellationToken::new();
+    let tracker = TaskTracker::new();
+    let mailbox = Arc::new(state.clone());
+
+    if ingress.smtp() {
+        spawn_mail_listener(
+            &mailbox,
+            ListenAddr::Tcp(state.config.bind_mail),
+            Protocol::Smtp,
+            &shutdown,
+            &tracker,
+        );
+    }
+    if ingress.lmtp() {
+        spawn_mail_listener(
+            &mailbox,
+            state.config.bind_lmtp.clone(),
+            Protocol::Lmtp,
+            &shutdown,
+            &tracker,
+        );
+    }
+    if state.secrets.is_some() {
+        imap::spawn_imap_poller(state.clone());
+    } else {
+        tracing::warn!("CREDENTIALS_KEY not set; IMAP pull mode disabled");
+    }
+    outbox::spawn_sheet_sync(state.clone());
+    summary::spawn_summary_refresh(state.clone());
+    pull::spawn_sheet_pull(state.clone());
+    webhooks::spawn_webhook_delivery(state.clone());
+    backup::spawn_backups(state.clone());
+    retention::spawn_retention(state.clone());
     spawn_trial_monitor(state.clone());
 
     let app = api::app_router(state.clone());
@@ -46,17 +149,51 @@ async fn main() -> Result<()> {
     tracing::info!("API listening on {}", listener.local_addr()?);
 
     axum::serve(listener, app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
+        .with_graceful_shutdown(shutdown_signal(shutdown.clone()))
         .await?;
 
+    // Let mail sessions that are mid-delivery finish before exiting.
+    tracker.close();
+    if tokio::time::timeout(SHUTDOWN_GRACE, tracker.wait())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Mail sessions still running after {:?}; exiting",
+            SHUTDOWN_GRACE
+        );
+    }
+
     Ok(())
 }
 
+fn spawn_mail_listener(
+    mailbox: &Arc<AppState>,
+    addr: ListenAddr,
+    protocol: Protocol,
+    shutdown: &CancellationToken,
+    tracker: &TaskTracker,
+) {
+    let listener = smtp::run_listener(
+        Arc::clone(mailbox),
+        addr,
+        protocol,
+        shutdown.clone(),
+        tracker.clone(),
+    );
+    tracker.spawn(async move {
+        if let Err(err) = listener.await {
+            tracing::error!(?err, ?protocol, "mail listener failed");
+        }
+    });
+}
+
 fn init_tracing() {
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     let _ = tracing_subscriber::fmt()
         .with_env_filter(filter)
         .with_target(false)
+        .with_writer(std::io::stderr)
         .try_init();
 }
 
@@ -80,7 +217,7 @@ async fn enforce_trials(state: &AppState) -> Result<()> {
     Ok(())
 }
 
-async fn shutdown_signal() {
+async fn shutdown_signal(shutdown: CancellationToken) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -104,4 +241,5 @@ async fn shutdown_signal() {
     }
 
     tracing::info!("Shutdown signal received");
+    shutdown.cancel();
 }
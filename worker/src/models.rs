@@ -1,3 +1,4 @@
+use crate::money::{self, Cents};
 use chrono::{NaiveDate, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
@@ -14,6 +15,16 @@ pub struct User {
     pub forward_key: String,
     pub paid: bool,
     pub created: NaiveDateTime,
+    #[serde(rename = "sheetError")]
+    pub sheet_error: Option<String>,
+    #[serde(rename = "sheetStatus")]
+    pub sheet_status: Option<String>,
+    #[serde(rename = "sheetVerifiedAt")]
+    pub sheet_verified_at: Option<NaiveDateTime>,
+    pub sink: SinkKind,
+    /// When the account is purged, if the user asked to delete it.
+    #[serde(rename = "deleteAfter")]
+    pub delete_after: Option<NaiveDateTime>,
 }
 
 impl User {
@@ -38,20 +49,562 @@ pub struct LogEntry {
     pub user_id: i64,
     #[serde(rename = "orderDate")]
     pub order_date: NaiveDate,
-    pub gross: f64,
-    pub tips: f64,
+    #[serde(with = "money::dollars")]
+    pub gross: Cents,
+    #[serde(with = "money::dollars")]
+    pub tips: Cents,
+    /// ISO 4217 code of `gross` and `tips`.
+    pub currency: String,
     pub mileage: Option<f64>,
+    pub platform: Option<String>,
     #[serde(rename = "parsedAt")]
     pub parsed_at: NaiveDateTime,
+    /// `pending`, `synced` or `failed`; `None` when the log was never queued
+    /// for a sheet.
+    #[serde(rename = "syncStatus")]
+    pub sync_status: Option<String>,
+    #[serde(rename = "syncError")]
+    pub sync_error: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct NewLogEntry {
     pub user_id: i64,
     pub order_date: NaiveDate,
-    pub gross: f64,
-    pub tips: f64,
+    pub gross: Cents,
+    pub tips: Cents,
+    pub currency: String,
     pub mileage: Option<f64>,
+    pub platform: Option<String>,
+}
+
+/// Partial edit of a log through the API; omitted fields are kept.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LogUpdate {
+    #[serde(rename = "orderDate")]
+    pub order_date: Option<NaiveDate>,
+    #[serde(with = "money::dollars::option", default)]
+    pub gross: Option<Cents>,
+    #[serde(with = "money::dollars::option", default)]
+    pub tips: Option<Cents>,
+    pub mileage: Option<f64>,
+    pub platform: Option<String>,
+}
+
+/// The sheet-mapped values of a log. Stored as JSON on the outbox row when
+/// a sync succeeds, as the base for telling sheet edits from DB edits.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct LogValues {
+    pub order_date: NaiveDate,
+    pub gross: Cents,
+    pub tips: Cents,
+    pub mileage: Option<f64>,
+    pub platform: Option<String>,
+}
+
+/// A live log with the state of its sheet row, for pulling sheet edits.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PullCandidate {
+    pub id: i64,
+    #[sqlx(flatten)]
+    pub values: LogValues,
+    pub sync_status: Option<String>,
+    pub synced_values: Option<sqlx::types::Json<LogValues>>,
+}
+
+/// One audited value pulled from a sheet.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct LogCorrection {
+    pub id: i64,
+    #[serde(rename = "logId")]
+    pub log_id: i64,
+    pub field: String,
+    #[serde(rename = "dbValue")]
+    pub db_value: Option<String>,
+    #[serde(rename = "sheetValue")]
+    pub sheet_value: Option<String>,
+    /// `applied`, `conflict` or `rejected`.
+    pub status: String,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewCorrection {
+    pub field: SheetField,
+    pub db_value: Option<String>,
+    pub sheet_value: Option<String>,
+    pub status: &'static str,
+}
+
+/// Outcome of pulling one user's sheet edits.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PullReport {
+    /// Sheet rows matched to a live log.
+    pub rows: usize,
+    /// Rows whose log ID is unknown or deleted.
+    pub unmatched: usize,
+    pub applied: usize,
+    pub conflicts: usize,
+    pub rejected: usize,
+}
+
+/// Totals for one period (week start or month) and platform, as aggregated
+/// for the summary tabs.
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct PeriodTotals {
+    pub period: String,
+    pub platform: String,
+    pub gross: Cents,
+    pub tips: Cents,
+    pub mileage: f64,
+    pub payouts: i64,
+}
+
+/// A due `sheet_outbox` row with what is needed to append it.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct SheetSync {
+    pub id: i64,
+    pub log_id: i64,
+    pub user_id: i64,
+    pub attempts: i64,
+    /// `upsert` or `delete`.
+    pub op: String,
+    pub seq: i64,
+}
+
+/// Ingress a message arrived through; stored on `messages.source`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageSource {
+    Smtp,
+    Lmtp,
+    Pipe,
+    Import,
+    Imap,
+}
+
+impl MessageSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Smtp => "smtp",
+            Self::Lmtp => "lmtp",
+            Self::Pipe => "pipe",
+            Self::Import => "import",
+            Self::Imap => "imap",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NewMessage {
+    pub user_id: i64,
+    pub dedupe_key: String,
+    pub message_id: Option<String>,
+    pub subject: Option<String>,
+    pub sender: Option<String>,
+    pub source: MessageSource,
+    pub status: &'static str,
+    pub error: Option<String>,
+    pub log_id: Option<i64>,
+}
+
+/// A row of the inbound message ledger, as included in data exports.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Message {
+    pub id: i64,
+    #[serde(rename = "dedupeKey")]
+    pub dedupe_key: String,
+    #[serde(rename = "messageId")]
+    pub message_id: Option<String>,
+    pub subject: Option<String>,
+    pub sender: Option<String>,
+    pub source: String,
+    pub status: String,
+    pub error: Option<String>,
+    #[serde(rename = "logId")]
+    pub log_id: Option<i64>,
+    #[serde(rename = "receivedAt")]
+    pub received_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ImportSummary {
+    pub total: i64,
+    pub imported: i64,
+    pub skipped: i64,
+    pub failed: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ImportJob {
+    pub id: String,
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    pub status: String,
+    pub total: i64,
+    pub imported: i64,
+    pub skipped: i64,
+    pub failed: i64,
+    pub error: Option<String>,
+    pub created: NaiveDateTime,
+    pub finished: Option<NaiveDateTime>,
+}
+
+/// `append` adds the selected logs below existing rows; `replace` clears the
+/// mapped data rows first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResyncMode {
+    #[default]
+    Append,
+    Replace,
+}
+
+impl ResyncMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResyncMode::Append => "append",
+            ResyncMode::Replace => "replace",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ResyncRequest {
+    #[serde(default)]
+    pub mode: ResyncMode,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ResyncJob {
+    pub id: String,
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    pub mode: String,
+    #[serde(rename = "from")]
+    pub date_from: Option<NaiveDate>,
+    #[serde(rename = "to")]
+    pub date_to: Option<NaiveDate>,
+    pub status: String,
+    pub total: i64,
+    pub written: i64,
+    pub error: Option<String>,
+    pub created: NaiveDateTime,
+    pub finished: Option<NaiveDateTime>,
+}
+
+/// A personal data export. Once `completed`, the archive can be downloaded
+/// with `token` until `expires`, after which it is deleted and the job is
+/// `expired`.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ExportJob {
+    pub id: String,
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    #[serde(skip_serializing)]
+    pub token: String,
+    pub status: String,
+    pub error: Option<String>,
+    pub created: NaiveDateTime,
+    pub finished: Option<NaiveDateTime>,
+    pub expires: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ImapAccount {
+    pub id: i64,
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    pub host: String,
+    pub port: i64,
+    pub tls: bool,
+    pub username: String,
+    #[serde(skip_serializing)]
+    pub password_enc: String,
+    pub folder: String,
+    #[serde(rename = "senderFilter")]
+    pub sender_filter: Option<String>,
+    #[serde(rename = "uidValidity")]
+    pub uid_validity: Option<i64>,
+    #[serde(rename = "lastUid")]
+    pub last_uid: i64,
+    #[serde(rename = "lastPolled")]
+    pub last_polled: Option<NaiveDateTime>,
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+    pub created: NaiveDateTime,
+}
+
+/// A user's OAuth grant for writing to their sheets as themselves.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct GoogleCredential {
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    pub scope: String,
+    #[serde(skip_serializing)]
+    pub refresh_token_enc: String,
+    /// Set when Google rejected the refresh token; writes fall back to the
+    /// service account until the user reconnects.
+    #[serde(rename = "revokedAt")]
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created: NaiveDateTime,
+    pub updated: NaiveDateTime,
+}
+
+/// An endpoint that receives the user's log events.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Webhook {
+    pub id: i64,
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    pub url: String,
+    /// Key for the `X-DriverSheet-Signature` HMAC of each payload.
+    pub secret: String,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookCreate {
+    pub url: String,
+}
+
+/// One event sent (or being sent) to a webhook, as shown in its delivery log.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    #[serde(rename = "webhookId")]
+    pub webhook_id: i64,
+    pub event: String,
+    #[serde(rename = "logId")]
+    pub log_id: Option<i64>,
+    /// `pending`, `delivered` or `failed`.
+    pub status: String,
+    pub attempts: i64,
+    #[serde(rename = "responseStatus")]
+    pub response_status: Option<i64>,
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+    pub created: NaiveDateTime,
+    #[serde(rename = "deliveredAt")]
+    pub delivered_at: Option<NaiveDateTime>,
+}
+
+/// A due delivery with its endpoint.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DueDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub user_id: i64,
+    pub url: String,
+    pub secret: String,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i64,
+}
+
+/// Body of a webhook delivery.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEvent {
+    /// `log.created`, `log.updated`, `log.deleted` or `test`.
+    pub event: &'static str,
+    #[serde(rename = "occurredAt")]
+    pub occurred_at: NaiveDateTime,
+    pub log: EventLog,
+}
+
+/// The log as sent in webhook payloads.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventLog {
+    pub id: i64,
+    #[serde(rename = "orderDate")]
+    pub order_date: NaiveDate,
+    #[serde(with = "money::dollars")]
+    pub gross: Cents,
+    #[serde(with = "money::dollars")]
+    pub tips: Cents,
+    pub currency: String,
+    pub mileage: Option<f64>,
+    pub platform: Option<String>,
+    #[serde(rename = "parsedAt")]
+    pub parsed_at: NaiveDateTime,
+}
+
+impl LogEvent {
+    pub fn new(event: &'static str, log: &LogEntry) -> Self {
+        Self {
+            event,
+            occurred_at: chrono::Utc::now().naive_utc(),
+            log: EventLog {
+                id: log.id,
+                order_date: log.order_date,
+                gross: log.gross,
+                tips: log.tips,
+                currency: log.currency.clone(),
+                mileage: log.mileage,
+                platform: log.platform.clone(),
+                parsed_at: log.parsed_at,
+            },
+        }
+    }
+}
+
+/// Where a user's logs are exported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum SinkKind {
+    /// The Google spreadsheet in `users.sheet_id`.
+    #[default]
+    Sheets,
+    /// The Excel Online workbook in `excel_workbooks`.
+    Excel,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SinkSelection {
+    pub sink: SinkKind,
+}
+
+/// The Excel Online workbook a user exports to.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ExcelWorkbook {
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+    /// OneDrive item id of the workbook.
+    #[serde(rename = "itemId")]
+    pub item_id: String,
+    pub scope: String,
+    #[serde(skip_serializing)]
+    pub refresh_token_enc: String,
+    /// Set when Microsoft rejected the refresh token; exports fail until the
+    /// user reconnects.
+    #[serde(rename = "revokedAt")]
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created: NaiveDateTime,
+    pub updated: NaiveDateTime,
+}
+
+/// Connects an Excel workbook: the Microsoft consent screen's code and the
+/// OneDrive item id of the workbook picked by the user.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExcelConnection {
+    pub code: String,
+    #[serde(rename = "redirectUri")]
+    pub redirect_uri: String,
+    #[serde(rename = "itemId")]
+    pub item_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GoogleAuthorization {
+    pub code: String,
+    #[serde(rename = "redirectUri")]
+    pub redirect_uri: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImapAccountUpsert {
+    pub host: String,
+    #[serde(default = "default_imap_port")]
+    pub port: u16,
+    #[serde(default = "default_true")]
+    pub tls: bool,
+    pub username: String,
+    pub password: String,
+    #[serde(default = "default_imap_folder")]
+    pub folder: String,
+    #[serde(rename = "senderFilter")]
+    pub sender_filter: Option<String>,
+}
+
+fn default_imap_port() -> u16 {
+    993
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_imap_folder() -> String {
+    "INBOX".to_string()
+}
+
+/// A log value that can be placed in a sheet column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SheetField {
+    Date,
+    Gross,
+    Tips,
+    Mileage,
+    Platform,
+    Currency,
+}
+
+impl SheetField {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Date => "date",
+            Self::Gross => "gross",
+            Self::Tips => "tips",
+            Self::Mileage => "mileage",
+            Self::Platform => "platform",
+            Self::Currency => "currency",
+        }
+    }
+}
+
+/// A column written after the mapped fields: a constant or, when it starts
+/// with `=`, a formula evaluated by Sheets.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SheetExtra {
+    pub header: String,
+    pub value: String,
+}
+
+/// Where and how a user's rows are written in their spreadsheet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SheetMapping {
+    pub tab: String,
+    #[serde(rename = "startColumn")]
+    pub start_column: String,
+    pub fields: sqlx::types::Json<Vec<SheetField>>,
+    #[serde(default)]
+    pub extras: sqlx::types::Json<Vec<SheetExtra>>,
+    #[serde(rename = "dateFormat")]
+    pub date_format: String,
+    /// Writes rows into per-period tabs instead of `tab`.
+    #[serde(default)]
+    pub rotation: TabRotation,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum TabRotation {
+    #[default]
+    None,
+    Monthly,
+    Yearly,
+}
+
+impl Default for SheetMapping {
+    fn default() -> Self {
+        Self {
+            tab: "Sheet1".to_string(),
+            start_column: "A".to_string(),
+            fields: sqlx::types::Json(vec![
+                SheetField::Date,
+                SheetField::Gross,
+                SheetField::Tips,
+                SheetField::Mileage,
+            ]),
+            extras: sqlx::types::Json(Vec::new()),
+            date_format: "%Y-%m-%d".to_string(),
+            rotation: TabRotation::None,
+        }
+    }
 }
 
 #[serde_as]
@@ -61,6 +614,14 @@ pub struct LemonWebhook {
     pub data: LemonData,
 }
 
+/// A Lemon Squeezy webhook event received for the user.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct BillingEvent {
+    pub id: i64,
+    pub event: String,
+    pub received: NaiveDateTime,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct LemonMeta {
     #[serde(rename = "event_name")]
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::iter::Sum;
+use std::ops::Add;
+
+/// Currency of amounts whose statement does not say otherwise; payout
+/// statements we parse are in US dollars.
+pub const DEFAULT_CURRENCY: &str = "USD";
+
+/// An amount in minor units (cents), stored as an `INTEGER` so totals add up
+/// exactly. Serde sees the raw integer; API fields that show amounts in
+/// major units use `#[serde(with = "money::dollars")]`.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type,
+)]
+#[serde(transparent)]
+#[sqlx(transparent)]
+pub struct Cents(pub i64);
+
+impl Cents {
+    /// Parses a decimal amount exactly: `1,234.56`, `$12`, `-0.5`. More than
+    /// two decimals is rejected rather than rounded.
+    pub fn parse(raw: &str) -> Option<Self> {
+        let cleaned = raw.trim().replace(['$', ','], "");
+        let (negative, digits) = match cleaned.trim().strip_prefix('-') {
+            Some(rest) => (true, rest.trim()),
+            None => (false, cleaned.trim()),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if whole.is_empty() && fraction.is_empty()
+            || fraction.len() > 2
+            || !whole
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+        let whole: i64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().ok()?
+        };
+        let fraction: i64 = format!("{fraction:0<2}").parse().ok()?;
+        let cents = whole.checked_mul(100)?.checked_add(fraction)?;
+        Some(Self(if negative { -cents } else { cents }))
+    }
+
+    /// Converts a number read from JSON or a spreadsheet. It must be within
+    /// rounding noise of a whole number of cents.
+    pub fn from_f64(amount: f64) -> Option<Self> {
+        let cents = amount * 100.0;
+        let rounded = cents.round();
+        (cents.is_finite() && (cents - rounded).abs() < 1e-6 && rounded.abs() < 9e15)
+            .then_some(Self(rounded as i64))
+    }
+
+    /// The amount in major units. Exact in the sense that the shortest
+    /// decimal printing of the result is the amount itself (`1234.56`), so
+    /// JSON and spreadsheet cells show it to the penny.
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / 100.0
+    }
+}
+
+/// `1234.56`, `-0.50`: always two decimals, no grouping or symbol.
+impl fmt::Display for Cents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        write!(f, "{sign}{}.{:02}", abs / 100, abs % 100)
+    }
+}
+
+impl Add for Cents {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self(self.0 + other.0)
+    }
+}
+
+impl Sum for Cents {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        Self(iter.map(|cents| cents.0).sum())
+    }
+}
+
+/// Serde adapter showing `Cents` in major units: a JSON number with at most
+/// two decimals on output, a number or decimal string on input.
+pub mod dollars {
+    use super::Cents;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(amount: &Cents, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(amount.to_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Cents, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let amount = match &value {
+            Value::Number(number) => number.as_f64().and_then(Cents::from_f64),
+            Value::String(text) => Cents::parse(text),
+            _ => None,
+        };
+        amount.ok_or_else(|| D::Error::custom(format!("invalid amount {value}")))
+    }
+
+    /// Reads optional amounts, e.g. in partial updates.
+    pub mod option {
+        use super::Cents;
+        use serde::{Deserialize, Deserializer};
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Cents>, D::Error> {
+            #[derive(Deserialize)]
+            struct Wrapper(#[serde(with = "super")] Cents);
+
+            let amount = Option::<Wrapper>::deserialize(deserializer)?;
+            Ok(amount.map(|Wrapper(amount)| amount))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats_exactly() {
+        assert_eq!(Cents::parse("1,234.56"), Some(Cents(123_456)));
+        assert_eq!(Cents::parse("$12"), Some(Cents(1200)));
+        assert_eq!(Cents::parse("-0.5"), Some(Cents(-50)));
+        assert_eq!(Cents::parse(".07"), Some(Cents(7)));
+        assert_eq!(Cents::parse("1.005"), None);
+        assert_eq!(Cents::parse("abc"), None);
+        assert_eq!(Cents::parse(""), None);
+        assert_eq!(Cents(123_456).to_string(), "1234.56");
+        assert_eq!(Cents(-50).to_string(), "-0.50");
+
+        // 0.1 + 0.2 drifts in f64; cents do not.
+        let total: Cents = [Cents(10), Cents(20)].into_iter().sum();
+        assert_eq!(total.to_f64(), 0.3);
+        assert_eq!(Cents::from_f64(1234.56), Some(Cents(123_456)));
+        assert_eq!(Cents::from_f64(0.125), None);
+    }
+
+    #[test]
+    fn dollars_adapter_reads_numbers_and_strings() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Amount {
+            #[serde(with = "dollars")]
+            gross: Cents,
+        }
+        #[derive(Debug, Deserialize)]
+        struct Amounts {
+            #[serde(with = "dollars")]
+            gross: Cents,
+            #[serde(with = "dollars::option", default)]
+            tips: Option<Cents>,
+        }
+
+        let amounts: Amounts = serde_json::from_str(r#"{"gross": 19.99, "tips": "2.50"}"#).unwrap();
+        assert_eq!(
+            (amounts.gross, amounts.tips),
+            (Cents(1999), Some(Cents(250)))
+        );
+        let amount = Amount {
+            gross: amounts.gross,
+        };
+        assert_eq!(
+            serde_json::to_string(&amount).unwrap(),
+            r#"{"gross":19.99}"#
+        );
+        let amounts: Amounts = serde_json::from_str(r#"{"gross": 1}"#).unwrap();
+        assert_eq!(amounts.tips, None);
+        assert!(serde_json::from_str::<Amounts>(r#"{"gross": 1.234}"#).is_err());
+    }
+}
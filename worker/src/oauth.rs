@@ -0,0 +1,238 @@
+use crate::config::AppConfig;
+use anyhow::{anyhow, Context, Result};
+use parking_lot::Mutex;
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Whose accounts an `OAuthClient` grants access to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    /// Google Sheets writes as the user.
+    Google,
+    /// Excel Online workbooks through Microsoft Graph.
+    Microsoft,
+}
+
+impl Provider {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Google => "Google",
+            Self::Microsoft => "Microsoft",
+        }
+    }
+}
+
+/// Refresh tokens the provider no longer accepts: the user revoked our access
+/// or the grant expired. Only reconnecting fixes it.
+#[derive(Debug, Error)]
+#[error("{} access was revoked: {reason}", provider.name())]
+pub struct GrantRevoked {
+    pub provider: Provider,
+    pub reason: String,
+}
+
+impl GrantRevoked {
+    pub fn find(err: &anyhow::Error) -> Option<&Self> {
+        err.chain().find_map(|cause| cause.downcast_ref::<Self>())
+    }
+
+    pub fn user_message(&self) -> String {
+        format!(
+            "DriverSheet can no longer access your {} account; reconnect it",
+            self.provider.name()
+        )
+    }
+}
+
+/// Tokens returned when a user grants us access.
+#[derive(Debug, Deserialize)]
+pub struct Grant {
+    pub access_token: String,
+    pub expires_in: u64,
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub scope: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+    #[serde(default)]
+    error_description: String,
+}
+
+/// An OAuth token endpoint for the web app's client, used to redeem users'
+/// authorization codes and refresh their access tokens.
+pub struct OAuthClient {
+    provider: Provider,
+    http: Client,
+    client_id: String,
+    client_secret: String,
+    base_url: String,
+    /// Access tokens by user, with the refresh token they came from.
+    cache: Mutex<HashMap<i64, CachedToken>>,
+}
+
+struct CachedToken {
+    refresh_token: String,
+    access_token: String,
+    expires: Instant,
+}
+
+/// Access tokens are refreshed this long before they expire.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+impl OAuthClient {
+    /// `None` unless `GOOGLE_CLIENT_ID` and `GOOGLE_CLIENT_SECRET` are set.
+    pub fn google(config: &AppConfig) -> Result<Option<Self>> {
+        Self::new(
+            Provider::Google,
+            &config.google_client_id,
+            &config.google_client_secret,
+            &config.google_oauth_url,
+        )
+    }
+
+    /// `None` unless `MICROSOFT_CLIENT_ID` and `MICROSOFT_CLIENT_SECRET` are
+    /// set.
+    pub fn microsoft(config: &AppConfig) -> Result<Option<Self>> {
+        Self::new(
+            Provider::Microsoft,
+            &config.microsoft_client_id,
+            &config.microsoft_client_secret,
+            &config.microsoft_oauth_url,
+        )
+    }
+
+    fn new(
+        provider: Provider,
+        client_id: &Option<String>,
+        client_secret: &Option<String>,
+        base_url: &str,
+    ) -> Result<Option<Self>> {
+        let (Some(client_id), Some(client_secret)) = (client_id, client_secret) else {
+            return Ok(None);
+        };
+        let http = Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .context("Failed to construct reqwest client")?;
+        Ok(Some(Self {
+            provider,
+            http,
+            client_id: client_id.clone(),
+            client_secret: client_secret.clone(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            cache: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Redeems an authorization code from the consent screen.
+    pub async fn exchange(&self, code: &str, redirect_uri: &str) -> Result<Grant> {
+        self.token_request(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+        ])
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to redeem {} authorization code",
+                self.provider.name()
+            )
+        })
+    }
+
+    /// A valid access token for `user_id`, refreshed when the cached one is
+    /// missing, about to expire, or from another refresh token.
+    pub async fn access_token(&self, user_id: i64, refresh_token: &str) -> Result<String> {
+        if let Some(cached) = self.cache.lock().get(&user_id) {
+            if cached.refresh_token == refresh_token && cached.expires > Instant::now() {
+                return Ok(cached.access_token.clone());
+            }
+        }
+        let grant = self
+            .token_request(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+            ])
+            .await
+            .with_context(|| format!("Failed to refresh {} access token", self.provider.name()))?;
+        let lifetime = Duration::from_secs(grant.expires_in).saturating_sub(EXPIRY_MARGIN);
+        self.cache.lock().insert(
+            user_id,
+            CachedToken {
+                refresh_token: refresh_token.to_string(),
+                access_token: grant.access_token.clone(),
+                expires: Instant::now() + lifetime,
+            },
+        );
+        Ok(grant.access_token)
+    }
+
+    /// Revokes the grant and forgets any cached access token. Microsoft has
+    /// no revocation endpoint for these grants; the user removes the app from
+    /// their account instead.
+    pub async fn revoke(&self, user_id: i64, refresh_token: &str) -> Result<()> {
+        self.cache.lock().remove(&user_id);
+        if self.provider == Provider::Microsoft {
+            return Ok(());
+        }
+        let response = self
+            .http
+            .post(format!("{}/revoke", self.base_url))
+            .form(&[("token", refresh_token)])
+            .send()
+            .await
+            .context("Failed to reach Google to revoke access")?;
+        // 400 means the token is already invalid, which is what we want.
+        if !response.status().is_success() && response.status().as_u16() != 400 {
+            return Err(anyhow!("Google revoke failed: {}", response.status()));
+        }
+        Ok(())
+    }
+
+    async fn token_request(&self, params: &[(&str, &str)]) -> Result<Grant> {
+        let mut form = vec![
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+        ];
+        form.extend_from_slice(params);
+        let response = self
+            .http
+            .post(format!("{}/token", self.base_url))
+            .form(&form)
+            .send()
+            .await
+            .with_context(|| format!("Failed to reach {} token endpoint", self.provider.name()))?;
+        if response.status().is_success() {
+            return response.json().await.with_context(|| {
+                format!(
+                    "{} token endpoint returned invalid JSON",
+                    self.provider.name()
+                )
+            });
+        }
+        let status = response.status();
+        let error: TokenError = response.json().await.unwrap_or(TokenError {
+            error: status.to_string(),
+            error_description: String::new(),
+        });
+        if error.error == "invalid_grant" {
+            return Err(GrantRevoked {
+                provider: self.provider,
+                reason: error.error_description,
+            }
+            .into());
+        }
+        Err(anyhow!(
+            "{} token endpoint returned {status}: {} {}",
+            self.provider.name(),
+            error.error,
+            error.error_description
+        ))
+    }
+}
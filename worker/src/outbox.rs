@@ -0,0 +1,225 @@
+use crate::db;
+use crate::models::{SheetMapping, SheetSync};
+use crate::oauth::{GrantRevoked, Provider};
+use crate::sink::{self, ExportSink, NoDestination};
+use crate::state::AppState;
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const BATCH_SIZE: i64 = 200;
+/// With backoff doubling from 30 seconds and capped at six hours, the last of
+/// 12 attempts comes about 14.5 hours after the first. Webhook deliveries
+/// retry on the same schedule.
+pub const MAX_ATTEMPTS: i64 = 12;
+const MAX_BACKOFF_SECS: i64 = 6 * 60 * 60;
+
+/// Drains `sheet_outbox`, writing or deleting each log's row in the user's
+/// sink (Google Sheets or Excel): runs every `SHEET_SYNC_SECS` and whenever a
+/// new log is queued. Rows for the same user are sent together.
+pub fn spawn_sheet_sync(state: AppState) {
+    let every = Duration::from_secs(state.config.sheet_sync_secs);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.sheet_sync.notified() => {}
+            }
+            if let Err(err) = sync_due(&state).await {
+                error!(?err, "sheet sync error");
+            }
+        }
+    });
+}
+
+/// Applies every due outbox row.
+pub async fn sync_due(state: &AppState) -> Result<()> {
+    loop {
+        let due = db::due_sheet_syncs(&state.pool, BATCH_SIZE).await?;
+        let batch_len = due.len() as i64;
+        // One spreadsheet per user, so grouping by user coalesces writes per
+        // spreadsheet into a single delete and a single upsert.
+        let mut users: Vec<(i64, Vec<SheetSync>)> = Vec::new();
+        for sync in due {
+            match users
+                .iter_mut()
+                .find(|(user_id, _)| *user_id == sync.user_id)
+            {
+                Some((_, syncs)) => syncs.push(sync),
+                None => users.push((sync.user_id, vec![sync])),
+            }
+        }
+        for (user_id, syncs) in users {
+            let mapping = db::sheet_mapping(&state.pool, user_id).await?;
+            if sync_user(state, user_id, &syncs, &mapping).await? {
+                db::mark_summary_stale(&state.pool, user_id).await?;
+            }
+        }
+        if batch_len < BATCH_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+/// Applies one user's due rows through their sink. Returns whether anything
+/// was written to Sheets, which makes the summary tabs stale.
+async fn sync_user(
+    state: &AppState,
+    user_id: i64,
+    syncs: &[SheetSync],
+    mapping: &SheetMapping,
+) -> Result<bool> {
+    let all: Vec<&SheetSync> = syncs.iter().collect();
+    let Some(user) = db::user_by_id(&state.pool, user_id).await? else {
+        settle(state, &all, Err(NoDestination.into())).await?;
+        return Ok(false);
+    };
+    let sink = match sink::for_user(state, &user).await {
+        Ok(sink) => sink,
+        Err(err) => {
+            settle(state, &all, Err(err)).await?;
+            return Ok(false);
+        }
+    };
+    let (deletes, upserts): (Vec<&SheetSync>, Vec<&SheetSync>) =
+        syncs.iter().partition(|sync| sync.op == "delete");
+    let mut wrote = false;
+
+    if !deletes.is_empty() {
+        let log_ids: Vec<i64> = deletes.iter().map(|sync| sync.log_id).collect();
+        let result = sink.delete_logs(mapping, &log_ids).await;
+        wrote |= settle(state, &deletes, result).await?;
+    }
+
+    let mut logs = Vec::new();
+    let mut pending = Vec::new();
+    for sync in upserts {
+        match db::log_by_id(&state.pool, sync.log_id).await? {
+            Some(log) => {
+                logs.push(log);
+                pending.push(sync);
+            }
+            None => {
+                let err = anyhow!("Log {} no longer exists", sync.log_id);
+                record_failure(state, sync, &err).await?;
+            }
+        }
+    }
+    if !logs.is_empty() {
+        let result = sink.upsert_logs(mapping, &logs).await;
+        wrote |= settle(state, &pending, result).await?;
+    }
+    Ok(wrote && sink.sheet_id().is_some())
+}
+
+/// Records the outcome of one batched request for every row it covered.
+async fn settle(state: &AppState, syncs: &[&SheetSync], result: Result<()>) -> Result<bool> {
+    match result {
+        Ok(()) => {
+            for sync in syncs {
+                db::mark_sheet_synced(&state.pool, sync).await?;
+            }
+            Ok(true)
+        }
+        Err(err) => {
+            for sync in syncs {
+                record_failure(state, sync, &err).await?;
+            }
+            Ok(false)
+        }
+    }
+}
+
+async fn record_failure(state: &AppState, sync: &SheetSync, err: &anyhow::Error) -> Result<()> {
+    let message = format!("{err:#}");
+    if let Some(pause) = sink::rate_limited(err) {
+        info!(
+            user_id = sync.user_id,
+            log_id = sync.log_id,
+            "sheet sync over quota, deferring {}s",
+            pause.as_secs()
+        );
+        return db::defer_sheet_sync(&state.pool, sync, &message, pause.as_secs() as i64).await;
+    }
+    if let Some(revoked) = GrantRevoked::find(err) {
+        match revoked.provider {
+            Provider::Google => db::revoke_google_credential(&state.pool, sync.user_id).await?,
+            Provider::Microsoft => db::revoke_excel_workbook(&state.pool, sync.user_id).await?,
+        }
+    }
+    if let Some(user_message) = sink::permanent(err) {
+        warn!(
+            user_id = sync.user_id,
+            log_id = sync.log_id,
+            "sheet sync failed: {message}"
+        );
+        return db::fail_sheet_sync(&state.pool, sync, &message, Some(&user_message)).await;
+    }
+    if err.is::<NoDestination>() {
+        return db::fail_sheet_sync(&state.pool, sync, &message, None).await;
+    }
+
+    let attempts = sync.attempts + 1;
+    if attempts >= MAX_ATTEMPTS {
+        warn!(
+            user_id = sync.user_id,
+            log_id = sync.log_id,
+            "sheet sync gave up: {message}"
+        );
+        return db::fail_sheet_sync(&state.pool, sync, &message, None).await;
+    }
+    let delay = backoff_secs(attempts);
+    info!(
+        user_id = sync.user_id,
+        log_id = sync.log_id,
+        attempts,
+        "sheet sync retrying in {delay}s: {message}"
+    );
+    db::retry_sheet_sync(&state.pool, sync, &message, delay).await
+}
+
+/// 30s, 60s, 120s, ... capped at six hours.
+pub fn backoff_secs(attempts: i64) -> i64 {
+    let exponent = attempts.clamp(1, 20) - 1;
+    (30i64 << exponent).min(MAX_BACKOFF_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Cents;
+    use crate::sheets_mock::{test_log, test_user, MockSheets};
+
+    #[tokio::test]
+    async fn revoked_grant_fails_rows_and_marks_the_credential() {
+        let (mock, url) = MockSheets::start().await;
+        mock.add_spreadsheet("sheet-1", &["Sheet1"]);
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, Some("sheet-1")).await;
+        let sealed = state.secrets.as_ref().unwrap().seal("refresh-1").unwrap();
+        db::save_google_credential(&state.pool, user.id, "scope", &sealed)
+            .await
+            .unwrap();
+        mock.revoke_grant("refresh-1");
+
+        let log = test_log(&state.pool, &user, "2024-08-15", Cents(1000)).await;
+        sync_due(&state).await.unwrap();
+
+        assert!(mock.appends().is_empty());
+        let log = db::log_by_id(&state.pool, log.id).await.unwrap().unwrap();
+        assert_eq!(log.sync_status.as_deref(), Some("failed"));
+        let user = db::user_by_id(&state.pool, user.id).await.unwrap().unwrap();
+        assert!(user.sheet_error.unwrap().contains("reconnect"));
+        let credential = db::google_credential(&state.pool, user.id).await.unwrap();
+        assert!(credential.unwrap().revoked_at.is_some());
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        assert_eq!(backoff_secs(1), 30);
+        assert_eq!(backoff_secs(2), 60);
+        assert_eq!(backoff_secs(5), 480);
+        assert_eq!(backoff_secs(11), MAX_BACKOFF_SECS);
+    }
+}
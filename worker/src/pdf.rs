@@ -0,0 +1,304 @@
+use crate::config::AppConfig;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+/// Extracted text larger than this is treated as a failure rather than
+/// buffered without bound.
+const MAX_TEXT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// How much of the child's stderr is kept for error messages.
+const MAX_STDERR_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum PdfError {
+    #[error("PDF extraction timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("PDF extractor crashed: {0}")]
+    Crashed(String),
+    #[error("PDF extraction failed: {0}")]
+    Failed(String),
+    #[error("PDF extractor could not be started: {0}")]
+    Spawn(#[from] std::io::Error),
+}
+
+/// Runs `pdf_extract` in a child process so hostile attachments can only take
+/// down that process. The child gets CPU-time and address-space limits via
+/// `setrlimit` and is killed when the wall-clock timeout passes.
+#[derive(Debug, Clone)]
+pub struct PdfSandbox {
+    program: PathBuf,
+    args: Vec<String>,
+    timeout: Duration,
+    cpu_secs: u64,
+    memory_bytes: u64,
+}
+
+impl PdfSandbox {
+    /// Re-executes the current binary with the hidden `extract-pdf` command.
+    pub fn from_config(config: &AppConfig) -> Result<Self, PdfError> {
+        Ok(Self {
+            program: std::env::current_exe()?,
+            args: vec!["extract-pdf".to_string()],
+            timeout: Duration::from_secs(config.pdf_timeout_secs),
+            cpu_secs: config.pdf_cpu_secs,
+            memory_bytes: config.pdf_memory_mb * 1024 * 1024,
+        })
+    }
+
+    pub async fn extract_text(&self, pdf: &[u8]) -> Result<String, PdfError> {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        self.apply_limits(&mut command);
+
+        let mut child = command.spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        let run = async {
+            // A child that dies early closes its end of the pipe; its exit
+            // status explains why, so the write error itself is not reported.
+            let write = async {
+                stdin.write_all(pdf).await.ok();
+                drop(stdin);
+            };
+            let mut text = Vec::new();
+            let mut stdout = stdout.take(MAX_TEXT_BYTES + 1);
+            let read_out = stdout.read_to_end(&mut text);
+            let read_err = read_capped(stderr, MAX_STDERR_BYTES);
+            let (_, out, errors) = tokio::join!(write, read_out, read_err);
+            out?;
+            let errors = errors?;
+            let status = child.wait().await?;
+            Ok::<_, std::io::Error>((status, text, errors))
+        };
+
+        let (status, text, errors) = match tokio::time::timeout(self.timeout, run).await {
+            Ok(result) => result?,
+            // Dropping the future drops the child, and kill_on_drop reaps it.
+            Err(_) => return Err(PdfError::Timeout(self.timeout)),
+        };
+
+        if !status.success() {
+            return Err(describe_failure(status, &errors));
+        }
+        if text.len() as u64 > MAX_TEXT_BYTES {
+            return Err(PdfError::Failed("extracted text too large".to_string()));
+        }
+        Ok(String::from_utf8_lossy(&text).into_owned())
+    }
+
+    #[cfg(unix)]
+    fn apply_limits(&self, command: &mut Command) {
+        let cpu = self.cpu_secs;
+        let memory = self.memory_bytes;
+        // SAFETY: the closure runs between fork and exec and only calls
+        // setrlimit, which is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                set_limit(libc::RLIMIT_CPU, cpu)?;
+                set_limit(libc::RLIMIT_AS, memory)?;
+                set_limit(libc::RLIMIT_CORE, 0)?;
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn apply_limits(&self, _command: &mut Command) {}
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(all(unix, not(all(target_os = "linux", target_env = "gnu"))))]
+type Resource = libc::c_int;
+
+#[cfg(unix)]
+fn set_limit(resource: Resource, value: u64) -> std::io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reads `reader` to the end but keeps only the first `limit` bytes. The
+/// child's stderr also carries `pdf_extract`'s diagnostics, so it has to be
+/// drained or a chatty PDF would stall the child on a full pipe.
+async fn read_capped(
+    mut reader: impl tokio::io::AsyncRead + Unpin,
+    limit: usize,
+) -> std::io::Result<Vec<u8>> {
+    let mut kept = Vec::new();
+    let mut chunk = [0u8; 8192];
+    loop {
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(kept);
+        }
+        let room = limit.saturating_sub(kept.len());
+        kept.extend_from_slice(&chunk[..read.min(room)]);
+    }
+}
+
+fn describe_failure(status: ExitStatus, stderr: &[u8]) -> PdfError {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            let reason = match signal {
+                libc::SIGXCPU | libc::SIGKILL => "CPU or memory limit exceeded",
+                libc::SIGSEGV | libc::SIGBUS => "memory fault",
+                libc::SIGABRT => "aborted",
+                _ => "killed",
+            };
+            return PdfError::Crashed(format!("{reason} (signal {signal})"));
+        }
+    }
+    let message = String::from_utf8_lossy(stderr).trim().to_string();
+    match status.code() {
+        Some(CHILD_PANIC) => PdfError::Crashed(if message.is_empty() {
+            "panicked".to_string()
+        } else {
+            message
+        }),
+        _ if message.is_empty() => PdfError::Failed(format!("extractor exited with {status}")),
+        _ => PdfError::Failed(message),
+    }
+}
+
+/// Exit code the child uses when `pdf_extract` panics.
+const CHILD_PANIC: i32 = 70;
+
+/// Entry point of `driversheet-worker extract-pdf`: PDF bytes on stdin, text
+/// on stdout. Returns the process exit code.
+pub fn run_child() -> i32 {
+    let mut pdf = Vec::new();
+    if let Err(err) = std::io::stdin().read_to_end(&mut pdf) {
+        eprintln!("failed to read PDF from stdin: {err}");
+        return 1;
+    }
+    let mut output = match take_stdout() {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("failed to redirect stdout: {err}");
+            return 1;
+        }
+    };
+
+    std::panic::set_hook(Box::new(|info| eprintln!("pdf_extract panicked: {info}")));
+    match std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem(&pdf)) {
+        Ok(Ok(text)) => {
+            if output
+                .write_all(text.as_bytes())
+                .and_then(|_| output.flush())
+                .is_err()
+            {
+                return 1;
+            }
+            0
+        }
+        Ok(Err(err)) => {
+            eprintln!("{err:?}");
+            1
+        }
+        Err(_) => CHILD_PANIC,
+    }
+}
+
+/// `pdf_extract` prints diagnostics such as "Unicode mismatch" with
+/// `println!`, which would end up in the extracted text. Points fd 1 at
+/// stderr and returns a handle on the original stdout for the result.
+#[cfg(unix)]
+fn take_stdout() -> std::io::Result<std::fs::File> {
+    use std::os::fd::FromRawFd;
+    std::io::stdout().flush()?;
+    // SAFETY: plain fd calls; the duplicate is owned by the returned File.
+    unsafe {
+        let original = libc::dup(libc::STDOUT_FILENO);
+        if original < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let output = std::fs::File::from_raw_fd(original);
+        if libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(not(unix))]
+fn take_stdout() -> std::io::Result<std::io::Stdout> {
+    Ok(std::io::stdout())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn shell(script: &str, timeout: Duration) -> PdfSandbox {
+        PdfSandbox {
+            program: PathBuf::from("/bin/sh"),
+            args: vec!["-c".to_string(), script.to_string()],
+            timeout,
+            cpu_secs: 5,
+            memory_bytes: 512 * 1024 * 1024,
+        }
+    }
+
+    #[tokio::test]
+    async fn extract_text_returns_child_stdout() {
+        let sandbox = shell("cat", Duration::from_secs(5));
+        let text = sandbox.extract_text(b"Gross $10.00").await.unwrap();
+        assert_eq!(text, "Gross $10.00");
+    }
+
+    #[tokio::test]
+    async fn extract_text_times_out() {
+        let sandbox = shell("sleep 5", Duration::from_millis(200));
+        let err = sandbox.extract_text(b"").await.unwrap_err();
+        assert!(matches!(err, PdfError::Timeout(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn extract_text_reports_crashes_and_failures() {
+        let crash = shell("kill -SEGV $$", Duration::from_secs(5));
+        let err = crash.extract_text(b"%PDF").await.unwrap_err();
+        assert!(matches!(err, PdfError::Crashed(_)), "{err}");
+
+        let fail = shell("echo 'bad xref' >&2; exit 1", Duration::from_secs(5));
+        let err = fail.extract_text(b"%PDF").await.unwrap_err();
+        assert_eq!(err.to_string(), "PDF extraction failed: bad xref");
+    }
+
+    #[tokio::test]
+    async fn extract_text_drains_noisy_stderr() {
+        let sandbox = shell(
+            "head -c 1000000 /dev/zero | tr '\\0' x >&2; printf 'Gross $10.00'",
+            Duration::from_secs(5),
+        );
+        let text = sandbox.extract_text(b"").await.unwrap();
+        assert_eq!(text, "Gross $10.00");
+    }
+
+    #[tokio::test]
+    async fn extract_text_enforces_cpu_limit() {
+        let mut sandbox = shell("while :; do :; done", Duration::from_secs(10));
+        sandbox.cpu_secs = 1;
+        let err = sandbox.extract_text(b"").await.unwrap_err();
+        assert!(matches!(err, PdfError::Crashed(_)), "{err}");
+    }
+}
@@ -0,0 +1,491 @@
+use crate::db;
+use crate::models::{
+    LogUpdate, LogValues, NewCorrection, PullCandidate, PullReport, SheetField, SheetMapping, User,
+};
+use crate::money::Cents;
+use crate::state::AppState;
+use anyhow::{anyhow, Result};
+use chrono::NaiveDate;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Pulls edits from every verified sheet every `SHEET_PULL_SECS`; `0`
+/// disables the job.
+pub fn spawn_sheet_pull(state: AppState) {
+    if state.config.sheet_pull_secs == 0 {
+        return;
+    }
+    let every = Duration::from_secs(state.config.sheet_pull_secs);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            let users = match db::users_with_sheets(&state.pool).await {
+                Ok(users) => users,
+                Err(err) => {
+                    error!(?err, "sheet pull error");
+                    continue;
+                }
+            };
+            for user in users {
+                match pull_edits(&state, &user).await {
+                    Ok(report) if report.applied + report.conflicts + report.rejected > 0 => {
+                        info!(user_id = user.id, ?report, "pulled sheet edits");
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!(user_id = user.id, "sheet pull failed: {err:#}"),
+                }
+            }
+        }
+    });
+}
+
+/// Reads the user's data rows, matches them to logs by the log ID column
+/// and applies values edited in the sheet to `logs`.
+///
+/// Each mapped cell is compared with the log and with the values last
+/// written to the sheet: a cell that changed while the log did not is
+/// applied; a cell that still holds the written value while the log changed
+/// is left for the outbox to overwrite; when both changed the log is kept and
+/// a conflict is recorded. Unparseable cells are recorded as rejected and
+/// blank cells are ignored.
+pub async fn pull_edits(state: &AppState, user: &User) -> Result<PullReport> {
+    let sheet_id = user
+        .sheet_id
+        .as_deref()
+        .ok_or_else(|| anyhow!("User {} has no sheet connected", user.id))?;
+    let mapping = db::sheet_mapping(&state.pool, user.id).await?;
+    let rows = state
+        .sheets_for(user.id)
+        .await?
+        .read_logs(sheet_id, &mapping)
+        .await?;
+    let logs: HashMap<i64, PullCandidate> = db::pull_candidates(&state.pool, user.id)
+        .await?
+        .into_iter()
+        .map(|log| (log.id, log))
+        .collect();
+
+    let mut report = PullReport::default();
+    let mut seen = HashSet::new();
+    for (log_id, cells) in rows {
+        if !seen.insert(log_id) {
+            continue;
+        }
+        let Some(log) = logs.get(&log_id) else {
+            report.unmatched += 1;
+            continue;
+        };
+        report.rows += 1;
+        let Some(corrections) = apply_row(state, user.id, &mapping, log.clone(), &cells).await?
+        else {
+            continue;
+        };
+        for correction in &corrections {
+            match correction.status {
+                "applied" => report.applied += 1,
+                "conflict" => report.conflicts += 1,
+                _ => report.rejected += 1,
+            }
+        }
+    }
+
+    if report.applied > 0 {
+        state.webhook_delivery.notify_one();
+        db::mark_summary_stale(&state.pool, user.id).await?;
+    }
+    Ok(report)
+}
+
+/// Compares one sheet row with its log and applies the edits. The log was
+/// read before the sheet rows were walked, so when it has changed since
+/// (an API edit) it is read again and compared afresh; an edit on both sides
+/// then becomes a conflict instead of overwriting the API's value. Returns
+/// the corrections recorded, or `None` when the row needs nothing.
+async fn apply_row(
+    state: &AppState,
+    user_id: i64,
+    mapping: &SheetMapping,
+    mut log: PullCandidate,
+    cells: &[Value],
+) -> Result<Option<Vec<NewCorrection>>> {
+    // A log edited faster than this is left for the next pull.
+    for _ in 0..3 {
+        let Some(edits) = compare(mapping, &log, cells) else {
+            return Ok(None);
+        };
+        if db::apply_sheet_edits(
+            &state.pool,
+            user_id,
+            log.id,
+            &log.values,
+            &edits.update,
+            &edits.base,
+            &edits.corrections,
+        )
+        .await?
+        {
+            return Ok(Some(edits.corrections));
+        }
+        match db::pull_candidate(&state.pool, user_id, log.id).await? {
+            Some(fresh) => log = fresh,
+            None => return Ok(None),
+        }
+    }
+    Ok(None)
+}
+
+/// What pulling one row changes.
+struct Edits {
+    update: LogUpdate,
+    /// The synced base after applying `update`.
+    base: LogValues,
+    corrections: Vec<NewCorrection>,
+}
+
+fn compare(mapping: &SheetMapping, log: &PullCandidate, cells: &[Value]) -> Option<Edits> {
+    let current = &log.values;
+    // Without a recorded base, a log whose sheet row is not waiting to be
+    // rewritten is assumed to match what was written.
+    let base = match &log.synced_values {
+        Some(values) => Some(values.0.clone()),
+        None if log.sync_status.as_deref() != Some("pending") => Some(current.clone()),
+        None => None,
+    };
+    let mut edits = Edits {
+        update: LogUpdate::default(),
+        base: base.clone().unwrap_or_else(|| current.clone()),
+        corrections: Vec::new(),
+    };
+
+    for (field, cell) in mapping.fields.iter().copied().zip(cells) {
+        // A log's currency is the statement's and is not edited in the sheet.
+        if field == SheetField::Currency {
+            continue;
+        }
+        let db_value = FieldValue::of(current, field);
+        let sheet_value = match FieldValue::parse(field, cell, &mapping.date_format) {
+            Ok(Some(value)) => value,
+            Ok(None) => continue,
+            Err(raw) => {
+                edits.corrections.push(NewCorrection {
+                    field,
+                    db_value: db_value.map(|value| value.to_string()),
+                    sheet_value: Some(raw),
+                    status: "rejected",
+                });
+                continue;
+            }
+        };
+        if db_value.as_ref() == Some(&sheet_value) {
+            continue;
+        }
+        let Some(base) = &base else {
+            continue;
+        };
+        let base_value = FieldValue::of(base, field);
+        if base_value.as_ref() == Some(&sheet_value) {
+            continue;
+        }
+        let status = if db_value == base_value {
+            sheet_value.apply(field, &mut edits.update, &mut edits.base);
+            "applied"
+        } else {
+            "conflict"
+        };
+        edits.corrections.push(NewCorrection {
+            field,
+            db_value: db_value.map(|value| value.to_string()),
+            sheet_value: Some(sheet_value.to_string()),
+            status,
+        });
+    }
+    (!edits.corrections.is_empty()).then_some(edits)
+}
+
+/// A mapped value, compared the way it is shown in the sheet: money exactly,
+/// mileage to the hundredth.
+#[derive(Debug, Clone)]
+enum FieldValue {
+    Date(NaiveDate),
+    Money(Cents),
+    Amount(f64),
+    Text(String),
+}
+
+impl PartialEq for FieldValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Date(a), Self::Date(b)) => a == b,
+            (Self::Money(a), Self::Money(b)) => a == b,
+            (Self::Amount(a), Self::Amount(b)) => (a * 100.0).round() == (b * 100.0).round(),
+            (Self::Text(a), Self::Text(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Date(date) => write!(f, "{}", date.format("%Y-%m-%d")),
+            Self::Money(amount) => write!(f, "{amount}"),
+            Self::Amount(amount) => write!(f, "{amount}"),
+            Self::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+impl FieldValue {
+    fn of(values: &LogValues, field: SheetField) -> Option<Self> {
+        match field {
+            SheetField::Date => Some(Self::Date(values.order_date)),
+            SheetField::Gross => Some(Self::Money(values.gross)),
+            SheetField::Tips => Some(Self::Money(values.tips)),
+            SheetField::Mileage => values.mileage.map(Self::Amount),
+            SheetField::Platform => values.platform.clone().map(Self::Text),
+            SheetField::Currency => None,
+        }
+    }
+
+    /// `Ok(None)` for a blank cell, `Err` with the raw text when the cell
+    /// does not hold a value of the field's type.
+    fn parse(field: SheetField, cell: &Value, date_format: &str) -> Result<Option<Self>, String> {
+        let text = match cell {
+            Value::Null => return Ok(None),
+            Value::String(text) if text.trim().is_empty() => return Ok(None),
+            Value::String(text) => text.trim().to_string(),
+            other => other.to_string(),
+        };
+        let value = match field {
+            SheetField::Date => NaiveDate::parse_from_str(&text, date_format)
+                .or_else(|_| NaiveDate::parse_from_str(&text, "%Y-%m-%d"))
+                .ok()
+                .map(Self::Date),
+            SheetField::Gross | SheetField::Tips => match cell {
+                Value::Number(n) => n.as_f64().and_then(Cents::from_f64),
+                _ => Cents::parse(&text),
+            }
+            .map(Self::Money),
+            SheetField::Mileage => match cell {
+                Value::Number(n) => n.as_f64(),
+                _ => text.replace(['$', ','], "").trim().parse().ok(),
+            }
+            .filter(|amount: &f64| amount.is_finite())
+            .map(Self::Amount),
+            SheetField::Platform | SheetField::Currency => Some(Self::Text(text.clone())),
+        };
+        value.map(Some).ok_or(text)
+    }
+
+    fn apply(&self, field: SheetField, update: &mut LogUpdate, base: &mut LogValues) {
+        match (field, self) {
+            (SheetField::Date, Self::Date(date)) => {
+                update.order_date = Some(*date);
+                base.order_date = *date;
+            }
+            (SheetField::Gross, Self::Money(amount)) => {
+                update.gross = Some(*amount);
+                base.gross = *amount;
+            }
+            (SheetField::Tips, Self::Money(amount)) => {
+                update.tips = Some(*amount);
+                base.tips = *amount;
+            }
+            (SheetField::Mileage, Self::Amount(amount)) => {
+                update.mileage = Some(*amount);
+                base.mileage = Some(*amount);
+            }
+            (SheetField::Platform, Self::Text(text)) => {
+                update.platform = Some(text.clone());
+                base.platform = Some(text.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::NewLogEntry;
+    use crate::outbox;
+    use crate::sheets_mock::{test_entry, test_log, test_user, MockSheets};
+    use serde_json::json;
+    use sqlx::types::Json;
+
+    fn values(gross: f64) -> LogValues {
+        LogValues {
+            order_date: NaiveDate::from_ymd_opt(2024, 8, 15).unwrap(),
+            gross: Cents::from_f64(gross).unwrap(),
+            tips: Cents(500),
+            mileage: None,
+            platform: None,
+        }
+    }
+
+    fn candidate(current: f64, synced: Option<f64>) -> PullCandidate {
+        PullCandidate {
+            id: 1,
+            values: values(current),
+            sync_status: Some("synced".to_string()),
+            synced_values: synced.map(|gross| Json(values(gross))),
+        }
+    }
+
+    fn row(gross: Value) -> Vec<Value> {
+        vec![json!("2024-08-15"), gross, json!(5), json!(""), json!(1)]
+    }
+
+    #[test]
+    fn sheet_edits_apply_and_both_sided_edits_conflict() {
+        let mapping = SheetMapping::default();
+
+        assert!(compare(&mapping, &candidate(10.0, Some(10.0)), &row(json!(10))).is_none());
+
+        let edits = compare(&mapping, &candidate(10.0, Some(10.0)), &row(json!(12.5))).unwrap();
+        assert_eq!(edits.update.gross, Some(Cents(1250)));
+        assert_eq!(edits.base.gross, Cents(1250));
+        assert_eq!(edits.corrections[0].status, "applied");
+        assert_eq!(edits.corrections[0].db_value.as_deref(), Some("10.00"));
+
+        // The DB moved on and the sheet still shows what was written.
+        assert!(compare(&mapping, &candidate(11.0, Some(10.0)), &row(json!(10))).is_none());
+
+        let edits = compare(&mapping, &candidate(11.0, Some(10.0)), &row(json!(12))).unwrap();
+        assert_eq!(edits.update.gross, None);
+        assert_eq!(edits.corrections[0].status, "conflict");
+    }
+
+    #[test]
+    fn currency_cells_are_not_pulled() {
+        let mapping = SheetMapping {
+            fields: Json(vec![SheetField::Gross, SheetField::Currency]),
+            ..SheetMapping::default()
+        };
+        let cells = [json!(10), json!("EUR"), json!(1)];
+        assert!(compare(&mapping, &candidate(10.0, Some(10.0)), &cells).is_none());
+    }
+
+    #[test]
+    fn unparseable_and_blank_cells() {
+        let mapping = SheetMapping::default();
+        let edits = compare(&mapping, &candidate(10.0, None), &row(json!("ten"))).unwrap();
+        assert_eq!(edits.corrections[0].status, "rejected");
+        assert_eq!(edits.corrections[0].sheet_value.as_deref(), Some("ten"));
+
+        assert!(compare(&mapping, &candidate(10.0, None), &row(json!(""))).is_none());
+        let edits = compare(&mapping, &candidate(10.0, None), &row(json!("$1,010.00"))).unwrap();
+        assert_eq!(edits.update.gross, Some(Cents(101_000)));
+    }
+
+    #[tokio::test]
+    async fn pull_applies_sheet_edits_and_records_corrections() {
+        let (mock, url) = MockSheets::start().await;
+        mock.add_spreadsheet("sheet-1", &["Sheet1"]);
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, Some("sheet-1")).await;
+        let sheets = MockSheets::client(&url);
+        sheets
+            .bootstrap("sheet-1", &SheetMapping::default())
+            .await
+            .unwrap();
+        let mut ids = Vec::new();
+        for gross in [1000, 2000] {
+            let log = test_log(&state.pool, &user, "2024-08-15", Cents(gross)).await;
+            ids.push(log.id);
+        }
+        outbox::sync_due(&state).await.unwrap();
+
+        // The first log is only edited in the sheet; the second is edited in
+        // both places before its update reaches the sheet.
+        mock.set_cell("sheet-1", "Sheet1", 1, 1, json!(12.5));
+        let update = LogUpdate {
+            gross: Some(Cents(2500)),
+            ..LogUpdate::default()
+        };
+        db::update_log(&state.pool, user.id, ids[1], &update)
+            .await
+            .unwrap();
+        mock.set_cell("sheet-1", "Sheet1", 2, 1, json!(30));
+        mock.set_cell("sheet-1", "Sheet1", 2, 2, json!("lots"));
+        mock.set_cell("sheet-1", "Sheet1", 3, 4, json!(999));
+
+        let report = pull_edits(&state, &user).await.unwrap();
+        assert_eq!(
+            report,
+            PullReport {
+                rows: 2,
+                unmatched: 1,
+                applied: 1,
+                conflicts: 1,
+                rejected: 1,
+            }
+        );
+        let logs = db::recent_logs(&state.pool, user.id, 10).await.unwrap();
+        let gross = |id| logs.iter().find(|log| log.id == id).unwrap().gross;
+        assert_eq!(gross(ids[0]), Cents(1250));
+        assert_eq!(gross(ids[1]), Cents(2500));
+
+        // A repeated pull finds nothing new and records nothing twice.
+        pull_edits(&state, &user).await.unwrap();
+        let corrections = db::log_corrections(&state.pool, user.id, 10).await.unwrap();
+        let recorded: Vec<_> = corrections
+            .iter()
+            .map(|c| (c.log_id, c.field.as_str(), c.status.as_str()))
+            .collect();
+        assert_eq!(
+            recorded,
+            [
+                (ids[1], "tips", "rejected"),
+                (ids[1], "gross", "conflict"),
+                (ids[0], "gross", "applied"),
+            ]
+        );
+
+        // The outbox then overwrites the conflicting cell with the log.
+        outbox::sync_due(&state).await.unwrap();
+        assert_eq!(mock.rows("sheet-1", "Sheet1")[2][1], json!(25.0));
+    }
+
+    #[tokio::test]
+    async fn sheet_edit_racing_an_api_edit_is_a_conflict() {
+        let (_mock, url) = MockSheets::start().await;
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, Some("sheet-1")).await;
+        let entry = NewLogEntry {
+            tips: Cents(500),
+            ..test_entry(&user, "2024-08-15", Cents(1000))
+        };
+        let log = db::insert_log(&state.pool, entry).await.unwrap();
+        db::mark_logs_synced(&state.pool, user.id, &[log.id])
+            .await
+            .unwrap();
+
+        // Read before the API edit lands, applied after it.
+        let stale = db::pull_candidate(&state.pool, user.id, log.id)
+            .await
+            .unwrap()
+            .unwrap();
+        let update = LogUpdate {
+            gross: Some(Cents(2500)),
+            ..LogUpdate::default()
+        };
+        db::update_log(&state.pool, user.id, log.id, &update)
+            .await
+            .unwrap();
+        let mapping = SheetMapping::default();
+        let corrections = apply_row(&state, user.id, &mapping, stale, &row(json!(12.5)))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(corrections.len(), 1);
+        assert_eq!(corrections[0].status, "conflict");
+        assert_eq!(corrections[0].db_value.as_deref(), Some("25.00"));
+        let current = db::log_by_id(&state.pool, log.id).await.unwrap().unwrap();
+        assert_eq!(current.gross, Cents(2500));
+    }
+}
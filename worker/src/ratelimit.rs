@@ -0,0 +1,114 @@
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket shared by every request made with one set of credentials,
+/// so a burst for one user cannot exhaust the per-minute quota for all of
+/// them. A quota response pauses the whole bucket.
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    capacity: f64,
+    per_sec: f64,
+    tokens: f64,
+    updated: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Allows `per_minute` requests a minute, with bursts of up to a tenth
+    /// of that.
+    pub fn per_minute(per_minute: u32) -> Self {
+        let per_minute = f64::from(per_minute.max(1));
+        let capacity = (per_minute / 10.0).max(1.0);
+        Self {
+            bucket: Mutex::new(Bucket {
+                capacity,
+                per_sec: per_minute / 60.0,
+                tokens: capacity,
+                updated: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    /// Waits until a request may be sent.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = self.bucket.lock().take(Instant::now());
+            match wait {
+                None => return,
+                Some(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+
+    /// Holds every request for `duration`, e.g. after a 429.
+    pub fn pause(&self, duration: Duration) {
+        self.bucket.lock().pause(Instant::now(), duration);
+    }
+}
+
+impl Bucket {
+    /// Takes a token, or returns how long to wait before trying again.
+    fn take(&mut self, now: Instant) -> Option<Duration> {
+        if let Some(until) = self.paused_until {
+            if now < until {
+                return Some(until - now);
+            }
+            self.paused_until = None;
+        }
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.per_sec))
+        }
+    }
+
+    fn pause(&mut self, now: Instant, duration: Duration) {
+        let until = self
+            .paused_until
+            .into_iter()
+            .fold(now + duration, Instant::max);
+        self.paused_until = Some(until);
+        self.tokens = 0.0;
+        self.updated = until;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_bursts_then_refills() {
+        let limiter = RateLimiter::per_minute(60);
+        let mut bucket = limiter.bucket.lock();
+        let start = bucket.updated;
+        for _ in 0..6 {
+            assert_eq!(bucket.take(start), None);
+        }
+        assert_eq!(bucket.take(start), Some(Duration::from_secs(1)));
+        assert_eq!(bucket.take(start + Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn pause_holds_requests() {
+        let limiter = RateLimiter::per_minute(60);
+        let mut bucket = limiter.bucket.lock();
+        let start = bucket.updated;
+        bucket.pause(start, Duration::from_secs(30));
+        assert_eq!(
+            bucket.take(start + Duration::from_secs(10)),
+            Some(Duration::from_secs(20))
+        );
+        let resumed = start + Duration::from_secs(30);
+        assert_eq!(bucket.take(resumed), Some(Duration::from_secs(1)));
+        assert_eq!(bucket.take(resumed + Duration::from_secs(1)), None);
+    }
+}
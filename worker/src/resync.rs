@@ -0,0 +1,157 @@
+use crate::db;
+use crate::models::{ResyncJob, ResyncMode, ResyncRequest, User};
+use crate::sink::{self, ExportSink};
+use crate::state::AppState;
+use anyhow::Result;
+use tracing::{info, warn};
+
+/// Logs per upsert; well under the Sheets request size limit.
+const BATCH_ROWS: usize = 500;
+
+impl ResyncRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.from, self.to) {
+            (Some(from), Some(to)) if from > to => Err("from must not be after to".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes the user's logs (all of them, or an order date range) into their
+/// sink in batches and returns how many rows were written. Rows already
+/// keyed to a log are updated in place, so an append-mode resync never
+/// duplicates them. A replace clears every data row, or with a date range
+/// only the rows of logs in it. The sink is
+/// bootstrapped first so a freshly connected sheet or workbook gets its
+/// headers.
+/// Progress is logged and, when `job_id` is set, written to `resync_jobs`.
+pub async fn resync_sheet(
+    state: &AppState,
+    user: &User,
+    request: &ResyncRequest,
+    job_id: Option<&str>,
+) -> Result<i64> {
+    let sink = sink::for_user(state, user).await?;
+    let mapping = db::sheet_mapping(&state.pool, user.id).await?;
+    let logs = db::logs_between(&state.pool, user.id, request.from, request.to).await?;
+    let total = logs.len() as i64;
+    if let Some(id) = job_id {
+        db::update_resync_job(&state.pool, id, "running", total, 0, None).await?;
+    }
+
+    sink.bootstrap(&mapping).await?;
+    if request.mode == ResyncMode::Replace {
+        if request.from.is_none() && request.to.is_none() {
+            sink.clear_logs(&mapping).await?;
+        } else {
+            // Only the range is rewritten; rows of logs outside it stay.
+            for batch in logs.chunks(BATCH_ROWS) {
+                let ids: Vec<i64> = batch.iter().map(|log| log.id).collect();
+                sink.delete_logs(&mapping, &ids).await?;
+            }
+        }
+    }
+
+    let mut written = 0;
+    for batch in logs.chunks(BATCH_ROWS) {
+        sink.upsert_logs(&mapping, batch).await?;
+        let ids: Vec<i64> = batch.iter().map(|log| log.id).collect();
+        db::mark_logs_synced(&state.pool, user.id, &ids).await?;
+
+        written += batch.len() as i64;
+        info!(user_id = user.id, written, total, "sheet resync progress");
+        if let Some(id) = job_id {
+            db::update_resync_job(&state.pool, id, "running", total, written, None).await?;
+        }
+    }
+    if sink.sheet_id().is_some() {
+        db::mark_summary_stale(&state.pool, user.id).await?;
+    }
+    Ok(written)
+}
+
+pub fn spawn_resync(state: AppState, user: User, job: ResyncJob, request: ResyncRequest) {
+    tokio::spawn(async move {
+        let update = match resync_sheet(&state, &user, &request, Some(&job.id)).await {
+            Ok(written) => {
+                info!(user_id = user.id, written, "sheet resync finished");
+                db::update_resync_job(&state.pool, &job.id, "completed", written, written, None)
+                    .await
+            }
+            Err(err) => {
+                warn!(user_id = user.id, "sheet resync failed: {err:#}");
+                let progress = db::resync_job(&state.pool, user.id, &job.id)
+                    .await
+                    .ok()
+                    .flatten()
+                    .unwrap_or(job.clone());
+                let message = format!("{err:#}");
+                db::update_resync_job(
+                    &state.pool,
+                    &job.id,
+                    "failed",
+                    progress.total,
+                    progress.written,
+                    Some(&message),
+                )
+                .await
+            }
+        };
+        if let Err(err) = update {
+            warn!("Failed to finish resync job {}: {err:#}", job.id);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Cents;
+    use crate::sheets_mock::{test_log, test_user, MockSheets};
+    use chrono::NaiveDate;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn ranged_replace_keeps_rows_outside_the_range() {
+        let (mock, url) = MockSheets::start().await;
+        mock.add_spreadsheet("sheet-1", &["Sheet1"]);
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, Some("sheet-1")).await;
+        for day in 1..=3 {
+            test_log(
+                &state.pool,
+                &user,
+                &format!("2024-08-{day:02}"),
+                Cents(1000),
+            )
+            .await;
+        }
+        let all = ResyncRequest {
+            mode: ResyncMode::Append,
+            from: None,
+            to: None,
+        };
+        assert_eq!(resync_sheet(&state, &user, &all, None).await.unwrap(), 3);
+
+        let day = NaiveDate::from_ymd_opt(2024, 8, 2);
+        let ranged = ResyncRequest {
+            mode: ResyncMode::Replace,
+            from: day,
+            to: day,
+        };
+        assert_eq!(resync_sheet(&state, &user, &ranged, None).await.unwrap(), 1);
+        let mut dates: Vec<_> = mock.rows("sheet-1", "Sheet1")[1..]
+            .iter()
+            .map(|row| row[0].clone())
+            .collect();
+        dates.sort_by_key(|date| date.to_string());
+        assert_eq!(
+            dates,
+            [
+                json!("2024-08-01"),
+                json!("2024-08-02"),
+                json!("2024-08-03")
+            ]
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::db;
+use crate::export;
+use crate::models::User;
+use crate::state::AppState;
+use anyhow::Result;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// How often deletions and message retention are enforced. Both work in
+/// days, so an hour late is fine.
+const RETENTION_SECS: u64 = 3600;
+
+/// Purges accounts whose deletion grace period ran out, scrubs messages
+/// older than `MESSAGE_RETENTION_DAYS` and deletes expired data exports.
+pub fn spawn_retention(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(RETENTION_SECS));
+        loop {
+            interval.tick().await;
+            if let Err(err) = enforce(&state).await {
+                error!(?err, "retention error");
+            }
+        }
+    });
+}
+
+async fn enforce(state: &AppState) -> Result<()> {
+    for user in db::users_due_for_deletion(&state.pool).await? {
+        purge(state, &user).await?;
+        info!(user_id = user.id, "purged deleted account");
+    }
+    let days = state.config.message_retention_days;
+    if days > 0 {
+        let scrubbed = db::scrub_messages(&state.pool, days as i64).await?;
+        if scrubbed > 0 {
+            info!(scrubbed, "scrubbed old message data");
+        }
+    }
+    export::expire_archives(state).await
+}
+
+/// Revokes the user's Google and Microsoft grants, best effort, then deletes
+/// the account. Their spreadsheet or workbook is theirs and is left alone.
+async fn purge(state: &AppState, user: &User) -> Result<()> {
+    if let Some(secrets) = &state.secrets {
+        if let (Some(oauth), Some(credential)) = (
+            &state.google_oauth,
+            db::google_credential(&state.pool, user.id).await?,
+        ) {
+            let revoked = match secrets.open(&credential.refresh_token_enc) {
+                Ok(refresh_token) => oauth.revoke(user.id, &refresh_token).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = revoked {
+                warn!(
+                    "Could not revoke Google grant for user {}: {err:#}",
+                    user.id
+                );
+            }
+        }
+        if let (Some(oauth), Some(workbook)) = (
+            &state.microsoft_oauth,
+            db::excel_workbook(&state.pool, user.id).await?,
+        ) {
+            if let Ok(refresh_token) = secrets.open(&workbook.refresh_token_enc) {
+                oauth.revoke(user.id, &refresh_token).await.ok();
+            }
+        }
+    }
+    export::remove_archives(state, user.id).await?;
+    db::purge_user(&state.pool, user.id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::UserUpsert;
+    use crate::sheets_mock::MockSheets;
+
+    #[tokio::test]
+    async fn enforce_purges_only_accounts_past_their_grace_period() {
+        let (_mock, url) = MockSheets::start().await;
+        let state = MockSheets::app_state(&url).await;
+        let user = |google_id: &str| UserUpsert {
+            google_id: google_id.to_string(),
+            email: format!("{google_id}@example.com"),
+            sheet_id: None,
+        };
+        let leaving = db::upsert_user(&state.pool, user("g-1")).await.unwrap();
+        let waiting = db::upsert_user(&state.pool, user("g-2")).await.unwrap();
+        db::schedule_user_deletion(&state.pool, leaving.id, 0)
+            .await
+            .unwrap();
+        db::schedule_user_deletion(&state.pool, waiting.id, 86_400)
+            .await
+            .unwrap();
+
+        enforce(&state).await.unwrap();
+        assert!(db::user_by_id(&state.pool, leaving.id)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(db::user_by_id(&state.pool, waiting.id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
@@ -1,78 +1,1397 @@
+use crate::config::AppConfig;
+use crate::models::{LogEntry, SheetField, SheetMapping, TabRotation};
+use crate::oauth::OAuthClient;
+use crate::ratelimit::RateLimiter;
+use crate::summary;
 use anyhow::{anyhow, Context, Result};
-use reqwest::Client;
-use serde_json::json;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{NaiveDate, Utc};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
+use thiserror::Error;
+use tracing::warn;
 use yup_oauth2::authenticator::DefaultAuthenticator;
 use yup_oauth2::{ServiceAccountAuthenticator, ServiceAccountKey};
 
-const SHEETS_SCOPE: &str = "https://www.googleapis.com/auth/spreadsheets";
+pub const SHEETS_SCOPE: &str = "https://www.googleapis.com/auth/spreadsheets";
+/// Lets the service account share spreadsheets it created itself.
+const DRIVE_FILE_SCOPE: &str = "https://www.googleapis.com/auth/drive.file";
+/// Tab of formulas added to spreadsheets we create.
+const SUMMARY_TAB: &str = "Summary";
+
+/// A non-2xx response from the Sheets API.
+#[derive(Debug, Error)]
+#[error("Sheets API error ({status}): {message}")]
+pub struct SheetsApiError {
+    pub status: u16,
+    pub message: String,
+    /// `Retry-After` of a 429, in seconds.
+    pub retry_after: Option<u64>,
+}
+
+impl SheetsApiError {
+    /// Errors that retrying will not fix until the user changes something:
+    /// the sheet was unshared or deleted, or the mapped tab/range is invalid.
+    /// Auth failures of our own service account (401), quota (429) and 5xx
+    /// are retried.
+    pub fn permanent(err: &anyhow::Error) -> Option<&Self> {
+        err.chain()
+            .filter_map(|cause| cause.downcast_ref::<Self>())
+            .find(|api| matches!(api.status, 400 | 403 | 404))
+    }
+
+    /// How long to hold off after a quota error. Quota errors are not the
+    /// row's fault, so callers retry them without counting an attempt.
+    pub fn rate_limited(err: &anyhow::Error) -> Option<Duration> {
+        err.chain()
+            .filter_map(|cause| cause.downcast_ref::<Self>())
+            .find(|api| api.status == 429)
+            .map(|api| Duration::from_secs(api.retry_after.unwrap_or(QUOTA_PAUSE_SECS)))
+    }
+
+    /// Short explanation suitable for showing to the user.
+    pub fn user_message(&self) -> String {
+        match self.status {
+            403 => {
+                "DriverSheet no longer has edit access to your sheet; share it again".to_string()
+            }
+            404 => "Your sheet was not found; it may have been deleted".to_string(),
+            _ => format!("Google Sheets rejected the update: {}", self.message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedSpreadsheet {
+    #[serde(rename = "sheetId")]
+    pub sheet_id: String,
+    pub url: String,
+}
+
+/// Where logs currently live in a spreadsheet.
+struct SheetIndex {
+    /// Log ID to every (tab, 1-based row) holding it.
+    rows: HashMap<i64, Vec<(String, usize)>>,
+    /// Tab ids by title.
+    tabs: HashMap<String, i64>,
+}
+
+/// Outcome of [`SheetsClient::verify_access`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SheetAccess {
+    Writable,
+    NotFound,
+    NotShared,
+    ReadOnly,
+}
+
+impl SheetAccess {
+    /// Value stored in `users.sheet_status` and the error code sent to the
+    /// frontend.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SheetAccess::Writable => "verified",
+            SheetAccess::NotFound => "not_found",
+            SheetAccess::NotShared => "not_shared",
+            SheetAccess::ReadOnly => "read_only",
+        }
+    }
+
+    /// Tells the user what to fix. `service_account` is `None` when writes use
+    /// the user's own Google account, which then needs edit access itself.
+    pub fn user_message(self, service_account: Option<&str>) -> String {
+        match (self, service_account) {
+            (SheetAccess::Writable, _) => "Spreadsheet connected".to_string(),
+            (SheetAccess::NotFound, _) => {
+                "Spreadsheet not found; check the link and try again".to_string()
+            }
+            (SheetAccess::NotShared, Some(service_account)) => {
+                format!("Share the spreadsheet with {service_account} as an Editor")
+            }
+            (SheetAccess::ReadOnly, Some(service_account)) => format!(
+                "{service_account} can view the spreadsheet but not edit it; make it an Editor"
+            ),
+            (SheetAccess::NotShared, None) => {
+                "Your Google account cannot edit this spreadsheet; ask its owner to share it with you as an Editor".to_string()
+            }
+            (SheetAccess::ReadOnly, None) => {
+                "Your Google account can view the spreadsheet but not edit it; ask its owner to make you an Editor".to_string()
+            }
+        }
+    }
+}
+
+/// Maps a failed Sheets call to an access problem. A 403 means `forbidden`
+/// at the stage that failed: not shared at all for the metadata read,
+/// read-only for the write.
+fn access_problem(err: &anyhow::Error, forbidden: SheetAccess) -> Option<SheetAccess> {
+    let api = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<SheetsApiError>())?;
+    match api.status {
+        400 | 404 => Some(SheetAccess::NotFound),
+        403 => Some(forbidden),
+        _ => None,
+    }
+}
+
+/// Where bearer tokens for the Sheets and Drive APIs come from.
+pub enum TokenSource {
+    /// The service account's JWT flow; yup-oauth2 caches and refreshes tokens.
+    ServiceAccount(DefaultAuthenticator),
+    /// A fixed token, for emulators and tests.
+    Static(String),
+    /// A user's own Google account, through their stored refresh token.
+    User {
+        oauth: Arc<OAuthClient>,
+        user_id: i64,
+        refresh_token: String,
+    },
+}
+
+impl TokenSource {
+    async fn token(&self) -> Result<String> {
+        match self {
+            TokenSource::ServiceAccount(authenticator) => {
+                let token = authenticator
+                    .token(&[SHEETS_SCOPE, DRIVE_FILE_SCOPE])
+                    .await
+                    .context("Failed to obtain OAuth token for Sheets API")?;
+                token
+                    .token()
+                    .map(str::to_string)
+                    .ok_or_else(|| anyhow!("Missing token string"))
+            }
+            TokenSource::Static(token) => Ok(token.clone()),
+            TokenSource::User {
+                oauth,
+                user_id,
+                refresh_token,
+            } => oauth.access_token(*user_id, refresh_token).await,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct SheetsClient {
-    authenticator: Arc<DefaultAuthenticator>,
+    tokens: Arc<TokenSource>,
     http: Client,
+    service_account: String,
+    sheets_api: String,
+    drive_api: String,
+    limiter: Arc<RateLimiter>,
 }
 
 impl SheetsClient {
-    pub async fn new(sa_key_json: &str) -> Result<Self> {
-        let key: ServiceAccountKey = serde_json::from_str(sa_key_json)
+    pub async fn new(config: &AppConfig) -> Result<Self> {
+        let key: ServiceAccountKey = serde_json::from_str(&config.google_sa_key)
             .context("Failed to parse GOOGLE_SA_KEY as service account JSON")?;
 
-        let auth = ServiceAccountAuthenticator::builder(key)
-            .build()
-            .await
-            .context("Failed to build service account authenticator")?;
+        let service_account = key.client_email.clone();
+        let tokens = match &config.sheets_access_token {
+            Some(token) => TokenSource::Static(token.clone()),
+            None => TokenSource::ServiceAccount(
+                ServiceAccountAuthenticator::builder(key)
+                    .build()
+                    .await
+                    .context("Failed to build service account authenticator")?,
+            ),
+        };
 
+        Self::with_tokens(
+            tokens,
+            &service_account,
+            &config.sheets_api_url,
+            &config.drive_api_url,
+            config.sheets_requests_per_min,
+        )
+    }
+
+    /// A client for the Sheets API at `sheets_url` and the Drive API at
+    /// `drive_url` (scheme and host, e.g. `https://sheets.googleapis.com`).
+    pub fn with_tokens(
+        tokens: TokenSource,
+        service_account: &str,
+        sheets_url: &str,
+        drive_url: &str,
+        requests_per_minute: u32,
+    ) -> Result<Self> {
         let http = Client::builder()
             .timeout(Duration::from_secs(10))
             .build()
             .context("Failed to construct reqwest client")?;
 
         Ok(Self {
-            authenticator: Arc::new(auth),
+            tokens: Arc::new(tokens),
             http,
+            service_account: service_account.to_string(),
+            sheets_api: format!("{}/v4/spreadsheets", sheets_url.trim_end_matches('/')),
+            drive_api: format!("{}/drive/v3", drive_url.trim_end_matches('/')),
+            limiter: Arc::new(RateLimiter::per_minute(requests_per_minute)),
         })
     }
 
-    pub async fn append_row(&self, sheet_id: &str, values: &[serde_json::Value]) -> Result<()> {
-        let token = self
-            .authenticator
-            .token(&[SHEETS_SCOPE])
+    /// The same client acting as the user through their own OAuth grant.
+    /// Requests still share the service account's rate limiter.
+    pub fn as_user(&self, oauth: Arc<OAuthClient>, user_id: i64, refresh_token: String) -> Self {
+        Self {
+            tokens: Arc::new(TokenSource::User {
+                oauth,
+                user_id,
+                refresh_token,
+            }),
+            ..self.clone()
+        }
+    }
+
+    /// Whether requests are made as a user rather than the service account.
+    pub fn is_user(&self) -> bool {
+        matches!(*self.tokens, TokenSource::User { .. })
+    }
+
+    /// The address users must share their spreadsheet with.
+    pub fn service_account_email(&self) -> &str {
+        &self.service_account
+    }
+
+    /// Checks that the spreadsheet exists and that the service account can
+    /// edit it. Edit access is proven by writing the current title back,
+    /// which changes nothing. Errors other than access problems (quota,
+    /// outages) are returned as `Err`.
+    pub async fn verify_access(&self, sheet_id: &str) -> Result<SheetAccess> {
+        let request = self
+            .http
+            .get(format!("{}/{sheet_id}", self.sheets_api))
+            .query(&[("fields", "properties.title")]);
+        let spreadsheet = match self.send(request).await {
+            Ok(spreadsheet) => spreadsheet,
+            Err(err) => return access_problem(&err, SheetAccess::NotShared).ok_or(err),
+        };
+
+        let title = spreadsheet["properties"]["title"].clone();
+        let touch = json!({ "updateSpreadsheetProperties": {
+            "properties": { "title": title },
+            "fields": "title"
+        } });
+        match self.batch_update(sheet_id, vec![touch]).await {
+            Ok(_) => Ok(SheetAccess::Writable),
+            Err(err) => access_problem(&err, SheetAccess::ReadOnly).ok_or(err),
+        }
+    }
+
+    /// Writes each log to the row carrying its ID in the log ID column, or
+    /// appends it when no such row exists, so retries and reprocessing never
+    /// duplicate rows. With tab rotation each log goes to its period's tab,
+    /// which is created on first use; a row left in another period's tab by
+    /// a date edit is removed.
+    pub async fn upsert_logs(
+        &self,
+        sheet_id: &str,
+        mapping: &SheetMapping,
+        logs: &[LogEntry],
+    ) -> Result<()> {
+        let mut index = self.index(sheet_id, mapping).await?;
+        let mut updates = Vec::new();
+        let mut appends: HashMap<String, Vec<Vec<Value>>> = HashMap::new();
+        let mut stale = Vec::new();
+        for log in logs {
+            let tab = mapping.tab_for(log.order_date);
+            let target = mapping.with_tab(&tab);
+            let mut found = None;
+            for (row_tab, row) in index.rows.get(&log.id).into_iter().flatten() {
+                if *row_tab == tab && found.is_none() {
+                    found = Some(*row);
+                } else {
+                    stale.push((row_tab.clone(), *row));
+                }
+            }
+            match found {
+                Some(row) => updates.push(json!({
+                    "range": target.row_range(row),
+                    "values": [target.row(log)]
+                })),
+                None => appends.entry(tab).or_default().push(target.row(log)),
+            }
+        }
+
+        if !updates.is_empty() {
+            let request = self
+                .http
+                .post(format!("{}/{sheet_id}/values:batchUpdate", self.sheets_api))
+                .json(&json!({ "valueInputOption": "USER_ENTERED", "data": updates }));
+            self.send(request)
+                .await
+                .context("Failed to update rows in Sheets API")?;
+        }
+        for (tab, rows) in appends {
+            let target = mapping.with_tab(&tab);
+            if !index.tabs.contains_key(&tab) {
+                self.bootstrap(sheet_id, &target).await?;
+                index.tabs = self.tabs(sheet_id).await?;
+            }
+            self.append_rows(sheet_id, &target.range(), &rows).await?;
+        }
+        self.delete_rows(sheet_id, &index, stale).await
+    }
+
+    /// Deletes every row keyed to one of `log_ids`. Logs that were never
+    /// written are ignored.
+    pub async fn delete_logs(
+        &self,
+        sheet_id: &str,
+        mapping: &SheetMapping,
+        log_ids: &[i64],
+    ) -> Result<()> {
+        let index = self.index(sheet_id, mapping).await?;
+        let doomed = log_ids
+            .iter()
+            .filter_map(|id| index.rows.get(id))
+            .flatten()
+            .cloned()
+            .collect();
+        self.delete_rows(sheet_id, &index, doomed).await
+    }
+
+    /// Every data row that carries a log ID in the tabs the mapping writes
+    /// to, as `(log id, mapped cells)`. Numbers are read unformatted and
+    /// dates as displayed.
+    pub async fn read_logs(
+        &self,
+        sheet_id: &str,
+        mapping: &SheetMapping,
+    ) -> Result<Vec<(i64, Vec<Value>)>> {
+        let tabs = self.tabs(sheet_id).await?;
+        let mut written: Vec<&String> = tabs.keys().filter(|tab| mapping.writes_to(tab)).collect();
+        if written.is_empty() {
+            return Ok(Vec::new());
+        }
+        written.sort();
+        let mut query: Vec<(&str, String)> = written
+            .iter()
+            .map(|tab| ("ranges", mapping.with_tab(tab).data_range()))
+            .collect();
+        query.push(("valueRenderOption", "UNFORMATTED_VALUE".to_string()));
+        query.push(("dateTimeRenderOption", "FORMATTED_STRING".to_string()));
+        let request = self
+            .http
+            .get(format!("{}/{sheet_id}/values:batchGet", self.sheets_api))
+            .query(&query);
+        let ranges = self
+            .send(request)
             .await
-            .context("Failed to obtain OAuth token for Sheets API")?;
+            .context("Failed to read sheet rows")?;
+
+        let width = mapping.width();
+        let rows = ranges["valueRanges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|range| range["values"].as_array())
+            .flatten()
+            .filter_map(|row| {
+                let mut cells = row.as_array()?.clone();
+                cells.resize(width, Value::Null);
+                Some((cell_log_id(&cells[width - 1])?, cells))
+            })
+            .collect();
+        Ok(rows)
+    }
 
+    /// Clears the data rows of every tab the mapping writes to.
+    pub async fn clear_logs(&self, sheet_id: &str, mapping: &SheetMapping) -> Result<()> {
+        let tabs = self.tabs(sheet_id).await?;
+        for tab in tabs.keys().filter(|tab| mapping.writes_to(tab)) {
+            self.clear(sheet_id, &mapping.with_tab(tab).data_range())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_rows(
+        &self,
+        sheet_id: &str,
+        index: &SheetIndex,
+        mut rows: Vec<(String, usize)>,
+    ) -> Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        // Bottom-up within each tab so earlier deletions do not shift later rows.
+        rows.sort_unstable_by(|a, b| b.cmp(a));
+        rows.dedup();
+        let requests = rows
+            .into_iter()
+            .filter_map(|(tab, row)| {
+                let tab_id = index.tabs.get(&tab)?;
+                Some(json!({ "deleteDimension": { "range": {
+                    "sheetId": tab_id,
+                    "dimension": "ROWS",
+                    "startIndex": row - 1,
+                    "endIndex": row
+                } } }))
+            })
+            .collect();
+        self.batch_update(sheet_id, requests)
+            .await
+            .context("Failed to delete rows in Sheets API")?;
+        Ok(())
+    }
+
+    /// Reads the log ID column of every tab the mapping writes to in one
+    /// `values:batchGet`.
+    async fn index(&self, sheet_id: &str, mapping: &SheetMapping) -> Result<SheetIndex> {
+        let tabs = self.tabs(sheet_id).await?;
+        let mut written: Vec<&String> = tabs.keys().filter(|tab| mapping.writes_to(tab)).collect();
+        written.sort();
+        let mut index = SheetIndex {
+            rows: HashMap::new(),
+            tabs: HashMap::new(),
+        };
+        if !written.is_empty() {
+            let mut query: Vec<(&str, String)> = written
+                .iter()
+                .map(|tab| ("ranges", mapping.with_tab(tab).id_range()))
+                .collect();
+            query.push(("majorDimension", "COLUMNS".to_string()));
+            let request = self
+                .http
+                .get(format!("{}/{sheet_id}/values:batchGet", self.sheets_api))
+                .query(&query);
+            let columns = self
+                .send(request)
+                .await
+                .context("Failed to read log ID columns")?;
+            for (tab, range) in written
+                .iter()
+                .zip(columns["valueRanges"].as_array().into_iter().flatten())
+            {
+                for (id, rows) in index_log_ids(&range["values"][0]) {
+                    let entry = index.rows.entry(id).or_default();
+                    entry.extend(rows.into_iter().map(|row| ((*tab).clone(), row)));
+                }
+            }
+        }
+        index.tabs = tabs;
+        Ok(index)
+    }
+
+    /// Appends several rows in one `values:append` call.
+    pub async fn append_rows(
+        &self,
+        sheet_id: &str,
+        range: &str,
+        rows: &[Vec<serde_json::Value>],
+    ) -> Result<()> {
+        let url = format!(
+            "{}/{}/values/{}:append",
+            self.sheets_api,
+            sheet_id,
+            urlencoding(range)
+        );
+        let request = self
+            .http
+            .post(url)
+            .query(&[("valueInputOption", "USER_ENTERED")])
+            .json(&json!({ "values": rows }));
+        self.send(request)
+            .await
+            .context("Failed to append rows to Sheets API")?;
+        Ok(())
+    }
+
+    /// Replaces the contents of `tab` with `rows` starting at A1. A missing
+    /// tab is created; an existing one is only overwritten when its id is
+    /// `owned`, so a user's own tab with the same title is never cleared.
+    /// Returns the id of the tab written, or `None` when it was left alone.
+    pub async fn write_table(
+        &self,
+        sheet_id: &str,
+        tab: &str,
+        owned: Option<i64>,
+        rows: &[Vec<Value>],
+    ) -> Result<Option<i64>> {
+        let tab_id = match self.tab_id(sheet_id, tab).await? {
+            Some(id) if Some(id) == owned => id,
+            Some(_) => return Ok(None),
+            None => {
+                let reply = self
+                    .batch_update(
+                        sheet_id,
+                        vec![json!({ "addSheet": { "properties": { "title": tab } } })],
+                    )
+                    .await
+                    .with_context(|| format!("Failed to create tab {tab:?}"))?;
+                reply["replies"][0]["addSheet"]["properties"]["sheetId"]
+                    .as_i64()
+                    .ok_or_else(|| anyhow!("Sheets API did not return the new tab id"))?
+            }
+        };
+        let quoted = quote_tab(tab);
+        self.clear(sheet_id, &format!("{quoted}!A:Z")).await?;
+        let url = format!(
+            "{}/{}/values/{}",
+            self.sheets_api,
+            sheet_id,
+            urlencoding(&format!("{quoted}!A1"))
+        );
+        let request = self
+            .http
+            .put(url)
+            .query(&[("valueInputOption", "RAW")])
+            .json(&json!({ "values": rows }));
+        self.send(request)
+            .await
+            .with_context(|| format!("Failed to write tab {tab:?}"))?;
+        Ok(Some(tab_id))
+    }
+
+    /// Clears values (not formatting) in `range`.
+    pub async fn clear(&self, sheet_id: &str, range: &str) -> Result<()> {
         let url = format!(
-            "https://sheets.googleapis.com/v4/spreadsheets/{}/values/Sheet1!A:D:append",
-            sheet_id
+            "{}/{}/values/{}:clear",
+            self.sheets_api,
+            sheet_id,
+            urlencoding(range)
         );
+        self.send(self.http.post(url).json(&json!({})))
+            .await
+            .context("Failed to clear sheet range")?;
+        Ok(())
+    }
 
-        let body = json!({
-            "values": [values]
-        });
+    /// Creates a spreadsheet from our template: the mapping's data tab with
+    /// headers and formats, plus a summary tab of totals. A spreadsheet
+    /// created by the service account is owned by it, so it is shared with
+    /// `editor` afterwards.
+    pub async fn create_spreadsheet(
+        &self,
+        title: &str,
+        mapping: &SheetMapping,
+        editor: Option<&str>,
+    ) -> Result<CreatedSpreadsheet> {
+        let request = self.http.post(&self.sheets_api).json(&json!({
+            "properties": { "title": title },
+            "sheets": [
+                { "properties": { "title": mapping.tab } },
+                { "properties": { "title": SUMMARY_TAB } }
+            ]
+        }));
+        let created = self
+            .send(request)
+            .await
+            .context("Failed to create spreadsheet")?;
+        let sheet_id = created["spreadsheetId"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Sheets API did not return a spreadsheet id"))?
+            .to_string();
+        let url = created["spreadsheetUrl"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("https://docs.google.com/spreadsheets/d/{sheet_id}/edit"));
 
-        let response = self
+        self.bootstrap(&sheet_id, mapping).await?;
+        let summary_url = format!(
+            "{}/{}/values/{}",
+            self.sheets_api,
+            sheet_id,
+            urlencoding(&format!("{}!A1", quote_tab(SUMMARY_TAB)))
+        );
+        let request = self
             .http
-            .post(url)
-            .bearer_auth(
-                token
-                    .token()
-                    .ok_or_else(|| anyhow!("Missing token string"))?,
-            )
+            .put(summary_url)
             .query(&[("valueInputOption", "USER_ENTERED")])
-            .json(&body)
+            .json(&json!({ "values": summary_rows(mapping) }));
+        self.send(request)
+            .await
+            .context("Failed to write summary tab")?;
+        if let Some(editor) = editor {
+            self.share(&sheet_id, editor).await?;
+        }
+
+        Ok(CreatedSpreadsheet { sheet_id, url })
+    }
+
+    /// Grants `email` edit access through Drive. Ownership cannot be moved
+    /// from a service account to a consumer account, so users are editors.
+    async fn share(&self, sheet_id: &str, email: &str) -> Result<()> {
+        let request = self
+            .http
+            .post(format!("{}/files/{sheet_id}/permissions", self.drive_api))
+            .query(&[("sendNotificationEmail", "true")])
+            .json(&json!({ "type": "user", "role": "writer", "emailAddress": email }));
+        self.send(request)
+            .await
+            .with_context(|| format!("Failed to share spreadsheet with {email}"))?;
+        Ok(())
+    }
+
+    /// Prepares a sheet for appends: creates the mapping's tab if missing,
+    /// writes the header row when row 1 is empty or lacks trailing columns,
+    /// freezes it and applies number
+    /// formats to the mapped columns. Every step is idempotent, so this is run
+    /// again whenever the sheet or mapping changes. A rotating mapping
+    /// bootstraps the current period's tab.
+    pub async fn bootstrap(&self, sheet_id: &str, mapping: &SheetMapping) -> Result<()> {
+        let current;
+        let mapping = if mapping.rotation == TabRotation::None {
+            mapping
+        } else {
+            current = mapping.with_tab(&mapping.tab_for(Utc::now().date_naive()));
+            &current
+        };
+        let tab_id = match self.tab_id(sheet_id, &mapping.tab).await? {
+            Some(id) => id,
+            None => {
+                let reply = self
+                    .batch_update(
+                        sheet_id,
+                        vec![json!({ "addSheet": { "properties": { "title": mapping.tab } } })],
+                    )
+                    .await
+                    .with_context(|| format!("Failed to create tab {:?}", mapping.tab))?;
+                reply["replies"][0]["addSheet"]["properties"]["sheetId"]
+                    .as_i64()
+                    .ok_or_else(|| anyhow!("Sheets API did not return the new tab id"))?
+            }
+        };
+
+        let header_url = format!(
+            "{}/{}/values/{}",
+            self.sheets_api,
+            sheet_id,
+            urlencoding(&mapping.header_range())
+        );
+        let current = self
+            .send(self.http.get(&header_url))
+            .await
+            .context("Failed to read header row")?;
+        let headers = mapping.headers();
+        let existing: Vec<&str> = current["values"][0]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|cell| cell.as_str().unwrap_or_default())
+            .collect();
+        if existing.len() < headers.len() && existing.iter().zip(&headers).all(|(a, b)| a == b) {
+            // Empty, or written before newer columns such as the log ID.
+            let request = self
+                .http
+                .put(&header_url)
+                .query(&[("valueInputOption", "RAW")])
+                .json(&json!({ "values": [headers] }));
+            self.send(request)
+                .await
+                .context("Failed to write header row")?;
+        } else if existing != headers {
+            warn!("Sheet {sheet_id} row 1 differs from the mapping headers; leaving it");
+        }
+
+        self.batch_update(sheet_id, format_requests(tab_id, mapping))
+            .await
+            .context("Failed to apply sheet formatting")?;
+        Ok(())
+    }
+
+    async fn tab_id(&self, sheet_id: &str, title: &str) -> Result<Option<i64>> {
+        Ok(self.tabs(sheet_id).await?.get(title).copied())
+    }
+
+    /// Tab ids by title.
+    async fn tabs(&self, sheet_id: &str) -> Result<HashMap<String, i64>> {
+        let request = self
+            .http
+            .get(format!("{}/{sheet_id}", self.sheets_api))
+            .query(&[("fields", "sheets.properties(sheetId,title)")]);
+        let spreadsheet = self
+            .send(request)
+            .await
+            .context("Failed to read spreadsheet metadata")?;
+        Ok(list_tabs(&spreadsheet))
+    }
+
+    async fn batch_update(&self, sheet_id: &str, requests: Vec<Value>) -> Result<Value> {
+        let request = self
+            .http
+            .post(format!("{}/{sheet_id}:batchUpdate", self.sheets_api))
+            .json(&json!({ "requests": requests }));
+        self.send(request).await
+    }
+
+    /// Authenticates `request`, sends it and returns the JSON body.
+    async fn send(&self, request: RequestBuilder) -> Result<Value> {
+        self.limiter.acquire().await;
+        let token = self.tokens.token().await?;
+        let response = request
+            .bearer_auth(token)
             .send()
             .await
-            .context("Failed to send append request to Sheets API")?;
+            .context("Failed to send request to Sheets API")?;
 
-        if !response.status().is_success() {
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                let pause = retry_after.unwrap_or(QUOTA_PAUSE_SECS);
+                warn!("Sheets API quota exceeded; pausing requests for {pause}s");
+                self.limiter.pause(Duration::from_secs(pause));
+            }
             let text = response
                 .text()
                 .await
                 .unwrap_or_else(|_| "<empty>".to_string());
-            return Err(anyhow!("Sheets API error: {}", text));
+            return Err(SheetsApiError {
+                status: status.as_u16(),
+                message: text,
+                retry_after,
+            }
+            .into());
+        }
+        Ok(response.json().await.unwrap_or(Value::Null))
+    }
+}
+
+/// Label/formula rows for the summary tab of a created spreadsheet; only
+/// fields the mapping writes get a total.
+fn summary_rows(mapping: &SheetMapping) -> Vec<Vec<Value>> {
+    let tab = quote_tab(&mapping.tab);
+    let mut rows = vec![vec![json!("Total"), json!("Value")]];
+    for (label, field) in [
+        ("Gross", SheetField::Gross),
+        ("Tips", SheetField::Tips),
+        ("Mileage", SheetField::Mileage),
+    ] {
+        if let Some(column) = mapping.column_of(field) {
+            rows.push(vec![
+                json!(label),
+                json!(format!("=SUM({tab}!{column}2:{column})")),
+            ]);
+        }
+    }
+    let (_, id_column) = mapping.columns();
+    rows.push(vec![
+        json!("Payouts logged"),
+        json!(format!("=COUNT({tab}!{id_column}2:{id_column})")),
+    ]);
+    rows
+}
+
+/// Maps log IDs to the rows holding them; a value that is not an ID (the
+/// header, blank or hand-typed cells) is skipped.
+fn index_log_ids(column: &Value) -> HashMap<i64, Vec<usize>> {
+    let mut rows: HashMap<i64, Vec<usize>> = HashMap::new();
+    for (index, cell) in column.as_array().into_iter().flatten().enumerate() {
+        if let Some(id) = cell_log_id(cell) {
+            rows.entry(id).or_default().push(index + 1);
+        }
+    }
+    rows
+}
+
+/// A log ID read back from the ID column, as a number or text.
+pub fn cell_log_id(cell: &Value) -> Option<i64> {
+    match cell {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn list_tabs(spreadsheet: &Value) -> HashMap<String, i64> {
+    spreadsheet["sheets"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|sheet| &sheet["properties"])
+        .filter_map(|properties| {
+            Some((
+                properties["title"].as_str()?.to_string(),
+                properties["sheetId"].as_i64()?,
+            ))
+        })
+        .collect()
+}
+
+/// batchUpdate requests that freeze and bold the header row and set number
+/// formats on the mapped data columns.
+fn format_requests(tab_id: i64, mapping: &SheetMapping) -> Vec<Value> {
+    let start = column_index(&mapping.start_column).unwrap_or(0);
+    let end = start + mapping.width();
+    let mut requests = vec![
+        json!({
+            "updateSheetProperties": {
+                "properties": { "sheetId": tab_id, "gridProperties": { "frozenRowCount": 1 } },
+                "fields": "gridProperties.frozenRowCount"
+            }
+        }),
+        json!({
+            "repeatCell": {
+                "range": {
+                    "sheetId": tab_id,
+                    "startRowIndex": 0,
+                    "endRowIndex": 1,
+                    "startColumnIndex": start,
+                    "endColumnIndex": end
+                },
+                "cell": { "userEnteredFormat": { "textFormat": { "bold": true } } },
+                "fields": "userEnteredFormat.textFormat.bold"
+            }
+        }),
+    ];
+    for (offset, field) in mapping.fields.iter().enumerate() {
+        let format = match field {
+            SheetField::Date => json!({
+                "type": "DATE",
+                "pattern": date_pattern(&mapping.date_format)
+            }),
+            // Logs carry their own currency, so amounts get no symbol; the
+            // `currency` field shows it.
+            SheetField::Gross | SheetField::Tips => json!({
+                "type": "NUMBER",
+                "pattern": "#,##0.00"
+            }),
+            SheetField::Mileage => json!({ "type": "NUMBER", "pattern": "#,##0.0" }),
+            SheetField::Platform | SheetField::Currency => continue,
+        };
+        requests.push(json!({
+            "repeatCell": {
+                "range": {
+                    "sheetId": tab_id,
+                    "startRowIndex": 1,
+                    "startColumnIndex": start + offset,
+                    "endColumnIndex": start + offset + 1
+                },
+                "cell": { "userEnteredFormat": { "numberFormat": format } },
+                "fields": "userEnteredFormat.numberFormat"
+            }
+        }));
+    }
+    requests
+}
+
+/// Translates the strftime date format into a Sheets date pattern so the
+/// displayed value matches what was written. Formats that have no
+/// equivalent fall back to ISO dates.
+fn date_pattern(format: &str) -> String {
+    let mut pattern = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            if c.is_ascii_alphanumeric() {
+                return "yyyy-mm-dd".to_string();
+            }
+            pattern.push(c);
+            continue;
         }
+        let token = match chars.next() {
+            Some('-') => match chars.next() {
+                Some('m') => "m",
+                Some('d') => "d",
+                _ => return "yyyy-mm-dd".to_string(),
+            },
+            Some('Y') => "yyyy",
+            Some('y') => "yy",
+            Some('m') => "mm",
+            Some('d') => "dd",
+            Some('e') => "d",
+            Some('b') => "mmm",
+            Some('B') => "mmmm",
+            Some('a') => "ddd",
+            Some('A') => "dddd",
+            _ => return "yyyy-mm-dd".to_string(),
+        };
+        pattern.push_str(token);
+    }
+    pattern
+}
 
+impl SheetMapping {
+    /// Checks a mapping submitted through the API; the message is shown to
+    /// the user.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tab.trim().is_empty() || self.tab.len() > 100 {
+            return Err("tab must be 1-100 characters".to_string());
+        }
+        if summary::TABS
+            .iter()
+            .any(|tab| tab.eq_ignore_ascii_case(self.tab.trim()))
+        {
+            return Err(format!("tab {:?} is reserved for summaries", self.tab));
+        }
+        let Some(start) = column_index(&self.start_column) else {
+            return Err("startColumn must be a column letter such as A or AB".to_string());
+        };
+        if self.fields.is_empty() {
+            return Err("fields must contain at least one field".to_string());
+        }
+        for (i, field) in self.fields.iter().enumerate() {
+            if self.fields[..i].contains(field) {
+                return Err(format!("field {field:?} appears more than once"));
+            }
+        }
+        if start + self.width() > MAX_COLUMNS {
+            return Err("mapping runs past the last sheet column".to_string());
+        }
+        if self
+            .extras
+            .iter()
+            .any(|extra| extra.header.trim().is_empty())
+        {
+            return Err("every extra column needs a header".to_string());
+        }
+        if self.date_format.is_empty()
+            || StrftimeItems::new(&self.date_format).any(|item| matches!(item, Item::Error))
+        {
+            return Err("dateFormat is not a valid strftime pattern".to_string());
+        }
         Ok(())
     }
+
+    /// Tab a log dated `date` is written to.
+    pub fn tab_for(&self, date: NaiveDate) -> String {
+        match self.rotation {
+            TabRotation::None => self.tab.clone(),
+            TabRotation::Monthly => date.format("%Y-%m").to_string(),
+            TabRotation::Yearly => date.format("%Y").to_string(),
+        }
+    }
+
+    /// Whether rows for this mapping can live in `tab`: the mapping's tab, or
+    /// any period tab when rotating.
+    pub fn writes_to(&self, tab: &str) -> bool {
+        let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        match self.rotation {
+            TabRotation::None => tab == self.tab,
+            TabRotation::Monthly => matches!(
+                tab.split_once('-'),
+                Some((year, month)) if year.len() == 4 && month.len() == 2 && digits(year) && digits(month)
+            ),
+            TabRotation::Yearly => tab.len() == 4 && digits(tab),
+        }
+    }
+
+    /// The same layout written to `tab`.
+    pub fn with_tab(&self, tab: &str) -> SheetMapping {
+        SheetMapping {
+            tab: tab.to_string(),
+            rotation: TabRotation::None,
+            ..self.clone()
+        }
+    }
+
+    /// Mapped fields and extras plus the trailing log ID column.
+    pub fn width(&self) -> usize {
+        self.fields.len() + self.extras.len() + 1
+    }
+
+    /// First and last mapped column names.
+    fn columns(&self) -> (String, String) {
+        let start = column_index(&self.start_column).unwrap_or(0);
+        (column_name(start), column_name(start + self.width() - 1))
+    }
+
+    /// Zero-based index of the first mapped column.
+    pub fn start_index(&self) -> usize {
+        column_index(&self.start_column).unwrap_or(0)
+    }
+
+    /// The mapped columns of rows `first..=last` (1-based) without a tab,
+    /// e.g. `C2:H9`, as Excel ranges are addressed within a worksheet.
+    pub fn address(&self, first: usize, last: usize) -> String {
+        let (first_column, last_column) = self.columns();
+        format!("{first_column}{first}:{last_column}{last}")
+    }
+
+    /// A1 range covering the mapped columns, e.g. `'Trips 2024'!C:H`.
+    pub fn range(&self) -> String {
+        let (first, last) = self.columns();
+        format!("{}!{first}:{last}", quote_tab(&self.tab))
+    }
+
+    /// Everything below the header row, e.g. `'Trips 2024'!C2:H`.
+    pub fn data_range(&self) -> String {
+        let (first, last) = self.columns();
+        format!("{}!{first}2:{last}", quote_tab(&self.tab))
+    }
+
+    /// The header row only, e.g. `'Trips 2024'!C1:H1`.
+    pub fn header_range(&self) -> String {
+        self.row_range(1)
+    }
+
+    /// One sheet row (1-based) of the mapped columns.
+    pub fn row_range(&self, row: usize) -> String {
+        let (first, last) = self.columns();
+        format!("{}!{first}{row}:{last}{row}", quote_tab(&self.tab))
+    }
+
+    /// Column name holding `field`, if the mapping includes it.
+    pub fn column_of(&self, field: SheetField) -> Option<String> {
+        let start = column_index(&self.start_column).unwrap_or(0);
+        let offset = self.fields.iter().position(|f| *f == field)?;
+        Some(column_name(start + offset))
+    }
+
+    /// The log ID column, e.g. `'Trips 2024'!H:H`.
+    pub fn id_range(&self) -> String {
+        let (_, last) = self.columns();
+        format!("{}!{last}:{last}", quote_tab(&self.tab))
+    }
+
+    pub fn headers(&self) -> Vec<String> {
+        self.fields
+            .iter()
+            .map(|field| {
+                match field {
+                    SheetField::Date => "Date",
+                    SheetField::Gross => "Gross",
+                    SheetField::Tips => "Tips",
+                    SheetField::Mileage => "Mileage",
+                    SheetField::Platform => "Platform",
+                    SheetField::Currency => "Currency",
+                }
+                .to_string()
+            })
+            .chain(self.extras.iter().map(|extra| extra.header.clone()))
+            .chain([LOG_ID_HEADER.to_string()])
+            .collect()
+    }
+
+    pub fn row(&self, entry: &LogEntry) -> Vec<Value> {
+        self.fields
+            .iter()
+            .map(|field| match field {
+                SheetField::Date => json!(entry.order_date.format(&self.date_format).to_string()),
+                SheetField::Gross => json!(entry.gross.to_f64()),
+                SheetField::Tips => json!(entry.tips.to_f64()),
+                SheetField::Mileage => json!(entry.mileage),
+                SheetField::Platform => json!(entry.platform),
+                SheetField::Currency => json!(entry.currency),
+            })
+            .chain(self.extras.iter().map(|extra| json!(extra.value)))
+            .chain([json!(entry.id)])
+            .collect()
+    }
+}
+
+/// Header of the trailing column that keys each row to `logs.id`.
+const LOG_ID_HEADER: &str = "Log ID";
+
+/// Pause after a 429 without `Retry-After`; Sheets quotas are per minute.
+const QUOTA_PAUSE_SECS: u64 = 60;
+
+/// Sheets allows up to 18,278 columns (`ZZZ`).
+const MAX_COLUMNS: usize = 18_278;
+
+/// Zero-based index of a column name: `A` is 0, `AA` is 26.
+fn column_index(name: &str) -> Option<usize> {
+    if name.is_empty() || name.len() > 3 || !name.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let index = name
+        .to_ascii_uppercase()
+        .bytes()
+        .fold(0usize, |acc, b| acc * 26 + usize::from(b - b'A' + 1));
+    Some(index - 1)
+}
+
+fn column_name(mut index: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'A' + (index % 26) as u8);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    name.reverse();
+    String::from_utf8(name).expect("column names are ASCII")
+}
+
+/// Tab names are always quoted so spaces and digits are safe; embedded
+/// quotes are doubled per the A1 notation rules.
+fn quote_tab(tab: &str) -> String {
+    format!("'{}'", tab.replace('\'', "''"))
+}
+
+fn urlencoding(range: &str) -> String {
+    range
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'!' | b':' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SheetExtra;
+    use crate::money::{Cents, DEFAULT_CURRENCY};
+    use sqlx::types::Json;
+
+    fn entry() -> LogEntry {
+        LogEntry {
+            id: 42,
+            user_id: 1,
+            order_date: NaiveDate::from_ymd_opt(2024, 8, 15).unwrap(),
+            gross: Cents(123456),
+            tips: Cents(7890),
+            currency: DEFAULT_CURRENCY.to_string(),
+            mileage: None,
+            platform: Some("Uber".to_string()),
+            parsed_at: NaiveDate::from_ymd_opt(2024, 8, 16)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            sync_status: None,
+            sync_error: None,
+        }
+    }
+
+    #[test]
+    fn default_mapping_keeps_legacy_columns_and_adds_log_id() {
+        let mapping = SheetMapping::default();
+        assert_eq!(mapping.range(), "'Sheet1'!A:E");
+        assert_eq!(mapping.id_range(), "'Sheet1'!E:E");
+        assert_eq!(
+            mapping.row(&entry()),
+            vec![
+                json!("2024-08-15"),
+                json!(1234.56),
+                json!(78.9),
+                Value::Null,
+                json!(42)
+            ]
+        );
+    }
+
+    #[test]
+    fn currency_field_writes_the_log_currency() {
+        let mapping = SheetMapping {
+            fields: Json(vec![SheetField::Gross, SheetField::Currency]),
+            ..SheetMapping::default()
+        };
+        assert_eq!(mapping.headers(), ["Gross", "Currency", "Log ID"]);
+        assert_eq!(
+            mapping.row(&entry()),
+            vec![json!(1234.56), json!("USD"), json!(42)]
+        );
+        assert_eq!(format_requests(7, &mapping).len(), 2 + 1);
+    }
+
+    #[test]
+    fn custom_mapping_orders_fields_and_appends_extras() {
+        let mapping = SheetMapping {
+            tab: "Driver's Ledger".to_string(),
+            start_column: "c".to_string(),
+            fields: Json(vec![SheetField::Tips, SheetField::Date]),
+            extras: Json(vec![SheetExtra {
+                header: "Platform".to_string(),
+                value: "Uber".to_string(),
+            }]),
+            date_format: "%m/%d/%Y".to_string(),
+            rotation: TabRotation::None,
+        };
+        assert!(mapping.validate().is_ok());
+        assert_eq!(mapping.range(), "'Driver''s Ledger'!C:F");
+        assert_eq!(mapping.header_range(), "'Driver''s Ledger'!C1:F1");
+        assert_eq!(mapping.data_range(), "'Driver''s Ledger'!C2:F");
+        assert_eq!(mapping.row_range(7), "'Driver''s Ledger'!C7:F7");
+        assert_eq!(mapping.headers(), ["Tips", "Date", "Platform", "Log ID"]);
+        assert_eq!(
+            mapping.row(&entry()),
+            vec![json!(78.9), json!("08/15/2024"), json!("Uber"), json!(42)]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_bad_mappings() {
+        let bad = |f: fn(&mut SheetMapping)| {
+            let mut mapping = SheetMapping::default();
+            f(&mut mapping);
+            mapping.validate().is_err()
+        };
+        assert!(bad(|m| m.tab = " ".to_string()));
+        assert!(bad(|m| m.start_column = "A1".to_string()));
+        assert!(bad(|m| m.fields = Json(vec![])));
+        assert!(bad(
+            |m| m.fields = Json(vec![SheetField::Tips, SheetField::Tips])
+        ));
+        assert!(bad(|m| m.date_format = "%Q".to_string()));
+        assert!(bad(|m| m.start_column = "ZZZ".to_string()));
+        assert!(bad(|m| m.tab = "driversheet weekly".to_string()));
+    }
+
+    #[test]
+    fn column_names_round_trip() {
+        for (index, name) in [(0, "A"), (25, "Z"), (26, "AA"), (701, "ZZ"), (702, "AAA")] {
+            assert_eq!(column_name(index), name);
+            assert_eq!(column_index(name), Some(index));
+        }
+    }
+
+    #[test]
+    fn format_requests_cover_mapped_columns() {
+        let mapping = SheetMapping {
+            start_column: "B".to_string(),
+            ..SheetMapping::default()
+        };
+        let requests = format_requests(7, &mapping);
+
+        assert_eq!(requests.len(), 2 + 4);
+        assert_eq!(
+            requests[0]["updateSheetProperties"]["properties"]["gridProperties"]["frozenRowCount"],
+            1
+        );
+        assert_eq!(requests[1]["repeatCell"]["range"]["endColumnIndex"], 6);
+        let date = &requests[2]["repeatCell"];
+        assert_eq!(date["range"]["startColumnIndex"], 1);
+        assert_eq!(
+            date["cell"]["userEnteredFormat"]["numberFormat"]["pattern"],
+            "yyyy-mm-dd"
+        );
+        let gross = &requests[3]["repeatCell"]["cell"]["userEnteredFormat"]["numberFormat"];
+        assert_eq!(gross["pattern"], "#,##0.00");
+    }
+
+    #[test]
+    fn date_pattern_translates_common_formats() {
+        assert_eq!(date_pattern("%m/%d/%Y"), "mm/dd/yyyy");
+        assert_eq!(date_pattern("%-d %b %y"), "d mmm yy");
+        assert_eq!(date_pattern("week %W"), "yyyy-mm-dd");
+    }
+
+    #[test]
+    fn list_tabs_maps_titles_to_ids() {
+        let spreadsheet = json!({ "sheets": [
+            { "properties": { "sheetId": 0, "title": "Sheet1" } },
+            { "properties": { "sheetId": 91, "title": "Trips" } }
+        ] });
+        let tabs = list_tabs(&spreadsheet);
+        assert_eq!(tabs.get("Trips"), Some(&91));
+        assert_eq!(tabs.get("trips"), None);
+    }
+
+    #[test]
+    fn rotation_picks_period_tabs() {
+        let date = NaiveDate::from_ymd_opt(2024, 8, 15).unwrap();
+        let mut mapping = SheetMapping::default();
+        assert_eq!(mapping.tab_for(date), mapping.tab);
+        assert!(mapping.writes_to(&mapping.tab.clone()));
+        assert!(!mapping.writes_to("2024-08"));
+
+        mapping.rotation = TabRotation::Monthly;
+        assert_eq!(mapping.tab_for(date), "2024-08");
+        assert!(mapping.writes_to("2023-12"));
+        assert!(!mapping.writes_to("2024"));
+        assert!(!mapping.writes_to("Summary"));
+
+        mapping.rotation = TabRotation::Yearly;
+        assert_eq!(mapping.tab_for(date), "2024");
+        assert!(mapping.writes_to("2023"));
+        assert!(!mapping.writes_to("2024-08"));
+        assert_eq!(mapping.with_tab("2024").rotation, TabRotation::None);
+    }
+
+    #[test]
+    fn permanent_errors_are_access_and_request_problems() {
+        let api = |status| -> anyhow::Error {
+            anyhow::Error::new(SheetsApiError {
+                status,
+                message: String::new(),
+                retry_after: None,
+            })
+            .context("Failed to append row to Sheets API")
+        };
+        assert!(SheetsApiError::permanent(&api(403)).is_some());
+        assert!(SheetsApiError::permanent(&api(404)).is_some());
+        assert!(SheetsApiError::permanent(&api(429)).is_none());
+        assert!(SheetsApiError::permanent(&api(503)).is_none());
+        assert!(SheetsApiError::permanent(&anyhow!("connection reset")).is_none());
+    }
+
+    #[test]
+    fn quota_errors_pause_for_retry_after() {
+        let quota = |retry_after| -> anyhow::Error {
+            anyhow::Error::new(SheetsApiError {
+                status: 429,
+                message: String::new(),
+                retry_after,
+            })
+            .context("Failed to update rows in Sheets API")
+        };
+        assert_eq!(
+            SheetsApiError::rate_limited(&quota(Some(7))),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(
+            SheetsApiError::rate_limited(&quota(None)),
+            Some(Duration::from_secs(QUOTA_PAUSE_SECS))
+        );
+        assert_eq!(SheetsApiError::rate_limited(&anyhow!("timeout")), None);
+    }
+
+    #[test]
+    fn index_log_ids_skips_header_and_blanks() {
+        let column = json!(["Log ID", "7", "", 9, "note", "7"]);
+        let rows = index_log_ids(&column);
+        assert_eq!(rows[&7], [2, 6]);
+        assert_eq!(rows[&9], [4]);
+        assert_eq!(rows.len(), 2);
+        assert!(index_log_ids(&Value::Null).is_empty());
+    }
+
+    #[test]
+    fn access_problem_depends_on_stage() {
+        let api = |status| -> anyhow::Error {
+            SheetsApiError {
+                status,
+                message: String::new(),
+                retry_after: None,
+            }
+            .into()
+        };
+        assert_eq!(
+            access_problem(&api(404), SheetAccess::NotShared),
+            Some(SheetAccess::NotFound)
+        );
+        assert_eq!(
+            access_problem(&api(403), SheetAccess::NotShared),
+            Some(SheetAccess::NotShared)
+        );
+        assert_eq!(
+            access_problem(&api(403), SheetAccess::ReadOnly),
+            Some(SheetAccess::ReadOnly)
+        );
+        assert_eq!(access_problem(&api(500), SheetAccess::ReadOnly), None);
+    }
+
+    #[test]
+    fn summary_rows_reference_mapped_columns() {
+        let mapping = SheetMapping {
+            start_column: "B".to_string(),
+            fields: Json(vec![SheetField::Date, SheetField::Tips, SheetField::Gross]),
+            ..SheetMapping::default()
+        };
+        let rows = summary_rows(&mapping);
+        assert_eq!(rows[1], [json!("Gross"), json!("=SUM('Sheet1'!D2:D)")]);
+        assert_eq!(rows[2], [json!("Tips"), json!("=SUM('Sheet1'!C2:C)")]);
+        assert_eq!(
+            rows[3],
+            [json!("Payouts logged"), json!("=COUNT('Sheet1'!E2:E)")]
+        );
+        assert_eq!(rows.len(), 4);
+    }
 }
@@ -0,0 +1,1024 @@
+//! In-process stand-in for the Sheets and Drive APIs, covering the calls
+//! `SheetsClient` makes. It keeps every spreadsheet in memory and records
+//! appended values, so tests can assert on the exact rows a flow writes. It
+//! also answers Google's and Microsoft's OAuth token endpoints for per-user
+//! grants, and the Graph workbook calls `ExcelClient` makes under `/graph`,
+//! where a workbook is a spreadsheet and its worksheets are tabs.
+
+use crate::config::AppConfig;
+use crate::crypto::SecretBox;
+use crate::db;
+use crate::excel::{ExcelClient, FILES_SCOPE};
+use crate::models::{LogEntry, NewLogEntry, User, UserUpsert};
+use crate::money::{Cents, DEFAULT_CURRENCY};
+use crate::oauth::OAuthClient;
+use crate::sheets::{SheetsClient, TokenSource, SHEETS_SCOPE};
+use crate::state::AppState;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub const MOCK_TOKEN: &str = "mock-token";
+pub const MOCK_SERVICE_ACCOUNT: &str = "sheets@mock.iam.gserviceaccount.com";
+const MOCK_MICROSOFT_CLIENT: &str = "mock-ms-client";
+
+#[derive(Clone, Default)]
+pub struct MockSheets {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    spreadsheets: HashMap<String, Spreadsheet>,
+    /// `(spreadsheet, range, values)` of every `values:append`, in order.
+    appends: Vec<(String, String, Vec<Vec<Value>>)>,
+    /// `(spreadsheet, email)` of every Drive permission granted.
+    shares: Vec<(String, String)>,
+    /// Bearer token of every Sheets/Drive request, in order.
+    bearers: Vec<String>,
+    /// Refresh tokens issued by the OAuth endpoint and whether they still work.
+    grants: HashMap<String, bool>,
+    /// Access tokens issued for user grants.
+    user_tokens: Vec<String>,
+    next_id: i64,
+}
+
+struct Spreadsheet {
+    title: String,
+    tabs: Vec<Tab>,
+}
+
+struct Tab {
+    id: i64,
+    title: String,
+    cells: Vec<Vec<Value>>,
+}
+
+impl MockSheets {
+    /// Serves the mock on an ephemeral local port and returns it with its
+    /// base URL, usable for both `sheets_url` and `drive_url`.
+    pub async fn start() -> (Self, String) {
+        let mock = Self::default();
+        let app = Router::new().fallback(handle).with_state(mock.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (mock, url)
+    }
+
+    /// A client for the mock at `url` with a static token and no practical
+    /// rate limit.
+    pub fn client(url: &str) -> SheetsClient {
+        SheetsClient::with_tokens(
+            TokenSource::Static(MOCK_TOKEN.to_string()),
+            MOCK_SERVICE_ACCOUNT,
+            url,
+            url,
+            60_000,
+        )
+        .unwrap()
+    }
+
+    /// App state over a fresh in-memory database whose Sheets and Graph
+    /// clients and OAuth endpoints are the mock at `url`.
+    pub async fn app_state(url: &str) -> AppState {
+        let pool = db::test_pool().await;
+        let config: AppConfig = serde_json::from_value(json!({
+            "database_url": "sqlite::memory:",
+            "google_sa_key": "",
+            "lemon_webhook_secret": "test",
+            "tmp_dir": std::env::temp_dir(),
+            "lemon_payment_url": "https://pay.invalid",
+            "credentials_key": null,
+            "sheet_sync_secs": 30,
+            "sheet_pull_secs": 0,
+            "webhook_delivery_secs": 30,
+            "summary_refresh_secs": 300,
+            "webhook_allow_private": true,
+            "imap_allow_private": true,
+            "backup_secs": 0,
+            "backup_dir": std::env::temp_dir(),
+            "backup_keep": 7,
+            "deletion_grace_days": 14,
+            "message_retention_days": 0,
+            "export_dir": std::env::temp_dir().join("driversheet-exports"),
+            "export_link_hours": 24,
+            "sheets_requests_per_min": 60_000,
+            "sheets_api_url": url,
+            "drive_api_url": url,
+            "sheets_access_token": MOCK_TOKEN,
+            "google_client_id": "mock-client",
+            "google_client_secret": "mock-secret",
+            "google_oauth_url": url,
+            "graph_api_url": format!("{url}/graph"),
+            "graph_requests_per_min": 60_000,
+            "microsoft_client_id": MOCK_MICROSOFT_CLIENT,
+            "microsoft_client_secret": "mock-ms-secret",
+            "microsoft_oauth_url": url,
+            "pdf_timeout_secs": 20,
+            "pdf_cpu_secs": 10,
+            "pdf_memory_mb": 512
+        }))
+        .unwrap();
+        let secrets = SecretBox::from_base64(&STANDARD.encode([7u8; 32])).unwrap();
+        let excel = ExcelClient::new(&config).unwrap();
+        let google_oauth = OAuthClient::google(&config).unwrap();
+        let microsoft_oauth = OAuthClient::microsoft(&config).unwrap();
+        AppState::new(
+            pool,
+            Self::client(url),
+            excel,
+            Some(secrets),
+            google_oauth,
+            microsoft_oauth,
+            config,
+        )
+        .unwrap()
+    }
+
+    /// Adds a spreadsheet shared with the service account.
+    pub fn add_spreadsheet(&self, sheet_id: &str, tabs: &[&str]) {
+        let mut inner = self.inner.lock();
+        let tabs = tabs.iter().map(|title| inner.new_tab(title)).collect();
+        inner.spreadsheets.insert(
+            sheet_id.to_string(),
+            Spreadsheet {
+                title: format!("Spreadsheet {sheet_id}"),
+                tabs,
+            },
+        );
+    }
+
+    /// Tab titles of a spreadsheet, in order.
+    pub fn tabs(&self, sheet_id: &str) -> Vec<String> {
+        self.inner.lock().spreadsheets[sheet_id]
+            .tabs
+            .iter()
+            .map(|tab| tab.title.clone())
+            .collect()
+    }
+
+    /// Cell values of a tab as a client would read them: trailing empty
+    /// rows and cells are dropped and empty cells read as `""`.
+    pub fn rows(&self, sheet_id: &str, tab: &str) -> Vec<Vec<Value>> {
+        let inner = self.inner.lock();
+        let tab = inner.spreadsheets[sheet_id]
+            .tabs
+            .iter()
+            .find(|t| t.title == tab)
+            .unwrap_or_else(|| panic!("no tab {tab:?}"));
+        trim(tab.cells.clone())
+    }
+
+    /// Overwrites one cell, as a user editing the sheet would. `row` and
+    /// `column` are zero-based.
+    pub fn set_cell(&self, sheet_id: &str, tab: &str, row: usize, column: usize, value: Value) {
+        let mut inner = self.inner.lock();
+        let tab = inner
+            .spreadsheets
+            .get_mut(sheet_id)
+            .and_then(|sheet| sheet.tabs.iter_mut().find(|t| t.title == tab))
+            .unwrap_or_else(|| panic!("no tab {tab:?}"));
+        if tab.cells.len() <= row {
+            tab.cells.resize(row + 1, Vec::new());
+        }
+        let cells = &mut tab.cells[row];
+        if cells.len() <= column {
+            cells.resize(column + 1, Value::Null);
+        }
+        cells[column] = value;
+    }
+
+    /// Values sent with each `values:append`, in order.
+    pub fn appends(&self) -> Vec<(String, Vec<Vec<Value>>)> {
+        self.inner
+            .lock()
+            .appends
+            .iter()
+            .map(|(_, range, values)| (range.clone(), values.clone()))
+            .collect()
+    }
+
+    /// Bearer tokens of the Sheets and Drive requests made so far.
+    pub fn bearers(&self) -> Vec<String> {
+        self.inner.lock().bearers.clone()
+    }
+
+    /// Makes a refresh token fail with `invalid_grant`, as when the user
+    /// removes our access from their Google account.
+    pub fn revoke_grant(&self, refresh_token: &str) {
+        self.inner
+            .lock()
+            .grants
+            .insert(refresh_token.to_string(), false);
+    }
+
+    /// Emails each spreadsheet was shared with.
+    pub fn shares(&self) -> Vec<(String, String)> {
+        self.inner.lock().shares.clone()
+    }
+}
+
+/// The driver most tests act as, with `sheet_id` connected when given.
+pub async fn test_user(pool: &db::Pool, sheet_id: Option<&str>) -> User {
+    db::upsert_user(
+        pool,
+        UserUpsert {
+            google_id: "g-1".to_string(),
+            email: "driver@example.com".to_string(),
+            sheet_id: sheet_id.map(str::to_string),
+        },
+    )
+    .await
+    .unwrap()
+}
+
+/// A log for `user` on `date` (`YYYY-MM-DD`) with $1.00 in tips and no
+/// mileage or platform. Tests that need other values override fields with
+/// struct update syntax.
+pub fn test_entry(user: &User, date: &str, gross: Cents) -> NewLogEntry {
+    NewLogEntry {
+        user_id: user.id,
+        order_date: date.parse().unwrap(),
+        gross,
+        tips: Cents(100),
+        currency: DEFAULT_CURRENCY.to_string(),
+        mileage: None,
+        platform: None,
+    }
+}
+
+/// Inserts [`test_entry`] for `user`.
+pub async fn test_log(pool: &db::Pool, user: &User, date: &str, gross: Cents) -> LogEntry {
+    db::insert_log(pool, test_entry(user, date, gross))
+        .await
+        .unwrap()
+}
+
+impl Inner {
+    fn new_tab(&mut self, title: &str) -> Tab {
+        self.next_id += 1;
+        Tab {
+            id: self.next_id,
+            title: title.to_string(),
+            cells: Vec::new(),
+        }
+    }
+}
+
+async fn handle(
+    State(mock): State<MockSheets>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let mut inner = mock.inner.lock();
+    match uri.path() {
+        "/token" => return inner.token(&form(&String::from_utf8_lossy(&body))),
+        "/revoke" => return Json(json!({})).into_response(),
+        _ => {}
+    }
+    let bearer = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .to_string();
+    if bearer != MOCK_TOKEN && !inner.user_tokens.contains(&bearer) {
+        return error(StatusCode::UNAUTHORIZED, "missing or wrong bearer token");
+    }
+    inner.bearers.push(bearer);
+    let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    let query = form(uri.query().unwrap_or_default());
+
+    if let Some(rest) = uri.path().strip_prefix("/graph/me/drive/items/") {
+        let segments: Vec<String> = rest.split('/').map(percent_decode).collect();
+        return inner.graph(&method, &segments, &body);
+    }
+    if let Some(rest) = uri.path().strip_prefix("/drive/v3/files/") {
+        let sheet_id = rest.trim_end_matches("/permissions");
+        let email = body["emailAddress"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        inner.shares.push((sheet_id.to_string(), email));
+        return Json(json!({ "id": "mock-permission" })).into_response();
+    }
+    let Some(path) = uri.path().strip_prefix("/v4/spreadsheets") else {
+        return error(StatusCode::NOT_FOUND, "unknown API");
+    };
+    if path.is_empty() && method == Method::POST {
+        return Json(inner.create(&body)).into_response();
+    }
+
+    let path = path.trim_start_matches('/');
+    let (sheet_id, rest) = match path.find(['/', ':']) {
+        Some(at) => path.split_at(at),
+        None => (path, ""),
+    };
+    let sheet_id = sheet_id.to_string();
+    let mut next_id = inner.next_id;
+    let Some(spreadsheet) = inner.spreadsheets.get_mut(&sheet_id) else {
+        return error(StatusCode::NOT_FOUND, "Requested entity was not found.");
+    };
+
+    let reply = match (method, rest) {
+        (Method::GET, "") => Ok(spreadsheet.metadata(&sheet_id)),
+        (Method::POST, ":batchUpdate") => spreadsheet.batch_update(&body, &mut next_id),
+        (Method::POST, "/values:batchUpdate") => body["data"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .try_for_each(|data| {
+                spreadsheet.write(data["range"].as_str().unwrap_or_default(), &data["values"])
+            })
+            .map(|()| json!({})),
+        (Method::GET, "/values:batchGet") => {
+            let columns = query
+                .iter()
+                .any(|(key, value)| key == "majorDimension" && value == "COLUMNS");
+            query
+                .iter()
+                .filter(|(key, _)| key == "ranges")
+                .map(|(_, range)| spreadsheet.read(range, columns))
+                .collect::<Result<Vec<_>, _>>()
+                .map(|ranges| json!({ "valueRanges": ranges }))
+        }
+        (method, rest) => match rest.strip_prefix("/values/") {
+            Some(range) => {
+                let range = percent_decode(range);
+                if let Some(range) = range.strip_suffix(":append") {
+                    let appended = spreadsheet.append(range, &body["values"]);
+                    if appended.is_ok() {
+                        let values = serde_json::from_value(body["values"].clone()).unwrap();
+                        inner.appends.push((sheet_id, range.to_string(), values));
+                    }
+                    appended.map(|()| json!({}))
+                } else if let Some(range) = range.strip_suffix(":clear") {
+                    spreadsheet.clear(range).map(|()| json!({}))
+                } else if method == Method::PUT {
+                    spreadsheet
+                        .write(&range, &body["values"])
+                        .map(|()| json!({}))
+                } else {
+                    spreadsheet.read(&range, false)
+                }
+            }
+            None => Err(format!("unsupported call {rest}")),
+        },
+    };
+    inner.next_id = next_id;
+    match reply {
+        Ok(reply) => Json(reply).into_response(),
+        Err(message) => error(StatusCode::BAD_REQUEST, &message),
+    }
+}
+
+fn form(encoded: &str) -> Vec<(String, String)> {
+    reqwest::Url::parse(&format!("http://mock/?{encoded}"))
+        .unwrap()
+        .query_pairs()
+        .into_owned()
+        .collect()
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    let body = json!({ "error": { "code": status.as_u16(), "message": message } });
+    (status, Json(body)).into_response()
+}
+
+impl Inner {
+    /// Google's and Microsoft's token endpoint: any code `X` redeems to
+    /// refresh token `refresh-X` with the Sheets scope, or Graph's
+    /// `Files.ReadWrite` for the Microsoft client, except `no-sheets`.
+    fn token(&mut self, form: &[(String, String)]) -> Response {
+        let param = |name: &str| {
+            form.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+                .unwrap_or_default()
+        };
+        let granted = if param("client_id") == MOCK_MICROSOFT_CLIENT {
+            format!("openid https://graph.microsoft.com/{FILES_SCOPE}")
+        } else {
+            SHEETS_SCOPE.to_string()
+        };
+        let (refresh_token, scope) = match param("grant_type").as_str() {
+            "authorization_code" => {
+                let code = param("code");
+                let scope = match code.as_str() {
+                    "no-sheets" => "openid email".to_string(),
+                    _ => granted,
+                };
+                let refresh_token = format!("refresh-{code}");
+                self.grants.insert(refresh_token.clone(), true);
+                (Some(refresh_token), scope)
+            }
+            "refresh_token" if self.grants.get(&param("refresh_token")) == Some(&true) => {
+                (None, granted)
+            }
+            _ => {
+                let body = json!({ "error": "invalid_grant", "error_description": "Bad Request" });
+                return (StatusCode::BAD_REQUEST, Json(body)).into_response();
+            }
+        };
+        let access_token = format!("user-token-{}", self.user_tokens.len() + 1);
+        self.user_tokens.push(access_token.clone());
+        Json(json!({
+            "access_token": access_token,
+            "expires_in": 3599,
+            "refresh_token": refresh_token,
+            "scope": scope,
+            "token_type": "Bearer"
+        }))
+        .into_response()
+    }
+
+    fn create(&mut self, body: &Value) -> Value {
+        self.next_id += 1;
+        let sheet_id = format!("mock-{}", self.next_id);
+        let titles: Vec<&str> = body["sheets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|sheet| sheet["properties"]["title"].as_str())
+            .collect();
+        let tabs = if titles.is_empty() {
+            vec![self.new_tab("Sheet1")]
+        } else {
+            titles.iter().map(|title| self.new_tab(title)).collect()
+        };
+        let spreadsheet = Spreadsheet {
+            title: body["properties"]["title"]
+                .as_str()
+                .unwrap_or("Untitled spreadsheet")
+                .to_string(),
+            tabs,
+        };
+        let mut reply = spreadsheet.metadata(&sheet_id);
+        reply["spreadsheetUrl"] = json!(format!("https://mock.invalid/{sheet_id}"));
+        self.spreadsheets.insert(sheet_id, spreadsheet);
+        reply
+    }
+    /// The Graph workbook API; `segments` follow `/me/drive/items/`.
+    fn graph(&mut self, method: &Method, segments: &[String], body: &Value) -> Response {
+        let mut next_id = self.next_id;
+        let Some(workbook) = self.spreadsheets.get_mut(&segments[0]) else {
+            return error(StatusCode::NOT_FOUND, "The resource could not be found.");
+        };
+        let path: Vec<&str> = segments[1..].iter().map(String::as_str).collect();
+        let reply = match (method.clone(), path.as_slice()) {
+            (Method::GET, []) => Ok(json!({ "id": segments[0], "name": workbook.title })),
+            (Method::GET, ["workbook", "worksheets"]) => {
+                let sheets: Vec<Value> = workbook
+                    .tabs
+                    .iter()
+                    .map(|tab| json!({ "id": tab.id.to_string(), "name": tab.title }))
+                    .collect();
+                Ok(json!({ "value": sheets }))
+            }
+            (Method::POST, ["workbook", "worksheets", "add"]) => {
+                let name = body["name"].as_str().unwrap_or_default();
+                if workbook.tabs.iter().any(|tab| tab.title == name) {
+                    Err(format!("A worksheet named {name:?} already exists."))
+                } else {
+                    next_id += 1;
+                    workbook.tabs.push(Tab {
+                        id: next_id,
+                        title: name.to_string(),
+                        cells: Vec::new(),
+                    });
+                    Ok(json!({ "id": next_id.to_string(), "name": name }))
+                }
+            }
+            (_, ["workbook", "worksheets", name, rest @ ..]) => {
+                if !workbook.tabs.iter().any(|tab| tab.title == *name) {
+                    return error(
+                        StatusCode::NOT_FOUND,
+                        "The requested resource doesn't exist.",
+                    );
+                }
+                match (method.clone(), rest) {
+                    (Method::GET, ["usedRange(valuesOnly=true)"]) => Ok(workbook.used_range(name)),
+                    (Method::POST, ["freezePanes", "freezeRows"]) => Ok(json!({})),
+                    (method, [range, action @ ..]) => match range
+                        .strip_prefix("range(address='")
+                        .and_then(|range| range.strip_suffix("')"))
+                    {
+                        Some(address) => {
+                            let range = format!("{name}!{address}");
+                            match (method, action) {
+                                (Method::GET, []) => workbook.grid(&range),
+                                (Method::PATCH, []) => {
+                                    workbook.write(&range, &body["values"]).map(|()| json!({}))
+                                }
+                                (Method::POST, ["clear"]) => {
+                                    workbook.clear(&range).map(|()| json!({}))
+                                }
+                                (Method::POST, ["delete"]) => {
+                                    workbook.shift_up(&range).map(|()| json!({}))
+                                }
+                                _ => Err(format!("unsupported call {range}")),
+                            }
+                        }
+                        None => Err(format!("unsupported call {range}")),
+                    },
+                    _ => Err("unsupported worksheet call".to_string()),
+                }
+            }
+            _ => Err("unsupported workbook call".to_string()),
+        };
+        self.next_id = next_id;
+        match reply {
+            Ok(reply) => Json(reply).into_response(),
+            Err(message) => error(StatusCode::BAD_REQUEST, &message),
+        }
+    }
+}
+
+impl Spreadsheet {
+    fn metadata(&self, sheet_id: &str) -> Value {
+        let sheets: Vec<Value> = self
+            .tabs
+            .iter()
+            .map(|tab| json!({ "properties": { "sheetId": tab.id, "title": tab.title } }))
+            .collect();
+        json!({
+            "spreadsheetId": sheet_id,
+            "properties": { "title": self.title },
+            "sheets": sheets
+        })
+    }
+
+    fn batch_update(&mut self, body: &Value, next_id: &mut i64) -> Result<Value, String> {
+        let mut replies = Vec::new();
+        for request in body["requests"].as_array().into_iter().flatten() {
+            if let Some(add) = request.get("addSheet") {
+                let title = add["properties"]["title"].as_str().unwrap_or_default();
+                if self.tabs.iter().any(|tab| tab.title == title) {
+                    return Err(format!("A sheet with the name \"{title}\" already exists."));
+                }
+                *next_id += 1;
+                self.tabs.push(Tab {
+                    id: *next_id,
+                    title: title.to_string(),
+                    cells: Vec::new(),
+                });
+                replies.push(json!({ "addSheet": { "properties": {
+                    "sheetId": *next_id,
+                    "title": title
+                } } }));
+            } else if let Some(delete) = request.get("deleteDimension") {
+                let range = &delete["range"];
+                let tab = self
+                    .tabs
+                    .iter_mut()
+                    .find(|tab| Some(tab.id) == range["sheetId"].as_i64())
+                    .ok_or("No grid with id")?;
+                let start = range["startIndex"].as_u64().unwrap_or(0) as usize;
+                let end = range["endIndex"].as_u64().unwrap_or(0) as usize;
+                if start < tab.cells.len() {
+                    tab.cells.drain(start..end.min(tab.cells.len()));
+                }
+                replies.push(json!({}));
+            } else if let Some(update) = request.get("updateSpreadsheetProperties") {
+                if let Some(title) = update["properties"]["title"].as_str() {
+                    self.title = title.to_string();
+                }
+                replies.push(json!({}));
+            } else {
+                // Formatting requests change nothing the mock tracks.
+                replies.push(json!({}));
+            }
+        }
+        Ok(json!({ "replies": replies }))
+    }
+
+    fn tab(&mut self, title: &str) -> Result<&mut Tab, String> {
+        self.tabs
+            .iter_mut()
+            .find(|tab| tab.title == title)
+            .ok_or_else(|| format!("Unable to parse range: {title}"))
+    }
+
+    fn read(&mut self, range: &str, columns: bool) -> Result<Value, String> {
+        let range_text = range.to_string();
+        let range = A1Range::parse(range)?;
+        let tab = self.tab(&range.tab)?;
+        let last_row = range.end_row.unwrap_or(usize::MAX);
+        let rows: Vec<Vec<Value>> = tab
+            .cells
+            .iter()
+            .enumerate()
+            .filter(|(row, _)| *row >= range.start_row && *row <= last_row)
+            .map(|(_, cells)| {
+                (range.start_col..=range.end_col)
+                    .map(|col| cells.get(col).cloned().unwrap_or(Value::Null))
+                    .collect()
+            })
+            .collect();
+        let values = if columns {
+            let width = range.end_col - range.start_col + 1;
+            trim(
+                (0..width)
+                    .map(|col| rows.iter().map(|row| row[col].clone()).collect())
+                    .collect(),
+            )
+        } else {
+            trim(rows)
+        };
+        let mut reply = json!({ "range": range_text });
+        if !values.is_empty() {
+            reply["values"] = json!(values);
+        }
+        Ok(reply)
+    }
+
+    fn write(&mut self, range: &str, values: &Value) -> Result<(), String> {
+        let range = A1Range::parse(range)?;
+        let tab = self.tab(&range.tab)?;
+        for (offset, row) in values.as_array().into_iter().flatten().enumerate() {
+            tab.set_row(range.start_row + offset, range.start_col, row);
+        }
+        Ok(())
+    }
+
+    /// Writes after the last row with a value in the range's columns, which
+    /// is how Sheets finds the end of a table.
+    fn append(&mut self, range: &str, values: &Value) -> Result<(), String> {
+        let range = A1Range::parse(range)?;
+        let tab = self.tab(&range.tab)?;
+        let used = tab
+            .cells
+            .iter()
+            .rposition(|cells| {
+                (range.start_col..=range.end_col)
+                    .any(|col| cells.get(col).is_some_and(|cell| !is_blank(cell)))
+            })
+            .map_or(0, |row| row + 1);
+        for (offset, row) in values.as_array().into_iter().flatten().enumerate() {
+            tab.set_row(used.max(range.start_row) + offset, range.start_col, row);
+        }
+        Ok(())
+    }
+
+    /// The smallest block holding every value of the tab, as Graph's
+    /// `usedRange(valuesOnly=true)` returns it; `A1` with one blank cell when
+    /// the tab is empty.
+    fn used_range(&self, title: &str) -> Value {
+        let tab = self.tabs.iter().find(|tab| tab.title == title).unwrap();
+        let filled = |row: &Vec<Value>| row.iter().position(|cell| !is_blank(cell));
+        let rows: Vec<usize> = (0..tab.cells.len())
+            .filter(|row| filled(&tab.cells[*row]).is_some())
+            .collect();
+        let (Some(first_row), Some(last_row)) = (rows.first(), rows.last()) else {
+            return json!({ "rowIndex": 0, "columnIndex": 0, "values": [[""]] });
+        };
+        let first_col = rows
+            .iter()
+            .filter_map(|row| filled(&tab.cells[*row]))
+            .min()
+            .unwrap();
+        let last_col = rows
+            .iter()
+            .filter_map(|row| tab.cells[*row].iter().rposition(|cell| !is_blank(cell)))
+            .max()
+            .unwrap();
+        let values: Vec<Vec<Value>> = (*first_row..=*last_row)
+            .map(|row| {
+                (first_col..=last_col)
+                    .map(|col| match tab.cells[row].get(col) {
+                        Some(cell) if !cell.is_null() => cell.clone(),
+                        _ => json!(""),
+                    })
+                    .collect()
+            })
+            .collect();
+        json!({ "rowIndex": first_row, "columnIndex": first_col, "values": values })
+    }
+
+    /// Every cell of a bounded range, blanks as `""`, as Graph reads ranges.
+    fn grid(&mut self, range: &str) -> Result<Value, String> {
+        let range = A1Range::parse(range)?;
+        let tab = self.tab(&range.tab)?;
+        let end_row = range.end_row.unwrap_or(range.start_row);
+        let values: Vec<Vec<Value>> = (range.start_row..=end_row)
+            .map(|row| {
+                (range.start_col..=range.end_col)
+                    .map(
+                        |col| match tab.cells.get(row).and_then(|cells| cells.get(col)) {
+                            Some(cell) if !cell.is_null() => cell.clone(),
+                            _ => json!(""),
+                        },
+                    )
+                    .collect()
+            })
+            .collect();
+        Ok(json!({ "values": values }))
+    }
+
+    /// Deletes the range's cells, moving the cells below it in the same
+    /// columns up.
+    fn shift_up(&mut self, range: &str) -> Result<(), String> {
+        let range = A1Range::parse(range)?;
+        let tab = self.tab(&range.tab)?;
+        let height = range.end_row.unwrap_or(range.start_row) - range.start_row + 1;
+        for row in range.start_row..tab.cells.len() {
+            for col in range.start_col..=range.end_col {
+                let below = tab
+                    .cells
+                    .get(row + height)
+                    .and_then(|cells| cells.get(col))
+                    .cloned()
+                    .unwrap_or(Value::Null);
+                let cells = &mut tab.cells[row];
+                if cells.len() <= col {
+                    cells.resize(col + 1, Value::Null);
+                }
+                cells[col] = below;
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, range: &str) -> Result<(), String> {
+        let range = A1Range::parse(range)?;
+        let tab = self.tab(&range.tab)?;
+        let last_row = range.end_row.unwrap_or(usize::MAX);
+        for (_, cells) in tab
+            .cells
+            .iter_mut()
+            .enumerate()
+            .filter(|(row, _)| *row >= range.start_row && *row <= last_row)
+        {
+            for col in range.start_col..=range.end_col.min(cells.len().saturating_sub(1)) {
+                cells[col] = Value::Null;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Tab {
+    fn set_row(&mut self, row: usize, start_col: usize, values: &Value) {
+        if self.cells.len() <= row {
+            self.cells.resize(row + 1, Vec::new());
+        }
+        let cells = &mut self.cells[row];
+        for (offset, value) in values.as_array().into_iter().flatten().enumerate() {
+            let col = start_col + offset;
+            if cells.len() <= col {
+                cells.resize(col + 1, Value::Null);
+            }
+            cells[col] = value.clone();
+        }
+    }
+}
+
+/// A parsed A1 range; rows and columns are zero-based and inclusive.
+struct A1Range {
+    tab: String,
+    start_col: usize,
+    end_col: usize,
+    start_row: usize,
+    end_row: Option<usize>,
+}
+
+impl A1Range {
+    fn parse(range: &str) -> Result<Self, String> {
+        let invalid = || format!("Unable to parse range: {range}");
+        let (tab, cells) = match range.strip_prefix('\'') {
+            Some(quoted) => {
+                let end = quoted.find("'!").ok_or_else(invalid)?;
+                (quoted[..end].replace("''", "'"), &quoted[end + 2..])
+            }
+            None => {
+                let (tab, cells) = range.split_once('!').ok_or_else(invalid)?;
+                (tab.to_string(), cells)
+            }
+        };
+        let (start, end) = cells.split_once(':').unwrap_or((cells, cells));
+        let (start_col, start_row) = cell(start).ok_or_else(invalid)?;
+        let (end_col, end_row) = cell(end).ok_or_else(invalid)?;
+        Ok(Self {
+            tab,
+            start_col,
+            end_col,
+            start_row: start_row.unwrap_or(0),
+            end_row: if end == start && start_row.is_some() {
+                start_row
+            } else {
+                end_row
+            },
+        })
+    }
+}
+
+/// `C5` as `(2, Some(4))`, `C` as `(2, None)`.
+fn cell(reference: &str) -> Option<(usize, Option<usize>)> {
+    let split = reference
+        .find(|c: char| c.is_ascii_digit())
+        .unwrap_or(reference.len());
+    let (letters, digits) = reference.split_at(split);
+    if letters.is_empty() || !letters.chars().all(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    let col = letters
+        .bytes()
+        .fold(0, |acc, b| acc * 26 + usize::from(b - b'A') + 1)
+        - 1;
+    let row = match digits {
+        "" => None,
+        digits => Some(digits.parse::<usize>().ok()?.checked_sub(1)?),
+    };
+    Some((col, row))
+}
+
+fn is_blank(value: &Value) -> bool {
+    value.is_null() || value.as_str() == Some("")
+}
+
+/// Drops trailing blank rows and cells and reads blanks as `""`, like the
+/// real API.
+fn trim(rows: Vec<Vec<Value>>) -> Vec<Vec<Value>> {
+    let mut rows: Vec<Vec<Value>> = rows
+        .into_iter()
+        .map(|mut row| {
+            while row.last().is_some_and(is_blank) {
+                row.pop();
+            }
+            row.into_iter()
+                .map(|value| if value.is_null() { json!("") } else { value })
+                .collect()
+        })
+        .collect();
+    while rows.last().is_some_and(Vec::is_empty) {
+        rows.pop();
+    }
+    rows
+}
+
+fn percent_decode(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], raw.get(i + 1..i + 3)) {
+            (b'%', Some(hex)) if u8::from_str_radix(hex, 16).is_ok() => {
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{LogEntry, SheetMapping, TabRotation};
+    use crate::money::{Cents, DEFAULT_CURRENCY};
+    use chrono::NaiveDate;
+
+    fn log(id: i64, date: (i32, u32, u32), gross: f64) -> LogEntry {
+        LogEntry {
+            id,
+            user_id: 1,
+            order_date: NaiveDate::from_ymd_opt(date.0, date.1, date.2).unwrap(),
+            gross: Cents::from_f64(gross).unwrap(),
+            tips: Cents(150),
+            currency: DEFAULT_CURRENCY.to_string(),
+            mileage: None,
+            platform: Some("Lyft".to_string()),
+            parsed_at: NaiveDate::from_ymd_opt(2024, 9, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            sync_status: None,
+            sync_error: None,
+        }
+    }
+
+    fn header(mapping: &SheetMapping) -> Vec<Value> {
+        mapping.headers().into_iter().map(Value::from).collect()
+    }
+
+    #[test]
+    fn parses_a1_ranges() {
+        let range = A1Range::parse("'Driver''s Ledger'!C2:F").unwrap();
+        assert_eq!(range.tab, "Driver's Ledger");
+        assert_eq!((range.start_col, range.end_col), (2, 5));
+        assert_eq!((range.start_row, range.end_row), (1, None));
+        let range = A1Range::parse("Sheet1!AA5").unwrap();
+        assert_eq!(
+            (range.start_col, range.start_row, range.end_row),
+            (26, 4, Some(4))
+        );
+        assert!(A1Range::parse("Sheet1").is_err());
+        assert_eq!(percent_decode("%27Trips%27!A%3AF"), "'Trips'!A:F");
+    }
+
+    #[tokio::test]
+    async fn upserts_update_rows_in_place_and_deletes_remove_them() {
+        let (mock, url) = MockSheets::start().await;
+        mock.add_spreadsheet("sheet", &["Sheet1"]);
+        let client = MockSheets::client(&url);
+        let mapping = SheetMapping::default();
+
+        client.bootstrap("sheet", &mapping).await.unwrap();
+        let logs = [log(1, (2024, 8, 1), 10.0), log(2, (2024, 8, 2), 20.0)];
+        client.upsert_logs("sheet", &mapping, &logs).await.unwrap();
+        client
+            .upsert_logs("sheet", &mapping, &[log(1, (2024, 8, 1), 11.0)])
+            .await
+            .unwrap();
+        assert_eq!(
+            mock.rows("sheet", "Sheet1"),
+            vec![
+                header(&mapping),
+                vec![
+                    json!("2024-08-01"),
+                    json!(11.0),
+                    json!(1.5),
+                    json!(""),
+                    json!(1)
+                ],
+                vec![
+                    json!("2024-08-02"),
+                    json!(20.0),
+                    json!(1.5),
+                    json!(""),
+                    json!(2)
+                ],
+            ]
+        );
+        assert_eq!(mock.appends().len(), 1);
+
+        client
+            .delete_logs("sheet", &mapping, &[1, 99])
+            .await
+            .unwrap();
+        let rows = mock.rows("sheet", "Sheet1");
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].last(), Some(&json!(2)));
+    }
+
+    #[tokio::test]
+    async fn rotation_moves_rows_between_period_tabs() {
+        let (mock, url) = MockSheets::start().await;
+        mock.add_spreadsheet("sheet", &["Sheet1"]);
+        let client = MockSheets::client(&url);
+        let mapping = SheetMapping {
+            rotation: TabRotation::Monthly,
+            ..SheetMapping::default()
+        };
+
+        client
+            .upsert_logs("sheet", &mapping, &[log(1, (2024, 7, 31), 10.0)])
+            .await
+            .unwrap();
+        client
+            .upsert_logs("sheet", &mapping, &[log(1, (2024, 8, 1), 10.0)])
+            .await
+            .unwrap();
+        assert_eq!(mock.tabs("sheet"), ["Sheet1", "2024-07", "2024-08"]);
+        assert_eq!(mock.rows("sheet", "2024-07"), vec![header(&mapping)]);
+        assert_eq!(mock.rows("sheet", "2024-08")[1][0], json!("2024-08-01"));
+    }
+
+    #[tokio::test]
+    async fn created_spreadsheets_are_shared_with_the_user() {
+        let (mock, url) = MockSheets::start().await;
+        let client = MockSheets::client(&url);
+        let created = client
+            .create_spreadsheet(
+                "DriverSheet",
+                &SheetMapping::default(),
+                Some("driver@example.com"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(mock.tabs(&created.sheet_id), ["Sheet1", "Summary"]);
+        assert_eq!(
+            mock.shares(),
+            [(created.sheet_id.clone(), "driver@example.com".to_string())]
+        );
+        assert_eq!(
+            mock.rows(&created.sheet_id, "Summary")[0][0],
+            json!("Total")
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use crate::db;
+use crate::excel::{ExcelClient, GraphApiError};
+use crate::models::{LogEntry, SheetMapping, SinkKind, User};
+use crate::oauth::{GrantRevoked, Provider};
+use crate::sheets::{SheetsApiError, SheetsClient};
+use crate::state::AppState;
+use anyhow::Result;
+use std::future::Future;
+use std::time::Duration;
+use thiserror::Error;
+
+/// A destination log rows are exported to. Every implementation lays rows out
+/// by the user's `SheetMapping`, keys them by the log ID column, and honours
+/// tab rotation.
+pub trait ExportSink: Send + Sync {
+    /// Writes each log to its row, appending rows for logs not exported yet.
+    fn upsert_logs(
+        &self,
+        mapping: &SheetMapping,
+        logs: &[LogEntry],
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Removes every row carrying one of the log IDs.
+    fn delete_logs(
+        &self,
+        mapping: &SheetMapping,
+        log_ids: &[i64],
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Clears the data rows the mapping writes to, keeping the header.
+    fn clear_logs(&self, mapping: &SheetMapping) -> impl Future<Output = Result<()>> + Send;
+
+    /// Creates the tab and header row if missing. Safe to re-run.
+    fn bootstrap(&self, mapping: &SheetMapping) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// The user has neither a sheet nor a workbook to export to.
+#[derive(Debug, Error)]
+#[error("No sheet or workbook connected")]
+pub struct NoDestination;
+
+pub struct SheetsSink {
+    pub client: SheetsClient,
+    pub sheet_id: String,
+}
+
+pub struct ExcelSink {
+    pub client: ExcelClient,
+    pub item_id: String,
+}
+
+impl ExportSink for SheetsSink {
+    async fn upsert_logs(&self, mapping: &SheetMapping, logs: &[LogEntry]) -> Result<()> {
+        self.client.upsert_logs(&self.sheet_id, mapping, logs).await
+    }
+
+    async fn delete_logs(&self, mapping: &SheetMapping, log_ids: &[i64]) -> Result<()> {
+        self.client
+            .delete_logs(&self.sheet_id, mapping, log_ids)
+            .await
+    }
+
+    async fn clear_logs(&self, mapping: &SheetMapping) -> Result<()> {
+        self.client.clear_logs(&self.sheet_id, mapping).await
+    }
+
+    async fn bootstrap(&self, mapping: &SheetMapping) -> Result<()> {
+        self.client.bootstrap(&self.sheet_id, mapping).await
+    }
+}
+
+impl ExportSink for ExcelSink {
+    async fn upsert_logs(&self, mapping: &SheetMapping, logs: &[LogEntry]) -> Result<()> {
+        self.client.upsert_logs(&self.item_id, mapping, logs).await
+    }
+
+    async fn delete_logs(&self, mapping: &SheetMapping, log_ids: &[i64]) -> Result<()> {
+        self.client
+            .delete_logs(&self.item_id, mapping, log_ids)
+            .await
+    }
+
+    async fn clear_logs(&self, mapping: &SheetMapping) -> Result<()> {
+        self.client.clear_logs(&self.item_id, mapping).await
+    }
+
+    async fn bootstrap(&self, mapping: &SheetMapping) -> Result<()> {
+        self.client.bootstrap(&self.item_id, mapping).await
+    }
+}
+
+/// The sink a user selected with `users.sink`.
+pub enum UserSink {
+    Sheets(SheetsSink),
+    Excel(ExcelSink),
+}
+
+impl UserSink {
+    /// The spreadsheet, for the Sheets-only summary tabs and sheet pull.
+    pub fn sheet_id(&self) -> Option<&str> {
+        match self {
+            Self::Sheets(sink) => Some(&sink.sheet_id),
+            Self::Excel(_) => None,
+        }
+    }
+}
+
+impl ExportSink for UserSink {
+    async fn upsert_logs(&self, mapping: &SheetMapping, logs: &[LogEntry]) -> Result<()> {
+        match self {
+            Self::Sheets(sink) => sink.upsert_logs(mapping, logs).await,
+            Self::Excel(sink) => sink.upsert_logs(mapping, logs).await,
+        }
+    }
+
+    async fn delete_logs(&self, mapping: &SheetMapping, log_ids: &[i64]) -> Result<()> {
+        match self {
+            Self::Sheets(sink) => sink.delete_logs(mapping, log_ids).await,
+            Self::Excel(sink) => sink.delete_logs(mapping, log_ids).await,
+        }
+    }
+
+    async fn clear_logs(&self, mapping: &SheetMapping) -> Result<()> {
+        match self {
+            Self::Sheets(sink) => sink.clear_logs(mapping).await,
+            Self::Excel(sink) => sink.clear_logs(mapping).await,
+        }
+    }
+
+    async fn bootstrap(&self, mapping: &SheetMapping) -> Result<()> {
+        match self {
+            Self::Sheets(sink) => sink.bootstrap(mapping).await,
+            Self::Excel(sink) => sink.bootstrap(mapping).await,
+        }
+    }
+}
+
+/// The user's selected sink. Fails with `NoDestination` when it is not
+/// connected, and with `GrantRevoked` when their Microsoft grant was revoked.
+pub async fn for_user(state: &AppState, user: &User) -> Result<UserSink> {
+    match user.sink {
+        SinkKind::Sheets => {
+            let sheet_id = user.sheet_id.clone().ok_or(NoDestination)?;
+            let client = state.sheets_for(user.id).await?;
+            Ok(UserSink::Sheets(SheetsSink { client, sheet_id }))
+        }
+        SinkKind::Excel => {
+            let workbook = db::excel_workbook(&state.pool, user.id)
+                .await?
+                .ok_or(NoDestination)?;
+            let (Some(oauth), Some(secrets)) = (&state.microsoft_oauth, &state.secrets) else {
+                return Err(NoDestination.into());
+            };
+            if workbook.revoked_at.is_some() {
+                return Err(GrantRevoked {
+                    provider: Provider::Microsoft,
+                    reason: "grant revoked earlier".to_string(),
+                }
+                .into());
+            }
+            let refresh_token = secrets.open(&workbook.refresh_token_enc)?;
+            Ok(UserSink::Excel(ExcelSink {
+                client: state.excel.as_user(oauth.clone(), user.id, refresh_token),
+                item_id: workbook.item_id,
+            }))
+        }
+    }
+}
+
+/// How long to hold off after either API throttled us.
+pub fn rate_limited(err: &anyhow::Error) -> Option<Duration> {
+    SheetsApiError::rate_limited(err).or_else(|| GraphApiError::rate_limited(err))
+}
+
+/// A user-facing message for errors retrying will not fix.
+pub fn permanent(err: &anyhow::Error) -> Option<String> {
+    if let Some(revoked) = GrantRevoked::find(err) {
+        return Some(revoked.user_message());
+    }
+    SheetsApiError::permanent(err)
+        .map(SheetsApiError::user_message)
+        .or_else(|| GraphApiError::permanent(err).map(GraphApiError::user_message))
+}
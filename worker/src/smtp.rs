@@ -0,0 +1,761 @@
+use crate::mail::{self, Delivery, Mailbox};
+use crate::models::MessageSource;
+use anyhow::{Context, Result};
+use serde_with::DeserializeFromStr;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    BufWriter,
+};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+
+const MAX_MESSAGE_BYTES: usize = 25 * 1024 * 1024;
+const MAX_LINE_BYTES: usize = 4096;
+const MAX_RECIPIENTS: usize = 100;
+/// RFC 5321 section 4.5.3.2 suggests at least five minutes between commands.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Where a mail listener binds: `127.0.0.1:24` or `unix:/run/driversheet/lmtp.sock`.
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => s.parse().map(Self::Tcp),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The two dialects share everything except the greeting verb and how DATA
+/// is answered: SMTP replies once, LMTP (RFC 2033) once per recipient.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Smtp,
+    Lmtp,
+}
+
+impl Protocol {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Smtp => "SMTP",
+            Self::Lmtp => "LMTP",
+        }
+    }
+
+    fn source(self) -> MessageSource {
+        match self {
+            Self::Smtp => MessageSource::Smtp,
+            Self::Lmtp => MessageSource::Lmtp,
+        }
+    }
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    async fn bind(addr: &ListenAddr) -> Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => TcpListener::bind(addr)
+                .await
+                .map(Self::Tcp)
+                .with_context(|| format!("Failed to bind {addr}")),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                if path.exists() {
+                    std::fs::remove_file(path).context("Failed to remove stale socket")?;
+                }
+                tokio::net::UnixListener::bind(path)
+                    .map(Self::Unix)
+                    .with_context(|| format!("Failed to bind socket {}", path.display()))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => anyhow::bail!("Unix sockets are not supported on this platform"),
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<(Box<dyn Connection>, String)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Box::new(stream), peer.to_string()))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), "unix".to_string()))
+            }
+        }
+    }
+}
+
+/// Accepts connections until `shutdown` fires. Sessions run concurrently on
+/// `tracker` so the caller can wait for in-flight deliveries to finish.
+pub async fn run_listener<M>(
+    mailbox: Arc<M>,
+    addr: ListenAddr,
+    protocol: Protocol,
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
+) -> Result<()>
+where
+    M: Mailbox + 'static,
+{
+    let listener = Listener::bind(&addr).await?;
+    info!("{} listening on {addr}", protocol.name());
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("{} accept failed: {err}", protocol.name());
+                    continue;
+                }
+            },
+        };
+        let mailbox = Arc::clone(&mailbox);
+        let shutdown = shutdown.clone();
+        tracker.spawn(async move {
+            if let Err(err) = serve_session(&*mailbox, stream, protocol, &shutdown).await {
+                warn!("{} session from {peer} ended: {err:?}", protocol.name());
+            }
+        });
+    }
+
+    info!("{} listener on {addr} stopped", protocol.name());
+    Ok(())
+}
+
+/// Runs one SMTP or LMTP conversation until QUIT, EOF, idle timeout or
+/// shutdown. Replies are flushed only once the client's pipelined commands
+/// have been consumed.
+pub async fn serve_session<M, S>(
+    mailbox: &M,
+    stream: S,
+    protocol: Protocol,
+    shutdown: &CancellationToken,
+) -> Result<()>
+where
+    M: Mailbox,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut greeted = false;
+    let mut sender: Option<String> = None;
+    let mut recipients: Vec<String> = Vec::new();
+
+    reply(
+        &mut writer,
+        &format!("220 driversheet.com {} ready", greeting(protocol)),
+    )
+    .await?;
+
+    loop {
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+        let line = tokio::select! {
+            _ = shutdown.cancelled() => {
+                reply(&mut writer, "421 4.3.2 Service shutting down").await?;
+                writer.flush().await?;
+                return Ok(());
+            }
+            line = tokio::time::timeout(IDLE_TIMEOUT, read_line(&mut reader)) => line,
+        };
+        let Ok(line) = line else {
+            reply(&mut writer, "421 4.4.2 Idle timeout").await?;
+            writer.flush().await?;
+            return Ok(());
+        };
+        let line = match line? {
+            Some(CommandLine::Text(line)) => line,
+            Some(CommandLine::TooLong) => {
+                reply(&mut writer, "500 5.5.2 Line too long").await?;
+                continue;
+            }
+            None => return Ok(()),
+        };
+        let (verb, arg) = match line.split_once(' ') {
+            Some((verb, arg)) => (verb.to_ascii_uppercase(), arg.trim()),
+            None => (line.to_ascii_uppercase(), ""),
+        };
+
+        match (verb.as_str(), protocol) {
+            ("EHLO", Protocol::Smtp) | ("LHLO", Protocol::Lmtp) => {
+                greeted = true;
+                sender = None;
+                recipients.clear();
+                reply(
+                    &mut writer,
+                    &format!(
+                        "250-driversheet.com\r\n250-PIPELINING\r\n250-SIZE {MAX_MESSAGE_BYTES}\r\n\
+                         250-8BITMIME\r\n250 ENHANCEDSTATUSCODES"
+                    ),
+                )
+                .await?;
+            }
+            ("HELO", Protocol::Smtp) => {
+                greeted = true;
+                sender = None;
+                recipients.clear();
+                reply(&mut writer, "250 driversheet.com").await?;
+            }
+            ("HELO" | "EHLO", Protocol::Lmtp) => {
+                reply(&mut writer, "500 5.5.1 Use LHLO for LMTP").await?;
+            }
+            ("LHLO", Protocol::Smtp) => {
+                reply(&mut writer, "500 5.5.1 Use EHLO or HELO").await?;
+            }
+            ("MAIL", _) if !greeted => {
+                let hello = match protocol {
+                    Protocol::Smtp => "EHLO",
+                    Protocol::Lmtp => "LHLO",
+                };
+                reply(&mut writer, &format!("503 5.5.1 Send {hello} first")).await?;
+            }
+            ("MAIL", _) => match path_argument(arg, "FROM:") {
+                Some(_) if declared_size(arg).is_some_and(|size| size > MAX_MESSAGE_BYTES) => {
+                    reply(&mut writer, "552 5.3.4 Message too large").await?;
+                }
+                Some(from) => {
+                    sender = Some(from.to_string());
+                    recipients.clear();
+                    reply(&mut writer, "250 2.1.0 Ok").await?;
+                }
+                None => reply(&mut writer, "501 5.5.4 Syntax: MAIL FROM:<address>").await?,
+            },
+            ("RCPT", _) if sender.is_none() => {
+                reply(&mut writer, "503 5.5.1 Need MAIL before RCPT").await?;
+            }
+            ("RCPT", _) if recipients.len() >= MAX_RECIPIENTS => {
+                reply(&mut writer, "452 4.5.3 Too many recipients").await?;
+            }
+            ("RCPT", _) => {
+                let Some(to) = path_argument(arg, "TO:") else {
+                    reply(&mut writer, "501 5.5.4 Syntax: RCPT TO:<address>").await?;
+                    continue;
+                };
+                let Some(key) = mail::parse_forward_key(to) else {
+                    warn!("Rejecting {} RCPT {to}", protocol.name());
+                    reply(&mut writer, "550 5.1.1 No such mailbox").await?;
+                    continue;
+                };
+                match mailbox.accepts(&key).await {
+                    Ok(true) => {
+                        recipients.push(key);
+                        reply(&mut writer, "250 2.1.5 Ok").await?;
+                    }
+                    Ok(false) => {
+                        warn!(
+                            "Rejecting {} RCPT for unknown forward key {key}",
+                            protocol.name()
+                        );
+                        reply(&mut writer, "550 5.1.1 No such mailbox").await?;
+                    }
+                    Err(err) => {
+                        error!("{} recipient lookup failed: {err:?}", protocol.name());
+                        reply(&mut writer, "451 4.3.0 Temporary lookup failure").await?;
+                    }
+                }
+            }
+            ("DATA", _) if recipients.is_empty() => {
+                reply(&mut writer, "503 5.5.1 No valid recipients").await?;
+            }
+            ("DATA", _) => {
+                reply(&mut writer, "354 End data with <CR><LF>.<CR><LF>").await?;
+                writer.flush().await?;
+                let data = read_data(&mut reader, MAX_MESSAGE_BYTES).await?;
+                let statuses: Vec<Status> = match data {
+                    Some(data) => {
+                        let mut statuses = Vec::with_capacity(recipients.len());
+                        for key in &recipients {
+                            statuses.push(deliver(mailbox, protocol, key, &data).await);
+                        }
+                        statuses
+                    }
+                    None => vec![Status::TooLarge; recipients.len()],
+                };
+                match protocol {
+                    Protocol::Lmtp => {
+                        for status in &statuses {
+                            reply(&mut writer, status.line()).await?;
+                        }
+                    }
+                    Protocol::Smtp => reply(&mut writer, Status::combine(&statuses).line()).await?,
+                }
+                sender = None;
+                recipients.clear();
+            }
+            ("RSET", _) => {
+                sender = None;
+                recipients.clear();
+                reply(&mut writer, "250 2.0.0 Ok").await?;
+            }
+            ("NOOP", _) => reply(&mut writer, "250 2.0.0 Ok").await?,
+            ("VRFY", _) => reply(&mut writer, "252 2.5.0 Cannot VRFY user").await?,
+            ("QUIT", _) => {
+                reply(&mut writer, "221 2.0.0 Bye").await?;
+                writer.flush().await?;
+                return Ok(());
+            }
+            _ => reply(&mut writer, "500 5.5.2 Command not recognized").await?,
+        }
+    }
+}
+
+fn greeting(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Smtp => "ESMTP",
+        Protocol::Lmtp => "LMTP",
+    }
+}
+
+/// Per-recipient outcome of a DATA transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Accepted,
+    UnknownRecipient,
+    Deferred,
+    Rejected,
+    TooLarge,
+}
+
+impl Status {
+    fn line(self) -> &'static str {
+        match self {
+            Self::Accepted => "250 2.0.0 Ok",
+            Self::UnknownRecipient => "550 5.1.1 No such mailbox",
+            Self::Deferred => "451 4.3.0 Temporary processing failure",
+            Self::Rejected => "554 5.6.0 Message could not be processed",
+            Self::TooLarge => "552 5.3.4 Message too large",
+        }
+    }
+
+    /// SMTP has a single reply for all recipients: ask the client to retry if
+    /// any delivery hit a transient error (dedupe skips the ones that already
+    /// succeeded), and only fail permanently when nothing was accepted.
+    fn combine(statuses: &[Status]) -> Status {
+        if statuses.contains(&Self::Deferred) {
+            Self::Deferred
+        } else if statuses.contains(&Self::Accepted) {
+            Self::Accepted
+        } else {
+            statuses.first().copied().unwrap_or(Self::Rejected)
+        }
+    }
+}
+
+async fn deliver<M: Mailbox>(mailbox: &M, protocol: Protocol, key: &str, data: &[u8]) -> Status {
+    match mailbox.deliver(protocol.source(), key, data).await {
+        Ok(Delivery::Logged | Delivery::Duplicate | Delivery::NoStatement) => Status::Accepted,
+        Ok(Delivery::UnknownRecipient) => Status::UnknownRecipient,
+        Err(err) if mail::is_transient(&err) => {
+            error!("{} delivery for {key} deferred: {err:?}", protocol.name());
+            Status::Deferred
+        }
+        Err(err) => {
+            error!("{} delivery for {key} rejected: {err:?}", protocol.name());
+            Status::Rejected
+        }
+    }
+}
+
+async fn reply<W: AsyncWrite + Unpin>(writer: &mut W, line: &str) -> Result<()> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// A command line as read from the client.
+#[derive(Debug, PartialEq, Eq)]
+enum CommandLine {
+    Text(String),
+    /// Longer than [`MAX_LINE_BYTES`]; the whole line was discarded so no
+    /// part of it is taken as the next command.
+    TooLong,
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<CommandLine>> {
+    let mut buf = Vec::new();
+    let read = (&mut *reader)
+        .take(MAX_LINE_BYTES as u64)
+        .read_until(b'\n', &mut buf)
+        .await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !buf.ends_with(b"\n") && read == MAX_LINE_BYTES {
+        // Skip to the end of the line in bounded chunks.
+        loop {
+            buf.clear();
+            let read = (&mut *reader)
+                .take(MAX_LINE_BYTES as u64)
+                .read_until(b'\n', &mut buf)
+                .await?;
+            if read == 0 || buf.ends_with(b"\n") {
+                return Ok(Some(CommandLine::TooLong));
+            }
+        }
+    }
+    let line = String::from_utf8_lossy(&buf);
+    Ok(Some(CommandLine::Text(
+        line.trim_end_matches(['\r', '\n']).to_string(),
+    )))
+}
+
+/// Reads the DATA section up to the lone `.` terminator, undoing dot-stuffing.
+/// Returns `None` when the message exceeds `limit` bytes; the rest of the
+/// section is still consumed, without buffering it, so the session stays in
+/// sync. Lines are read in chunks of at most [`MAX_LINE_BYTES`], so an
+/// unterminated line cannot grow memory past the limit either.
+async fn read_data<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    limit: usize,
+) -> Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    let mut oversized = false;
+    let mut chunk = Vec::new();
+    // Whether `chunk` starts a line; only then can it be the terminator or
+    // carry a stuffed dot.
+    let mut line_start = true;
+    loop {
+        chunk.clear();
+        let mut bounded = (&mut *reader).take(MAX_LINE_BYTES as u64);
+        let read = tokio::time::timeout(IDLE_TIMEOUT, bounded.read_until(b'\n', &mut chunk))
+            .await
+            .context("timed out during DATA")??;
+        if read == 0 {
+            anyhow::bail!("connection closed during DATA");
+        }
+        let line_end = chunk.ends_with(b"\n");
+        let content = if line_end {
+            let content = &chunk[..chunk.len() - 1];
+            content.strip_suffix(b"\r").unwrap_or(content)
+        } else {
+            &chunk[..]
+        };
+        if line_start && line_end && content == b"." {
+            break;
+        }
+        if !oversized {
+            let content = if line_start {
+                content.strip_prefix(b".").unwrap_or(content)
+            } else {
+                content
+            };
+            // A CRLF split across chunks: the CR was already kept.
+            if line_end && !line_start && content.is_empty() && data.ends_with(b"\r") {
+                data.pop();
+            }
+            data.extend_from_slice(content);
+            if line_end {
+                data.extend_from_slice(b"\r\n");
+            }
+            if data.len() > limit {
+                oversized = true;
+                data = Vec::new();
+            }
+        }
+        line_start = line_end;
+    }
+    Ok((!oversized).then_some(data))
+}
+
+fn path_argument<'a>(arg: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = arg.get(..prefix.len())?;
+    if !head.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = arg[prefix.len()..].trim_start();
+    let path = rest.split_whitespace().next()?;
+    Some(path.trim_start_matches('<').trim_end_matches('>'))
+}
+
+/// The `SIZE=` parameter of MAIL FROM (RFC 1870), if the client sent one.
+fn declared_size(arg: &str) -> Option<usize> {
+    arg.split_whitespace().skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.eq_ignore_ascii_case("SIZE")
+            .then(|| value.parse().ok())
+            .flatten()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    #[derive(Default)]
+    struct FakeMailbox {
+        delivered: Mutex<Vec<(MessageSource, String, Vec<u8>)>>,
+    }
+
+    impl Mailbox for FakeMailbox {
+        async fn accepts(&self, forward_key: &str) -> Result<bool> {
+            Ok(forward_key != "nobody")
+        }
+
+        async fn deliver(
+            &self,
+            source: MessageSource,
+            forward_key: &str,
+            data: &[u8],
+        ) -> Result<Delivery> {
+            self.delivered
+                .lock()
+                .push((source, forward_key.to_string(), data.to_vec()));
+            Ok(Delivery::Logged)
+        }
+    }
+
+    async fn converse(mailbox: &FakeMailbox, protocol: Protocol, input: &str) -> String {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        client_write.write_all(input.as_bytes()).await.unwrap();
+        let shutdown = CancellationToken::new();
+        let session = serve_session(mailbox, server, protocol, &shutdown);
+        let mut output = String::new();
+        let read = async {
+            client_read.read_to_string(&mut output).await.unwrap();
+        };
+        let (result, _) = tokio::join!(session, read);
+        result.unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn lmtp_session_replies_per_recipient() {
+        let mailbox = FakeMailbox::default();
+        let output = converse(
+            &mailbox,
+            Protocol::Lmtp,
+            "LHLO mx.example\r\n\
+             MAIL FROM:<payouts@uber.com>\r\n\
+             RCPT TO:<user-abc123@driversheet.com>\r\n\
+             RCPT TO:<user-nobody@driversheet.com>\r\n\
+             RCPT TO:<User-Def456@DriverSheet.com>\r\n\
+             DATA\r\n\
+             Subject: statement\r\n\
+             \r\n\
+             ..leading dot\r\n\
+             .\r\n\
+             QUIT\r\n",
+        )
+        .await;
+
+        let replies: Vec<&str> = output.lines().collect();
+        assert_eq!(replies[0], "220 driversheet.com LMTP ready");
+        assert_eq!(replies[6], "250 2.1.0 Ok");
+        assert_eq!(replies[7], "250 2.1.5 Ok");
+        assert_eq!(replies[8], "550 5.1.1 No such mailbox");
+        assert_eq!(replies[9], "250 2.1.5 Ok");
+        assert!(replies[10].starts_with("354"));
+        assert_eq!(&replies[11..13], ["250 2.0.0 Ok", "250 2.0.0 Ok"]);
+        assert_eq!(replies[13], "221 2.0.0 Bye");
+
+        let delivered = mailbox.delivered.lock();
+        assert_eq!(delivered.len(), 2);
+        assert_eq!(delivered[0].0, MessageSource::Lmtp);
+        assert_eq!(delivered[0].1, "abc123");
+        assert_eq!(delivered[1].1, "def456");
+        assert_eq!(
+            delivered[0].2,
+            b"Subject: statement\r\n\r\n.leading dot\r\n".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn lmtp_session_enforces_command_order() {
+        let mailbox = FakeMailbox::default();
+        let output = converse(
+            &mailbox,
+            Protocol::Lmtp,
+            "MAIL FROM:<a@b.c>\r\nLHLO x\r\nRCPT TO:<user-abc@driversheet.com>\r\nDATA\r\nQUIT\r\n",
+        )
+        .await;
+
+        let replies: Vec<&str> = output.lines().collect();
+        assert_eq!(replies[1], "503 5.5.1 Send LHLO first");
+        assert_eq!(replies[7], "503 5.5.1 Need MAIL before RCPT");
+        assert_eq!(replies[8], "503 5.5.1 No valid recipients");
+        assert!(mailbox.delivered.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn smtp_session_answers_data_once_for_pipelined_recipients() {
+        let mailbox = FakeMailbox::default();
+        let output = converse(
+            &mailbox,
+            Protocol::Smtp,
+            "EHLO mail.uber.com\r\n\
+             MAIL FROM:<payouts@uber.com> SIZE=2048\r\n\
+             RCPT TO:<user-abc123@driversheet.com>\r\n\
+             RCPT TO:<user-def456@driversheet.com>\r\n\
+             DATA\r\n\
+             Subject: statement\r\n\
+             \r\n\
+             body\r\n\
+             .\r\n\
+             MAIL FROM:<big@uber.com> SIZE=999999999\r\n\
+             QUIT\r\n",
+        )
+        .await;
+
+        let replies: Vec<&str> = output.lines().collect();
+        assert_eq!(replies[0], "220 driversheet.com ESMTP ready");
+        assert!(replies.contains(&"250-PIPELINING"));
+        assert_eq!(
+            &replies[6..9],
+            ["250 2.1.0 Ok", "250 2.1.5 Ok", "250 2.1.5 Ok"]
+        );
+        assert!(replies[9].starts_with("354"));
+        assert_eq!(replies[10], "250 2.0.0 Ok");
+        assert_eq!(replies[11], "552 5.3.4 Message too large");
+        assert_eq!(replies[12], "221 2.0.0 Bye");
+        assert_eq!(replies.len(), 13);
+
+        let delivered = mailbox.delivered.lock();
+        assert_eq!(delivered.len(), 2);
+        assert!(delivered
+            .iter()
+            .all(|(source, _, _)| *source == MessageSource::Smtp));
+    }
+
+    #[tokio::test]
+    async fn data_is_read_in_bounded_chunks() {
+        // A line longer than a chunk, with a dot and a CRLF on the chunk
+        // boundaries, comes through intact.
+        let long = format!("{}.", "x".repeat(MAX_LINE_BYTES - 1));
+        let tail = format!("{}\r", "y".repeat(MAX_LINE_BYTES - 1));
+        let input = format!("..a\r\n{long}\r\n{tail}\n.\r\nQUIT\r\n");
+        let mut reader = input.as_bytes();
+        let data = read_data(&mut reader, 1 << 20).await.unwrap().unwrap();
+        let expected = format!(".a\r\n{long}\r\n{}\r\n", "y".repeat(MAX_LINE_BYTES - 1));
+        assert_eq!(String::from_utf8(data).unwrap(), expected);
+        assert_eq!(
+            read_line(&mut reader).await.unwrap(),
+            Some(CommandLine::Text("QUIT".to_string()))
+        );
+
+        // An unterminated line past the limit is dropped, not buffered, and
+        // the session stays in sync.
+        let input = format!("{}\r\n.\r\nQUIT\r\n", "z".repeat(64 * 1024));
+        let mut reader = input.as_bytes();
+        assert_eq!(read_data(&mut reader, 10_000).await.unwrap(), None);
+        assert_eq!(
+            read_line(&mut reader).await.unwrap(),
+            Some(CommandLine::Text("QUIT".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn overlong_command_lines_are_rejected_whole() {
+        // The tail of the long line must not run as a command of its own.
+        let injected = format!(
+            "NOOP {}RCPT TO:<user-def456@driversheet.com>\r\nQUIT\r\n",
+            "x".repeat(3 * MAX_LINE_BYTES)
+        );
+        let output = converse(&FakeMailbox::default(), Protocol::Smtp, &injected).await;
+        let replies: Vec<&str> = output.lines().collect();
+        assert_eq!(&replies[1..], ["500 5.5.2 Line too long", "221 2.0.0 Bye"]);
+
+        let mut reader = injected.as_bytes();
+        assert_eq!(
+            read_line(&mut reader).await.unwrap(),
+            Some(CommandLine::TooLong)
+        );
+        assert_eq!(
+            read_line(&mut reader).await.unwrap(),
+            Some(CommandLine::Text("QUIT".to_string()))
+        );
+        assert_eq!(read_line(&mut reader).await.unwrap(), None);
+
+        let mut exact = format!("{}\n", "y".repeat(MAX_LINE_BYTES - 1));
+        exact.push_str("QUIT\r\n");
+        let mut reader = exact.as_bytes();
+        assert!(matches!(
+            read_line(&mut reader).await.unwrap(),
+            Some(CommandLine::Text(line)) if line.len() == MAX_LINE_BYTES - 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn session_says_goodbye_on_shutdown() {
+        let mailbox = FakeMailbox::default();
+        let (client, server) = tokio::io::duplex(1024);
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+
+        serve_session(&mailbox, server, Protocol::Smtp, &shutdown)
+            .await
+            .unwrap();
+
+        let mut output = String::new();
+        let (mut client_read, _client_write) = tokio::io::split(client);
+        client_read.read_to_string(&mut output).await.unwrap();
+        assert_eq!(
+            output,
+            "220 driversheet.com ESMTP ready\r\n421 4.3.2 Service shutting down\r\n"
+        );
+    }
+
+    #[test]
+    fn status_combine_prefers_retry_then_success() {
+        use Status::*;
+        assert_eq!(Status::combine(&[Accepted, Deferred]), Deferred);
+        assert_eq!(Status::combine(&[Rejected, Accepted]), Accepted);
+        assert_eq!(Status::combine(&[Rejected, Rejected]), Rejected);
+        assert_eq!(Status::combine(&[TooLarge]), TooLarge);
+    }
+
+    #[test]
+    fn listen_addr_parses_tcp_and_unix() {
+        assert_eq!(
+            "127.0.0.1:24".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp("127.0.0.1:24".parse().unwrap())
+        );
+        assert_eq!(
+            "unix:/run/driversheet/lmtp.sock"
+                .parse::<ListenAddr>()
+                .unwrap(),
+            ListenAddr::Unix(PathBuf::from("/run/driversheet/lmtp.sock"))
+        );
+        assert!("not-an-addr".parse::<ListenAddr>().is_err());
+    }
+}
@@ -1,21 +1,79 @@
-use crate::{config::AppConfig, sheets::SheetsClient};
+use crate::{
+    config::AppConfig, crypto::SecretBox, db, excel::ExcelClient, oauth::OAuthClient,
+    sheets::SheetsClient, webhooks::PublicResolver,
+};
+use anyhow::{Context, Result};
 use std::sync::Arc;
-
-use sqlx::SqlitePool;
+use std::time::Duration;
+use tokio::sync::Notify;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub pool: SqlitePool,
+    pub pool: db::Pool,
     pub sheets: Arc<SheetsClient>,
+    /// Graph client for Excel sinks; always acts as a user.
+    pub excel: Arc<ExcelClient>,
     pub config: Arc<AppConfig>,
+    pub secrets: Option<Arc<SecretBox>>,
+    /// Set when users can connect their own Google account.
+    pub google_oauth: Option<Arc<OAuthClient>>,
+    /// Set when users can connect an Excel workbook.
+    pub microsoft_oauth: Option<Arc<OAuthClient>>,
+    /// Wakes the sheet sync task when a log is queued in `sheet_outbox`.
+    pub sheet_sync: Arc<Notify>,
+    /// Wakes the webhook task when a delivery is queued.
+    pub webhook_delivery: Arc<Notify>,
+    /// Client for webhook deliveries: short timeout, no redirects, and only
+    /// public addresses unless `WEBHOOK_ALLOW_PRIVATE` is set.
+    pub webhook_http: reqwest::Client,
 }
 
 impl AppState {
-    pub fn new(pool: SqlitePool, sheets: SheetsClient, config: AppConfig) -> Self {
-        Self {
+    pub fn new(
+        pool: db::Pool,
+        sheets: SheetsClient,
+        excel: ExcelClient,
+        secrets: Option<SecretBox>,
+        google_oauth: Option<OAuthClient>,
+        microsoft_oauth: Option<OAuthClient>,
+        config: AppConfig,
+    ) -> Result<Self> {
+        let mut webhook_http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .redirect(reqwest::redirect::Policy::none());
+        if !config.webhook_allow_private {
+            webhook_http = webhook_http.dns_resolver(Arc::new(PublicResolver));
+        }
+        // Falling back to a default client would silently drop the resolver
+        // and the redirect policy, so a build failure is fatal.
+        let webhook_http = webhook_http
+            .build()
+            .context("Failed to build the webhook HTTP client")?;
+        Ok(Self {
             pool,
             sheets: Arc::new(sheets),
+            excel: Arc::new(excel),
             config: Arc::new(config),
+            secrets: secrets.map(Arc::new),
+            google_oauth: google_oauth.map(Arc::new),
+            microsoft_oauth: microsoft_oauth.map(Arc::new),
+            sheet_sync: Arc::new(Notify::new()),
+            webhook_delivery: Arc::new(Notify::new()),
+            webhook_http,
+        })
+    }
+
+    /// The Sheets client to use for `user_id`: their own Google account when
+    /// they connected one, otherwise the service account.
+    pub async fn sheets_for(&self, user_id: i64) -> Result<SheetsClient> {
+        if let (Some(oauth), Some(secrets)) = (&self.google_oauth, &self.secrets) {
+            if let Some(credential) = db::google_credential(&self.pool, user_id).await? {
+                if credential.revoked_at.is_none() {
+                    let refresh_token = secrets.open(&credential.refresh_token_enc)?;
+                    return Ok(self.sheets.as_user(oauth.clone(), user_id, refresh_token));
+                }
+            }
         }
+        Ok(SheetsClient::clone(&self.sheets))
     }
 }
@@ -0,0 +1,186 @@
+use crate::db;
+use crate::models::{PeriodTotals, SinkKind};
+use crate::state::AppState;
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::{error, warn};
+
+const WEEKLY_TAB: &str = "DriverSheet Weekly";
+const MONTHLY_TAB: &str = "DriverSheet Monthly";
+
+/// Titles of the summary tabs; mappings may not write logs to them.
+pub const TABS: [&str; 2] = [WEEKLY_TAB, MONTHLY_TAB];
+
+/// Rewrites stale summary tabs every `SUMMARY_REFRESH_SECS`. Syncs, resyncs
+/// and pulled edits only mark the user stale, so a burst of writes costs one
+/// refresh instead of one per batch.
+pub fn spawn_summary_refresh(state: AppState) {
+    let every = Duration::from_secs(state.config.summary_refresh_secs);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(err) = refresh_stale(&state).await {
+                error!(?err, "summary refresh error");
+            }
+        }
+    });
+}
+
+async fn refresh_stale(state: &AppState) -> Result<()> {
+    for user_id in db::take_stale_summaries(&state.pool).await? {
+        let Some(user) = db::user_by_id(&state.pool, user_id).await? else {
+            continue;
+        };
+        let (SinkKind::Sheets, Some(sheet_id)) = (user.sink, &user.sheet_id) else {
+            continue;
+        };
+        if let Err(err) = refresh(state, user_id, sheet_id).await {
+            warn!(user_id, "summary tab refresh failed: {err:#}");
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the weekly and monthly summary tabs with totals computed from
+/// `logs`. Only tabs the worker created are rewritten; a tab of the same name
+/// made by the user is left alone.
+async fn refresh(state: &AppState, user_id: i64, sheet_id: &str) -> Result<()> {
+    let sheets = state.sheets_for(user_id).await?;
+    for (tab, weekly, label) in [(WEEKLY_TAB, true, "Week of"), (MONTHLY_TAB, false, "Month")] {
+        let totals = db::period_totals(&state.pool, user_id, weekly).await?;
+        let owned = db::summary_tab_id(&state.pool, user_id, sheet_id, tab).await?;
+        match sheets
+            .write_table(sheet_id, tab, owned, &table(label, &totals))
+            .await?
+        {
+            Some(tab_id) if owned != Some(tab_id) => {
+                db::save_summary_tab(&state.pool, user_id, sheet_id, tab, tab_id).await?;
+            }
+            Some(_) => {}
+            None => warn!(
+                user_id,
+                "tab {tab:?} was not created by DriverSheet; leaving it"
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// One `All` row per period, followed by a row per platform when the period
+/// has more than one. `totals` must be grouped by period.
+fn table(label: &str, totals: &[PeriodTotals]) -> Vec<Vec<Value>> {
+    let mut rows = vec![vec![
+        json!(label),
+        json!("Platform"),
+        json!("Gross"),
+        json!("Tips"),
+        json!("Mileage"),
+        json!("Payouts"),
+    ]];
+    for period in totals.chunk_by(|a, b| a.period == b.period) {
+        let all = PeriodTotals {
+            period: period[0].period.clone(),
+            platform: "All".to_string(),
+            gross: period.iter().map(|t| t.gross).sum(),
+            tips: period.iter().map(|t| t.tips).sum(),
+            mileage: period.iter().map(|t| t.mileage).sum(),
+            payouts: period.iter().map(|t| t.payouts).sum(),
+        };
+        rows.push(row(&all));
+        if period.len() > 1 {
+            rows.extend(period.iter().map(row));
+        }
+    }
+    rows
+}
+
+fn row(totals: &PeriodTotals) -> Vec<Value> {
+    vec![
+        json!(totals.period),
+        json!(totals.platform),
+        json!(totals.gross.to_f64()),
+        json!(totals.tips.to_f64()),
+        json!((totals.mileage * 100.0).round() / 100.0),
+        json!(totals.payouts),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Cents;
+    use crate::sheets_mock::{test_user, MockSheets};
+
+    fn totals(period: &str, platform: &str, gross: f64) -> PeriodTotals {
+        PeriodTotals {
+            period: period.to_string(),
+            platform: platform.to_string(),
+            gross: Cents::from_f64(gross).unwrap(),
+            tips: Cents(100),
+            mileage: 0.0,
+            payouts: 1,
+        }
+    }
+
+    #[test]
+    fn table_adds_all_rows_and_splits_multi_platform_periods() {
+        let rows = table(
+            "Month",
+            &[
+                totals("2024-09", "Uber", 10.1),
+                totals("2024-08", "Lyft", 5.0),
+                totals("2024-08", "Uber", 7.2),
+            ],
+        );
+
+        let summary: Vec<(Value, Value, Value)> = rows
+            .iter()
+            .map(|r| (r[0].clone(), r[1].clone(), r[2].clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (json!("Month"), json!("Platform"), json!("Gross")),
+                (json!("2024-09"), json!("All"), json!(10.1)),
+                (json!("2024-08"), json!("All"), json!(12.2)),
+                (json!("2024-08"), json!("Lyft"), json!(5.0)),
+                (json!("2024-08"), json!("Uber"), json!(7.2)),
+            ]
+        );
+        assert_eq!(rows[2][5], json!(2));
+    }
+
+    #[tokio::test]
+    async fn refresh_only_rewrites_tabs_it_created() {
+        let (mock, url) = MockSheets::start().await;
+        mock.add_spreadsheet("sheet-1", &["Sheet1", WEEKLY_TAB]);
+        mock.set_cell("sheet-1", WEEKLY_TAB, 0, 0, json!("my own notes"));
+        let state = MockSheets::app_state(&url).await;
+        let user = test_user(&state.pool, Some("sheet-1")).await;
+
+        // Marked twice, refreshed once.
+        db::mark_summary_stale(&state.pool, user.id).await.unwrap();
+        db::mark_summary_stale(&state.pool, user.id).await.unwrap();
+        refresh_stale(&state).await.unwrap();
+        let requests = mock.bearers().len();
+        refresh_stale(&state).await.unwrap();
+        assert_eq!(mock.bearers().len(), requests);
+
+        db::mark_summary_stale(&state.pool, user.id).await.unwrap();
+        refresh_stale(&state).await.unwrap();
+        assert_eq!(
+            mock.rows("sheet-1", WEEKLY_TAB),
+            [vec![json!("my own notes")]]
+        );
+        assert_eq!(mock.rows("sheet-1", MONTHLY_TAB)[0][0], json!("Month"));
+        assert_eq!(mock.tabs("sheet-1"), ["Sheet1", WEEKLY_TAB, MONTHLY_TAB]);
+        assert!(
+            db::summary_tab_id(&state.pool, user.id, "sheet-1", MONTHLY_TAB)
+                .await
+                .unwrap()
+                .is_some()
+        );
+    }
+}
@@ -0,0 +1,426 @@
+use crate::db;
+use crate::models::{DueDelivery, LogEntry, LogEvent, Webhook, WebhookDelivery};
+use crate::money::{Cents, DEFAULT_CURRENCY};
+use crate::outbox::{backoff_secs, MAX_ATTEMPTS};
+use crate::state::AppState;
+use anyhow::Result;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+pub const SIGNATURE_HEADER: &str = "X-DriverSheet-Signature";
+pub const EVENT_HEADER: &str = "X-DriverSheet-Event";
+pub const DELIVERY_HEADER: &str = "X-DriverSheet-Delivery";
+
+const BATCH_SIZE: i64 = 100;
+/// Response bodies kept in the delivery log are cut to this many bytes.
+const MAX_ERROR_BODY: usize = 500;
+
+/// Drains `webhook_deliveries` every `WEBHOOK_DELIVERY_SECS` and whenever a
+/// log change is queued.
+pub fn spawn_webhook_delivery(state: AppState) {
+    let every = Duration::from_secs(state.config.webhook_delivery_secs);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.webhook_delivery.notified() => {}
+            }
+            if let Err(err) = deliver_due(&state).await {
+                error!(?err, "webhook delivery error");
+            }
+        }
+    });
+}
+
+/// Sends every due delivery once.
+pub async fn deliver_due(state: &AppState) -> Result<()> {
+    loop {
+        let due = db::due_webhook_deliveries(&state.pool, BATCH_SIZE).await?;
+        let batch_len = due.len() as i64;
+        for delivery in due {
+            deliver(state, &delivery, true).await?;
+        }
+        if batch_len < BATCH_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends a `test` event to the webhook right away, without retries, and
+/// returns its delivery log entry.
+pub async fn send_test(state: &AppState, webhook: &Webhook) -> Result<WebhookDelivery> {
+    let now = Utc::now().naive_utc();
+    let sample = LogEntry {
+        id: 0,
+        user_id: webhook.user_id,
+        order_date: now.date(),
+        gross: Cents(0),
+        tips: Cents(0),
+        currency: DEFAULT_CURRENCY.to_string(),
+        mileage: None,
+        platform: None,
+        parsed_at: now,
+        sync_status: None,
+        sync_error: None,
+    };
+    let delivery =
+        db::insert_webhook_delivery(&state.pool, webhook, &LogEvent::new("test", &sample)).await?;
+    deliver(state, &delivery, false).await?;
+    db::webhook_delivery(&state.pool, delivery.id).await
+}
+
+/// POSTs the payload and records the outcome. Any 2xx response counts as
+/// delivered; anything else is retried with backoff when `retry` is set.
+async fn deliver(state: &AppState, delivery: &DueDelivery, retry: bool) -> Result<()> {
+    if !state.config.webhook_allow_private {
+        if let Ok(url) = reqwest::Url::parse(&delivery.url) {
+            // Checked again on every attempt: the host's DNS may have been
+            // changed to an internal address since the webhook was created.
+            if let Err(err) = check_public(&url).await {
+                if err.is::<NonPublicHost>() {
+                    warn!(
+                        user_id = delivery.user_id,
+                        webhook_id = delivery.webhook_id,
+                        "webhook delivery refused: {err}"
+                    );
+                    let message = format!("{err}");
+                    return db::record_webhook_failure(
+                        &state.pool,
+                        delivery.id,
+                        None,
+                        &message,
+                        None,
+                    )
+                    .await;
+                }
+            }
+        }
+    }
+    let result = state
+        .webhook_http
+        .post(&delivery.url)
+        .header("Content-Type", "application/json")
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, delivery.id.to_string())
+        .header(SIGNATURE_HEADER, sign(&delivery.secret, &delivery.payload))
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+    let (status, message) = match result {
+        Ok(response) if response.status().is_success() => {
+            let status = i64::from(response.status().as_u16());
+            return db::mark_webhook_delivered(&state.pool, delivery.id, status).await;
+        }
+        Ok(response) => {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            let body: String = body.chars().take(MAX_ERROR_BODY).collect();
+            (
+                Some(i64::from(status.as_u16())),
+                format!("{status}: {body}").trim().to_string(),
+            )
+        }
+        Err(err) => (None, format!("{:#}", anyhow::Error::from(err))),
+    };
+
+    let attempts = delivery.attempts + 1;
+    let retry_in = (retry && attempts < MAX_ATTEMPTS).then(|| backoff_secs(attempts));
+    match retry_in {
+        Some(delay) => info!(
+            webhook_id = delivery.webhook_id,
+            delivery_id = delivery.id,
+            attempts,
+            "webhook delivery retrying in {delay}s: {message}"
+        ),
+        None => warn!(
+            user_id = delivery.user_id,
+            webhook_id = delivery.webhook_id,
+            delivery_id = delivery.id,
+            "webhook delivery failed: {message}"
+        ),
+    }
+    db::record_webhook_failure(&state.pool, delivery.id, status, &message, retry_in).await
+}
+
+/// The webhook URL points at a loopback, private, link-local or otherwise
+/// non-public address. Deliveries there would let users read responses from
+/// services on our network.
+#[derive(Debug, Error)]
+#[error("{host} is not a public address")]
+pub struct NonPublicHost {
+    pub host: String,
+}
+
+/// Resolves the URL's host and fails with `NonPublicHost` unless every
+/// address is public. Lookup failures are returned as other errors.
+pub async fn check_public(url: &reqwest::Url) -> Result<()> {
+    let port = url.port_or_known_default().unwrap_or(443);
+    resolve_public(url.host_str().unwrap_or_default(), port).await?;
+    Ok(())
+}
+
+/// Resolves `host` and returns its addresses, failing with `NonPublicHost`
+/// unless every one is public. Connecting to exactly these addresses leaves
+/// no second lookup to rebind.
+pub async fn resolve_public(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    // IPv6 literals come bracketed, `[::1]`.
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = match literal.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) if host.is_empty() => Vec::new(),
+        Err(_) => tokio::net::lookup_host((host, port)).await?.collect(),
+    };
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+        return Err(NonPublicHost {
+            host: host.to_string(),
+        }
+        .into());
+    }
+    Ok(addrs)
+}
+
+/// Whether `ip` is routable on the public internet.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public_v4(mapped),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (carrier-grade NAT), 100.64.0.0/10.
+        || (a == 100 && (64..128).contains(&b))
+        // Benchmarking, 198.18.0.0/15.
+        || (a == 198 && (b == 18 || b == 19))
+        // Reserved, 240.0.0.0/4.
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, fc00::/7.
+        || (first & 0xfe00) == 0xfc00
+        // Link-local, fe80::/10.
+        || (first & 0xffc0) == 0xfe80
+        // Documentation, 2001:db8::/32.
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// DNS resolver for the webhook client that drops non-public addresses, so
+/// a host that passed `check_public` cannot be re-pointed at an internal
+/// address between the check and the connection.
+pub struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: hyper_014::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                let err = NonPublicHost {
+                    host: name.as_str().to_string(),
+                };
+                return Err(err.into());
+            }
+            let addrs: reqwest::dns::Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Hex HMAC-SHA256 of the body, keyed with the webhook's secret; the same
+/// scheme we verify Lemon's webhooks with.
+pub fn sign(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(payload.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub fn new_secret() -> String {
+    let mut bytes = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("whsec_{}", hex::encode(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::NewLogEntry;
+    use crate::sheets_mock::{test_entry, test_user, MockSheets};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// A receiver that answers with the queued statuses (then 200) and keeps
+    /// every request.
+    #[derive(Clone, Default)]
+    struct Receiver {
+        statuses: Arc<Mutex<Vec<u16>>>,
+        received: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+    }
+
+    async fn start_receiver(statuses: &[u16]) -> (Receiver, String) {
+        let receiver = Receiver::default();
+        receiver.statuses.lock().extend(statuses.iter().rev());
+        let handler = {
+            let receiver = receiver.clone();
+            move |headers: HeaderMap, body: String| async move {
+                receiver.received.lock().push((headers, body));
+                let status = receiver.statuses.lock().pop().unwrap_or(200);
+                StatusCode::from_u16(status).unwrap()
+            }
+        };
+        let app = Router::new().route("/hook", post(handler));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (receiver, url)
+    }
+
+    #[tokio::test]
+    async fn log_events_are_signed_and_retried() {
+        let (_mock, url) = MockSheets::start().await;
+        let state = MockSheets::app_state(&url).await;
+        let (receiver, hook_url) = start_receiver(&[500]).await;
+        let user = test_user(&state.pool, None).await;
+        let webhook = db::create_webhook(&state.pool, user.id, &hook_url, &new_secret())
+            .await
+            .unwrap();
+        let entry = NewLogEntry {
+            platform: Some("uber".to_string()),
+            ..test_entry(&user, "2024-08-15", Cents(1000))
+        };
+        let log = db::insert_log(&state.pool, entry).await.unwrap();
+
+        deliver_due(&state).await.unwrap();
+        let deliveries = db::webhook_deliveries(&state.pool, webhook.id, 10)
+            .await
+            .unwrap();
+        assert_eq!(deliveries[0].status, "pending");
+        assert_eq!(deliveries[0].response_status, Some(500));
+
+        sqlx::query("UPDATE webhook_deliveries SET next_attempt = CURRENT_TIMESTAMP")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        deliver_due(&state).await.unwrap();
+        let deliveries = db::webhook_deliveries(&state.pool, webhook.id, 10)
+            .await
+            .unwrap();
+        assert_eq!(deliveries[0].status, "delivered");
+        assert_eq!(deliveries[0].attempts, 2);
+        assert_eq!(deliveries[0].log_id, Some(log.id));
+
+        let received = receiver.received.lock().clone();
+        assert_eq!(received.len(), 2);
+        let (headers, body) = &received[1];
+        assert_eq!(headers[SIGNATURE_HEADER], sign(&webhook.secret, body));
+        assert_eq!(headers[EVENT_HEADER], "log.created");
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["event"], "log.created");
+        assert_eq!(payload["log"]["id"], log.id);
+        assert_eq!(payload["log"]["orderDate"], "2024-08-15");
+        assert_eq!(payload["log"]["platform"], "uber");
+
+        db::delete_log(&state.pool, user.id, log.id).await.unwrap();
+        deliver_due(&state).await.unwrap();
+        let (headers, _) = receiver.received.lock().last().cloned().unwrap();
+        assert_eq!(headers[EVENT_HEADER], "log.deleted");
+    }
+
+    #[tokio::test]
+    async fn test_events_are_not_retried() {
+        let (_mock, url) = MockSheets::start().await;
+        let state = MockSheets::app_state(&url).await;
+        let (receiver, hook_url) = start_receiver(&[410]).await;
+        let user = test_user(&state.pool, None).await;
+        let webhook = db::create_webhook(&state.pool, user.id, &hook_url, "secret")
+            .await
+            .unwrap();
+
+        let delivery = send_test(&state, &webhook).await.unwrap();
+        assert_eq!(delivery.status, "failed");
+        assert_eq!(delivery.response_status, Some(410));
+        assert_eq!(delivery.log_id, None);
+        deliver_due(&state).await.unwrap();
+        assert_eq!(receiver.received.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn only_public_addresses_are_delivered_to() {
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(private.parse().unwrap()), "{private}");
+        }
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(is_public("2606:4700::1111".parse().unwrap()));
+        for url in [
+            "http://localhost:8080/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+        ] {
+            let err = check_public(&reqwest::Url::parse(url).unwrap())
+                .await
+                .unwrap_err();
+            assert!(err.is::<NonPublicHost>(), "{url}: {err}");
+        }
+
+        // A stored webhook whose host now resolves internally is refused at
+        // delivery time, without retries.
+        let (_mock, url) = MockSheets::start().await;
+        let mut state = MockSheets::app_state(&url).await;
+        let mut config = (*state.config).clone();
+        config.webhook_allow_private = false;
+        state.config = Arc::new(config);
+        let (receiver, hook_url) = start_receiver(&[]).await;
+        let user = test_user(&state.pool, None).await;
+        let webhook = db::create_webhook(&state.pool, user.id, &hook_url, "secret")
+            .await
+            .unwrap();
+        let delivery = send_test(&state, &webhook).await.unwrap();
+        assert_eq!(delivery.status, "failed");
+        assert!(delivery
+            .last_error
+            .unwrap()
+            .contains("is not a public address"));
+        assert!(receiver.received.lock().is_empty());
+    }
+}